@@ -0,0 +1,84 @@
+//! Compares hashing large files as a stream with storing them, which hashes the staged copy in
+//! parallel where the algorithm supports it.
+//!
+//! Run with `cargo bench --bench hashing`. The size of the test file in MiB can be set with
+//! `GIT_ASSETS_BENCH_MIB`, it should exceed the CPU caches by far for meaningful results.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use git_assets_lib::hash::{ContentHash, SHA256, SHA256_TREE};
+use git_assets_lib::store::Store;
+
+/// How often each benchmark is repeated; the fastest run is reported.
+const RUNS: usize = 5;
+
+fn main() -> io::Result<()> {
+    let mib: usize = std::env::var("GIT_ASSETS_BENCH_MIB")
+        .ok()
+        .and_then(|mib| mib.parse().ok())
+        .unwrap_or(256);
+    let base_dir = std::env::temp_dir().join(format!("git-assets.bench.{}", std::process::id()));
+    std::fs::create_dir_all(&base_dir)?;
+    let input = base_dir.join("input");
+    write_input(&input, mib)?;
+    let store = Store::open_or_create(base_dir.join("store"))?;
+
+    println!(
+        "hashing and storing a {} MiB file, best of {} runs",
+        mib, RUNS
+    );
+    bench("hash_stream", mib, || {
+        ContentHash::hash_stream(SHA256, &mut File::open(&input)?).map(drop)
+    })?;
+    bench("hash_file", mib, || {
+        ContentHash::hash_file(SHA256, &mut File::open(&input)?).map(drop)
+    })?;
+    bench("store from stdin", mib, || {
+        let mut staging_file = store.new_staging_file()?;
+        io::copy(&mut File::open(&input)?, &mut staging_file)?;
+        store.make_permanent(staging_file).map(drop)
+    })?;
+    bench("store_path", mib, || store.store_path(&input).map(drop))?;
+    let tree_store = Store::open_or_create_with(base_dir.join("tree-store"), SHA256_TREE)?;
+    bench("store_path (tree)", mib, || {
+        tree_store.store_path(&input).map(drop)
+    })?;
+
+    std::fs::remove_dir_all(base_dir)
+}
+
+/// Write `mib` MiB of incompressible data to `path`.
+fn write_input(path: &Path, mib: usize) -> io::Result<()> {
+    let mut file = io::BufWriter::new(File::create(path)?);
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut block = vec![0u8; 1024 * 1024];
+    for _ in 0..mib {
+        for byte in block.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        file.write_all(&block)?;
+    }
+    file.flush()
+}
+
+fn bench<F: FnMut() -> io::Result<()>>(name: &str, mib: usize, mut run: F) -> io::Result<()> {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        run()?;
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "{:>20}: {:>8.1} ms, {:>7.1} MiB/s",
+        name,
+        best.as_secs_f64() * 1000.0,
+        mib as f64 / best.as_secs_f64()
+    );
+    Ok(())
+}
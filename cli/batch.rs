@@ -0,0 +1,48 @@
+//! Framing for `--batch` modes, which process many files in a single invocation.
+//!
+//! Every payload, in both directions, is preceded by its length in bytes as a decimal number on
+//! a line of its own. A request that fails is answered by a line `error <message>` instead.
+
+use std::io::{self, BufRead, Read, Write};
+
+/// Read the length line of the next payload, or return `None` at the end of the input.
+pub fn read_length<R: BufRead>(input: &mut R) -> io::Result<Option<u64>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    line.trim_end_matches('\n').parse().map(Some).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected the length of the next payload, got {:?}", line),
+        )
+    })
+}
+
+/// Read a whole payload of the given length.
+pub fn read_payload<R: Read>(input: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    input.take(length).read_to_end(&mut payload)?;
+    if payload.len() as u64 != length {
+        return Err(truncated());
+    }
+    Ok(payload)
+}
+
+/// The error for input that ends in the middle of a payload.
+pub fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "input ends within a payload")
+}
+
+/// Write a complete payload with its length line.
+pub fn write_payload<W: Write>(out: &mut W, payload: &[u8]) -> io::Result<()> {
+    writeln!(out, "{}", payload.len())?;
+    out.write_all(payload)
+}
+
+/// Answer a request that failed.
+pub fn write_error<W: Write>(out: &mut W, error: &dyn std::fmt::Display) -> io::Result<()> {
+    // The message has to stay on one line
+    let message = error.to_string().replace('\n', " ");
+    writeln!(out, "error {}", message)
+}
@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 
+/// The classes of failures, each of which exits with its own status, see `exit_code`.
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum CliErrorKind {
     /// No store path has been specified, but the command was not run within a git repository.
@@ -12,6 +13,22 @@ pub enum CliErrorKind {
     NoSuchContent,
     /// The store is in an inconsistent state
     Inconsistent,
+    /// Running git failed.
+    Git,
+    /// The requested output format is not supported by the command.
+    UnsupportedFormat,
+    /// Copying objects from or to another store failed.
+    Transfer,
+    /// The command needs a remote store, but none was configured.
+    NoRemote,
+    /// A hash given on the command line is invalid or matches several objects.
+    InvalidHash,
+    /// An object cannot be deleted because it is still referenced.
+    StillReferenced,
+    /// An object is not signed by a trusted signer, although signatures are verified.
+    BadSignature,
+    /// A setting from a configuration file, the git config or the environment is invalid.
+    InvalidConfig,
     /// An unexpected error occurred.
     UnexpectedError,
 }
@@ -38,10 +55,54 @@ impl CliError {
         Self::with_source(CliErrorKind::NoSuchContent, Box::new(source))
     }
 
+    pub fn invalid_hash<E: Error + 'static>(source: E) -> Self {
+        Self::with_source(CliErrorKind::InvalidHash, Box::new(source))
+    }
+
+    pub fn invalid_config<E: Error + 'static>(source: E) -> Self {
+        Self::with_source(CliErrorKind::InvalidConfig, Box::new(source))
+    }
+
+    pub fn git<E: Error + 'static>(source: E) -> Self {
+        Self::with_source(CliErrorKind::Git, Box::new(source))
+    }
+
+    pub fn inconsistent<E: Error + 'static>(source: E) -> Self {
+        Self::with_source(CliErrorKind::Inconsistent, Box::new(source))
+    }
+
+    pub fn transfer<E: Error + 'static>(source: E) -> Self {
+        Self::with_source(CliErrorKind::Transfer, Box::new(source))
+    }
+
     pub fn kind(&self) -> CliErrorKind {
         self.kind
     }
+}
 
+impl CliErrorKind {
+    /// The exit status of the process when failing with this kind of error.
+    ///
+    /// These are part of the interface, so that scripts and hooks can tell failures apart, and
+    /// must not change. Status 1 is left for unexpected errors and for commands like `exists`
+    /// that answer "no" by exiting with it.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            CliErrorKind::UnexpectedError => 1,
+            CliErrorKind::NotInGitRepo => 2,
+            CliErrorKind::NoSuchContent => 3,
+            CliErrorKind::Inconsistent => 4,
+            CliErrorKind::Transfer => 5,
+            CliErrorKind::NoRemote => 6,
+            CliErrorKind::StoreAccess => 7,
+            CliErrorKind::Git => 8,
+            CliErrorKind::InvalidConfig => 9,
+            CliErrorKind::UnsupportedFormat => 10,
+            CliErrorKind::InvalidHash => 11,
+            CliErrorKind::StillReferenced => 12,
+            CliErrorKind::BadSignature => 13,
+        }
+    }
 }
 
 impl fmt::Display for CliErrorKind {
@@ -51,6 +112,20 @@ impl fmt::Display for CliErrorKind {
             CliErrorKind::StoreAccess => "Could not access the data store due to some underlying error.",
             CliErrorKind::NoSuchContent => "A referenced content file was not found.",
             CliErrorKind::Inconsistent => "The store is in an inconsistent state.",
+            CliErrorKind::Git => "Running git failed.",
+            CliErrorKind::UnsupportedFormat => {
+                "The requested output format is not supported by this command."
+            }
+            CliErrorKind::Transfer => "Copying objects between stores failed.",
+            CliErrorKind::NoRemote => {
+                "No remote store has been configured, pass --remote, set GIT_ASSETS_REMOTE or the `remote` setting, e.g. with git config assets.remote."
+            }
+            CliErrorKind::InvalidHash => "The given hash is invalid or ambiguous.",
+            CliErrorKind::StillReferenced => "The object is still referenced.",
+            CliErrorKind::BadSignature => "The object is not signed by a trusted signer.",
+            CliErrorKind::InvalidConfig => {
+                "A setting of git-assets, from a configuration file, the git config or the environment, is invalid."
+            }
             CliErrorKind::UnexpectedError => "An unexpected error occurred.",
         };
         f.write_str(msg)
@@ -77,10 +152,7 @@ impl Error for CliError {
 
 impl From<CliErrorKind> for CliError {
     fn from(kind: CliErrorKind) -> Self {
-        CliError {
-            kind,
-            source: None,
-        }
+        CliError { kind, source: None }
     }
 }
 
@@ -0,0 +1,109 @@
+//! Diagnostic messages on stderr, filtered by the verbosity flags and `GIT_ASSETS_LOG`.
+//!
+//! `GIT_ASSETS_LOG` takes a comma-separated list of directives that override the flags. Each
+//! is either a level for everything, e.g. `debug`, or a module path and a level for that module,
+//! e.g. `git_assets_lib::backend=trace`. The most specific directive for a module applies.
+
+use std::io;
+use std::time::Instant;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Environment variable with the directives that select which messages are printed.
+pub const LOG_ENV: &str = "GIT_ASSETS_LOG";
+
+struct Logger {
+    /// Level for modules without a directive of their own.
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    /// Messages are prefixed with the time since this instant, to see where time is spent.
+    start: Instant,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "git-assets: [{:8.3}s {:<5} {}] {}",
+                self.start.elapsed().as_secs_f64(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger. Without `GIT_ASSETS_LOG`, only warnings and errors are printed, each
+/// `verbose` step adds a level, and `quiet` leaves only errors.
+pub fn init(verbose: u64, quiet: bool) -> io::Result<()> {
+    let default = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let mut logger = Logger {
+        default,
+        modules: Vec::new(),
+        start: Instant::now(),
+    };
+    if let Some(directives) = std::env::var_os(LOG_ENV) {
+        let directives = directives.to_string_lossy();
+        parse_directives(&mut logger, &directives).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: {}", LOG_ENV, message),
+            )
+        })?;
+    }
+    let max_level = logger
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.default, std::cmp::max);
+    log::set_max_level(max_level);
+    log::set_logger(Box::leak(Box::new(logger))).map_err(|err| io::Error::other(err.to_string()))
+}
+
+fn parse_directives(logger: &mut Logger, directives: &str) -> Result<(), String> {
+    let parse_level = |level: &str| {
+        level.parse::<LevelFilter>().map_err(|_| {
+            format!(
+                "invalid level {:?}, use off, error, warn, info, debug or trace",
+                level
+            )
+        })
+    };
+    for directive in directives.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            _ if directive.is_empty() => {}
+            Some((module, level)) => logger
+                .modules
+                .push((module.trim().to_string(), parse_level(level.trim())?)),
+            None => logger.default = parse_level(directive)?,
+        }
+    }
+    Ok(())
+}
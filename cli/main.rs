@@ -1,129 +1,5318 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::env;
 use std::error::Error;
-use std::io::{self};
-use std::path::PathBuf;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
 
+use serde::Serialize;
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
-use git_assets_lib;
-use git_assets_lib::store;
+use git_assets_lib::annex;
+#[cfg(feature = "archive")]
+use git_assets_lib::archive;
+use git_assets_lib::attributes;
+use git_assets_lib::config;
+use git_assets_lib::date::civil_date;
+use git_assets_lib::git;
+use git_assets_lib::hash::{self, ContentHash, HashAlgorithm};
+use git_assets_lib::history;
+use git_assets_lib::hooks;
+use git_assets_lib::lfs;
+use git_assets_lib::media_type;
+use git_assets_lib::metadata;
+use git_assets_lib::parallel;
+use git_assets_lib::server;
+use git_assets_lib::signing;
+use git_assets_lib::store::{self, PointerFormat};
+
+mod batch;
 
 mod errors;
 use errors::{CliError, CliErrorKind};
 
+mod logging;
+
+mod progress;
+use progress::{format_bytes, Progress, ProgressBar};
+
 type CliResult<T> = Result<T, CliError>;
 
 #[derive(StructOpt)]
 #[structopt(about = "binary asset handling for git")]
 struct GitAssets {
+    /// The store, a directory or URL. Defaults to the environment variable `GIT_ASSETS_STORE`,
+    /// the `store` setting, or else `x-assets` in the git directory of the repository.
     #[structopt(long, short, parse(from_os_str))]
     store: Option<PathBuf>,
+    /// Other stores on this machine (e.g. of sibling clones), or URLs of peers in the LAN
+    /// running `serve`, to copy objects from when they are missing in the store, before the
+    /// remote. May be given multiple times. Defaults to the `alternates` setting, e.g. the
+    /// `assets.alternate` git config values.
+    #[structopt(long = "alternate", parse(from_os_str), number_of_values = 1)]
+    alternates: Vec<PathBuf>,
+    /// Remote store (a directory or URL) that objects are pushed to and fetched from.
+    /// Defaults to the environment variable `GIT_ASSETS_REMOTE`, or else the `remote` setting,
+    /// e.g. the `assets.remote` git config value.
+    #[structopt(long, parse(from_os_str))]
+    remote: Option<PathBuf>,
+    /// Print machine-readable JSON on stdout, like `--format json` does for a single command.
+    /// Fails for commands that have no JSON output.
+    #[structopt(long)]
+    json: bool,
+    /// Print what is being done and how long it takes on stderr. Given twice, print details
+    /// such as every git command that is run. The environment variable `GIT_ASSETS_LOG`
+    /// overrides this, e.g. `GIT_ASSETS_LOG=git_assets_lib::backend=trace`.
+    #[structopt(long, short, parse(from_occurrences))]
+    verbose: u64,
+    /// Print only errors, not even warnings or progress.
+    #[structopt(long, short, conflicts_with = "verbose")]
+    quiet: bool,
     #[structopt(subcommand)]
     command: Command,
 }
 
 #[derive(StructOpt)]
 enum Command {
+    /// Configure git to use git-assets as the `assets` filter, and create the store.
+    ///
+    /// Files are then managed by git-assets once they are marked with `filter=assets` in
+    /// `.gitattributes`.
+    Install {
+        /// Configure the filter for all repositories of the current user instead of only the
+        /// current one.
+        #[structopt(long)]
+        global: bool,
+    },
+    /// Set up the current repository in one step: install the filter, create the store,
+    /// track the given patterns and register the repository with the store.
+    Init {
+        /// Patterns of files to track, e.g. `*.psd`.
+        patterns: Vec<String>,
+        /// Address objects by this hash algorithm: `sha256` (the default), `sha512_256`, or
+        /// `sha256_tree`, which hashes large files on all cores when storing them by path.
+        /// It can only be chosen while the store is still empty.
+        #[structopt(long, parse(try_from_str = parse_algorithm))]
+        hash: Option<&'static dyn HashAlgorithm>,
+    },
+    /// Remove the filter configuration written by `install`.
+    Uninstall {
+        /// Remove the filter from the global git config instead of the current repository.
+        #[structopt(long)]
+        global: bool,
+        /// Replace pointer files in the working tree by the contents they refer to.
+        #[structopt(long)]
+        smudge: bool,
+        /// Delete the store of the repository, including all objects in it.
+        #[structopt(long)]
+        delete_store: bool,
+    },
+    /// Record that the current repository uses the store, so that `gc` and `delete` also keep
+    /// the objects it references.
+    Register {
+        /// List the registered repositories instead.
+        #[structopt(long)]
+        list: bool,
+    },
+    /// Remove the registration of the current repository, or of the repository with the given
+    /// git directory, e.g. one that has been deleted.
+    Unregister {
+        /// The git directory of the repository, the current one by default.
+        #[structopt(parse(from_os_str))]
+        git_dir: Option<PathBuf>,
+    },
+    /// Route files matching the given patterns through the filter by adding them to the
+    /// `.gitattributes` file at the root of the repository.
+    Track {
+        /// Patterns in `.gitattributes` syntax, e.g. `*.psd`.
+        #[structopt(required_unless = "above")]
+        patterns: Vec<String>,
+        /// Also track the files in the working tree that are larger than this size, e.g.
+        /// `5MiB`, by adding patterns for their extensions. Files without an extension are
+        /// added individually.
+        #[structopt(long, parse(try_from_str = config::parse_size))]
+        above: Option<u64>,
+        /// With `--above`, add each large file individually instead of its extension.
+        #[structopt(long, requires = "above")]
+        per_file: bool,
+    },
+    /// Stop routing files matching the given patterns through the filter, by removing them
+    /// from the `.gitattributes` file at the root of the repository.
+    Untrack {
+        /// Patterns as given to `track`.
+        #[structopt(required = true)]
+        patterns: Vec<String>,
+        /// Put the contents of files that are no longer tracked into the index, so that they
+        /// are committed instead of pointers.
+        #[structopt(long)]
+        restore: bool,
+    },
     /// Store the contents received on stdin in the store, and print a reference to the file on stdout.
     ///
     /// To be used as a git clean filter.
-    StoreFile,
+    StoreFile {
+        /// Store the contents of this file instead of stdin, which is faster for large files.
+        #[structopt(parse(from_os_str), conflicts_with = "batch")]
+        file: Option<PathBuf>,
+        /// Split large files into content defined chunks, so that revisions that differ only
+        /// slightly share most of their storage. Also enabled by the `chunked` setting.
+        #[structopt(long)]
+        chunked: bool,
+        /// Store many files in one go: read each file as its length in bytes on a line of its
+        /// own followed by the contents, and answer each with a reference framed the same way.
+        #[structopt(long)]
+        batch: bool,
+        /// Format of the printed reference: `v1`, `v2`, which also records the size and media
+        /// type of the contents, `json`, which records the same as a JSON object, or `lfs` for
+        /// Git LFS pointers. Defaults to the `pointer` setting, or else `v1`.
+        #[structopt(long)]
+        pointer: Option<PointerFormat>,
+        /// Pass contents smaller than this size, e.g. `4KiB`, through unchanged instead of
+        /// storing them. Defaults to the `min-size` setting, or else 0.
+        #[structopt(long, parse(try_from_str = config::parse_size))]
+        min_size: Option<u64>,
+    },
     /// Read a reference to the file contents from stdin, and write the contents to stdout.
     ///
-    /// To be used as a git smudge filter.
-    RetrieveFile,
+    /// To be used as a git smudge filter. If the environment variable `GIT_ASSETS_SKIP_SMUDGE`
+    /// or else the git config `assets.skipSmudge` is true, the reference is written unchanged
+    /// instead, and `checkout` can replace it by the contents later. Input that is not a
+    /// reference, e.g. a small file passed through by `store-file --min-size`, is written
+    /// unchanged as well.
+    RetrieveFile {
+        /// Retrieve many files in one go: read each reference as its length in bytes on a line
+        /// of its own followed by the reference, and answer each with the contents framed the
+        /// same way, or with a line `error <message>`.
+        #[structopt(long)]
+        batch: bool,
+    },
+    /// Replace pointer files in the working tree by their contents, e.g. after checking out
+    /// with `GIT_ASSETS_SKIP_SMUDGE`.
+    ///
+    /// Files skipped with `--pointer` are only checked out again when a pattern names them.
+    Checkout {
+        /// Only check out files matching any of these patterns, in `.gitattributes` syntax.
+        patterns: Vec<String>,
+        /// Replace the unmodified files matching the patterns by their pointers instead, to
+        /// free space, and record them as skipped.
+        #[structopt(long)]
+        pointer: bool,
+    },
     /// Validate the store contents, i.e. that all data files are consistent (their name matches the hash),
     /// and that there are no unexpected files that don't belong there.
-    Validate,
+    Validate {
+        /// The number of objects to hash in parallel. Defaults to the `jobs` setting, or else
+        /// one per CPU.
+        #[structopt(long, short)]
+        jobs: Option<usize>,
+        /// Hash all objects, including those that are unchanged since they were last verified.
+        #[structopt(long)]
+        full: bool,
+        /// Repair the store instead of failing: move corrupt objects into `lost+found/`, delete
+        /// unexpected files and remove staging files older than a day.
+        #[structopt(long)]
+        fix: bool,
+        /// With `--fix`, only print what would be repaired, without changing anything.
+        #[structopt(long, short = "n", requires = "fix")]
+        dry_run: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// List every asset referenced by a commit, with its hash and size.
+    Manifest {
+        /// The revision to list the assets of.
+        #[structopt(default_value = "HEAD")]
+        rev: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Check that the store has the objects of all assets referenced by a commit, and
+    /// summarize the result. Fails if any object is missing.
+    Status {
+        /// List the hashes and paths of the assets whose objects are missing.
+        #[structopt(long)]
+        missing: bool,
+        /// The revision to check.
+        #[structopt(default_value = "HEAD")]
+        rev: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Check the setup for common mistakes and suggest how to fix them: the filter
+    /// configuration, the store and the remote store, the patterns in `.gitattributes` and
+    /// the files committed in `HEAD`. Fails if any check does.
+    Doctor {
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// List the assets that were added, removed or changed between two revisions.
+    Diff {
+        /// The old revision.
+        old: String,
+        /// The new revision.
+        #[structopt(default_value = "HEAD")]
+        new: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Show how many bytes the assets referenced by a revision take up.
+    ///
+    /// Sizes are reported both deduplicated (each object counted once) and as the plain sum
+    /// over all asset files.
+    Du {
+        /// The revision to inspect.
+        #[structopt(default_value = "HEAD")]
+        rev: String,
+        /// Break the totals down by directory.
+        #[structopt(long)]
+        by_path: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// List all objects in the store with their hash, size and modification time.
+    ///
+    /// This includes the chunks of objects stored in chunks, while the chunked objects
+    /// themselves are marked as such.
+    Ls {
+        /// Sort by `hash`, `size` or `time`.
+        #[structopt(long, default_value = "hash")]
+        sort: SortKey,
+        /// Reverse the order.
+        #[structopt(long)]
+        reverse: bool,
+        /// Only list objects whose labels match, e.g. `license=cc0`, `license!=cc0`, `temporary`
+        /// or `!temporary`. May be given multiple times, and all of them have to match.
+        #[structopt(long = "filter", number_of_values = 1)]
+        filters: Vec<metadata::LabelFilter>,
+        /// Output format, either `text`, `json` or `csv`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Write the contents of an object to stdout.
+    Cat {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+    },
+    /// Show the object that a tracked file refers to: its hash, where the store keeps it, its
+    /// size, and whether the file in the working tree has the same contents.
+    Which {
+        /// The file, as staged in the index.
+        path: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Show what the store knows about an object: where it keeps it, its size, whether it is
+    /// pinned, and who stored it first, from which repository and when.
+    Info {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Summarize a tracked file, given its contents or a pointer, by media type, size and hash.
+    ///
+    /// Meant to be configured as `textconv` of a git diff driver, so that `git diff` shows
+    /// changes to tracked files as e.g. `image/png 4.2 MiB 3a7bd3e2360a3d29…`.
+    Textconv {
+        /// The file to summarize, as passed by git.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Merge the pointers of a tracked file, to be used as git merge driver.
+    ///
+    /// Changes on one side only are taken over. If both sides refer to different objects, the
+    /// file conflicts and both objects are shown, unless `--ours` or `--theirs` settles it.
+    MergeDriver {
+        /// The pointer in the common ancestor (`%O`).
+        #[structopt(parse(from_os_str))]
+        base: PathBuf,
+        /// Our pointer (`%A`), which is replaced by the result.
+        #[structopt(parse(from_os_str))]
+        ours: PathBuf,
+        /// Their pointer (`%B`).
+        #[structopt(parse(from_os_str))]
+        theirs: PathBuf,
+        /// Path of the merged file (`%P`), for messages.
+        path: Option<String>,
+        /// Keep our object when both sides changed.
+        #[structopt(long = "ours", conflicts_with = "prefer-theirs")]
+        prefer_ours: bool,
+        /// Take their object when both sides changed.
+        #[structopt(long = "theirs")]
+        prefer_theirs: bool,
+    },
+    /// Find the files whose pointers refer to an object, e.g. one that `validate` reports as
+    /// corrupt, and print each with the commit it was found in. Exits with status 1 if there
+    /// are none.
+    GrepHash {
+        /// The hash of the object, or a prefix of at least 4 characters.
+        hash: String,
+        /// Search the history of all refs instead of `HEAD`, printing every path once, with the
+        /// most recent commit that has the object there.
+        #[structopt(long)]
+        all: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Check whether an object is in the store, exiting with status 0 if it is and 1 if not.
+    #[structopt(alias = "has")]
+    Exists {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+    },
+    /// Protect an object from `gc`, e.g. because something outside of git refers to it.
+    Pin {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        #[structopt(required_unless = "list")]
+        hash: Option<String>,
+        /// List the pinned objects instead.
+        #[structopt(long)]
+        list: bool,
+    },
+    /// Remove the pin of an object, so that `gc` deletes it once it is no longer referenced.
+    Unpin {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+    },
+    /// Delete a single object from the store, e.g. one that was committed by accident.
+    ///
+    /// Refuses to delete objects that are pinned or still referenced by a commit reachable from
+    /// a ref (including reflogs) or by the index.
+    Delete {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+        /// Delete the object even if it is still referenced.
+        #[structopt(long)]
+        force: bool,
+        /// Only print what would be deleted, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+    },
+    /// Delete all objects from the store that are not referenced by any commit reachable from
+    /// a ref (including reflogs) or by the index, of this or any registered repository.
+    ///
+    /// Objects stored during the grace period before, by default 2 hours, are kept, so that
+    /// files being added while `gc` runs don't lose their objects.
+    Gc {
+        /// Instead of objects, remove files from the staging directory that were left behind
+        /// by interrupted filters.
+        #[structopt(long)]
+        staging: bool,
+        /// With `--staging`, only remove files older than this, e.g. `30m`, `12h` or `7d`.
+        #[structopt(long, default_value = "1d", parse(try_from_str = config::parse_duration))]
+        older_than: Duration,
+        /// Keep unreferenced objects stored less than this long ago, e.g. `30m` or `0s`, since
+        /// they may belong to files that are being added. Defaults to the `gc-grace-period`
+        /// setting, or else 2 hours.
+        #[structopt(long, parse(try_from_str = config::parse_duration), conflicts_with = "staging")]
+        grace_period: Option<Duration>,
+        /// Only print what would be removed, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+    },
+    /// Like `gc`, but keep unreferenced objects according to a retention policy.
+    ///
+    /// Options that are not given are taken from the policy saved in the store.
+    Prune {
+        /// Only delete objects stored longer ago than this, e.g. `12h` or `90d`.
+        #[structopt(long, parse(try_from_str = config::parse_duration))]
+        older_than: Option<Duration>,
+        /// Keep this many of the most recently stored unreferenced objects.
+        #[structopt(long)]
+        keep_recent: Option<usize>,
+        /// Save the given options as the policy of the store.
+        #[structopt(long)]
+        save: bool,
+        /// Only delete objects whose labels match, e.g. `temporary=true`, see `ls --filter`.
+        /// May be given multiple times, and all of them have to match. Not saved by `--save`.
+        #[structopt(long = "filter", number_of_values = 1)]
+        filters: Vec<metadata::LabelFilter>,
+        /// Only print what would be removed, without changing anything.
+        #[structopt(long, short = "n", conflicts_with = "save")]
+        dry_run: bool,
+    },
+    /// Delete the least recently used objects that the remote store has, until the store is no
+    /// larger than the maximum size saved in the store or given by `--max-size`.
+    ///
+    /// This also happens after `fetch` once a maximum size has been saved.
+    Evict {
+        /// The maximum size of the store, e.g. `500M` or `20G`.
+        #[structopt(long, parse(try_from_str = config::parse_size))]
+        max_size: Option<u64>,
+        /// Save the given maximum size in the store.
+        #[structopt(long)]
+        save: bool,
+    },
+    /// Show or set the quota of the store, the size that storing new files may not exceed.
+    Quota {
+        /// The new quota, e.g. `500M` or `20G`.
+        #[structopt(parse(try_from_str = config::parse_size), conflicts_with = "remove")]
+        size: Option<u64>,
+        /// Remove the quota.
+        #[structopt(long)]
+        remove: bool,
+    },
+    /// Show statistics about the store.
+    Stats {
+        /// Instead of the current state, show how the assets referenced from all refs grew
+        /// over time, per month and per author.
+        #[structopt(long)]
+        history: bool,
+        /// Output format, either `text`, `json` or `csv` (csv only with `--history`).
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Download the objects referenced by a revision that are missing in the store from the
+    /// remote store, e.g. after cloning, or before switching to a branch with many assets.
+    #[structopt(alias = "prefetch")]
+    Fetch {
+        /// The commit or tree to fetch the assets of.
+        #[structopt(default_value = "HEAD")]
+        rev: String,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Upload all objects that are missing in the remote store.
+    Push {
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Upload the objects referenced by the commits that git is about to push, to be used as
+    /// git pre-push hook, see `hooks install`.
+    ///
+    /// Reads the refs being pushed from stdin, as git passes them to the hook. Does nothing if
+    /// no remote store is configured, and fails if an object is missing locally, which stops
+    /// git from pushing commits whose assets nobody could retrieve.
+    PrePush {
+        /// Name of the git remote being pushed to, as passed by git.
+        remote_name: Option<String>,
+        /// URL of the git remote being pushed to, as passed by git. Not needed.
+        #[structopt(name = "url")]
+        _url: Option<String>,
+    },
+    /// Copy objects from one store to another, e.g. from the store of a repository into a shared
+    /// store. Objects that are already present are skipped, all others are verified on the way.
+    Copy {
+        /// The store to copy from, the current store by default.
+        #[structopt(long, parse(from_os_str))]
+        from: Option<PathBuf>,
+        /// The store to copy to, the current store by default.
+        #[structopt(long, parse(from_os_str))]
+        to: Option<PathBuf>,
+        /// Only copy the objects whose hashes are read from stdin, one per line, instead of all.
+        #[structopt(long)]
+        stdin: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Pack objects into a tar archive, e.g. for carrying them to a machine without network
+    /// access. Objects stored in chunks are reassembled. Needs the `archive` feature.
+    #[cfg_attr(not(feature = "archive"), allow(dead_code))]
+    Export {
+        /// The archive to write, or `-` for stdout.
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+        /// Only export the objects whose hashes are read from stdin, one per line, instead of all.
+        #[structopt(long)]
+        stdin: bool,
+        /// Only export objects whose labels match, e.g. `license=redistributable`, see
+        /// `ls --filter`. May be given multiple times, and all of them have to match.
+        #[structopt(long = "filter", number_of_values = 1)]
+        filters: Vec<metadata::LabelFilter>,
+    },
+    /// Add the objects of an archive written by `export` to the store, verifying their hashes.
+    /// Needs the `archive` feature.
+    #[cfg_attr(not(feature = "archive"), allow(dead_code))]
+    Import {
+        /// The archive to read, or `-` for stdin.
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Serve the store over HTTP, so that others can share it without any other setup.
+    ///
+    /// Objects are read with `GET /objects/<hash>`, checked for with `HEAD`, and uploaded with
+    /// `PUT`, which only stores contents matching the hash. Large objects can also be uploaded
+    /// in parts with the tus protocol, starting with `POST /uploads/<hash>` and
+    /// `Upload-Length`, and resumed at the `Upload-Offset` the server reports for the upload.
+    /// The server also works as a
+    /// read-only remote store, e.g. `--remote http://<address>`. The served store may be remote
+    /// as well, e.g. `--store s3://bucket/prefix`.
+    ///
+    /// With `--namespace <name>=<store>`, another store is served below `/<name>/`, e.g. for
+    /// clients using `--remote http://<address>/<name>`.
+    ///
+    /// If the settings `read-tokens`, `write-tokens` or `token-file` are set, requests need one
+    /// of the tokens as `Authorization: Bearer <token>`, and only write tokens may upload.
+    /// Tokens in the token file may be limited to some namespaces, see `tokens add`. Clients
+    /// send the token in `GIT_ASSETS_TOKEN`.
+    ///
+    /// After every upload, the webhooks given by `--webhook` or the `webhooks` setting get a
+    /// `POST` with a JSON object of the `hash`, `size` and `namespace` of the object, and the
+    /// `uploader`, the id of the token it was uploaded with as shown by `tokens list`.
+    ///
+    /// With `--tls-cert` and `--tls-key` (or the settings of the same names), only HTTPS
+    /// connections are accepted. Clients that don't trust the certificate authority by default
+    /// name a file containing it in `GIT_ASSETS_CA_FILE`. With `--tls-client-ca`, clients also
+    /// need a certificate, which they name in `GIT_ASSETS_CLIENT_CERT` and
+    /// `GIT_ASSETS_CLIENT_KEY`.
+    ///
+    /// Needs git-assets built with the `serve` feature.
+    #[cfg_attr(not(feature = "serve"), allow(dead_code))]
+    Serve {
+        /// The address to listen on, e.g. `0.0.0.0:8080` to accept connections from other
+        /// machines.
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// The number of requests answered at the same time. Defaults to the `jobs` setting,
+        /// or else one per CPU.
+        #[structopt(long, short)]
+        jobs: Option<usize>,
+        /// File with a token and its scope, `read` or `write`, on each line. Overrides the
+        /// `token-file` setting.
+        #[structopt(long, parse(from_os_str))]
+        token_file: Option<PathBuf>,
+        /// PEM file with the certificate chain to present, which enables HTTPS. Overrides the
+        /// `tls-cert` setting.
+        #[structopt(long, parse(from_os_str))]
+        tls_cert: Option<PathBuf>,
+        /// PEM file with the private key of the certificate. Overrides the `tls-key` setting.
+        #[structopt(long, parse(from_os_str))]
+        tls_key: Option<PathBuf>,
+        /// PEM file with the certificate authorities that issue client certificates. Only
+        /// clients with such a certificate may connect. Overrides the `tls-client-ca` setting.
+        #[structopt(long, parse(from_os_str))]
+        tls_client_ca: Option<PathBuf>,
+        /// Serve another store below `/<name>/`, given as `<name>=<store>`. May be given
+        /// multiple times.
+        #[structopt(long = "namespace", parse(try_from_str = parse_namespace))]
+        namespaces: Vec<(String, PathBuf)>,
+        /// URL to post a JSON notification to after every upload. May be given multiple
+        /// times. Overrides the `webhooks` setting.
+        #[structopt(long = "webhook")]
+        webhooks: Vec<String>,
+    },
+    /// Label objects with `key=value` pairs, e.g. `license=cc0`, independent of the history.
+    ///
+    /// `info` shows the labels of an object, and `ls --filter` selects objects by them.
+    Note(NoteCommand),
+    /// Manage the tokens in the token file of `serve`.
+    Tokens(TokensCommand),
+    /// Move the current repository from or to other tools for managing large files.
+    Migrate(MigrateCommand),
+    /// Install or remove git hooks that run git-assets.
+    Hooks(HooksCommand),
+    /// Work with archives written by `export`, e.g. as backups. Needs the `archive` feature.
+    Backup(BackupCommand),
+    /// Print a script for tab completion of git-assets in the given shell.
+    ///
+    /// For example, `git-assets completions bash > /etc/bash_completion.d/git-assets`.
+    Completions {
+        /// The shell, one of `bash`, `zsh`, `fish`, `powershell` or `elvish`.
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+#[derive(StructOpt)]
+enum MigrateCommand {
+    /// Switch the current repository from Git LFS to git-assets: add the objects of Git LFS to
+    /// the store, install the filter, and route the patterns tracked by Git LFS through it.
+    ///
+    /// Pointers keep the Git LFS format (`assets.pointer` is set to `lfs`), so files that
+    /// are already committed don't change.
+    ImportLfs {
+        /// Only print what would be imported, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Switch the current repository from git-assets to Git LFS: copy all objects of the
+    /// store to Git LFS, replace the pointers staged in the index by Git LFS pointers, and
+    /// route the tracked patterns through Git LFS.
+    ///
+    /// The git config is left alone; the settings Git LFS needs are printed instead, since they
+    /// only work once Git LFS is installed.
+    ExportLfs {
+        /// Only print what would be exported, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Switch the current repository from git-annex to git-assets: add the objects of
+    /// git-annex with `SHA256E` or `SHA256` keys to the store, replace the annexed files in
+    /// the index and the working tree by pointers to them, and track their extensions.
+    ///
+    /// Annexed files whose contents are not present locally are left alone, so that they can
+    /// be retrieved with `git annex get` and imported by running this again.
+    ImportAnnex {
+        /// Only print what would be imported, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Rewrite the whole history of the current repository so that files matching the
+    /// patterns are committed as pointers, with their contents in the store, and so that every
+    /// commit tracks the patterns. Installs the filter and checks out the rewritten HEAD.
+    ///
+    /// All commit ids change, so this is meant for repositories that are about to start using
+    /// git-assets, and clones must be made anew afterwards. The working tree must be clean.
+    History {
+        /// Patterns of files to turn into pointers, in `.gitattributes` syntax.
+        #[structopt(required = true)]
+        patterns: Vec<String>,
+        /// Only print what would be rewritten, without changing anything.
+        #[structopt(long, short = "n")]
+        dry_run: bool,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+}
+
+#[derive(StructOpt)]
+enum HooksCommand {
+    /// Install `post-checkout` and `post-merge` hooks in the current repository that run
+    /// `checkout`, so that pointer files left behind by skipped smudging or missing objects are
+    /// replaced by their contents after switching branches or pulling, and a `pre-push` hook
+    /// that runs `pre-push`.
+    Install {
+        /// Overwrite existing hooks that were not installed by git-assets.
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Remove the hooks installed by `hooks install`.
+    Uninstall,
 }
 
-fn find_git_repo() -> io::Result<Option<PathBuf>> {
-    for ancestor in env::current_dir()?.ancestors() {
-        let git_dir = ancestor.join(".git");
-        if git_dir.is_dir() {
-            return Ok(Some(git_dir.join("x-assets")));
+#[derive(StructOpt)]
+enum NoteCommand {
+    /// Add labels to an object, replacing the values of those it has already.
+    Add {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+        /// The labels, e.g. `license=cc0`.
+        #[structopt(required = true, parse(try_from_str = metadata::parse_label))]
+        labels: Vec<(String, String)>,
+    },
+    /// Remove labels from an object.
+    Remove {
+        /// The hash of the object, or an unambiguous prefix of at least 4 characters.
+        hash: String,
+        /// The keys of the labels.
+        #[structopt(required = true)]
+        keys: Vec<String>,
+    },
+}
+
+#[derive(StructOpt)]
+enum TokensCommand {
+    /// Add a token to the token file and print it. Without `--token`, a random one is
+    /// generated.
+    Add {
+        /// The token to add, instead of a random one.
+        #[structopt(long)]
+        token: Option<String>,
+        /// What the token allows, `read` or `write`.
+        #[structopt(long, default_value = "read")]
+        scope: server::Scope,
+        /// Only allow the token in this namespace, `default` for the served store itself. May
+        /// be given multiple times. Without it, the token is allowed in every namespace.
+        #[structopt(long = "namespace")]
+        namespaces: Vec<String>,
+        /// The token file, instead of the one of the `token-file` setting.
+        #[structopt(long, parse(from_os_str))]
+        token_file: Option<PathBuf>,
+    },
+    /// Remove a token from the token file, given as itself or by the id shown by `list`.
+    Remove {
+        token: String,
+        /// The token file, instead of the one of the `token-file` setting.
+        #[structopt(long, parse(from_os_str))]
+        token_file: Option<PathBuf>,
+    },
+    /// List the tokens of the token file by their id, which doesn't give them away, with what
+    /// they allow.
+    List {
+        /// The token file, instead of the one of the `token-file` setting.
+        #[structopt(long, parse(from_os_str))]
+        token_file: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt)]
+enum BackupCommand {
+    /// Check that an archive written by `export` holds every object of its manifest, and that
+    /// their contents match their hashes. Needs no store.
+    #[cfg_attr(not(feature = "archive"), allow(dead_code))]
+    Verify {
+        /// The archive to check, or `-` for stdin.
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+        /// Output format, either `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+}
+
+/// The hooks installed by `hooks install`, with the git-assets arguments they run.
+const HOOKS: &[(&str, &str)] = &[
+    ("post-checkout", "checkout"),
+    ("post-merge", "checkout"),
+    ("pre-push", "pre-push \"$@\""),
+];
+
+/// How commands print their results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    /// Comma separated values, for commands producing a single table.
+    Csv,
+}
+
+impl Command {
+    /// Switch the output format of the command to JSON, for `--json`.
+    fn select_json(&mut self) -> CliResult<()> {
+        match self {
+            Command::Validate { format, .. }
+            | Command::Manifest { format, .. }
+            | Command::Status { format, .. }
+            | Command::Doctor { format }
+            | Command::Diff { format, .. }
+            | Command::Du { format, .. }
+            | Command::Ls { format, .. }
+            | Command::Which { format, .. }
+            | Command::Info { format, .. }
+            | Command::GrepHash { format, .. }
+            | Command::Stats { format, .. }
+            | Command::Fetch { format, .. }
+            | Command::Push { format, .. }
+            | Command::Copy { format, .. }
+            | Command::Import { format, .. }
+            | Command::Backup(BackupCommand::Verify { format, .. })
+            | Command::Migrate(MigrateCommand::ImportLfs { format, .. })
+            | Command::Migrate(MigrateCommand::ExportLfs { format, .. })
+            | Command::Migrate(MigrateCommand::ImportAnnex { format, .. })
+            | Command::Migrate(MigrateCommand::History { format, .. }) => {
+                *format = OutputFormat::Json;
+                Ok(())
+            }
+            _ => Err(CliErrorKind::UnsupportedFormat.into()),
+        }
+    }
+}
+
+/// How `ls` orders the objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Hash,
+    Size,
+    /// Modification time.
+    Time,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(SortKey::Hash),
+            "size" => Ok(SortKey::Size),
+            "time" => Ok(SortKey::Time),
+            _ => Err(format!("unknown sort key: {}", s)),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format: {}", s)),
         }
     }
-    Ok(None)
+}
+
+/// The default store of the repository at `location`, which all of its worktrees share, or
+/// `None` outside of a repository.
+///
+/// Like git itself, this honors `GIT_COMMON_DIR` and `GIT_DIR`, which git sets when running
+/// hooks and which scripts set to work on a repository elsewhere. `GIT_WORK_TREE` does not
+/// matter for the store, and the git commands run by git-assets inherit it.
+///
+/// Submodules use the store of their superproject instead if the git config
+/// `assets.submodules` of the superproject is `shared`.
+fn find_git_repo(location: Option<&git::Location>) -> io::Result<Option<PathBuf>> {
+    let location = match location {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+    let mut common_dir = location.common_dir.clone();
+    let mut superproject = location.superproject.clone();
+    while let Some(repo) = superproject {
+        match repo.config_get("assets.submodules")?.as_deref() {
+            Some("shared") => {}
+            None | Some("separate") => break,
+            Some(other) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "assets.submodules must be `shared` or `separate`, not `{}`",
+                        other
+                    ),
+                ))
+            }
+        }
+        let location = match repo.locate()? {
+            Some(location) => location,
+            None => break,
+        };
+        common_dir = location.common_dir;
+        superproject = location.superproject;
+    }
+    Ok(Some(common_dir.join("x-assets")))
 }
 
 fn main() {
     let opts = GitAssets::from_args();
 
-    match run(opts) {
-        Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1)
-        }
-        Ok(()) => {}
+    if let Err(err) = run(opts) {
+        eprintln!("{}", err);
+        std::process::exit(err.kind().exit_code())
     }
 }
 
 fn run(opts: GitAssets) -> CliResult<()> {
-    let store_path = opts
+    logging::init(opts.verbose, opts.quiet).map_err(CliError::invalid_config)?;
+    log::debug!("command line: {:?}", env::args().collect::<Vec<_>>());
+    let start = Instant::now();
+    let result = run_command(opts);
+    log::info!("finished in {:.3}s", start.elapsed().as_secs_f64());
+    result
+}
+
+fn run_command(mut opts: GitAssets) -> CliResult<()> {
+    if opts.json {
+        opts.command.select_json()?;
+    }
+    // Need neither a repository nor the configuration
+    match &opts.command {
+        Command::Completions { shell } => {
+            GitAssets::clap().gen_completions_to("git-assets", *shell, &mut io::stdout());
+            return Ok(());
+        }
+        #[cfg(feature = "archive")]
+        Command::Backup(BackupCommand::Verify { archive, format }) => {
+            return backup_verify(archive, *format)
+        }
+        #[cfg(not(feature = "archive"))]
+        Command::Backup(_) => return Err(needs_feature("archive")),
+        _ => {}
+    }
+    // Filters run once per file, so the repository is only asked for what is needed once
+    let repo = open_repo()?;
+    let location = repo.locate()?;
+    let work_tree = location
+        .as_ref()
+        .and_then(|location| location.work_tree.as_deref());
+    let config = config::Config::load(&repo, work_tree).map_err(CliError::invalid_config)?;
+    if let Some(allowed) = config.allowed_remotes.clone() {
+        let _ = ALLOWED_REMOTES.set(allowed);
+    }
+    let pointer_format = config.pointer.unwrap_or(PointerFormat::V1);
+    let provenance = metadata::Provenance::new(
+        config.user.clone(),
+        location.as_ref().map(|location| {
+            location
+                .work_tree
+                .clone()
+                .unwrap_or_else(|| location.common_dir.clone())
+        }),
+    );
+    // Only a store given on the command line is written into the git config by the commands
+    // that install the filter, the others are found again when the filter runs
+    let explicit_store = opts.store.is_some();
+    let store = match opts
         .store
-        .or(find_git_repo()?)
-        .ok_or(CliErrorKind::NotInGitRepo)?;
+        .clone()
+        .or_else(|| env_path("GIT_ASSETS_STORE"))
+        .or(config.store)
+    {
+        Some(store) => Some(store),
+        None => find_git_repo(location.as_ref())?,
+    };
+    if let Some(store) = &store {
+        check_remote_allowed(store)?;
+    }
+    match opts.command {
+        Command::Install { global } => return install(store, explicit_store, global),
+        Command::Init { patterns, hash } => return init(store, explicit_store, &patterns, hash),
+        Command::Uninstall {
+            global,
+            smudge,
+            delete_store,
+        } => return uninstall(store, global, smudge, delete_store),
+        Command::Migrate(MigrateCommand::ImportLfs { dry_run, format }) => {
+            return migrate_import_lfs(
+                store,
+                explicit_store,
+                cpu_jobs(config.jobs),
+                dry_run,
+                format,
+            )
+        }
+        Command::Migrate(MigrateCommand::History {
+            patterns,
+            dry_run,
+            format,
+        }) => {
+            return migrate_history(
+                store,
+                explicit_store,
+                &patterns,
+                pointer_format,
+                cpu_jobs(config.jobs),
+                dry_run,
+                format,
+            )
+        }
+        Command::Hooks(HooksCommand::Install { force }) => return install_hooks(opts.store, force),
+        Command::Hooks(HooksCommand::Uninstall) => return uninstall_hooks(),
+        Command::Tokens(command) => {
+            let token_file = match &command {
+                TokensCommand::Add { token_file, .. }
+                | TokensCommand::Remove { token_file, .. }
+                | TokensCommand::List { token_file } => token_file.clone(),
+            };
+            let token_file = token_file.or(config.token_file).ok_or_else(|| {
+                CliError::invalid_config(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no token file, see --token-file and the token-file setting",
+                ))
+            })?;
+            return match command {
+                TokensCommand::Add {
+                    token,
+                    scope,
+                    namespaces,
+                    ..
+                } => add_token(&token_file, token, scope, namespaces),
+                TokensCommand::Remove { token, .. } => remove_token(&token_file, &token),
+                TokensCommand::List { .. } => list_tokens(&token_file),
+            };
+        }
+        _ => {}
+    }
+
+    let store_path = store.ok_or(CliErrorKind::NotInGitRepo)?;
+    let alternates = if opts.alternates.is_empty() {
+        config.alternates.clone().unwrap_or_default()
+    } else {
+        opts.alternates
+    };
+    let remote = opts
+        .remote
+        .or_else(|| env_path("GIT_ASSETS_REMOTE"))
+        .or(config.remote);
+    if let Some(remote) = &remote {
+        check_remote_allowed(remote)?;
+    }
 
     match opts.command {
-        Command::StoreFile => store_file(store_path),
-        Command::RetrieveFile => retrieve_file(store_path),
-        Command::Validate => validate(store_path),
+        Command::Install { .. }
+        | Command::Init { .. }
+        | Command::Uninstall { .. }
+        | Command::Migrate(MigrateCommand::ImportLfs { .. })
+        | Command::Migrate(MigrateCommand::History { .. })
+        | Command::Hooks(_)
+        | Command::Tokens(_)
+        | Command::Backup(_)
+        | Command::Completions { .. } => unreachable!("handled above"),
+        Command::Register { list } => register(store_path, list),
+        Command::Unregister { git_dir } => unregister(store_path, git_dir),
+        Command::Track {
+            patterns,
+            above,
+            per_file,
+        } => track(patterns, above, per_file),
+        Command::Untrack { patterns, restore } => untrack(store_path, &patterns, restore),
+        Command::StoreFile {
+            file,
+            chunked,
+            batch: false,
+            pointer,
+            min_size,
+        } => store_file(
+            store_path,
+            file,
+            chunked || config.chunked == Some(true),
+            pointer.unwrap_or(pointer_format),
+            min_size.or(config.min_size).unwrap_or(0),
+            config.sign == Some(true),
+            provenance,
+        ),
+        Command::StoreFile {
+            chunked,
+            batch: true,
+            pointer,
+            min_size,
+            ..
+        } => store_file_batch(
+            store_path,
+            chunked || config.chunked == Some(true),
+            pointer.unwrap_or(pointer_format),
+            min_size.or(config.min_size).unwrap_or(0),
+            cpu_jobs(config.jobs),
+            config.sign == Some(true),
+            provenance,
+        ),
+        Command::RetrieveFile { batch: false } => retrieve_file(
+            store_path,
+            remote,
+            &alternates,
+            skip_smudge(config.skip_smudge)?,
+            config.verify_reads == Some(true),
+            config.verify_signatures == Some(true),
+        ),
+        Command::RetrieveFile { batch: true } => retrieve_file_batch(
+            store_path,
+            remote,
+            &alternates,
+            skip_smudge(config.skip_smudge)?,
+            config.verify_reads == Some(true),
+            config.verify_signatures == Some(true),
+        ),
+        Command::Checkout {
+            patterns,
+            pointer: true,
+        } => checkout_pointers(&patterns),
+        Command::Checkout {
+            patterns,
+            pointer: false,
+        } => checkout(
+            store_path,
+            remote,
+            &alternates,
+            &patterns,
+            config.verify_reads == Some(true),
+        ),
+        Command::Validate {
+            jobs,
+            full,
+            fix,
+            dry_run,
+            format,
+        } => validate(store_path, jobs.or(config.jobs), full, fix, dry_run, format),
+        Command::Manifest { rev, format } => manifest(store_path, &rev, format),
+        Command::Status {
+            missing,
+            rev,
+            format,
+        } => status(store_path, &rev, missing, format),
+        Command::Doctor { format } => {
+            doctor(store_path, remote, config.min_size.unwrap_or(0), format)
+        }
+        Command::Diff { old, new, format } => diff(store_path, &old, &new, format),
+        Command::Du {
+            rev,
+            by_path,
+            format,
+        } => du(store_path, &rev, by_path, format),
+        Command::Ls {
+            sort,
+            reverse,
+            filters,
+            format,
+        } => ls(store_path, sort, reverse, &filters, format),
+        Command::Cat { hash } => cat(store_path, &hash, config.verify_reads == Some(true)),
+        Command::Which { path, format } => which(store_path, &path, format),
+        Command::Info { hash, format } => info(store_path, &hash, format),
+        Command::Textconv { file } => textconv(store_path, &file),
+        Command::MergeDriver {
+            base,
+            ours,
+            theirs,
+            path,
+            prefer_ours,
+            prefer_theirs,
+        } => {
+            let prefer = match (prefer_ours, prefer_theirs) {
+                (true, _) => Some(MergeSide::Ours),
+                (_, true) => Some(MergeSide::Theirs),
+                _ => None,
+            };
+            merge_driver(store_path, &base, &ours, &theirs, path, prefer)
+        }
+        Command::GrepHash { hash, all, format } => grep_hash(&hash, all, format),
+        Command::Exists { hash } => exists(store_path, &hash),
+        Command::Pin { hash, list: false } => pin(store_path, &hash.expect("hash is required")),
+        Command::Pin { list: true, .. } => list_pins(store_path),
+        Command::Unpin { hash } => unpin(store_path, &hash),
+        Command::Note(NoteCommand::Add { hash, labels }) => note_add(store_path, &hash, labels),
+        Command::Note(NoteCommand::Remove { hash, keys }) => note_remove(store_path, &hash, &keys),
+        Command::Delete {
+            hash,
+            force,
+            dry_run,
+        } => delete(store_path, &hash, force, dry_run),
+        Command::Gc {
+            staging: true,
+            older_than,
+            dry_run,
+            ..
+        } => gc_staging(store_path, older_than, dry_run),
+        Command::Gc {
+            grace_period,
+            dry_run,
+            ..
+        } => gc(
+            store_path,
+            grace_period
+                .or(config.gc_grace_period)
+                .unwrap_or(store::GC_GRACE_PERIOD),
+            dry_run,
+        ),
+        Command::Prune {
+            older_than,
+            keep_recent,
+            save,
+            filters,
+            dry_run,
+        } => prune(store_path, older_than, keep_recent, save, &filters, dry_run),
+        Command::Evict { max_size, save } => evict(store_path, remote, max_size, save),
+        Command::Quota { size, remove } => quota(store_path, size, remove),
+        Command::Stats { history, format } => {
+            if history {
+                stats_history(store_path, format)
+            } else {
+                stats(store_path, format)
+            }
+        }
+        Command::Fetch { rev, format } => fetch(
+            store_path,
+            remote,
+            &rev,
+            config.verify_signatures == Some(true),
+            format,
+        ),
+        Command::Push { format } => push(store_path, remote, format),
+        Command::PrePush { remote_name, .. } => {
+            pre_push(store_path, remote, remote_name.as_deref())
+        }
+        Command::Copy {
+            from,
+            to,
+            stdin,
+            format,
+        } => copy(store_path, from, to, stdin, format),
+        #[cfg(feature = "archive")]
+        Command::Export {
+            archive,
+            stdin,
+            filters,
+        } => export(store_path, &archive, stdin, &filters),
+        #[cfg(feature = "archive")]
+        Command::Import { archive, format } => {
+            import(store_path, &archive, cpu_jobs(config.jobs), format)
+        }
+        #[cfg(not(feature = "archive"))]
+        Command::Export { .. } | Command::Import { .. } => Err(needs_feature("archive")),
+        #[cfg(not(feature = "serve"))]
+        Command::Serve { .. } => Err(needs_feature("serve")),
+        #[cfg(feature = "serve")]
+        Command::Serve {
+            listen,
+            jobs,
+            token_file,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            namespaces,
+            webhooks,
+        } => {
+            let grant = |token, scope| server::TokenGrant {
+                token,
+                scope,
+                namespaces: Vec::new(),
+            };
+            let mut tokens = Vec::new();
+            for token in config.read_tokens.unwrap_or_default() {
+                tokens.push(grant(token, server::Scope::Read));
+            }
+            for token in config.write_tokens.unwrap_or_default() {
+                tokens.push(grant(token, server::Scope::Write));
+            }
+            if let Some(file) = token_file.or(config.token_file) {
+                tokens.extend(server::read_token_file(&file).map_err(CliError::invalid_config)?);
+            }
+            let client_ca = tls_client_ca.or(config.tls_client_ca);
+            let tls = match (tls_cert.or(config.tls_cert), tls_key.or(config.tls_key)) {
+                (Some(cert), Some(key)) => Some((cert, key, client_ca)),
+                (None, None) if client_ca.is_none() => None,
+                _ => {
+                    return Err(CliError::invalid_config(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "HTTPS needs both a certificate and a private key, see --tls-cert and \
+                         --tls-key",
+                    )))
+                }
+            };
+            serve(
+                store_path,
+                &listen,
+                jobs.or(config.jobs),
+                tokens,
+                tls,
+                namespaces,
+                if webhooks.is_empty() {
+                    config.webhooks.unwrap_or_default()
+                } else {
+                    webhooks
+                },
+            )
+        }
+        Command::Migrate(MigrateCommand::ExportLfs { dry_run, format }) => {
+            migrate_export_lfs(store_path, cpu_jobs(config.jobs), dry_run, format)
+        }
+        Command::Migrate(MigrateCommand::ImportAnnex { dry_run, format }) => migrate_import_annex(
+            store_path,
+            pointer_format,
+            cpu_jobs(config.jobs),
+            dry_run,
+            format,
+        ),
+    }
+}
+
+/// The number of threads to use given the `jobs` setting or option, one per CPU by default.
+fn cpu_jobs(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |jobs| jobs.get()))
+}
+
+/// The path in an environment variable, unless it is unset or empty.
+fn env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Open the store at the given location, a local directory or the URL of a remote store.
+fn open_store(location: PathBuf) -> CliResult<store::Store> {
+    check_remote_allowed(&location)?;
+    if store::is_url(&location) {
+        // Staging in a shared directory would let other users interfere with it
+        let staging_dir = config::user_staging_dir().ok_or_else(|| {
+            CliError::store_access(io::Error::new(
+                io::ErrorKind::NotFound,
+                "remote stores need a home directory to stage objects in",
+            ))
+        })?;
+        // Opening it already reads the hash algorithm from the server
+        store::Store::open_url(&location.to_string_lossy(), staging_dir).map_err(|err| {
+            match err.kind() {
+                io::ErrorKind::InvalidInput
+                | io::ErrorKind::Unsupported
+                | io::ErrorKind::InvalidData
+                | io::ErrorKind::PermissionDenied => CliError::store_access(err),
+                _ => CliError::transfer(err),
+            }
+        })
+    } else {
+        store::Store::open_or_create(location).map_err(CliError::store_access)
+    }
+}
+
+/// The remote stores that may be used, from the `allowed-remotes` setting. Set once the
+/// configuration is resolved, and unset if any may be used.
+static ALLOWED_REMOTES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Fail if `location` is the URL of a remote store that the `allowed-remotes` setting doesn't
+/// allow. Local stores are always allowed.
+fn check_remote_allowed(location: &Path) -> CliResult<()> {
+    match ALLOWED_REMOTES.get() {
+        Some(allowed)
+            if store::is_url(location)
+                && !config::remote_allowed(&location.to_string_lossy(), allowed) =>
+        {
+            Err(CliError::invalid_config(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is not an allowed remote store, see `git config assets.allowedRemote`",
+                    location.display()
+                ),
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Open the remote store given by `--remote`, or else by the configuration.
+fn open_remote(remote: Option<PathBuf>) -> CliResult<store::Store> {
+    open_store(remote.ok_or(CliErrorKind::NoRemote)?)
+}
+
+/// Whether the smudge filter passes pointers through, as set by the environment variable
+/// `GIT_ASSETS_SKIP_SMUDGE`, or else by the git config `assets.skipSmudge`, which is given as
+/// `configured`.
+fn skip_smudge(configured: Option<bool>) -> CliResult<bool> {
+    if let Some(value) = env::var_os("GIT_ASSETS_SKIP_SMUDGE") {
+        let value = value.to_string_lossy();
+        return config::parse_bool(&value).ok_or_else(|| {
+            CliError::invalid_config(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("GIT_ASSETS_SKIP_SMUDGE must be a boolean, not `{}`", value),
+            ))
+        });
+    }
+    Ok(configured.unwrap_or(false))
+}
+
+/// Whether the clean filter passes the contents through instead of storing them: if they are
+/// a pointer already, e.g. one left by `checkout --pointer` or `skip-smudge`, like git-lfs does,
+/// and otherwise if they are smaller than `min_size`, unless they would be taken for a pointer
+/// when checked out.
+///
+/// Contents of `pass_through_limit(min_size)` bytes or more are always stored.
+fn passes_through(contents: &[u8], min_size: u64) -> bool {
+    if looks_like_pointer(contents) {
+        contents.len() as u64 <= store::MAX_POINTER_LEN && parse_pointer(contents).is_ok()
+    } else {
+        (contents.len() as u64) < min_size
+    }
+}
+
+/// How many bytes of the contents decide whether `passes_through` holds.
+fn pass_through_limit(min_size: u64) -> u64 {
+    min_size.max(store::MAX_POINTER_LEN + 1)
+}
+
+/// Whether contents start like a pointer in any format, even though they may not parse as one.
+///
+/// The smudge filter only passes through contents that don't, so that a damaged pointer fails
+/// instead of being checked out as it is.
+fn looks_like_pointer(contents: &[u8]) -> bool {
+    contents.starts_with(b"git-assets v")
+        || contents.starts_with(b"{")
+        || contents.starts_with(b"version https://git-lfs")
+}
+
+/// Parse contents that look like a pointer, failing with `InvalidData` if they are damaged.
+fn parse_pointer(contents: &[u8]) -> io::Result<store::StoreFileRef> {
+    store::StoreFileRef::parse_from_stream(&mut &contents[..]).map_err(|_| {
+        let first_line = contents.split(|&b| b == b'\n').next().unwrap_or_default();
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "malformed pointer starting with {:?}",
+                String::from_utf8_lossy(first_line)
+            ),
+        )
+    })
+}
+
+/// The error for commands that need a cargo feature that git-assets was built without.
+#[cfg(not(all(feature = "archive", feature = "serve")))]
+fn needs_feature(feature: &str) -> CliError {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "this command needs git-assets built with the `{}` feature",
+            feature
+        ),
+    )
+    .into()
+}
+
+/// Access the git repository in the current directory.
+fn open_repo() -> CliResult<git::Repository> {
+    Ok(git::Repository::new(env::current_dir()?))
+}
+
+/// Write the filter configuration to the local or global git config, and create the store.
+///
+/// A store given with `--store` is passed on to the filter commands, otherwise they find the
+/// store of the repository they run in the same way as all other commands.
+fn install(store: Option<PathBuf>, explicit_store: bool, global: bool) -> CliResult<()> {
+    for (key, value) in configure_filter(store, explicit_store, global)? {
+        println!("{} = {}", key, value);
+    }
+    Ok(())
+}
+
+/// Like `install`, but return the settings that were written instead of printing them.
+fn configure_filter(
+    store: Option<PathBuf>,
+    explicit_store: bool,
+    global: bool,
+) -> CliResult<Vec<(String, String)>> {
+    let repo = open_repo()?;
+    let settings = filter_settings(store.as_deref().filter(|_| explicit_store))?;
+    // The global configuration also applies to repositories that don't exist yet, so their
+    // stores are only created by a local installation
+    if explicit_store || !global {
+        let _ = open_store(store.ok_or(CliErrorKind::NotInGitRepo)?)?;
+    }
+
+    let scope = if global { "--global" } else { "--local" };
+    for (key, value) in &settings {
+        repo.run(&["config", scope, key, value])
+            .map_err(CliError::git)?;
+    }
+    Ok(settings)
+}
+
+/// The git config settings that install the filter, passing on an explicitly given store.
+fn filter_settings(store: Option<&Path>) -> CliResult<Vec<(String, String)>> {
+    let store_arg = store_arg(store)?;
+    let filter = attributes::DEFAULT_FILTER;
+    Ok(vec![
+        ("clean", format!("git-assets{} store-file", store_arg)),
+        ("smudge", format!("git-assets{} retrieve-file", store_arg)),
+        ("required", "true".to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (format!("filter.{}.{}", filter, name), value))
+    .collect())
+}
+
+/// The `--store` argument for a command that git runs through the shell, or nothing if no
+/// store is given.
+///
+/// Git runs filters and hooks from the root of the working tree, so relative paths are made
+/// absolute.
+fn store_arg(store: Option<&Path>) -> CliResult<String> {
+    let store = match store {
+        Some(store) if store.is_relative() && !store::is_url(store) => {
+            env::current_dir()?.join(store)
+        }
+        Some(store) => store.to_path_buf(),
+        None => return Ok(String::new()),
+    };
+    Ok(format!(
+        " --store {}",
+        git::shell_quote(&store.to_string_lossy())
+    ))
+}
+
+/// Install the hooks in `HOOKS` into the current repository, passing on an explicitly given
+/// store like `install` does.
+fn install_hooks(store: Option<PathBuf>, force: bool) -> CliResult<()> {
+    let hooks_dir = open_repo()?.hooks_dir().map_err(CliError::git)?;
+    let store_arg = store_arg(store.as_deref())?;
+    // Check all hooks first, so that either all or none of them are installed
+    if !force {
+        for (name, _) in HOOKS {
+            let path = hooks_dir.join(name);
+            if path.exists() && !hooks::is_installed(&path)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already exists, pass --force to overwrite it",
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+        }
+    }
+    for (name, args) in HOOKS {
+        let command = format!("git-assets{} {}", store_arg, args);
+        hooks::install(&hooks_dir, name, &command, force)?;
+        println!("installed {}", hooks_dir.join(name).display());
+    }
+    Ok(())
+}
+
+/// Remove the hooks written by `install_hooks`, leaving all others alone.
+fn uninstall_hooks() -> CliResult<()> {
+    let hooks_dir = open_repo()?.hooks_dir().map_err(CliError::git)?;
+    for (name, _) in HOOKS {
+        if hooks::uninstall(&hooks_dir, name)? {
+            println!("removed {}", hooks_dir.join(name).display());
+        }
+    }
+    Ok(())
+}
+
+/// Install the filter in the current repository, track the given patterns and register the
+/// repository with its store.
+fn init(
+    store: Option<PathBuf>,
+    explicit_store: bool,
+    patterns: &[String],
+    algorithm: Option<&'static dyn HashAlgorithm>,
+) -> CliResult<()> {
+    install(store.clone(), explicit_store, false)?;
+    if !patterns.is_empty() {
+        track(patterns.to_vec(), None, false)?;
+    }
+
+    let store_path = store.ok_or(CliErrorKind::NotInGitRepo)?;
+    // Only local stores keep track of their repositories
+    if store::is_url(&store_path) {
+        return match algorithm {
+            Some(_) => Err(CliError::store_access(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the hash algorithm of remote stores cannot be chosen",
+            ))),
+            None => Ok(()),
+        };
+    }
+    let store = match algorithm {
+        Some(algorithm) => store::Store::open_or_create_with(store_path, algorithm)
+            .map_err(CliError::store_access)?,
+        None => open_store(store_path)?,
+    };
+    let git_dir = open_repo()?.git_dir().map_err(CliError::git)?;
+    store
+        .register_repo(&git_dir)
+        .map_err(CliError::store_access)?;
+    println!(
+        "registered {} with {}",
+        git_dir.display(),
+        store.base_dir().display()
+    );
+    Ok(())
+}
+
+/// Remove the filter configuration, optionally restoring the contents of pointer files and
+/// deleting the store.
+fn uninstall(
+    store: Option<PathBuf>,
+    global: bool,
+    smudge: bool,
+    delete_store: bool,
+) -> CliResult<()> {
+    let repo = open_repo()?;
+    if store.is_none() && (smudge || delete_store) {
+        return Err(CliErrorKind::NotInGitRepo.into());
+    }
+    let store_path = store;
+
+    if smudge {
+        let store = open_store(store_path.clone().expect("store is needed for smudging"))?;
+        let root = repo.work_tree().map_err(CliError::git)?;
+        for (path, store_ref) in repo.worktree_pointers().map_err(CliError::git)? {
+            store
+                .write_to_file(&store_ref, &root.join(&path))
+                .map_err(restore_error)?;
+            println!("restored {}", path);
+        }
+    }
+
+    let scope = if global { "--global" } else { "--local" };
+    let section = format!("filter.{}", attributes::DEFAULT_FILTER);
+    let pattern = format!("^{}\\.", section.replace('.', "\\."));
+    // Removing a section that does not exist is an error for git
+    if repo
+        .run(&["config", scope, "--get-regexp", &pattern])
+        .is_ok()
+    {
+        repo.run(&["config", scope, "--remove-section", &section])
+            .map_err(CliError::git)?;
+        println!("removed {} from the git config", section);
+    }
+
+    match store_path {
+        Some(store_path) if delete_store => {
+            if store::is_url(&store_path) {
+                return Err(CliError::store_access(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "refusing to delete a remote store",
+                )));
+            }
+            if store_path.exists() {
+                std::fs::remove_dir_all(&store_path).map_err(CliError::store_access)?;
+                println!("deleted {}", store_path.display());
+            }
+        }
+        Some(store_path) if !global => {
+            println!("the store is left in {}", store_path.display())
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Add patterns to `.gitattributes`, and report the files that are tracked because of them.
+fn track(mut patterns: Vec<String>, above: Option<u64>, per_file: bool) -> CliResult<()> {
+    let repo = open_repo()?;
+    if let Some(threshold) = above {
+        let large = large_file_patterns(&repo, threshold, per_file)?;
+        if large.is_empty() {
+            println!("no untracked files above {}", format_bytes(threshold));
+        }
+        patterns.extend(large);
+    }
+    if patterns.is_empty() {
+        return Ok(());
+    }
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let changes = edit_gitattributes(&repo, |contents| {
+        attributes::add_tracking_patterns(contents, attributes::DEFAULT_FILTER, &patterns)
+    })?;
+    for pattern in &patterns {
+        println!("tracking {}", pattern);
+    }
+    for path in &changes.tracked {
+        println!("now tracked: {}", path);
+    }
+    if !changes.tracked.is_empty() {
+        println!("Files that are already in the index only move to the store once they are added again, e.g. with `git add --renormalize .`");
+    }
+    Ok(())
+}
+
+/// Find the files in the working tree that are larger than `threshold` bytes but not yet
+/// routed through the filter, and return the patterns that would track them.
+fn large_file_patterns(
+    repo: &git::Repository,
+    threshold: u64,
+    per_file: bool,
+) -> CliResult<Vec<String>> {
+    let files = repo.ls_files(true).map_err(CliError::git)?;
+    let files: Vec<&str> = files.iter().map(String::as_str).collect();
+    let tracked = attributes::tracked_paths(
+        repo,
+        attributes::DEFAULT_FILTER,
+        &files,
+        attributes::AttrSource::WorkTree,
+    )
+    .map_err(CliError::git)?;
+    let root = repo.work_tree().map_err(CliError::git)?;
+
+    let mut patterns = std::collections::BTreeSet::new();
+    for path in files {
+        if tracked.iter().any(|tracked| tracked == path) {
+            continue;
+        }
+        // Files deleted from the working tree and symlinks are not converted
+        let is_large = std::fs::symlink_metadata(root.join(path))
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() > threshold);
+        if is_large {
+            let extension = attributes::extension_pattern(path).filter(|_| !per_file);
+            patterns.insert(extension.unwrap_or_else(|| attributes::path_pattern(path)));
+        }
+    }
+    Ok(patterns.into_iter().collect())
+}
+
+/// Remove patterns from `.gitattributes`, optionally putting the contents of the files that
+/// are no longer tracked back into the index.
+fn untrack(store_path: PathBuf, patterns: &[String], restore: bool) -> CliResult<()> {
+    let repo = open_repo()?;
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let mut removed = Vec::new();
+    let changes = edit_gitattributes(&repo, |contents| {
+        let (contents, removed_patterns) =
+            attributes::remove_tracking_patterns(contents, attributes::DEFAULT_FILTER, &patterns);
+        removed = removed_patterns;
+        contents
+    })?;
+    for pattern in &patterns {
+        if removed.iter().any(|removed| removed == pattern) {
+            println!("no longer tracking {}", pattern);
+        } else {
+            println!("{} was not tracked", pattern);
+        }
+    }
+    for path in &changes.untracked {
+        println!("no longer tracked: {}", path);
+    }
+    if !restore || changes.untracked.is_empty() {
+        return Ok(());
     }
+
+    // Files that were never smudged still contain pointers
+    let store = open_store(store_path)?;
+    let root = repo.work_tree().map_err(CliError::git)?;
+    for (path, store_ref) in repo.worktree_pointers().map_err(CliError::git)? {
+        if changes.untracked.contains(&path) {
+            store
+                .write_to_file(&store_ref, &root.join(&path))
+                .map_err(restore_error)?;
+        }
+    }
+    // Paths are relative to the root, not to the current directory
+    let pathspecs: Vec<String> = changes
+        .untracked
+        .iter()
+        .map(|path| format!(":(top,literal){}", path))
+        .collect();
+    let mut args = vec!["add", "--renormalize", "--"];
+    args.extend(pathspecs.iter().map(String::as_str));
+    repo.run(&args).map_err(CliError::git)?;
+    for path in &changes.untracked {
+        println!("restored {}", path);
+    }
+    Ok(())
+}
+
+/// The path of the `.gitattributes` file at the root of the repository, and its contents,
+/// which are empty if it doesn't exist.
+fn read_gitattributes(repo: &git::Repository) -> CliResult<(PathBuf, String)> {
+    let path = repo
+        .work_tree()
+        .map_err(CliError::git)?
+        .join(".gitattributes");
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    Ok((path, contents))
+}
+
+/// Like `edit_gitattributes` in a dry run: pass the contents of `.gitattributes` to `edit`,
+/// but don't write the result.
+fn preview_gitattributes(
+    repo: &git::Repository,
+    edit: impl FnOnce(&str) -> String,
+) -> CliResult<()> {
+    let (_, contents) = read_gitattributes(repo)?;
+    edit(&contents);
+    Ok(())
+}
+
+/// Files whose tracking status changed by editing `.gitattributes`.
+struct TrackingChanges {
+    /// Files that are now routed through the filter.
+    tracked: Vec<String>,
+    /// Files that are no longer routed through the filter.
+    untracked: Vec<String>,
+}
+
+/// Replace the contents of the `.gitattributes` file at the root of the repository, and find
+/// out which files in the working tree are affected.
+fn edit_gitattributes(
+    repo: &git::Repository,
+    edit: impl FnOnce(&str) -> String,
+) -> CliResult<TrackingChanges> {
+    let mut files = repo.ls_files(true).map_err(CliError::git)?;
+    files.sort();
+    let files: Vec<&str> = files.iter().map(String::as_str).collect();
+    let tracked_files = || {
+        attributes::tracked_paths(
+            repo,
+            attributes::DEFAULT_FILTER,
+            &files,
+            attributes::AttrSource::WorkTree,
+        )
+        .map_err(CliError::git)
+    };
+    let before = tracked_files()?;
+
+    let (path, contents) = read_gitattributes(repo)?;
+    std::fs::write(path, edit(&contents))?;
+
+    let after = tracked_files()?;
+    Ok(TrackingChanges {
+        tracked: after
+            .iter()
+            .filter(|path| !before.contains(path))
+            .cloned()
+            .collect(),
+        untracked: before
+            .into_iter()
+            .filter(|path| !after.contains(path))
+            .collect(),
+    })
 }
 
+/// Store a file from the working directory in the store, and with `sign`, sign it with the
+/// git signing key, see `signing`. New objects are recorded with `provenance`, see `metadata`.
+fn store_file(
+    store_path: PathBuf,
+    file: Option<PathBuf>,
+    chunked: bool,
+    pointer: PointerFormat,
+    min_size: u64,
+    sign: bool,
+    provenance: metadata::Provenance,
+) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_chunked(chunked);
+    store.set_provenance(provenance);
+    store.clean_stale_staging();
+    let signer = if sign { Some(open_repo()?) } else { None };
 
-/// Store a file from the working directory in the store
-fn store_file(store_path: PathBuf) -> CliResult<()> {
-    let store = store::Store::open_or_create(store_path).map_err(CliError::store_access)?;
+    if let Some(file) = file {
+        if std::fs::metadata(&file)?.len() < pass_through_limit(min_size) {
+            let contents = std::fs::read(&file)?;
+            if passes_through(&contents, min_size) {
+                io::stdout().write_all(&contents)?;
+                return Ok(());
+            }
+        }
+        let store_ref = store.store_path(&file).map_err(CliError::store_access)?;
+        log_stored(&store_ref);
+        if let Some(repo) = &signer {
+            sign_object(&store, repo, store_ref.hash())?;
+        }
+        println!("{}", store_ref.to_pointer(pointer));
+        return Ok(());
+    }
+
+    let mut stdin = io::stdin().lock();
+    let mut head = Vec::new();
+    (&mut stdin)
+        .take(pass_through_limit(min_size))
+        .read_to_end(&mut head)?;
+    if passes_through(&head, min_size) {
+        io::stdout().write_all(&head)?;
+        return Ok(());
+    }
 
-    // Copy stdin (where git provides the file contents) to a temporary file,
-    // which also computes the hash while writing.
-    let mut staging_file = store.new_staging_file().map_err(CliError::store_access)?;
-    io::copy(&mut io::stdin().lock(), &mut staging_file)?;
+    // Copy stdin (where git provides the file contents) to a temporary file, which also
+    // computes the hash while writing unless it is computed in parallel afterwards.
+    let staging_file = store
+        .new_staging_file_parallel()
+        .map_err(CliError::store_access)?;
+    let mut progress = Progress::new(staging_file, "storing", None);
+    progress.write_all(&head)?;
+    io::copy(&mut stdin, &mut progress)?;
+    let staging_file = progress.finish();
     // If writing was successful, we make the file permanent.
-    let store_ref = store.make_permanent(staging_file).map_err(CliError::store_access)?;
+    let store_ref = store
+        .make_permanent(staging_file)
+        .map_err(CliError::store_access)?;
+    log_stored(&store_ref);
+    if let Some(repo) = &signer {
+        sign_object(&store, repo, store_ref.hash())?;
+    }
 
     // Print reference to stdout so that we can fetch the contents back during retrieve
-    println!("{}", store_ref.to_string());
+    println!("{}", store_ref.to_pointer(pointer));
 
     Ok(())
 }
 
-/// Read a file from the store and put it in the working directory.
-fn retrieve_file(store_path: PathBuf) -> CliResult<()> {
-    // Parse the reference to the actual file
-    let store_ref = store::StoreFileRef::parse_from_stream(&mut io::stdin().lock())?;
-    // And dereference it using the given store
-    let store = store::Store::open_or_create(store_path).map_err(CliError::store_access)?;
-    let mut file = store.open_ref(&store_ref).map_err(CliError::no_such_content)?;
-    io::copy(&mut file, &mut io::stdout().lock())?;
+/// Store every file framed on stdin, see `batch`.
+///
+/// Files are read from stdin in order, while `jobs` threads hash and store the ones read
+/// before. Answers are written in the order of the files, each as soon as it is ready.
+fn store_file_batch(
+    store_path: PathBuf,
+    chunked: bool,
+    pointer: PointerFormat,
+    min_size: u64,
+    jobs: usize,
+    sign: bool,
+    provenance: metadata::Provenance,
+) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_chunked(chunked);
+    store.set_provenance(provenance);
+    let store = &store;
+    let signer = if sign { Some(open_repo()?) } else { None };
+    let signer = &signer;
 
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let files = std::iter::from_fn(|| {
+        let length = match batch::read_length(&mut input) {
+            Ok(Some(length)) => length,
+            Ok(None) => return None,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(stage_batch_file(store, &mut input, length, min_size))
+    });
+    parallel::map_ordered(
+        jobs,
+        files,
+        |file| -> io::Result<Vec<u8>> {
+            match file {
+                BatchFile::PassThrough(contents) => Ok(contents),
+                BatchFile::Staged(staging_file) => {
+                    let store_ref = store.make_permanent(staging_file)?;
+                    log_stored(&store_ref);
+                    if let Some(repo) = signer {
+                        // Only the message of the error is answered, which its kind is part of
+                        sign_object(store, repo, store_ref.hash())
+                            .map_err(|err| io::Error::other(err.to_string()))?;
+                    }
+                    Ok(format!("{}\n", store_ref.to_pointer(pointer)).into_bytes())
+                }
+            }
+        },
+        |answer| {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            // The input is still in sync after failing to store a file, e.g. due to the quota
+            match answer {
+                Ok(payload) => batch::write_payload(&mut out, &payload)?,
+                Err(err) => batch::write_error(&mut out, &err)?,
+            }
+            out.flush()
+        },
+    )?;
     Ok(())
 }
 
-/// Check whether the store contents are consistent.
-fn validate(store_path: PathBuf) -> CliResult<()> {
-    // And dereference it using the given store
-    let store = store::Store::open_or_create(store_path).map_err(CliError::store_access)?;
-    let report = store.validate()?;
+/// A file read by `store_file_batch`.
+enum BatchFile {
+    /// Contents that are answered as they are, see `passes_through`.
+    PassThrough(Vec<u8>),
+    /// Contents that are yet to be hashed and stored.
+    Staged(store::StagingFile),
+}
 
-    if report.is_valid() {
-        Ok(())
+/// Read the next file of `length` bytes for `store_file_batch` from `input`.
+fn stage_batch_file<R: Read>(
+    store: &store::Store,
+    input: &mut R,
+    length: u64,
+    min_size: u64,
+) -> io::Result<BatchFile> {
+    let small = if length < pass_through_limit(min_size) {
+        Some(batch::read_payload(input, length)?)
     } else {
-        for hash_mismatch in &report.hash_mismatches {
-            println!(
-                "hash-mismatch: {}: {} != {}",
-                hash_mismatch.file_name.display(),
-                hash_mismatch.expected_hash,
-                hash_mismatch.actual_hash
-            );
+        None
+    };
+    let small = match small {
+        Some(contents) if passes_through(&contents, min_size) => {
+            return Ok(BatchFile::PassThrough(contents))
         }
+        small => small,
+    };
+    let mut staging_file = store.new_deferred_staging_file_with(store.algorithm())?;
+    let copied = match &small {
+        Some(contents) => io::copy(&mut contents.as_slice(), &mut staging_file),
+        None => io::copy(&mut input.take(length), &mut staging_file),
+    };
+    if copied.as_ref().ok() != Some(&length) {
+        staging_file.discard()?;
+        return Err(copied.err().unwrap_or_else(batch::truncated));
+    }
+    Ok(BatchFile::Staged(staging_file))
+}
 
-        for unexpected_file in &report.unexpected_files {
-            println!("unexpected: {}", unexpected_file.display());
+/// Sign a stored object with the git signing key, unless it is signed already, see `signing`.
+fn sign_object(store: &store::Store, repo: &git::Repository, hash: &ContentHash) -> CliResult<()> {
+    if store
+        .signature(hash)
+        .map_err(CliError::store_access)?
+        .is_none()
+    {
+        let signature = signing::sign(repo, hash).map_err(CliError::invalid_config)?;
+        store
+            .add_signature(hash, &signature)
+            .map_err(CliError::store_access)?;
+    }
+    Ok(())
+}
+
+/// Fail unless an object is signed by a signer that git trusts, see `signing`.
+fn check_signature(
+    store: &store::Store,
+    repo: &git::Repository,
+    hash: &ContentHash,
+) -> CliResult<()> {
+    let verification = match store.signature(hash).map_err(CliError::store_access)? {
+        Some(signature) => {
+            signing::verify(repo, hash, &signature).map_err(CliError::invalid_config)?
         }
+        None => signing::Verification::Untrusted("it is not signed".into()),
+    };
+    match verification {
+        signing::Verification::Trusted(signer) => {
+            log::info!("{} is signed by {}", hash, signer);
+            Ok(())
+        }
+        signing::Verification::Untrusted(reason) => Err(CliError::with_source(
+            CliErrorKind::BadSignature,
+            Box::new(io::Error::other(format!("{}: {}", hash, reason))),
+        )),
+    }
+}
 
-        Err(CliErrorKind::Inconsistent.into())
+fn log_stored(store_ref: &store::StoreFileRef) {
+    log::info!(
+        "stored {} ({})",
+        store_ref.hash(),
+        format_bytes(store_ref.size().unwrap_or(0))
+    );
+}
+
+/// Read a file from the store and put it in the working directory.
+///
+/// With `skip`, the pointer is put there instead, see `skip_smudge`. With `verify_signatures`,
+/// only objects signed by a trusted signer are put there.
+fn retrieve_file(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    alternates: &[PathBuf],
+    skip: bool,
+    verify_reads: bool,
+    verify_signatures: bool,
+) -> CliResult<()> {
+    if skip {
+        io::copy(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+        return Ok(());
+    }
+    // Parse the reference to the actual file, passing anything else through
+    let mut stdin = io::stdin().lock();
+    let mut head = Vec::new();
+    (&mut stdin)
+        .take(store::MAX_POINTER_LEN)
+        .read_to_end(&mut head)?;
+    if !looks_like_pointer(&head) {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&head)?;
+        io::copy(&mut stdin, &mut stdout)?;
+        return Ok(());
+    }
+    let store_ref = parse_pointer(&head).map_err(CliError::no_such_content)?;
+    // And dereference it using the given store, falling back to the alternates and the remote
+    let mut store = open_store(store_path)?;
+    store.set_verify_reads(verify_reads);
+    let present = store
+        .object_size(&store_ref)
+        .map_err(CliError::store_access)?
+        .is_some();
+    if !present && !fetch_from_alternates(&store, &store_ref, alternates) {
+        if let Some(remote) = &remote {
+            // Downloading is the slow part, so it gets a progress bar of its own
+            let remote = open_store(remote.clone())?;
+            let mut bar = ProgressBar::new("downloading", store_ref.size());
+            store
+                .fetch_from_remote_with(
+                    &remote,
+                    std::iter::once(store_ref.hash().clone()),
+                    &mut |progress| bar.set_done(progress.bytes),
+                )
+                .map_err(CliError::transfer)?;
+            bar.finish();
+        }
+    }
+    if verify_signatures {
+        let has_signature = store
+            .signature(store_ref.hash())
+            .map_err(CliError::store_access)?
+            .is_some();
+        // Objects that were present before they were signed may only be signed on the remote
+        if let (false, Some(remote)) = (has_signature, remote) {
+            store.set_remote(open_store(remote)?);
+        }
+        check_signature(&store, &open_repo()?, store_ref.hash())?;
+    }
+    let mut file = store
+        .open_ref(&store_ref)
+        .map_err(CliError::no_such_content)?;
+    let total = file.size();
+    let mut progress = Progress::new(io::stdout().lock(), "retrieving", Some(total));
+    io::copy(&mut file, &mut progress).map_err(read_error)?;
+    progress.finish().flush()?;
+    log::info!("retrieved {} ({})", store_ref.hash(), format_bytes(total));
+
+    Ok(())
+}
+
+/// Retrieve the contents of every reference framed on stdin, see `batch`.
+fn retrieve_file_batch(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    alternates: &[PathBuf],
+    skip: bool,
+    verify_reads: bool,
+    verify_signatures: bool,
+) -> CliResult<()> {
+    let repo = open_repo()?;
+    let mut store = open_store(store_path)?;
+    store.set_verify_reads(verify_reads);
+    if let Some(remote) = remote {
+        store.set_remote(open_store(remote)?);
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    while let Some(length) = batch::read_length(&mut input)? {
+        let pointer = batch::read_payload(&mut input, length)?;
+        if skip || !looks_like_pointer(&pointer) {
+            batch::write_payload(&mut out, &pointer)?;
+            out.flush()?;
+            continue;
+        }
+        let store_ref = match parse_pointer(&pointer) {
+            Ok(store_ref) => store_ref,
+            Err(err) => {
+                batch::write_error(&mut out, &err)?;
+                out.flush()?;
+                continue;
+            }
+        };
+        // Alternates are local, so they go before the remote
+        let file = store.object_size(&store_ref).and_then(|size| {
+            if size.is_none() {
+                fetch_from_alternates(&store, &store_ref, alternates);
+            }
+            store.open_ref(&store_ref)
+        });
+        if let (Ok(_), true) = (&file, verify_signatures) {
+            if let Err(err) = check_signature(&store, &repo, store_ref.hash()) {
+                batch::write_error(&mut out, &err)?;
+                out.flush()?;
+                continue;
+            }
+        }
+        match file {
+            Ok(mut file) => {
+                writeln!(out, "{}", file.size())?;
+                let copied = io::copy(&mut file, &mut out).map_err(read_error)?;
+                if copied != file.size() {
+                    return Err(batch::truncated().into());
+                }
+                log::info!("retrieved {} ({})", store_ref.hash(), format_bytes(copied));
+            }
+            Err(err) => batch::write_error(&mut out, &err)?,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Replace the pointer files in the working tree that match any of the patterns, or all of
+/// them without patterns, by their contents.
+///
+/// Objects missing from the store are fetched from the alternates or the remote. Files whose
+/// contents cannot be found anywhere keep their pointer and are reported. Files skipped with
+/// `checkout --pointer` are left alone, unless a pattern matches them.
+fn checkout(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    alternates: &[PathBuf],
+    patterns: &[String],
+    verify_reads: bool,
+) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_verify_reads(verify_reads);
+    if let Some(remote) = remote {
+        store.set_remote(open_store(remote)?);
+    }
+    let repo = open_repo()?;
+    let root = repo.work_tree().map_err(CliError::git)?;
+    let git_dir = repo.git_dir().map_err(CliError::git)?;
+    let mut skipped = read_skipped(&git_dir)?;
+
+    let mut missing = 0;
+    let mut checked_out = Vec::new();
+    for (path, store_ref) in repo.worktree_pointers().map_err(CliError::git)? {
+        if patterns.is_empty() {
+            if skipped.contains(&path) {
+                continue;
+            }
+        } else if !patterns
+            .iter()
+            .any(|pattern| attributes::pattern_matches(pattern, &path))
+        {
+            continue;
+        }
+        if store
+            .object_size(&store_ref)
+            .map_err(CliError::store_access)?
+            .is_none()
+        {
+            fetch_from_alternates(&store, &store_ref, alternates);
+        }
+        match store.write_to_file(&store_ref, &root.join(&path)) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                println!("missing: {}", path);
+                missing += 1;
+                continue;
+            }
+            Err(err) => return Err(read_error(err)),
+        }
+        println!("checked out {}", path);
+        checked_out.push(path);
+    }
+    refresh_index(root, &checked_out, false)?;
+    let skipped_before = skipped.len();
+    skipped.retain(|path| !checked_out.contains(path));
+    if skipped.len() != skipped_before {
+        write_skipped(&git_dir, &skipped)?;
+    }
+
+    if missing == 0 {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
     }
 }
+
+/// Replace the unmodified files in the working tree that match any of the patterns, or all of
+/// them without patterns, by their pointers, and record them as skipped.
+///
+/// Files whose contents differ from what is staged are left alone, since replacing them would
+/// lose the changes.
+fn checkout_pointers(patterns: &[String]) -> CliResult<()> {
+    let repo = open_repo()?;
+    let root = repo.work_tree().map_err(CliError::git)?;
+    let git_dir = repo.git_dir().map_err(CliError::git)?;
+
+    let entries: Vec<git::IndexEntry> = repo
+        .index_entries()
+        .map_err(CliError::git)?
+        .into_iter()
+        .filter(|entry| {
+            patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|pattern| attributes::pattern_matches(pattern, &entry.path))
+        })
+        .collect();
+    let blobs: Vec<&str> = entries.iter().map(|entry| entry.blob.as_str()).collect();
+    let contents = repo.cat_blobs(&blobs).map_err(CliError::git)?;
+
+    let mut skipped = read_skipped(&git_dir)?;
+    let mut replaced = Vec::new();
+    let mut modified = 0;
+    for (entry, pointer) in entries.iter().zip(contents) {
+        let store_ref = match store::StoreFileRef::parse_from_stream(&mut pointer.as_slice()) {
+            Ok(store_ref) => store_ref,
+            Err(_) => continue,
+        };
+        let path = root.join(&entry.path);
+        let mut file = match std::fs::File::open(&path) {
+            Ok(file) if file.metadata()?.is_file() => file,
+            // Deleted files are not there to be replaced
+            _ => continue,
+        };
+        // Already a pointer, only remember to leave it alone
+        if store::StoreFileRef::parse_from_stream(&mut file).is_ok() {
+            skipped.insert(entry.path.clone());
+            continue;
+        }
+        let mut file = std::fs::File::open(&path)?;
+        if ContentHash::hash_file(store_ref.hash().algorithm(), &mut file)? != *store_ref.hash() {
+            println!("modified: {}", entry.path);
+            modified += 1;
+            continue;
+        }
+
+        // An index entry that is not older than the index looks racily clean to git, which
+        // then compares the file by cleaning it again, to a pointer to the pointer. Keeping
+        // the modification time of the contents, but at least a bit in the past, avoids that.
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()?
+            .min(SystemTime::now() - Duration::from_secs(2));
+        let temp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+        let written = std::fs::File::create(&temp_path)
+            .and_then(|mut temp| {
+                temp.write_all(&pointer)?;
+                temp.set_permissions(metadata.permissions())?;
+                temp.set_modified(modified)
+            })
+            .and_then(|()| std::fs::rename(&temp_path, &path));
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(err.into());
+        }
+        println!("skipped {}", entry.path);
+        skipped.insert(entry.path.clone());
+        replaced.push(entry.path.clone());
+    }
+    refresh_index(root, &replaced, true)?;
+    write_skipped(&git_dir, &skipped)?;
+
+    if modified == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} modified files were left alone, commit or discard the changes first",
+                modified
+            ),
+        )
+        .into())
+    }
+}
+
+/// Update the index entries of files whose contents were exchanged for their pointer or the
+/// other way round. git considers files whose size changed as modified until then.
+///
+/// Refreshing cleans contents to the same pointer again. Files that are `pointers` are passed
+/// through unchanged instead, since storing them would result in a pointer to the pointer.
+fn refresh_index(root: PathBuf, paths: &[String], pointers: bool) -> CliResult<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let paths = paths.join("\0");
+    let pass_through = format!("filter.{}.clean=cat", attributes::DEFAULT_FILTER);
+    let mut args = vec!["update-index", "-z", "--stdin"];
+    if pointers {
+        args.splice(0..0, ["-c", pass_through.as_str()]);
+    }
+    // The paths are relative to the root
+    git::Repository::new(root)
+        .run_with_input(&args, paths.into_bytes())
+        .map_err(CliError::git)?;
+    Ok(())
+}
+
+/// Name of the file in the git directory of a worktree that lists the files skipped with
+/// `checkout --pointer`, one path relative to the root per line.
+const SKIPPED_FILE: &str = "x-assets-skipped";
+
+/// Read the paths skipped with `checkout --pointer`.
+fn read_skipped(git_dir: &Path) -> CliResult<BTreeSet<String>> {
+    match std::fs::read_to_string(git_dir.join(SKIPPED_FILE)) {
+        Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the list of paths skipped with `checkout --pointer`.
+fn write_skipped(git_dir: &Path, skipped: &BTreeSet<String>) -> CliResult<()> {
+    let path = git_dir.join(SKIPPED_FILE);
+    if skipped.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+    let contents: String = skipped.iter().flat_map(|path| [path, "\n"]).collect();
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Classify a failure to copy the contents of an object: contents that turn out to be corrupt
+/// while reading them with `verify-reads` mean that the store is inconsistent.
+fn read_error(err: io::Error) -> CliError {
+    if err.kind() == io::ErrorKind::InvalidData {
+        CliError::inconsistent(err)
+    } else {
+        err.into()
+    }
+}
+
+/// Turn an error of `Store::write_to_file` into the error of a command that restores the
+/// contents of pointer files.
+fn restore_error(err: io::Error) -> CliError {
+    if err.kind() == io::ErrorKind::NotFound {
+        CliError::no_such_content(err)
+    } else {
+        read_error(err)
+    }
+}
+
+/// The size of an object as recorded in its pointer, or else as found in the store, or `None`
+/// if neither knows it.
+fn object_size(store: &store::Store, store_ref: &store::StoreFileRef) -> CliResult<Option<u64>> {
+    match store_ref.size() {
+        Some(size) => Ok(Some(size)),
+        None => store.object_size(store_ref).map_err(CliError::store_access),
+    }
+}
+
+/// Try to copy a missing object from one of the alternate stores, returning whether it worked.
+///
+/// Alternates given by URL are peers, e.g. `serve` running on another machine in the LAN.
+/// Problems with individual alternates are only reported as warnings, since the object might
+/// still be found in another one.
+fn fetch_from_alternates(
+    store: &store::Store,
+    store_ref: &store::StoreFileRef,
+    alternates: &[PathBuf],
+) -> bool {
+    for alternate in alternates {
+        let opened = if store::is_url(alternate) {
+            open_store(alternate.clone()).map_err(|err| io::Error::other(err.to_string()))
+        } else {
+            store::Store::open(alternate.clone())
+        };
+        let result = opened.and_then(|alternate| store.fetch_from(&alternate, store_ref));
+        match result {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(err) => log::warn!("skipping alternate store {}: {}", alternate.display(), err),
+        }
+    }
+    false
+}
+
+/// Check whether the store contents are consistent.
+fn validate(
+    store_path: PathBuf,
+    jobs: Option<usize>,
+    full: bool,
+    fix: bool,
+    dry_run: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    // And dereference it using the given store
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let jobs = cpu_jobs(jobs);
+    let report = store.validate_with(jobs, full)?;
+    let repair = if fix {
+        // The same age that `gc --staging` uses by default
+        let repair = store
+            .repair(&report, Duration::from_secs(24 * 60 * 60))
+            .map_err(CliError::store_access)?;
+        Some(repair)
+    } else {
+        None
+    };
+    let valid = report.is_valid();
+
+    if format == OutputFormat::Json {
+        print_json(&Validation {
+            valid,
+            report,
+            repair,
+            dry_run,
+        })?;
+    } else {
+        print_validation(&report, repair.as_ref(), dry_run);
+    }
+    if valid || (fix && !dry_run) {
+        Ok(())
+    } else {
+        Err(CliErrorKind::Inconsistent.into())
+    }
+}
+
+/// Print the problems found by `validate`, and what `--fix` did, or would do, about them.
+fn print_validation(
+    report: &store::ValidationReport,
+    repair: Option<&store::RepairReport>,
+    dry_run: bool,
+) {
+    for hash_mismatch in &report.hash_mismatches {
+        println!(
+            "hash-mismatch: {}: {} != {}",
+            hash_mismatch.file_name.display(),
+            hash_mismatch.expected_hash,
+            hash_mismatch.actual_hash
+        );
+    }
+
+    for unexpected_file in &report.unexpected_files {
+        println!("unexpected: {}", unexpected_file.display());
+    }
+
+    for broken_manifest in &report.broken_manifests {
+        println!("broken-manifest: {}", broken_manifest.display());
+    }
+
+    for malformed_ref in &report.malformed_refs {
+        println!("malformed-ref: {}", malformed_ref.display());
+    }
+
+    for stale_ref in &report.stale_refs {
+        println!("stale-ref: {}", stale_ref.display());
+    }
+
+    if let Some(repair) = repair {
+        let (quarantined, removed) = if dry_run {
+            ("would quarantine", "would remove")
+        } else {
+            ("quarantined", "removed")
+        };
+        for path in &repair.quarantined {
+            println!("{}: {}", quarantined, path.display());
+        }
+        for path in repair.removed.iter().chain(&repair.staging.removed) {
+            println!("{}: {}", removed, path.display());
+        }
+    }
+}
+
+/// The outcome of `validate`, for JSON output.
+#[derive(Serialize)]
+struct Validation {
+    valid: bool,
+    #[serde(flatten)]
+    report: store::ValidationReport,
+    /// What `--fix` did, if given.
+    repair: Option<store::RepairReport>,
+    /// Whether `--fix` only reported what it would do.
+    dry_run: bool,
+}
+
+/// The assets referenced by a single commit.
+#[derive(Serialize)]
+struct Manifest {
+    commit: String,
+    assets: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    hash: ContentHash,
+    /// Size of the object as recorded in the pointer, or else as found in the local store.
+    size: Option<u64>,
+    /// Media type of the contents, if the pointer records it.
+    media_type: Option<String>,
+}
+
+/// Print the paths, hashes and sizes of all assets referenced by a commit, and for JSON also
+/// their media types.
+fn manifest(store_path: PathBuf, rev: &str, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+    let commit = repo.resolve_commit(rev).map_err(CliError::git)?;
+
+    let mut assets = Vec::new();
+    for pointer in repo.pointers(&commit).map_err(CliError::git)? {
+        assets.push(ManifestEntry {
+            size: object_size(&store, &pointer.store_ref)?,
+            media_type: pointer.store_ref.media_type().map(str::to_string),
+            path: pointer.path,
+            hash: pointer.store_ref.hash().clone(),
+        });
+    }
+    let manifest = Manifest { commit, assets };
+
+    match format {
+        OutputFormat::Text => {
+            for entry in &manifest.assets {
+                let size = entry.size.map_or("-".to_string(), |s| s.to_string());
+                println!("{} {:>12} {}", entry.hash, size, entry.path);
+            }
+        }
+        OutputFormat::Json => print_json(&manifest)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    Ok(())
+}
+
+/// Which of the assets referenced by a commit the store lacks.
+#[derive(Serialize)]
+struct AssetStatus {
+    commit: String,
+    /// Number of assets referenced by the commit.
+    assets: usize,
+    /// Number of distinct objects they refer to.
+    objects: usize,
+    missing: Vec<MissingAsset>,
+}
+
+#[derive(Serialize)]
+struct MissingAsset {
+    path: String,
+    hash: ContentHash,
+}
+
+/// Check the objects of all assets referenced by a commit against the store.
+fn status(
+    store_path: PathBuf,
+    rev: &str,
+    list_missing: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+    let commit = repo.resolve_commit(rev).map_err(CliError::git)?;
+    let pointers = repo.pointers(&commit).map_err(CliError::git)?;
+
+    let mut objects = HashSet::new();
+    let mut missing = HashSet::new();
+    for pointer in &pointers {
+        let hash = pointer.store_ref.hash();
+        // Several files can share an object, which only needs to be looked up once
+        if objects.insert(hash.clone())
+            && store
+                .object_size(&pointer.store_ref)
+                .map_err(CliError::store_access)?
+                .is_none()
+        {
+            missing.insert(hash.clone());
+        }
+    }
+    let status = AssetStatus {
+        commit,
+        assets: pointers.len(),
+        objects: objects.len(),
+        missing: pointers
+            .into_iter()
+            .filter(|pointer| missing.contains(pointer.store_ref.hash()))
+            .map(|pointer| MissingAsset {
+                hash: pointer.store_ref.hash().clone(),
+                path: pointer.path,
+            })
+            .collect(),
+    };
+
+    match format {
+        OutputFormat::Text => {
+            if list_missing {
+                for asset in &status.missing {
+                    println!("{} {}", asset.hash, asset.path);
+                }
+            } else {
+                println!(
+                    "{} assets ({} objects), {} missing ({} objects)",
+                    status.assets,
+                    status.objects,
+                    status.missing.len(),
+                    missing.len()
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&status)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    if status.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// The outcome of one of the checks of `doctor`.
+#[derive(Serialize)]
+struct Diagnosis {
+    check: &'static str,
+    health: Health,
+    message: String,
+    /// What to do about it, unless everything is fine.
+    fix: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Health {
+    Ok,
+    /// Works, but probably not as intended.
+    Warning,
+    Error,
+}
+
+impl Diagnosis {
+    fn ok(check: &'static str, message: String) -> Diagnosis {
+        Diagnosis {
+            check,
+            health: Health::Ok,
+            message,
+            fix: None,
+        }
+    }
+
+    fn problem(check: &'static str, health: Health, message: String, fix: String) -> Diagnosis {
+        Diagnosis {
+            check,
+            health,
+            message,
+            fix: Some(fix),
+        }
+    }
+}
+
+/// Check the whole setup of the current repository, see `Command::Doctor`.
+fn doctor(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    min_size: u64,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    let repo = open_repo()?;
+    let mut diagnoses = vec![
+        diagnose_filter(&repo)?,
+        diagnose_store(&store_path),
+        diagnose_remote(remote),
+    ];
+    diagnose_head(&repo, min_size, &mut diagnoses)?;
+
+    let errors = diagnoses
+        .iter()
+        .filter(|diagnosis| diagnosis.health == Health::Error)
+        .count();
+    match format {
+        OutputFormat::Text => {
+            for diagnosis in &diagnoses {
+                let health = match diagnosis.health {
+                    Health::Ok => "ok",
+                    Health::Warning => "warning",
+                    Health::Error => "error",
+                };
+                println!("{:<8}{}: {}", health, diagnosis.check, diagnosis.message);
+                if let Some(fix) = &diagnosis.fix {
+                    println!("{:<8}fix: {}", "", fix);
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&diagnoses)?,
+        OutputFormat::Csv => unreachable!("rejected above"),
+    }
+    if errors == 0 {
+        Ok(())
+    } else {
+        Err(CliError::invalid_config(io::Error::other(format!(
+            "doctor found {} problems",
+            errors
+        ))))
+    }
+}
+
+/// Check that git runs git-assets as the filter, and that it can find the program.
+fn diagnose_filter(repo: &git::Repository) -> CliResult<Diagnosis> {
+    let filter = attributes::DEFAULT_FILTER;
+    let get = |name: &str| {
+        repo.config_get(&format!("filter.{}.{}", filter, name))
+            .map_err(CliError::git)
+    };
+    let (clean, smudge) = match (get("clean")?, get("smudge")?) {
+        (Some(clean), Some(smudge)) => (clean, smudge),
+        _ => {
+            return Ok(Diagnosis::problem(
+                "filter",
+                Health::Error,
+                format!("the `{}` filter is not configured", filter),
+                "run `git assets install`".to_string(),
+            ))
+        }
+    };
+    for command in [&clean, &smudge] {
+        let program = command_program(command);
+        if !program_exists(&program) {
+            return Ok(Diagnosis::problem(
+                "filter",
+                Health::Error,
+                format!("git cannot find `{}`, which the filter runs", program),
+                "put git-assets on the PATH, or run `git assets install` again".to_string(),
+            ));
+        }
+    }
+    if get("required")?.and_then(|value| config::parse_bool(&value)) != Some(true) {
+        return Ok(Diagnosis::problem(
+            "filter",
+            Health::Warning,
+            "the filter is not required, so files are committed with their contents if it fails"
+                .to_string(),
+            format!("git config filter.{}.required true", filter),
+        ));
+    }
+    Ok(Diagnosis::ok(
+        "filter",
+        format!("`{}` and `{}`", clean, smudge),
+    ))
+}
+
+/// The program a filter command runs, i.e. its first word, which may be quoted.
+fn command_program(command: &str) -> String {
+    let command = command.trim_start();
+    match command.chars().next() {
+        Some(quote @ ('\'' | '"')) => command[1..].split(quote).next().unwrap_or("").to_string(),
+        _ => command.split_whitespace().next().unwrap_or("").to_string(),
+    }
+}
+
+/// Whether a program can be run, either by its path or by searching the `PATH`.
+fn program_exists(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file() || (cfg!(windows) && candidate.with_extension("exe").is_file())
+    })
+}
+
+/// Check that the store can be opened and written to.
+fn diagnose_store(store_path: &Path) -> Diagnosis {
+    let written = open_store(store_path.to_path_buf()).and_then(|store| {
+        let staging_file = store.new_staging_file().map_err(CliError::store_access)?;
+        staging_file.discard().map_err(CliError::store_access)
+    });
+    match written {
+        Ok(()) => Diagnosis::ok("store", format!("{} is writable", store_path.display())),
+        Err(err) => Diagnosis::problem(
+            "store",
+            Health::Error,
+            format!(
+                "cannot write to {}: {}",
+                store_path.display(),
+                err.source().map_or(err.to_string(), ToString::to_string)
+            ),
+            "check that the store exists and is writable, or run `git assets install`".to_string(),
+        ),
+    }
+}
+
+/// Check that the remote store, if any, can be reached.
+fn diagnose_remote(remote: Option<PathBuf>) -> Diagnosis {
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return Diagnosis::ok("remote", "none configured".to_string()),
+    };
+    // Looking up an object that is unlikely to exist needs a working connection
+    let probe = store::StoreFileRef::from_hash(ContentHash::hash_bytes(hash::SHA256, b""));
+    let reached = open_store(remote.clone())
+        .and_then(|remote| remote.object_size(&probe).map_err(CliError::store_access));
+    match reached {
+        Ok(_) => Diagnosis::ok("remote", format!("{} is reachable", remote.display())),
+        Err(err) => Diagnosis::problem(
+            "remote",
+            Health::Error,
+            format!(
+                "cannot reach {}: {}",
+                remote.display(),
+                err.source().map_or(err.to_string(), ToString::to_string)
+            ),
+            "check the connection, or the `remote` setting (e.g. `git config assets.remote`)"
+                .to_string(),
+        ),
+    }
+}
+
+/// Check that the files committed in `HEAD` agree with `.gitattributes`: tracked files should
+/// be committed as pointers, and pointers should be tracked.
+fn diagnose_head(
+    repo: &git::Repository,
+    min_size: u64,
+    diagnoses: &mut Vec<Diagnosis>,
+) -> CliResult<()> {
+    let attributes = repo
+        .work_tree()
+        .ok()
+        .and_then(|root| std::fs::read_to_string(root.join(".gitattributes")).ok())
+        .unwrap_or_default();
+    let filter = attributes::DEFAULT_FILTER;
+    if !attributes.contains(&format!("filter={}", filter)) {
+        diagnoses.push(Diagnosis::problem(
+            "attributes",
+            Health::Warning,
+            "no patterns in .gitattributes use the filter".to_string(),
+            "track files with e.g. `git assets track '*.psd'`".to_string(),
+        ));
+    }
+    if repo.resolve_commit("HEAD").is_err() {
+        diagnoses.push(Diagnosis::ok("pointers", "no commits yet".to_string()));
+        return Ok(());
+    }
+
+    let entries = repo.ls_tree("HEAD").map_err(CliError::git)?;
+    let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    let tracked: HashSet<String> =
+        attributes::tracked_paths(repo, filter, &paths, attributes::AttrSource::WorkTree)
+            .map_err(CliError::git)?
+            .into_iter()
+            .collect();
+    let small: Vec<&git::TreeEntry> = entries
+        .iter()
+        .filter(|entry| entry.size <= store::MAX_POINTER_LEN)
+        .collect();
+    let blobs: Vec<&str> = small.iter().map(|entry| entry.blob.as_str()).collect();
+    let contents = repo.cat_blobs(&blobs).map_err(CliError::git)?;
+    let mut pointers = HashSet::new();
+    let mut malformed = Vec::new();
+    for (entry, contents) in small.iter().zip(contents) {
+        if store::StoreFileRef::parse_from_stream(&mut contents.as_slice()).is_ok() {
+            pointers.insert(entry.path.as_str());
+        } else if looks_like_pointer(&contents) {
+            malformed.push(entry.path.as_str());
+        }
+    }
+
+    // Small files may pass through the filter unchanged, see `passes_through`
+    let unfiltered: Vec<&str> = entries
+        .iter()
+        .filter(|entry| {
+            tracked.contains(&entry.path)
+                && !pointers.contains(entry.path.as_str())
+                && !malformed.contains(&entry.path.as_str())
+                && entry.size >= min_size
+        })
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let untracked: Vec<&str> = pointers
+        .iter()
+        .copied()
+        .filter(|path| !tracked.contains(*path))
+        .collect();
+    let example = |paths: &[&str]| {
+        let mut paths = paths.to_vec();
+        paths.sort_unstable();
+        match paths.len() {
+            1 => paths[0].to_string(),
+            n => format!("{} and {} more", paths[0], n - 1),
+        }
+    };
+
+    if !unfiltered.is_empty() {
+        diagnoses.push(Diagnosis::problem(
+            "attributes",
+            Health::Error,
+            format!(
+                "tracked files are committed with their contents instead of a pointer: {}",
+                example(&unfiltered)
+            ),
+            "git add --renormalize . && git commit".to_string(),
+        ));
+    }
+    if !untracked.is_empty() {
+        diagnoses.push(Diagnosis::problem(
+            "attributes",
+            Health::Warning,
+            format!(
+                "pointers are committed for files that are not tracked, so they are checked \
+                 out as pointers: {}",
+                example(&untracked)
+            ),
+            "track them with `git assets track <pattern>`".to_string(),
+        ));
+    }
+    if unfiltered.is_empty() && untracked.is_empty() {
+        diagnoses.push(Diagnosis::ok(
+            "attributes",
+            format!("{} tracked files in HEAD", tracked.len()),
+        ));
+    }
+    if malformed.is_empty() {
+        diagnoses.push(Diagnosis::ok(
+            "pointers",
+            format!("{} pointers in HEAD", pointers.len()),
+        ));
+    } else {
+        diagnoses.push(Diagnosis::problem(
+            "pointers",
+            Health::Error,
+            format!("malformed pointers in HEAD: {}", example(&malformed)),
+            "restore the files from an earlier commit, or store them again with \
+             `git add --renormalize <path>`"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Asset changes between two revisions.
+#[derive(Serialize)]
+struct AssetDiff {
+    changes: Vec<AssetChange>,
+    /// Net change in bytes, counting only objects whose size is known.
+    delta: i64,
+    /// Number of objects whose size is neither recorded in their pointer nor known to the local
+    /// store.
+    unknown_sizes: usize,
+}
+
+#[derive(Serialize)]
+struct AssetChange {
+    path: String,
+    status: &'static str,
+    old_hash: Option<ContentHash>,
+    new_hash: Option<ContentHash>,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+}
+
+/// Show which assets differ between two revisions, and by how many bytes.
+fn diff(store_path: PathBuf, old: &str, new: &str, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+    let old_pointers = repo.pointers(old).map_err(CliError::git)?;
+    let new_pointers = repo.pointers(new).map_err(CliError::git)?;
+
+    let mut report = AssetDiff {
+        changes: Vec::new(),
+        delta: 0,
+        unknown_sizes: 0,
+    };
+    for change in git::diff_pointers(old_pointers, new_pointers) {
+        let path = change.path().to_string();
+        let (status, old_entry, new_entry) = match change {
+            git::PointerChange::Added(new) => ("added", None, Some(new)),
+            git::PointerChange::Removed(old) => ("removed", Some(old), None),
+            git::PointerChange::Changed { old, new } => ("changed", Some(old), Some(new)),
+        };
+        let mut sizes = [None, None];
+        for (size, entry) in sizes.iter_mut().zip(&[&old_entry, &new_entry]) {
+            if let Some(entry) = entry {
+                *size = object_size(&store, &entry.store_ref)?;
+                if size.is_none() {
+                    report.unknown_sizes += 1;
+                }
+            }
+        }
+        report.delta += sizes[1].unwrap_or(0) as i64 - sizes[0].unwrap_or(0) as i64;
+        report.changes.push(AssetChange {
+            path,
+            status,
+            old_hash: old_entry.map(|e| e.store_ref.hash().clone()),
+            new_hash: new_entry.map(|e| e.store_ref.hash().clone()),
+            old_size: sizes[0],
+            new_size: sizes[1],
+        });
+    }
+
+    match format {
+        OutputFormat::Text => {
+            let show = |size: Option<u64>| size.map_or("?".to_string(), format_bytes);
+            for change in &report.changes {
+                match change.status {
+                    "added" => println!("A {} ({})", change.path, show(change.new_size)),
+                    "removed" => println!("D {} ({})", change.path, show(change.old_size)),
+                    _ => println!(
+                        "M {} ({} -> {})",
+                        change.path,
+                        show(change.old_size),
+                        show(change.new_size)
+                    ),
+                }
+            }
+            let sign = if report.delta < 0 { "-" } else { "+" };
+            println!(
+                "total: {}{} ({} bytes)",
+                sign,
+                format_bytes(report.delta.unsigned_abs()),
+                report.delta
+            );
+            if report.unknown_sizes > 0 {
+                println!(
+                    "{} objects are missing from the store and were not counted",
+                    report.unknown_sizes
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    Ok(())
+}
+
+/// Storage used by the assets below a directory.
+#[derive(Serialize, Default)]
+struct DirectoryUsage {
+    /// Directory relative to the repository root, `.` for the root itself.
+    path: String,
+    /// Number of asset files.
+    files: usize,
+    /// Bytes counting every distinct object once.
+    deduplicated: u64,
+    /// Bytes summed over all asset files.
+    total: u64,
+    /// Number of asset files whose object is missing from the local store.
+    unknown_sizes: usize,
+    #[serde(skip)]
+    seen: HashSet<ContentHash>,
+}
+
+/// Sum up the sizes of the objects referenced by a revision, optionally per directory.
+fn du(store_path: PathBuf, rev: &str, by_path: bool, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+
+    let mut usage: BTreeMap<String, DirectoryUsage> = BTreeMap::new();
+    for pointer in repo.pointers(rev).map_err(CliError::git)? {
+        let size = object_size(&store, &pointer.store_ref)?;
+
+        // Attribute the file to the root and, if requested, to every directory containing it
+        let mut dirs = vec![".".to_string()];
+        if by_path {
+            let components: Vec<&str> = pointer.path.split('/').collect();
+            for depth in 1..components.len() {
+                dirs.push(components[..depth].join("/"));
+            }
+        }
+        for dir in dirs {
+            let entry = usage.entry(dir.clone()).or_default();
+            entry.path = dir;
+            entry.files += 1;
+            match size {
+                Some(size) => {
+                    entry.total += size;
+                    if entry.seen.insert(pointer.store_ref.hash().clone()) {
+                        entry.deduplicated += size;
+                    }
+                }
+                None => entry.unknown_sizes += 1,
+            }
+        }
+    }
+    let usage: Vec<DirectoryUsage> = usage.into_values().collect();
+
+    match format {
+        OutputFormat::Text => {
+            println!("{:>12} {:>12} {:>8}  path", "dedup", "total", "files");
+            for dir in &usage {
+                println!(
+                    "{:>12} {:>12} {:>8}  {}",
+                    format_bytes(dir.deduplicated),
+                    format_bytes(dir.total),
+                    dir.files,
+                    dir.path
+                );
+            }
+            if let Some(unknown) = usage.first().map(|root| root.unknown_sizes) {
+                if unknown > 0 {
+                    println!(
+                        "{} asset files reference objects missing from the store and were not counted",
+                        unknown
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&usage)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    Ok(())
+}
+
+/// List the objects in the store, or only those with labels matching all `filters`.
+fn ls(
+    store_path: PathBuf,
+    sort: SortKey,
+    reverse: bool,
+    filters: &[metadata::LabelFilter],
+    format: OutputFormat,
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let mut objects = store
+        .iter()
+        .and_then(|objects| objects.collect::<io::Result<Vec<_>>>())
+        .map_err(CliError::store_access)?;
+    if !filters.is_empty() {
+        let mut selected = Vec::new();
+        for object in objects {
+            if matches_filters(&store, &object.hash, filters)? {
+                selected.push(object);
+            }
+        }
+        objects = selected;
+    }
+    match sort {
+        SortKey::Hash => objects.sort_by(|a, b| a.hash.cmp(&b.hash)),
+        SortKey::Size => objects.sort_by_key(|object| object.size),
+        SortKey::Time => objects.sort_by_key(|object| object.modified),
+    }
+    if reverse {
+        objects.reverse();
+    }
+
+    let timestamp = |object: &store::StoredObject| {
+        object
+            .modified
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+    };
+    match format {
+        OutputFormat::Text => {
+            for object in &objects {
+                let line = format!(
+                    "{} {:>12} {:<19} {}",
+                    object.hash,
+                    object.size,
+                    timestamp(object).map_or("-".to_string(), format_time),
+                    if object.chunked { "chunked" } else { "" }
+                );
+                println!("{}", line.trim_end());
+            }
+        }
+        OutputFormat::Json => print_json(&objects)?,
+        OutputFormat::Csv => {
+            println!("hash,size,modified,chunked");
+            for object in &objects {
+                println!(
+                    "{},{},{},{}",
+                    object.hash,
+                    object.size,
+                    timestamp(object).map_or(String::new(), |t| t.to_string()),
+                    object.chunked
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the labels of an object match all `filters`.
+fn matches_filters(
+    store: &store::Store,
+    hash: &ContentHash,
+    filters: &[metadata::LabelFilter],
+) -> CliResult<bool> {
+    if filters.is_empty() {
+        return Ok(true);
+    }
+    let labels = store.metadata(hash).map_err(CliError::store_access)?.labels;
+    Ok(filters.iter().all(|filter| filter.matches(&labels)))
+}
+
+/// Find the single object matching a hash given on the command line.
+fn resolve_hash(store: &store::Store, prefix: &str) -> CliResult<ContentHash> {
+    let mut matches = store.resolve_hash(prefix).map_err(|err| {
+        if err.kind() == io::ErrorKind::InvalidInput {
+            CliError::invalid_hash(err)
+        } else {
+            CliError::store_access(err)
+        }
+    })?;
+    match matches.len() {
+        0 => Err(CliError::no_such_content(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no object matches {}", prefix),
+        ))),
+        1 => Ok(matches.remove(0)),
+        _ => {
+            for hash in &matches {
+                eprintln!("candidate: {}", hash);
+            }
+            Err(CliError::invalid_hash(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} matches {} objects", prefix, matches.len()),
+            )))
+        }
+    }
+}
+
+/// Write the contents of an object to stdout.
+fn cat(store_path: PathBuf, hash: &str, verify_reads: bool) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_verify_reads(verify_reads);
+    let hash = resolve_hash(&store, hash)?;
+    let mut file = store
+        .open_ref(&store::StoreFileRef::from_hash(hash))
+        .map_err(CliError::no_such_content)?;
+    let total = file.size();
+    let mut progress = Progress::new(io::stdout().lock(), "retrieving", Some(total));
+    io::copy(&mut file, &mut progress).map_err(read_error)?;
+    progress.finish().flush()?;
+    Ok(())
+}
+
+/// Exit with status 1 if the object is missing, without reading its contents.
+fn exists(store_path: PathBuf, hash: &str) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let present = match resolve_hash(&store, hash) {
+        Ok(hash) => store
+            .object_size(&store::StoreFileRef::from_hash(hash))
+            .map_err(CliError::store_access)?
+            .is_some(),
+        Err(err) if err.kind() == CliErrorKind::NoSuchContent => false,
+        Err(err) => return Err(err),
+    };
+    if !present {
+        // Not an error, so don't print anything
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The object a tracked file refers to, see `Command::Which`.
+#[derive(Serialize)]
+struct WhichObject {
+    /// Path relative to the root of the repository.
+    path: String,
+    hash: ContentHash,
+    /// Where the store keeps the object, if it has it.
+    location: Option<PathBuf>,
+    /// Size of the object, if it is in the store.
+    size: Option<u64>,
+    /// State of the file in the working tree: `matches`, `modified`, `pointer` if it still
+    /// contains the pointer, or `deleted`.
+    worktree: &'static str,
+}
+
+/// Show the object behind the pointer staged for a file, and compare the working tree to it.
+fn which(store_path: PathBuf, path: &str, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+    // Format: <mode> SP <object> SP <stage> TAB <path>
+    let out = repo
+        .run(&["ls-files", "--stage", "--full-name", "-z", "--", path])
+        .map_err(CliError::git)?;
+    let record = String::from_utf8_lossy(out.split(|b| *b == 0).next().unwrap_or_default());
+    let (blob, full_path) = match record.split_once('\t') {
+        Some((meta, full_path)) => (meta.split(' ').nth(1).unwrap_or_default(), full_path),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not in the index", path),
+            )
+            .into())
+        }
+    };
+    let contents = repo.cat_blobs(&[blob]).map_err(CliError::git)?;
+    let store_ref =
+        store::StoreFileRef::parse_from_stream(&mut contents[0].as_slice()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not stored as a pointer", full_path),
+            )
+        })?;
+
+    let worktree_path = repo.work_tree().map_err(CliError::git)?.join(full_path);
+    let worktree = match std::fs::File::open(&worktree_path) {
+        Ok(mut file) => {
+            let hash = ContentHash::hash_file(store_ref.hash().algorithm(), &mut file)?;
+            let mut file = std::fs::File::open(&worktree_path)?;
+            if &hash == store_ref.hash() {
+                "matches"
+            } else if store::StoreFileRef::parse_from_stream(&mut file).is_ok() {
+                "pointer"
+            } else {
+                "modified"
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => "deleted",
+        Err(err) => return Err(err.into()),
+    };
+
+    let object = WhichObject {
+        path: full_path.to_string(),
+        hash: store_ref.hash().clone(),
+        location: store
+            .object_location(&store_ref)
+            .map_err(CliError::store_access)?,
+        size: store
+            .object_size(&store_ref)
+            .map_err(CliError::store_access)?,
+        worktree,
+    };
+    match format {
+        OutputFormat::Text => {
+            println!("path: {}", object.path);
+            println!("hash: {}", object.hash);
+            match (&object.location, object.size) {
+                (Some(location), Some(size)) => {
+                    println!("location: {}", location.display());
+                    println!("size: {}", format_bytes(size));
+                }
+                _ => println!("location: missing from the store"),
+            }
+            println!("working tree: {}", object.worktree);
+        }
+        OutputFormat::Json => print_json(&object)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    Ok(())
+}
+
+/// What the store knows about an object, see `Command::Info`.
+#[derive(Serialize)]
+struct ObjectInfo {
+    hash: ContentHash,
+    /// Where the store keeps the object, if it has it.
+    location: Option<PathBuf>,
+    /// Size of the object, if it is in the store.
+    size: Option<u64>,
+    pinned: bool,
+    #[serde(flatten)]
+    metadata: metadata::ObjectMetadata,
+}
+
+/// Show what the store knows about an object.
+fn info(store_path: PathBuf, hash: &str, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let hash = resolve_hash(&store, hash)?;
+    let store_ref = store::StoreFileRef::from_hash(hash.clone());
+    let object = ObjectInfo {
+        location: store
+            .object_location(&store_ref)
+            .map_err(CliError::store_access)?,
+        size: store
+            .object_size(&store_ref)
+            .map_err(CliError::store_access)?,
+        pinned: store
+            .pinned()
+            .map_err(CliError::store_access)?
+            .contains(&hash),
+        metadata: store.metadata(&hash).map_err(CliError::store_access)?,
+        hash,
+    };
+    match format {
+        OutputFormat::Text => {
+            println!("hash: {}", object.hash);
+            match (&object.location, object.size) {
+                (Some(location), Some(size)) => {
+                    println!("location: {}", location.display());
+                    println!("size: {}", format_bytes(size));
+                }
+                _ => println!("location: missing from the store"),
+            }
+            println!("pinned: {}", if object.pinned { "yes" } else { "no" });
+            match &object.metadata.provenance {
+                Some(provenance) => {
+                    let unknown = || "unknown".to_string();
+                    println!(
+                        "stored by: {}",
+                        provenance.user.clone().unwrap_or_else(unknown)
+                    );
+                    println!(
+                        "stored from: {}",
+                        provenance
+                            .repo
+                            .as_ref()
+                            .map_or_else(unknown, |repo| repo.display().to_string())
+                    );
+                    println!("stored at: {}", format_time(provenance.time));
+                }
+                None => println!("stored by: unknown, it was stored before this was recorded"),
+            }
+            for (key, value) in &object.metadata.labels {
+                println!("label: {}={}", key, value);
+            }
+        }
+        OutputFormat::Json => print_json(&object)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    Ok(())
+}
+
+/// Print a one-line summary of a tracked file for use in `git diff`.
+///
+/// Git passes the contents as they would be checked out, so the file is usually the object
+/// itself, which is summarized directly. For pointers, e.g. with smudging skipped, size and
+/// media type are taken from the pointer if it records them, and looked up in the store
+/// otherwise.
+fn textconv(store_path: PathBuf, file: &Path) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let sniff = |reader: &mut dyn Read| -> io::Result<&'static str> {
+        let mut prefix = Vec::with_capacity(media_type::SNIFF_LEN);
+        reader
+            .take(media_type::SNIFF_LEN as u64)
+            .read_to_end(&mut prefix)?;
+        Ok(media_type::detect(&prefix))
+    };
+    let (hash, size, media_type) =
+        match store::StoreFileRef::parse_from_stream(&mut std::fs::File::open(file)?) {
+            Ok(store_ref) => {
+                let size = object_size(&store, &store_ref)?;
+                let media_type = match (store_ref.media_type(), size) {
+                    (Some(media_type), _) => media_type.to_string(),
+                    // Without a size, the object is missing and there is nothing to sniff.
+                    (None, None) => "unknown".to_string(),
+                    (None, Some(_)) => store
+                        .open_ref(&store_ref)
+                        .and_then(|mut reader| sniff(&mut reader))
+                        .map_err(CliError::store_access)?
+                        .to_string(),
+                };
+                (store_ref.hash().clone(), size, media_type)
+            }
+            Err(_) => {
+                let mut contents = std::fs::File::open(file)?;
+                let hash = ContentHash::hash_file(store.algorithm(), &mut contents)?;
+                let size = contents.metadata()?.len();
+                let media_type = sniff(&mut std::fs::File::open(file)?)?;
+                (hash, Some(size), media_type.to_string())
+            }
+        };
+    println!(
+        "{} {} {:.8}…",
+        media_type,
+        size.map_or("(missing from the store)".to_string(), format_bytes),
+        hash
+    );
+    Ok(())
+}
+
+/// Side whose object `merge_driver` keeps when both changed the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MergeSide {
+    Ours,
+    Theirs,
+}
+
+/// Merge the pointers of a file like git would merge a binary file, but comparing the objects
+/// they refer to rather than their text. Exits with status 1 if they conflict.
+fn merge_driver(
+    store_path: PathBuf,
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    path: Option<String>,
+    prefer: Option<MergeSide>,
+) -> CliResult<()> {
+    let parse = |file: &Path| -> CliResult<store::StoreFileRef> {
+        let mut file = std::fs::File::open(file)?;
+        store::StoreFileRef::parse_from_stream(&mut file).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "cannot merge {}: not stored as a pointer on both sides",
+                    path.as_deref().unwrap_or("file")
+                ),
+            )
+            .into()
+        })
+    };
+    let our_ref = parse(ours)?;
+    let their_ref = parse(theirs)?;
+    // The file may not exist in the common ancestor, which git passes as an empty file
+    let base_hash = std::fs::File::open(base)
+        .and_then(|mut file| store::StoreFileRef::parse_from_stream(&mut file))
+        .ok()
+        .map(|base_ref| base_ref.hash().clone());
+
+    let side = if our_ref.hash() == their_ref.hash() || base_hash.as_ref() == Some(their_ref.hash())
+    {
+        MergeSide::Ours
+    } else if base_hash.as_ref() == Some(our_ref.hash()) {
+        MergeSide::Theirs
+    } else if let Some(side) = prefer {
+        side
+    } else {
+        let store = open_store(store_path)?;
+        let describe = |store_ref: &store::StoreFileRef| -> CliResult<String> {
+            let size = object_size(&store, store_ref)?;
+            Ok(format!(
+                "{} ({})",
+                store_ref.hash(),
+                size.map_or("missing from the store".to_string(), format_bytes)
+            ))
+        };
+        eprintln!(
+            "conflict in {}: both sides changed the object",
+            path.as_deref().unwrap_or("file")
+        );
+        eprintln!("  ours:   {}", describe(&our_ref)?);
+        eprintln!("  theirs: {}", describe(&their_ref)?);
+        // Leaves our pointer in place, which git checks out as the conflicted file
+        std::process::exit(1);
+    };
+    if side == MergeSide::Theirs {
+        std::fs::copy(theirs, ours)?;
+    }
+    Ok(())
+}
+
+/// A file whose pointer refers to the object searched by `grep_hash`.
+#[derive(Serialize)]
+struct HashMatch {
+    commit: String,
+    path: String,
+    hash: ContentHash,
+}
+
+/// Find the files in `HEAD`, or in the history of all refs, whose pointers match a hash prefix.
+fn grep_hash(prefix: &str, all: bool, format: OutputFormat) -> CliResult<()> {
+    let prefix = prefix.to_ascii_lowercase();
+    let digest_prefix = prefix
+        .split_once('-')
+        .map_or(prefix.as_str(), |(_, rest)| rest);
+    if digest_prefix.len() < 4 || !digest_prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(CliError::invalid_hash(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "not a hash or hash prefix of at least 4 characters: {}",
+                prefix
+            ),
+        )));
+    }
+    let repo = open_repo()?;
+    let commits = if all {
+        // Newest first, so that every path is found in its most recent commit first
+        repo.log(&["--all", "--date-order"])
+            .map_err(CliError::git)?
+            .into_iter()
+            .map(|commit| commit.id)
+            .collect()
+    } else {
+        vec![repo.resolve_commit("HEAD").map_err(CliError::git)?]
+    };
+
+    let mut cache = git::PointerCache::new();
+    let mut seen = HashSet::new();
+    let mut matches = Vec::new();
+    for commit in commits {
+        for pointer in repo
+            .pointers_cached(&commit, &mut cache)
+            .map_err(CliError::git)?
+        {
+            let hash = pointer.store_ref.hash();
+            if hash.matches_prefix(&prefix) && seen.insert((pointer.path.clone(), hash.clone())) {
+                matches.push(HashMatch {
+                    commit: commit.clone(),
+                    hash: hash.clone(),
+                    path: pointer.path,
+                });
+            }
+        }
+    }
+
+    match format {
+        OutputFormat::Text => {
+            for found in &matches {
+                println!("{} {} {}", found.commit, found.hash, found.path);
+            }
+        }
+        OutputFormat::Json => print_json(&matches)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    if matches.is_empty() {
+        // Not an error, so don't print anything
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Register the current repository with the store, or list the registered repositories.
+fn register(store_path: PathBuf, list: bool) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    if list {
+        for git_dir in store.registered_repos().map_err(CliError::store_access)? {
+            println!("{}", git_dir.display());
+        }
+        return Ok(());
+    }
+    let git_dir = open_repo()?.git_dir().map_err(CliError::git)?;
+    store
+        .register_repo(&git_dir)
+        .map_err(CliError::store_access)?;
+    println!(
+        "registered {} with {}",
+        git_dir.display(),
+        store.base_dir().display()
+    );
+    Ok(())
+}
+
+fn unregister(store_path: PathBuf, git_dir: Option<PathBuf>) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let git_dir = match git_dir {
+        Some(git_dir) => env::current_dir()?.join(git_dir),
+        None => open_repo()?.git_dir().map_err(CliError::git)?,
+    };
+    if store
+        .unregister_repo(&git_dir)
+        .map_err(CliError::store_access)?
+    {
+        println!("unregistered {}", git_dir.display());
+    } else {
+        println!("{} was not registered", git_dir.display());
+    }
+    Ok(())
+}
+
+/// Collect the objects referenced by the current repository and all repositories registered
+/// with the store, see `Repository::reachable_objects`.
+///
+/// Fails if a registered repository no longer exists, since its objects cannot be told apart
+/// from garbage then.
+fn referenced_objects(store: &store::Store) -> CliResult<HashSet<ContentHash>> {
+    let repo = open_repo()?;
+    let mut reachable = repo.reachable_objects().map_err(CliError::git)?;
+    let current = repo.git_dir().map_err(CliError::git)?.canonicalize()?;
+    for git_dir in store.registered_repos().map_err(CliError::store_access)? {
+        if git_dir == current {
+            continue;
+        }
+        if !git_dir.is_dir() {
+            return Err(CliError::store_access(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "the registered repository {} no longer exists, unregister it first",
+                    git_dir.display()
+                ),
+            )));
+        }
+        let registered = git::Repository::new(git_dir)
+            .reachable_objects()
+            .map_err(CliError::git)?;
+        reachable.extend(registered);
+    }
+    Ok(reachable)
+}
+
+fn pin(store_path: PathBuf, hash: &str) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let hash = resolve_hash(&store, hash)?;
+    if store.pin(&hash).map_err(CliError::store_access)? {
+        println!("pinned {}", hash);
+    } else {
+        println!("{} is already pinned", hash);
+    }
+    Ok(())
+}
+
+fn list_pins(store_path: PathBuf) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    for hash in store.pinned().map_err(CliError::store_access)? {
+        println!("{}", hash);
+    }
+    Ok(())
+}
+
+fn unpin(store_path: PathBuf, hash: &str) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    // Pins of objects that are gone already can still be removed by their full hash
+    let hash = resolve_hash(&store, hash)?;
+    if store.unpin(&hash).map_err(CliError::store_access)? {
+        println!("unpinned {}", hash);
+    } else {
+        println!("{} was not pinned", hash);
+    }
+    Ok(())
+}
+
+/// Add labels to an object, see `metadata`.
+fn note_add(store_path: PathBuf, hash: &str, labels: Vec<(String, String)>) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let hash = resolve_hash(&store, hash)?;
+    if store
+        .object_size(&store::StoreFileRef::from_hash(hash.clone()))
+        .map_err(CliError::store_access)?
+        .is_none()
+    {
+        return Err(CliError::no_such_content(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in the store", hash),
+        )));
+    }
+    store
+        .update_metadata(&hash, |metadata| metadata.labels.extend(labels))
+        .map_err(CliError::store_access)?;
+    Ok(())
+}
+
+/// Remove labels from an object. Keys it doesn't have are skipped with a warning.
+fn note_remove(store_path: PathBuf, hash: &str, keys: &[String]) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let hash = resolve_hash(&store, hash)?;
+    let mut missing = Vec::new();
+    store
+        .update_metadata(&hash, |metadata| {
+            for key in keys {
+                if metadata.labels.remove(key).is_none() {
+                    missing.push(key);
+                }
+            }
+        })
+        .map_err(CliError::store_access)?;
+    for key in missing {
+        log::warn!("{} has no label {}", hash, key);
+    }
+    Ok(())
+}
+
+/// Delete an object from the store, unless it is still referenced.
+fn delete(store_path: PathBuf, hash: &str, force: bool, dry_run: bool) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let hash = resolve_hash(&store, hash)?;
+    let pinned = store
+        .pinned()
+        .map_err(CliError::store_access)?
+        .contains(&hash);
+    if !force {
+        if pinned {
+            return Err(CliError::with_source(
+                CliErrorKind::StillReferenced,
+                format!("{} is pinned, pass --force to delete it anyway", hash).into(),
+            ));
+        }
+        let reachable = referenced_objects(&store)?;
+        if reachable.contains(&hash) {
+            return Err(CliError::with_source(
+                CliErrorKind::StillReferenced,
+                format!(
+                    "{} is referenced by a repository, pass --force to delete it anyway",
+                    hash
+                )
+                .into(),
+            ));
+        }
+    }
+    if store.remove(&hash).map_err(CliError::store_access)? {
+        if dry_run {
+            println!("would delete {}", hash);
+            return Ok(());
+        }
+        println!("deleted {}", hash);
+        if pinned {
+            store.unpin(&hash).map_err(CliError::store_access)?;
+        }
+        Ok(())
+    } else {
+        Err(CliError::no_such_content(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in the store", hash),
+        )))
+    }
+}
+
+/// Remove unreferenced objects from the store.
+fn gc(store_path: PathBuf, grace_period: Duration, dry_run: bool) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    store.clean_stale_staging();
+    let reachable = referenced_objects(&store)?;
+    let report = store
+        .collect_garbage_with(reachable.into_iter(), grace_period)
+        .map_err(CliError::store_access)?;
+    print_garbage(&report, dry_run);
+    Ok(())
+}
+
+/// Print the objects removed by `gc` or `prune`, or those that would be in a dry run.
+fn print_garbage(report: &store::GarbageReport, dry_run: bool) {
+    let (removed, kept) = if dry_run {
+        ("would remove", "would keep")
+    } else {
+        ("removed", "kept")
+    };
+    for hash in &report.removed {
+        println!("{} {}", removed, hash);
+    }
+    println!(
+        "{} {} objects ({}), {} {}",
+        removed,
+        report.removed.len(),
+        format_bytes(report.freed_bytes),
+        kept,
+        report.kept
+    );
+}
+
+/// Delete unreferenced objects that are not retained by the given or saved retention policy.
+fn prune(
+    store_path: PathBuf,
+    older_than: Option<Duration>,
+    keep_recent: Option<usize>,
+    save: bool,
+    filters: &[metadata::LabelFilter],
+    dry_run: bool,
+) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let mut config = store.config().map_err(CliError::store_access)?;
+    if let Some(older_than) = older_than {
+        config.retention.older_than_secs = Some(older_than.as_secs());
+    }
+    if let Some(keep_recent) = keep_recent {
+        config.retention.keep_recent = Some(keep_recent);
+    }
+    if save {
+        store.set_config(&config).map_err(CliError::store_access)?;
+    }
+
+    let mut reachable = referenced_objects(&store)?;
+    if !filters.is_empty() {
+        // Objects that aren't selected are kept as if they were referenced
+        for object in store.top_level_objects().map_err(CliError::store_access)? {
+            if !matches_filters(&store, &object.hash, filters)? {
+                reachable.insert(object.hash);
+            }
+        }
+    }
+    let report = store
+        .prune(reachable.into_iter(), &config.retention)
+        .map_err(CliError::store_access)?;
+    print_garbage(&report, dry_run);
+    Ok(())
+}
+
+/// Delete the least recently used objects that are also in the remote store.
+fn evict(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    max_size: Option<u64>,
+    save: bool,
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let mut config = store.config().map_err(CliError::store_access)?;
+    if max_size.is_some() {
+        config.max_size = max_size;
+    }
+    if save {
+        store.set_config(&config).map_err(CliError::store_access)?;
+    }
+    let max_size = match config.max_size {
+        Some(max_size) => max_size,
+        None => {
+            return Err(CliError::store_access(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no maximum size has been set, pass --max-size",
+            )))
+        }
+    };
+
+    let remote = open_remote(remote)?;
+    let report = store.evict(max_size, &remote).map_err(CliError::transfer)?;
+    for hash in &report.removed {
+        println!("evicted {}", hash);
+    }
+    println!(
+        "evicted {} objects ({}), kept {}",
+        report.removed.len(),
+        format_bytes(report.freed_bytes),
+        report.kept
+    );
+    Ok(())
+}
+
+fn quota(store_path: PathBuf, size: Option<u64>, remove: bool) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let mut config = store.config().map_err(CliError::store_access)?;
+    if size.is_some() || remove {
+        config.quota = size;
+        store.set_config(&config).map_err(CliError::store_access)?;
+    }
+    let used = store.stats().map_err(CliError::store_access)?.bytes;
+    match config.quota {
+        Some(quota) => println!("{} of {} used", format_bytes(used), format_bytes(quota)),
+        None => println!("{} used, no quota", format_bytes(used)),
+    }
+    Ok(())
+}
+
+/// Remove old files from the staging directory.
+fn gc_staging(store_path: PathBuf, older_than: Duration, dry_run: bool) -> CliResult<()> {
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let report = store
+        .clean_staging(older_than)
+        .map_err(CliError::store_access)?;
+    let (removed, kept) = if dry_run {
+        ("would remove", "would keep")
+    } else {
+        ("removed", "kept")
+    };
+    for path in &report.removed {
+        println!("{} {}", removed, path.display());
+    }
+    println!(
+        "{} {} staging files ({}), {} {}",
+        removed,
+        report.removed.len(),
+        format_bytes(report.freed_bytes),
+        kept,
+        report.kept
+    );
+    Ok(())
+}
+
+/// Print the number of objects and the bytes used by the store, among others.
+fn stats(store_path: PathBuf, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let stats = store.stats().map_err(CliError::store_access)?;
+
+    match format {
+        OutputFormat::Text => {
+            println!("objects: {}", stats.objects);
+            println!(
+                "size: {} ({} bytes)",
+                format_bytes(stats.bytes),
+                stats.bytes
+            );
+            println!("chunked objects: {}", stats.chunked_objects);
+            println!(
+                "staging: {} files ({})",
+                stats.staging_files,
+                format_bytes(stats.staging_bytes)
+            );
+            println!("registered repositories: {}", stats.registered_repos);
+            if !stats.largest.is_empty() {
+                println!("largest objects:");
+                for object in &stats.largest {
+                    println!("{:>12} {}", format_bytes(object.size), object.hash);
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&stats)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    Ok(())
+}
+
+/// Download the objects referenced by a revision that are not in the store yet.
+///
+/// With `verify_signatures`, fails if any of the objects is not signed by a trusted signer.
+fn fetch(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    rev: &str,
+    verify_signatures: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    store.clean_stale_staging();
+    let remote = open_remote(remote)?;
+    let repo = open_repo()?;
+    let pointers = repo.pointers(rev).map_err(CliError::git)?;
+
+    let mut seen = HashSet::new();
+    let hashes = pointers
+        .iter()
+        .map(|pointer| pointer.store_ref.hash().clone())
+        .filter(|hash| seen.insert(hash.clone()));
+    let mut bar = ProgressBar::for_files("fetching");
+    let report = store
+        .fetch_from_remote_with(&remote, hashes, &mut |progress| bar.set_transfer(progress))
+        .map_err(CliError::transfer)?;
+    bar.finish();
+    let mut unsigned = None;
+    if verify_signatures {
+        let mut checked = HashSet::new();
+        for pointer in &pointers {
+            let hash = pointer.store_ref.hash();
+            if report.missing.contains(hash) || !checked.insert(hash) {
+                continue;
+            }
+            // Objects that were present before may have been signed on the remote since
+            store
+                .fetch_signature(&remote, hash)
+                .map_err(CliError::transfer)?;
+            if let Err(err) = check_signature(&store, &repo, hash) {
+                if err.kind() != CliErrorKind::BadSignature {
+                    return Err(err);
+                }
+                log::error!("{}: {}", pointer.path, err.source().expect("has a reason"));
+                unsigned = Some(err);
+            }
+        }
+    }
+    // Objects that were just fetched count as used, so they are the last to go
+    if let Some(max_size) = store.config().map_err(CliError::store_access)?.max_size {
+        store.evict(max_size, &remote).map_err(CliError::transfer)?;
+    }
+
+    match format {
+        OutputFormat::Text => {
+            for pointer in &pointers {
+                if report.missing.contains(pointer.store_ref.hash()) {
+                    println!("missing: {} {}", pointer.store_ref.hash(), pointer.path);
+                }
+            }
+            println!(
+                "fetched {} objects ({}), {} already present",
+                report.transferred.len(),
+                format_bytes(report.bytes),
+                report.skipped
+            );
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    if let Some(err) = unsigned {
+        Err(err)
+    } else if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// Upload the objects of the store that the remote is missing.
+fn push(store_path: PathBuf, remote: Option<PathBuf>, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let remote = open_remote(remote)?;
+    let mut bar = ProgressBar::for_files("pushing");
+    let report = store
+        .push_to_with(&remote, &mut |progress| bar.set_transfer(progress))
+        .map_err(CliError::transfer)?;
+    bar.finish();
+
+    match format {
+        OutputFormat::Text => println!(
+            "pushed {} objects ({}), {} already present",
+            report.transferred.len(),
+            format_bytes(report.bytes),
+            report.skipped
+        ),
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    Ok(())
+}
+
+/// Upload the objects referenced by the outgoing commits of the refs read from stdin, see
+/// `Command::PrePush`.
+fn pre_push(
+    store_path: PathBuf,
+    remote: Option<PathBuf>,
+    remote_name: Option<&str>,
+) -> CliResult<()> {
+    let remote = match remote {
+        Some(remote) => open_store(remote)?,
+        None => return Ok(()),
+    };
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+
+    // Commits that the git remote has already are not outgoing
+    let mut revs = Vec::new();
+    let mut known = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        // Format: <local ref> SP <local object> SP <remote ref> SP <remote object>
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (local, remote_object) = match fields[..] {
+            [_, local, _, remote_object] => (local, remote_object),
+            _ => continue,
+        };
+        // Deleting a ref pushes nothing
+        if local.bytes().all(|byte| byte == b'0') {
+            continue;
+        }
+        revs.push(local.to_string());
+        if !remote_object.bytes().all(|byte| byte == b'0')
+            && repo.run(&["cat-file", "-e", remote_object]).is_ok()
+        {
+            known.push(remote_object.to_string());
+        }
+    }
+    if revs.is_empty() {
+        return Ok(());
+    }
+    let remotes = remote_name.map(|name| format!("--remotes={}", name));
+    let mut args: Vec<&str> = revs.iter().map(String::as_str).collect();
+    args.push("--not");
+    args.extend(known.iter().map(String::as_str));
+    args.extend(remotes.as_deref());
+    let hashes = repo.referenced_objects(&args).map_err(CliError::git)?;
+
+    let mut bar = ProgressBar::for_files("pushing");
+    let report = remote
+        .fetch_from_remote_with(&store, hashes.into_iter(), &mut |progress| {
+            bar.set_transfer(progress)
+        })
+        .map_err(CliError::transfer)?;
+    bar.finish();
+    for hash in &report.missing {
+        println!("missing: {}", hash);
+    }
+    println!(
+        "pushed {} objects ({}), {} already present",
+        report.transferred.len(),
+        format_bytes(report.bytes),
+        report.skipped
+    );
+    if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// Copy all objects, or those given on stdin, between two stores.
+fn copy(
+    store_path: PathBuf,
+    from: Option<PathBuf>,
+    to: Option<PathBuf>,
+    from_stdin: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if from.is_none() && to.is_none() {
+        return Err(CliError::store_access(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pass --from or --to to copy from or to another store",
+        )));
+    }
+    let source = open_store(from.unwrap_or_else(|| store_path.clone()))?;
+    let target = open_store(to.unwrap_or(store_path))?;
+    let hashes = if from_stdin {
+        Some(read_hashes(&source)?)
+    } else {
+        None
+    };
+    let mut bar = ProgressBar::for_files("copying");
+    let report = source
+        .copy_to(&target, hashes, &mut |progress| bar.set_transfer(progress))
+        .map_err(CliError::transfer)?;
+    bar.finish();
+
+    match format {
+        OutputFormat::Text => {
+            for hash in &report.missing {
+                println!("missing: {}", hash);
+            }
+            println!(
+                "copied {} objects ({}), {} already present",
+                report.transferred.len(),
+                format_bytes(report.bytes),
+                report.skipped
+            );
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+    if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// Read hashes, or unambiguous prefixes of objects in `store`, from stdin, one per line.
+fn read_hashes(store: &store::Store) -> CliResult<Vec<ContentHash>> {
+    let mut hashes = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            hashes.push(resolve_hash(store, line.trim())?);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Write the given or all objects to an archive, or only those whose labels match `filters`.
+#[cfg(feature = "archive")]
+fn export(
+    store_path: PathBuf,
+    archive_path: &Path,
+    from_stdin: bool,
+    filters: &[metadata::LabelFilter],
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let hashes: Vec<ContentHash> = if from_stdin {
+        read_hashes(&store)?
+    } else {
+        store
+            .top_level_objects()
+            .map_err(CliError::store_access)?
+            .into_iter()
+            .map(|object| object.hash)
+            .collect()
+    };
+    let mut selected = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        if matches_filters(&store, &hash, filters)? {
+            selected.push(hash);
+        }
+    }
+    let hashes = selected;
+
+    let report = if archive_path == Path::new("-") {
+        archive::export(&store, hashes, io::stdout().lock())
+    } else {
+        let file = std::fs::File::create(archive_path)?;
+        let result = archive::export(&store, hashes, io::BufWriter::new(file));
+        // Don't leave a truncated archive behind
+        if result.is_err() {
+            let _ = std::fs::remove_file(archive_path);
+        }
+        result
+    };
+    let report = report.map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => CliError::no_such_content(err),
+        _ => CliError::store_access(err),
+    })?;
+    // The archive itself may go to stdout
+    eprintln!(
+        "exported {} objects ({})",
+        report.transferred.len(),
+        format_bytes(report.bytes)
+    );
+    Ok(())
+}
+
+/// Add the objects of an archive to the store.
+#[cfg(feature = "archive")]
+fn import(
+    store_path: PathBuf,
+    archive_path: &Path,
+    jobs: usize,
+    format: OutputFormat,
+) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let report = if archive_path == Path::new("-") {
+        archive::import(&store, io::stdin().lock(), jobs)
+    } else {
+        let file = std::fs::File::open(archive_path)?;
+        archive::import(&store, io::BufReader::new(file), jobs)
+    }
+    .map_err(CliError::store_access)?;
+
+    match format {
+        OutputFormat::Text => {
+            for hash in &report.missing {
+                println!("missing: {}", hash);
+            }
+            println!(
+                "imported {} objects ({}), {} already present",
+                report.transferred.len(),
+                format_bytes(report.bytes),
+                report.skipped
+            );
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// Check an archive against its manifest and the hashes of its objects.
+#[cfg(feature = "archive")]
+fn backup_verify(archive_path: &Path, format: OutputFormat) -> CliResult<()> {
+    let report = if archive_path == Path::new("-") {
+        archive::verify(io::stdin().lock())
+    } else {
+        let file = std::fs::File::open(archive_path)?;
+        archive::verify(io::BufReader::new(file))
+    }
+    .map_err(|err| match err.kind() {
+        io::ErrorKind::InvalidData => CliError::inconsistent(err),
+        _ => CliError::from(err),
+    })?;
+
+    match format {
+        OutputFormat::Text => {
+            for hash in &report.corrupt {
+                println!("corrupt: {}", hash);
+            }
+            for hash in &report.missing {
+                println!("missing: {}", hash);
+            }
+            println!(
+                "verified {} objects ({})",
+                report.verified,
+                format_bytes(report.bytes)
+            );
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => return Err(CliErrorKind::UnsupportedFormat.into()),
+    }
+
+    if !report.corrupt.is_empty() {
+        Err(CliErrorKind::Inconsistent.into())
+    } else if !report.missing.is_empty() {
+        Err(CliErrorKind::NoSuchContent.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Answer HTTP requests for the objects of the store until the process is stopped.
+///
+/// Without tokens, anyone who can reach the server may read and upload objects. With a
+/// certificate and private key, connections use HTTPS, and with certificate authorities for
+/// clients, only clients with a certificate issued by them may connect.
+#[cfg(feature = "serve")]
+fn serve(
+    store_path: PathBuf,
+    listen: &str,
+    jobs: Option<usize>,
+    tokens: Vec<server::TokenGrant>,
+    tls: Option<(PathBuf, PathBuf, Option<PathBuf>)>,
+    namespaces: Vec<(String, PathBuf)>,
+    webhooks: Vec<String>,
+) -> CliResult<()> {
+    let location = store_path.clone();
+    let store = open_store(store_path)?;
+    let mut server = match tls {
+        Some((cert, key, client_ca)) => {
+            server::Server::bind_tls(store, listen, &cert, &key, client_ca.as_deref()).map_err(
+                |err| match err.kind() {
+                    io::ErrorKind::AddrNotAvailable => CliError::from(err),
+                    _ => CliError::invalid_config(err),
+                },
+            )?
+        }
+        None => server::Server::bind(store, listen)?,
+    };
+    for (name, location) in namespaces {
+        let store = open_store(location)?;
+        server
+            .add_namespace(name, store)
+            .map_err(CliError::invalid_config)?;
+    }
+    for url in webhooks {
+        server.add_webhook(url).map_err(CliError::invalid_config)?;
+    }
+    let open = tokens.is_empty();
+    for grant in tokens {
+        if grant.namespaces.is_empty() {
+            server.add_token(grant.token, grant.scope, None);
+            continue;
+        }
+        for namespace in grant.namespaces {
+            server.add_token(grant.token.clone(), grant.scope, Some(namespace));
+        }
+    }
+    match (server.addr(), server.url()) {
+        (Some(addr), Some(url)) => {
+            if open && !addr.ip().is_loopback() {
+                log::warn!(
+                    "no tokens are configured, anyone who can reach {} may upload objects",
+                    addr
+                );
+            }
+            if !server.is_tls() && !addr.ip().is_loopback() {
+                log::warn!("connections to {} are not encrypted", addr);
+            }
+            println!("serving {} on {}", location.display(), url)
+        }
+        _ => println!("serving {} on {}", location.display(), listen),
+    }
+    io::stdout().flush()?;
+    let jobs = cpu_jobs(jobs);
+    server.run(jobs).map_err(CliError::store_access)
+}
+
+/// Parse the value of `serve --namespace`, `<name>=<store>`.
+fn parse_namespace(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, store)) if server::is_valid_namespace(name) && !store.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(store)))
+        }
+        Some((name, _)) if !server::is_valid_namespace(name) => Err(format!(
+            "{:?} can't be the name of a namespace, use ASCII letters, digits, `-`, `_` and `.`",
+            name
+        )),
+        _ => Err(format!("expected <name>=<store>, not {:?}", value)),
+    }
+}
+
+/// Append a token to the token file, creating it if needed, and print the token if it was
+/// generated.
+fn add_token(
+    token_file: &Path,
+    token: Option<String>,
+    scope: server::Scope,
+    namespaces: Vec<String>,
+) -> CliResult<()> {
+    // Don't append to a file that `serve` would reject
+    if token_file.exists() {
+        server::read_token_file(token_file).map_err(CliError::invalid_config)?;
+    }
+    let generated = token.is_none();
+    let token = match token {
+        Some(token) => token,
+        None => generate_token()?,
+    };
+    let line = server::TokenGrant {
+        token,
+        scope,
+        namespaces,
+    };
+    // Check the line the same way `serve` will read it
+    let contents = format!(
+        "{}
+",
+        line
+    );
+    let parsed = server::parse_token_line(&contents).map_err(|message| {
+        CliError::invalid_config(io::Error::new(io::ErrorKind::InvalidInput, message))
+    })?;
+    if parsed.as_ref() != Some(&line) {
+        return Err(CliError::invalid_config(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tokens can't contain whitespace or start with `#`",
+        )));
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(token_file)?;
+    file.write_all(contents.as_bytes())?;
+    if generated {
+        println!("{}", line.token);
+    }
+    Ok(())
+}
+
+/// A random token of 48 hex digits.
+fn generate_token() -> CliResult<String> {
+    let mut bytes = [0u8; 24];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("can't generate a token, pass one with --token: {}", err),
+            )
+        })?;
+    Ok(hex::encode(bytes))
+}
+
+/// Remove the lines of a token, given as itself or by its id, from the token file.
+fn remove_token(token_file: &Path, token: &str) -> CliResult<()> {
+    let contents = std::fs::read_to_string(token_file)?;
+    let mut removed = 0;
+    let kept: String = contents
+        .split_inclusive('\n')
+        .filter(|line| match server::parse_token_line(line) {
+            Ok(Some(grant)) if grant.token == token || grant.id() == token => {
+                removed += 1;
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    if removed == 0 {
+        return Err(CliError::no_such_content(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in {}", token, token_file.display()),
+        )));
+    }
+    let temp_path = token_file.with_extension(format!("tmp.{}", std::process::id()));
+    let written = std::fs::write(&temp_path, kept)
+        .and_then(|()| {
+            let permissions = std::fs::metadata(token_file)?.permissions();
+            std::fs::set_permissions(&temp_path, permissions)
+        })
+        .and_then(|()| std::fs::rename(&temp_path, token_file));
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Print the tokens of the token file by id, with their scope and namespaces.
+fn list_tokens(token_file: &Path) -> CliResult<()> {
+    for grant in server::read_token_file(token_file).map_err(CliError::invalid_config)? {
+        let namespaces = if grant.namespaces.is_empty() {
+            "*".to_string()
+        } else {
+            grant.namespaces.join(" ")
+        };
+        println!("{} {} {}", grant.id(), grant.scope, namespaces);
+    }
+    Ok(())
+}
+
+/// The outcome of `migrate import-lfs`.
+#[derive(Serialize)]
+struct LfsImport {
+    objects: store::TransferReport,
+    /// The git config settings that were written.
+    config: BTreeMap<String, String>,
+    /// Patterns in `.gitattributes` that were switched from Git LFS to the filter.
+    patterns: Vec<String>,
+    /// Whether nothing was changed, because of `--dry-run`.
+    dry_run: bool,
+}
+
+/// Add the objects of Git LFS to the store, install the filter and switch the patterns
+/// tracked by Git LFS over to it, keeping pointers in the Git LFS format.
+fn migrate_import_lfs(
+    store: Option<PathBuf>,
+    explicit_store: bool,
+    jobs: usize,
+    dry_run: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    let repo = open_repo()?;
+    let git_dir = repo.git_dir().map_err(CliError::git)?;
+    let store_path = store.ok_or(CliErrorKind::NotInGitRepo)?;
+    let mut target = open_store(store_path.clone())?;
+    target.set_dry_run(dry_run);
+    let objects = lfs::import_objects(&target, &lfs::objects_dir(&git_dir), jobs)
+        .map_err(CliError::store_access)?;
+
+    // Only switch over once all objects are in the store
+    let mut config: BTreeMap<String, String> = if dry_run {
+        filter_settings(Some(store_path.as_path()).filter(|_| explicit_store))?
+            .into_iter()
+            .collect()
+    } else {
+        repo.run(&["config", "--local", "assets.pointer", "lfs"])
+            .map_err(CliError::git)?;
+        configure_filter(Some(store_path), explicit_store, false)?
+            .into_iter()
+            .collect()
+    };
+    config.insert("assets.pointer".to_string(), "lfs".to_string());
+    let mut patterns = Vec::new();
+    let switch_patterns = |contents: &str| {
+        let (contents, replaced) = attributes::replace_filter(
+            contents,
+            "lfs",
+            &attributes::tracking_attributes(attributes::DEFAULT_FILTER),
+        );
+        patterns = replaced;
+        contents
+    };
+    if dry_run {
+        preview_gitattributes(&repo, switch_patterns)?;
+    } else {
+        edit_gitattributes(&repo, switch_patterns)?;
+    }
+
+    let report = LfsImport {
+        objects,
+        config,
+        patterns,
+        dry_run,
+    };
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{} {} objects ({}), {} already present",
+                if dry_run { "would import" } else { "imported" },
+                report.objects.transferred.len(),
+                format_bytes(report.objects.bytes),
+                report.objects.skipped
+            );
+            let set = if dry_run { "would set " } else { "" };
+            for (key, value) in &report.config {
+                println!("{}{} = {}", set, key, value);
+            }
+            let track = if dry_run { "would track" } else { "tracking" };
+            for pattern in &report.patterns {
+                println!("{} {}", track, pattern);
+            }
+            if !dry_run {
+                println!("Commit .gitattributes to complete the migration.");
+            }
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => unreachable!("rejected above"),
+    }
+    Ok(())
+}
+
+/// The outcome of `migrate export-lfs`.
+#[derive(Serialize)]
+struct LfsExport {
+    objects: store::TransferReport,
+    /// Files whose staged pointer was replaced by a Git LFS pointer.
+    pointers: Vec<String>,
+    /// Patterns in `.gitattributes` that were switched from the filter to Git LFS.
+    patterns: Vec<String>,
+    /// The git config settings that Git LFS needs, as written by `git lfs install`.
+    config: BTreeMap<String, String>,
+    /// Whether nothing was changed, because of `--dry-run`.
+    dry_run: bool,
+}
+
+/// Copy the objects of the store to Git LFS, and turn the staged pointers and the tracked
+/// patterns into their Git LFS equivalents.
+fn migrate_export_lfs(
+    store_path: PathBuf,
+    jobs: usize,
+    dry_run: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let repo = open_repo()?;
+    let git_dir = repo.git_dir().map_err(CliError::git)?;
+
+    let hashes = store
+        .iter()
+        .and_then(|objects| {
+            objects
+                .map(|object| object.map(|object| object.hash))
+                .collect::<io::Result<Vec<ContentHash>>>()
+        })
+        .map_err(CliError::store_access)?;
+    let objects = lfs::export_objects(&store, hashes, &lfs::objects_dir(&git_dir), jobs)
+        .map_err(CliError::store_access)?;
+
+    // Pointers are only found in files routed through the filter
+    let entries = repo.index_entries().map_err(CliError::git)?;
+    let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    let tracked: HashSet<String> = attributes::tracked_paths(
+        &repo,
+        attributes::DEFAULT_FILTER,
+        &paths,
+        attributes::AttrSource::Index,
+    )
+    .map_err(CliError::git)?
+    .into_iter()
+    .collect();
+    let entries: Vec<&git::IndexEntry> = entries
+        .iter()
+        .filter(|entry| tracked.contains(&entry.path))
+        .collect();
+    let blobs: Vec<&str> = entries.iter().map(|entry| entry.blob.as_str()).collect();
+    let contents = repo.cat_blobs(&blobs).map_err(CliError::git)?;
+    let mut updated = Vec::new();
+    for (entry, content) in entries.into_iter().zip(contents) {
+        let store_ref = match store::StoreFileRef::parse_from_stream(&mut content.as_slice()) {
+            Ok(store_ref) => store_ref,
+            Err(_) => continue,
+        };
+        let size = store
+            .object_size(&store_ref)
+            .map_err(CliError::store_access)?
+            .ok_or(CliErrorKind::NoSuchContent)?;
+        let pointer = format!(
+            "{}\n",
+            store_ref.with_size(size).to_pointer(PointerFormat::Lfs)
+        );
+        if pointer.as_bytes() != content.as_slice() {
+            let blob = if dry_run {
+                repo.hash_blob(pointer.as_bytes())
+            } else {
+                repo.write_blob(pointer.as_bytes())
+            }
+            .map_err(CliError::git)?;
+            updated.push(git::IndexEntry {
+                blob,
+                ..entry.clone()
+            });
+        }
+    }
+    let mut patterns = Vec::new();
+    let switch_patterns = |contents: &str| {
+        let (contents, replaced) = attributes::replace_filter(
+            contents,
+            attributes::DEFAULT_FILTER,
+            attributes::LFS_ATTRIBUTES,
+        );
+        patterns = replaced;
+        contents
+    };
+    if dry_run {
+        preview_gitattributes(&repo, switch_patterns)?;
+    } else {
+        repo.update_index(&updated).map_err(CliError::git)?;
+        edit_gitattributes(&repo, switch_patterns)?;
+    }
+
+    let config = [
+        ("filter.lfs.clean", "git-lfs clean -- %f"),
+        ("filter.lfs.smudge", "git-lfs smudge -- %f"),
+        ("filter.lfs.process", "git-lfs filter-process"),
+        ("filter.lfs.required", "true"),
+    ];
+    let report = LfsExport {
+        objects,
+        pointers: updated.into_iter().map(|entry| entry.path).collect(),
+        patterns,
+        config: config
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        dry_run,
+    };
+    match format {
+        OutputFormat::Text => {
+            for hash in &report.objects.missing {
+                println!("missing: {}", hash);
+            }
+            println!(
+                "{} {} objects ({}), {} already present",
+                if dry_run { "would export" } else { "exported" },
+                report.objects.transferred.len(),
+                format_bytes(report.objects.bytes),
+                report.objects.skipped
+            );
+            let convert = if dry_run {
+                "would convert pointer"
+            } else {
+                "converted pointer"
+            };
+            for path in &report.pointers {
+                println!("{}: {}", convert, path);
+            }
+            let track = if dry_run { "would track" } else { "tracking" };
+            for pattern in &report.patterns {
+                println!("{} {} with Git LFS", track, pattern);
+            }
+            if !dry_run {
+                println!("Install Git LFS and configure it, e.g. with `git lfs install`:");
+                for (key, value) in &report.config {
+                    println!("  git config {} '{}'", key, value);
+                }
+                println!("Then remove the git-assets filter with `git assets uninstall` and commit .gitattributes.");
+            }
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => unreachable!("rejected above"),
+    }
+
+    if report.objects.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// The outcome of `migrate import-annex`.
+#[derive(Serialize)]
+struct AnnexImport {
+    objects: store::TransferReport,
+    /// Annexed files that were replaced by pointers.
+    pointers: Vec<String>,
+    /// Annexed files that were left alone, because their contents are not in the store.
+    missing: Vec<String>,
+    /// Patterns that were added to `.gitattributes`.
+    patterns: Vec<String>,
+    /// Whether nothing was changed, because of `--dry-run`.
+    dry_run: bool,
+}
+
+/// Patterns for tracking the converted annexed files: `*.<ext>` if every file in the index
+/// with that extension was converted, so that new ones are tracked as well, or else the path
+/// of the file itself.
+fn annex_tracking_patterns(converted: &[String], index_paths: &[&str]) -> Vec<String> {
+    let converted_set: HashSet<&str> = converted.iter().map(String::as_str).collect();
+    let mut patterns: Vec<String> = converted
+        .iter()
+        .map(|path| {
+            let suffix = match Path::new(path).extension() {
+                Some(extension) => format!(".{}", extension.to_string_lossy()),
+                None => return format!("/{}", path),
+            };
+            let all_converted = index_paths
+                .iter()
+                .filter(|other| other.rsplit('/').next().unwrap_or(other).ends_with(&suffix))
+                .all(|other| converted_set.contains(other));
+            if all_converted {
+                format!("*{}", suffix)
+            } else {
+                format!("/{}", path)
+            }
+        })
+        .collect();
+    patterns.sort();
+    patterns.dedup();
+    patterns
+}
+
+/// Add the objects of git-annex to the store, and turn annexed files into tracked pointers.
+fn migrate_import_annex(
+    store_path: PathBuf,
+    pointer_format: PointerFormat,
+    jobs: usize,
+    dry_run: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    let mut store = open_store(store_path)?;
+    store.set_dry_run(dry_run);
+    let repo = open_repo()?;
+    let git_dir = repo.git_dir().map_err(CliError::git)?;
+    let root = repo.work_tree().map_err(CliError::git)?;
+    let objects = annex::import_objects(&store, &annex::objects_dir(&git_dir), jobs)
+        .map_err(CliError::store_access)?;
+
+    // Annexed files are committed as symlinks to the object, or as pointers to it when they
+    // are unlocked
+    let entries = repo.index_entries().map_err(CliError::git)?;
+    let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    let unlocked: HashSet<String> =
+        attributes::tracked_paths(&repo, "annex", &paths, attributes::AttrSource::WorkTree)
+            .map_err(CliError::git)?
+            .into_iter()
+            .collect();
+    let entries: Vec<&git::IndexEntry> = entries
+        .iter()
+        .filter(|entry| entry.mode == "120000" || unlocked.contains(&entry.path))
+        .collect();
+    let blobs: Vec<&str> = entries.iter().map(|entry| entry.blob.as_str()).collect();
+    let contents = repo.cat_blobs(&blobs).map_err(CliError::git)?;
+
+    let mut converted = Vec::new();
+    let mut updated = Vec::new();
+    let mut missing = Vec::new();
+    for (entry, content) in entries.into_iter().zip(contents) {
+        let key = match std::str::from_utf8(&content)
+            .ok()
+            .filter(|target| target.contains("annex/objects/"))
+            .and_then(|target| annex::AnnexKey::from_link_target(target.trim_end()))
+        {
+            Some(key) => key,
+            None => continue,
+        };
+        let store_ref = store::StoreFileRef::from_hash(key.hash);
+        let size = match store
+            .object_size(&store_ref)
+            .map_err(CliError::store_access)?
+        {
+            // In a dry run, the objects that would be imported are not in the store yet
+            _ if dry_run && objects.transferred.contains(store_ref.hash()) => {
+                converted.push(entry.path.clone());
+                continue;
+            }
+            Some(size) => size,
+            None => {
+                missing.push(entry.path.clone());
+                continue;
+            }
+        };
+        converted.push(entry.path.clone());
+        if dry_run {
+            continue;
+        }
+        let pointer = format!(
+            "{}\n",
+            store_ref.clone().with_size(size).to_pointer(pointer_format)
+        );
+        let blob = repo.write_blob(pointer.as_bytes()).map_err(CliError::git)?;
+
+        // Replace the symlink by the contents, as if the pointer had been checked out. The
+        // symlink only goes away once the contents are complete.
+        store
+            .write_to_file(&store_ref, &root.join(&entry.path))
+            .map_err(restore_error)?;
+        updated.push(git::IndexEntry {
+            mode: "100644".to_string(),
+            blob,
+            path: entry.path.clone(),
+        });
+    }
+    let patterns = annex_tracking_patterns(&converted, &paths);
+    if !dry_run {
+        repo.update_index(&updated).map_err(CliError::git)?;
+
+        // git-annex routes all files through its filter, overriding `.gitattributes`
+        let info_attributes = git_dir.join("info").join("attributes");
+        if let Ok(contents) = std::fs::read_to_string(&info_attributes) {
+            std::fs::write(
+                &info_attributes,
+                attributes::remove_filter(&contents, "annex"),
+            )?;
+        }
+        if !patterns.is_empty() {
+            let new_patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+            edit_gitattributes(&repo, |contents| {
+                attributes::add_tracking_patterns(
+                    contents,
+                    attributes::DEFAULT_FILTER,
+                    &new_patterns,
+                )
+            })?;
+        }
+    }
+
+    let report = AnnexImport {
+        objects,
+        pointers: converted,
+        missing,
+        patterns,
+        dry_run,
+    };
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{} {} objects ({}), {} already present",
+                if dry_run { "would import" } else { "imported" },
+                report.objects.transferred.len(),
+                format_bytes(report.objects.bytes),
+                report.objects.skipped
+            );
+            let convert = if dry_run {
+                "would convert"
+            } else {
+                "converted"
+            };
+            for path in &report.pointers {
+                println!("{}: {}", convert, path);
+            }
+            for path in &report.missing {
+                println!("missing: {}", path);
+            }
+            let track = if dry_run { "would track" } else { "tracking" };
+            for pattern in &report.patterns {
+                println!("{} {}", track, pattern);
+            }
+            if !dry_run {
+                println!("Commit the changes to complete the migration.");
+            }
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => unreachable!("rejected above"),
+    }
+
+    if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(CliErrorKind::NoSuchContent.into())
+    }
+}
+
+/// The outcome of `migrate history`.
+#[derive(Serialize)]
+struct HistoryRewrite {
+    #[serde(flatten)]
+    rewrite: history::RewriteReport,
+    /// Patterns tracked by every rewritten commit.
+    patterns: Vec<String>,
+    /// Whether nothing was changed, because of `--dry-run`.
+    dry_run: bool,
+}
+
+/// Rewrite all refs so that files matching the patterns are committed as pointers, then
+/// install the filter and check out the rewritten HEAD.
+fn migrate_history(
+    store: Option<PathBuf>,
+    explicit_store: bool,
+    patterns: &[String],
+    pointer_format: PointerFormat,
+    jobs: usize,
+    dry_run: bool,
+    format: OutputFormat,
+) -> CliResult<()> {
+    if format == OutputFormat::Csv {
+        return Err(CliErrorKind::UnsupportedFormat.into());
+    }
+    let repo = open_repo()?;
+    // The working tree is reset to the rewritten HEAD in the end
+    let changes = repo
+        .run(&["status", "--porcelain", "--untracked-files=no"])
+        .map_err(CliError::git)?;
+    if !changes.is_empty() {
+        return Err(CliError::git(io::Error::other(
+            "the working tree has uncommitted changes",
+        )));
+    }
+    let store_path = store.ok_or(CliErrorKind::NotInGitRepo)?;
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let mut target = open_store(store_path.clone())?;
+    target.set_dry_run(dry_run);
+    let rewrite =
+        history::rewrite(&repo, &target, &patterns, pointer_format, jobs).map_err(CliError::git)?;
+
+    if !dry_run {
+        // Checking out the pointers needs the filter
+        configure_filter(Some(store_path), explicit_store, false)?;
+        repo.run(&["reset", "--hard", "--quiet"])
+            .map_err(CliError::git)?;
+    }
+
+    let report = HistoryRewrite {
+        rewrite,
+        patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+        dry_run,
+    };
+    match format {
+        OutputFormat::Text if dry_run => {
+            println!(
+                "would rewrite {} commits, store {} objects ({})",
+                report.rewrite.commits,
+                report.rewrite.objects.len(),
+                format_bytes(report.rewrite.bytes)
+            );
+            for pattern in &report.patterns {
+                println!("would track {}", pattern);
+            }
+        }
+        OutputFormat::Text => {
+            println!(
+                "rewrote {} commits, stored {} objects ({})",
+                report.rewrite.commits,
+                report.rewrite.objects.len(),
+                format_bytes(report.rewrite.bytes)
+            );
+            for pattern in &report.patterns {
+                println!("tracking {}", pattern);
+            }
+            println!("The old blobs stay in the repository until it is cleaned up with");
+            println!("  git reflog expire --expire=now --all && git gc --prune=now");
+        }
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => unreachable!("rejected above"),
+    }
+    Ok(())
+}
+
+/// Store growth within a single month.
+#[derive(Serialize, Default)]
+struct MonthlyGrowth {
+    /// Month in `YYYY-MM` format.
+    month: String,
+    /// Number of objects that were first referenced in this month.
+    new_objects: u64,
+    /// Size of these objects.
+    new_bytes: u64,
+    /// Size of all objects referenced until the end of this month.
+    total_bytes: u64,
+}
+
+/// Bytes introduced by a single author.
+#[derive(Serialize, Default)]
+struct Contributor {
+    name: String,
+    new_objects: u64,
+    new_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct GrowthHistory {
+    months: Vec<MonthlyGrowth>,
+    /// Authors sorted by the number of bytes they added, largest first.
+    contributors: Vec<Contributor>,
+    /// Number of referenced objects missing from the local store, whose size is unknown.
+    unknown_sizes: u64,
+}
+
+/// Number of contributors shown in the text output.
+const TOP_CONTRIBUTORS: usize = 10;
+
+/// Walk the history of all refs and attribute every object to the month and author of the
+/// commit that referenced it first.
+fn stats_history(store_path: PathBuf, format: OutputFormat) -> CliResult<()> {
+    let store = open_store(store_path)?;
+    let repo = open_repo()?;
+    let commits = repo
+        .log(&["--all", "--reverse", "--date-order"])
+        .map_err(CliError::git)?;
+
+    let mut cache = git::PointerCache::new();
+    let mut seen = HashSet::new();
+    let mut months: BTreeMap<String, MonthlyGrowth> = BTreeMap::new();
+    let mut contributors: BTreeMap<String, Contributor> = BTreeMap::new();
+    let mut unknown_sizes = 0;
+
+    for commit in commits {
+        let pointers = repo
+            .pointers_cached(&commit.id, &mut cache)
+            .map_err(CliError::git)?;
+        for pointer in pointers {
+            if !seen.insert(pointer.store_ref.hash().clone()) {
+                continue;
+            }
+            let size = object_size(&store, &pointer.store_ref)?;
+            if size.is_none() {
+                unknown_sizes += 1;
+            }
+            let month = format_month(commit.time);
+            let growth = months.entry(month.clone()).or_default();
+            growth.month = month;
+            growth.new_objects += 1;
+            growth.new_bytes += size.unwrap_or(0);
+
+            let contributor = contributors.entry(commit.author.clone()).or_default();
+            contributor.name = commit.author.clone();
+            contributor.new_objects += 1;
+            contributor.new_bytes += size.unwrap_or(0);
+        }
+    }
+
+    let mut total = 0;
+    let months: Vec<MonthlyGrowth> = months
+        .into_values()
+        .map(|mut growth| {
+            total += growth.new_bytes;
+            growth.total_bytes = total;
+            growth
+        })
+        .collect();
+    let mut contributors: Vec<Contributor> = contributors.into_values().collect();
+    contributors.sort_by_key(|c| std::cmp::Reverse(c.new_bytes));
+    let history = GrowthHistory {
+        months,
+        contributors,
+        unknown_sizes,
+    };
+
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{:<8} {:>8} {:>12} {:>12}",
+                "month", "objects", "new", "total"
+            );
+            for growth in &history.months {
+                println!(
+                    "{:<8} {:>8} {:>12} {:>12}",
+                    growth.month,
+                    growth.new_objects,
+                    format_bytes(growth.new_bytes),
+                    format_bytes(growth.total_bytes)
+                );
+            }
+            println!();
+            println!("top contributors:");
+            for contributor in history.contributors.iter().take(TOP_CONTRIBUTORS) {
+                println!(
+                    "{:>12} {:>8} objects  {}",
+                    format_bytes(contributor.new_bytes),
+                    contributor.new_objects,
+                    contributor.name
+                );
+            }
+            if history.unknown_sizes > 0 {
+                println!(
+                    "{} objects are missing from the store and were not counted",
+                    history.unknown_sizes
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&history)?,
+        OutputFormat::Csv => {
+            println!("month,new_objects,new_bytes,total_bytes");
+            for growth in &history.months {
+                println!(
+                    "{},{},{},{}",
+                    growth.month, growth.new_objects, growth.new_bytes, growth.total_bytes
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_algorithm(name: &str) -> Result<&'static dyn HashAlgorithm, String> {
+    hash::algorithm(name).ok_or_else(|| format!("unsupported hash algorithm: {}", name))
+}
+
+/// Format a unix timestamp as `YYYY-MM` (in UTC).
+fn format_month(timestamp: i64) -> String {
+    let (year, month, _) = civil_date(timestamp);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Format a unix timestamp as `YYYY-MM-DD HH:MM:SS` (in UTC).
+fn format_time(timestamp: i64) -> String {
+    let (year, month, day) = civil_date(timestamp);
+    let secs_of_day = timestamp.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Print a value as pretty JSON on stdout.
+fn print_json<T: Serialize>(value: &T) -> CliResult<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, value).map_err(io::Error::from)?;
+    writeln!(out)?;
+    Ok(())
+}
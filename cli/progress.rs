@@ -0,0 +1,174 @@
+//! Progress bars on stderr for large clean/smudge transfers and transfers between stores.
+
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use git_assets_lib::store::TransferProgress;
+
+/// Single files smaller than this are not worth reporting progress for.
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Time before the first and between two progress updates.
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Width of the bar itself, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// A progress line on stderr that is redrawn in place.
+///
+/// It only appears if stderr is a terminal, and once the transfer has been running for a
+/// moment, so that quick transfers and logs stay free of it.
+pub struct ProgressBar {
+    /// What is being done, e.g. "storing".
+    action: &'static str,
+    /// Transfers below this number of bytes, in total if known, are not shown.
+    threshold: u64,
+    /// Expected number of bytes, if known in advance.
+    total: Option<u64>,
+    done: u64,
+    /// Number of files transferred so far and in total, for transfers of many files.
+    files: Option<(usize, Option<usize>)>,
+    enabled: bool,
+    start: Instant,
+    last_report: Option<Instant>,
+}
+
+impl ProgressBar {
+    /// Progress of transferring a single file, shown only if it is large.
+    pub fn new(action: &'static str, total: Option<u64>) -> Self {
+        Self {
+            action,
+            threshold: PROGRESS_THRESHOLD,
+            total,
+            done: 0,
+            files: None,
+            // Progress counts as a warning, so that `--quiet` hides it
+            enabled: io::stderr().is_terminal() && log::log_enabled!(log::Level::Warn),
+            start: Instant::now(),
+            last_report: None,
+        }
+    }
+
+    /// Progress of transferring many files between stores, shown regardless of their size.
+    pub fn for_files(action: &'static str) -> Self {
+        Self {
+            threshold: 0,
+            ..Self::new(action, None)
+        }
+    }
+
+    /// Set the number of bytes transferred so far.
+    pub fn set_done(&mut self, done: u64) {
+        self.done = done;
+        self.maybe_report();
+    }
+
+    /// Update the progress of a transfer between stores.
+    pub fn set_transfer(&mut self, progress: &TransferProgress) {
+        self.total = progress.total.map(|(_, bytes)| bytes);
+        self.files = Some((progress.files, progress.total.map(|(files, _)| files)));
+        self.set_done(progress.bytes);
+    }
+
+    /// Print the final state if any progress was reported.
+    pub fn finish(&self) {
+        if self.last_report.is_some() {
+            self.report();
+            eprintln!();
+        }
+    }
+
+    fn maybe_report(&mut self) {
+        if !self.enabled || std::cmp::max(self.done, self.total.unwrap_or(0)) < self.threshold {
+            return;
+        }
+        let now = Instant::now();
+        let last = self.last_report.unwrap_or(self.start);
+        if now.duration_since(last) < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Some(now);
+        self.report();
+    }
+
+    fn report(&self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (self.done as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let mut line = format!("git-assets: {} ", self.action);
+        if let Some(total) = self.total {
+            let fraction = self.done.min(total) as f64 / std::cmp::max(total, 1) as f64;
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            line += &format!(
+                "[{}{}] {:3}% {} / {}",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                (fraction * 100.0) as u64,
+                format_bytes(self.done),
+                format_bytes(total)
+            );
+        } else {
+            line += &format_bytes(self.done);
+        }
+        match self.files {
+            Some((files, Some(total))) => line += &format!(", {}/{} files", files, total),
+            Some((files, None)) => line += &format!(", {} files", files),
+            None => {}
+        }
+        line += &format!(", {}/s", format_bytes(rate));
+        // Git passes the filter's stderr through, so carriage returns update the line in place.
+        eprint!("\r{}\x1b[K", line);
+    }
+}
+
+/// A writer that forwards everything to an inner writer while showing the progress of the
+/// transfer on stderr.
+pub struct Progress<W> {
+    inner: W,
+    bar: ProgressBar,
+}
+
+impl<W: Write> Progress<W> {
+    pub fn new(inner: W, action: &'static str, total: Option<u64>) -> Self {
+        Self {
+            inner,
+            bar: ProgressBar::new(action, total),
+        }
+    }
+
+    /// Print the final state if any progress was reported, and return the inner writer.
+    pub fn finish(self) -> W {
+        self.bar.finish();
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Progress<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n_written = self.inner.write(buf)?;
+        self.bar.set_done(self.bar.done + n_written as u64);
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Format a byte count using binary units, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,217 @@
+//! Importing objects from the local object storage of git-annex.
+//!
+//! git-annex keeps every object in `.git/annex/objects/<hash-dirs>/<key>/<key>`, and commits
+//! symlinks pointing there instead of the contents. Keys look like
+//! `SHA256E-s<size>--<hex-digest><extension>`, where the backend `SHA256E` (or `SHA256`, which
+//! has no extension) means that the digest is the SHA-256 hash of the contents, i.e. the hash
+//! identifying the object in a store using `sha256`. Objects of other backends, e.g. `SHA1` or
+//! `WORM`, cannot be imported.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{self, ContentHash};
+use crate::parallel;
+use crate::store::{Store, StoreFileRef, TransferReport};
+
+/// The parts of a git-annex key that matter for importing the object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnexKey {
+    pub hash: ContentHash,
+    /// Size of the contents, if it is part of the key.
+    pub size: Option<u64>,
+}
+
+impl AnnexKey {
+    /// Parse a key of the `SHA256E` or `SHA256` backend, returning `None` for all others.
+    pub fn parse(key: &str) -> Option<AnnexKey> {
+        let (fields, name) = key.split_once("--")?;
+        let mut fields = fields.split('-');
+        let digest_hex = match fields.next()? {
+            "SHA256" => name,
+            // The extension follows the digest
+            "SHA256E" => name.get(..2 * hash::SHA256.digest_len())?,
+            _ => return None,
+        };
+        let mut size = None;
+        for field in fields {
+            if let Some(value) = field.strip_prefix('s') {
+                size = Some(value.parse().ok()?);
+            }
+        }
+        let digest = hex::decode(digest_hex).ok()?;
+        Some(AnnexKey {
+            hash: ContentHash::from_digest(hash::SHA256, &digest)?,
+            size,
+        })
+    }
+
+    /// Find the key in the target of a symlink committed by git-annex, which ends in the
+    /// object file named after the key.
+    pub fn from_link_target(target: &str) -> Option<AnnexKey> {
+        AnnexKey::parse(target.rsplit('/').next()?)
+    }
+}
+
+/// The directory containing the objects of git-annex, given the git directory of a repository.
+pub fn objects_dir(git_dir: &Path) -> PathBuf {
+    git_dir.join("annex").join("objects")
+}
+
+/// Add all objects with supported keys in the git-annex object directory `objects_dir` to
+/// `store`.
+///
+/// Objects that are already present are skipped, all others are verified against the hash
+/// and size in their key before they are stored, on `jobs` threads. A missing directory
+/// contains no objects.
+pub fn import_objects(
+    store: &Store,
+    objects_dir: &Path,
+    jobs: usize,
+) -> io::Result<TransferReport> {
+    let mut objects = Vec::new();
+    find_objects(objects_dir, &mut objects)?;
+    objects.sort_by(|(a, _), (b, _)| a.hash.cmp(&b.hash));
+    // The same object can be there under several keys, e.g. with different extensions
+    objects.dedup_by(|(a, _), (b, _)| a.hash == b.hash);
+
+    let mut report = TransferReport::default();
+    parallel::map_ordered(
+        jobs,
+        objects.into_iter().map(Ok),
+        |(key, path)| import_object(store, key, &path),
+        |imported| {
+            match imported? {
+                (hash, Some(size)) => {
+                    report.bytes += size;
+                    report.transferred.push(hash);
+                }
+                (_, None) => report.skipped += 1,
+            }
+            Ok(())
+        },
+    )?;
+    Ok(report)
+}
+
+/// Store the object with the given key from the file at `path`, returning its size, or `None`
+/// if the store already has it.
+fn import_object(
+    store: &Store,
+    key: AnnexKey,
+    path: &Path,
+) -> io::Result<(ContentHash, Option<u64>)> {
+    let store_ref = StoreFileRef::from_hash(key.hash.clone());
+    if store.object_size(&store_ref)?.is_some() {
+        return Ok((key.hash, None));
+    }
+    let mut staging_file = store.new_staging_file_with(hash::SHA256)?;
+    let copied =
+        std::fs::File::open(path).and_then(|mut file| io::copy(&mut file, &mut staging_file));
+    let size = match copied {
+        Ok(size) if key.size.map_or(true, |expected| expected == size) => size,
+        Ok(size) => {
+            staging_file.discard()?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has {} bytes, but its key says otherwise",
+                    path.display(),
+                    size
+                ),
+            ));
+        }
+        Err(err) => {
+            staging_file.discard()?;
+            return Err(err);
+        }
+    };
+    store.make_permanent_verified(staging_file, &key.hash)?;
+    Ok((key.hash, Some(size)))
+}
+
+/// Collect the object files below `dir`, which are named like the directory containing them.
+fn find_objects(dir: &Path, objects: &mut Vec<(AnnexKey, PathBuf)>) -> io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_objects(&path, objects)?;
+            continue;
+        }
+        let name = entry.file_name();
+        let key = name
+            .to_str()
+            .filter(|name| dir.file_name().and_then(|dir| dir.to_str()) == Some(name))
+            .and_then(AnnexKey::parse);
+        if let Some(key) = key {
+            objects.push((key, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{import_objects, AnnexKey};
+    use crate::hash::{ContentHash, SHA256};
+    use crate::store::{Store, StoreFileRef};
+
+    #[test]
+    fn parse_annex_keys() {
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        let key = AnnexKey::parse(&format!("SHA256E-s3--{}.tar.gz", hash)).unwrap();
+        assert_eq!((key.hash, key.size), (hash.clone(), Some(3)));
+        let key = AnnexKey::parse(&format!("SHA256--{}", hash)).unwrap();
+        assert_eq!((key.hash, key.size), (hash.clone(), None));
+        let target = format!(
+            "../.git/annex/objects/Xx/Yy/SHA256E-s3--{0}/SHA256E-s3--{0}",
+            hash
+        );
+        assert_eq!(AnnexKey::from_link_target(&target).unwrap().hash, hash);
+
+        assert!(AnnexKey::parse(&format!("SHA256--{}.txt", hash)).is_none());
+        assert!(AnnexKey::parse("SHA1-s3--0beec7b5ea3f0fdbc95d0dd47f3c5bc275da8a33").is_none());
+        assert!(AnnexKey::parse("WORM-s3-m1600000000--foo.txt").is_none());
+    }
+
+    #[test]
+    fn import_annex_objects() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.annex_import.{}", std::process::id()));
+        let objects_dir = base_dir.join("annex").join("objects");
+        std::fs::create_dir_all(&base_dir).unwrap();
+        let store = Store::open_or_create(base_dir.join("store")).unwrap();
+
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        let key = format!("SHA256E-s3--{}.txt", hash);
+        let dir = objects_dir.join("Xx").join("Yy").join(&key);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(&key), b"foo").unwrap();
+        // Unsupported backends are left alone
+        let dir = objects_dir.join("Zz").join("Zz").join("WORM-s3--bar");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("WORM-s3--bar"), b"bar").unwrap();
+
+        let report = import_objects(&store, &objects_dir, 2).unwrap();
+        assert_eq!(report.transferred, std::slice::from_ref(&hash));
+        let store_ref = StoreFileRef::from_hash(hash);
+        assert_eq!(store.object_size(&store_ref).unwrap(), Some(3));
+
+        // Objects not matching their key are not imported
+        let corrupt = ContentHash::hash_bytes(SHA256, b"baz");
+        let key = format!("SHA256E-s4--{}", corrupt);
+        let dir = objects_dir.join("Ab").join("Cd").join(&key);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(&key), b"baz").unwrap();
+        assert!(import_objects(&store, &objects_dir, 2).is_err());
+        assert!(store.validate().unwrap().is_valid());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+//! Portable archives of store objects, e.g. for carrying assets to a machine without network access.
+//!
+//! An archive is a tar file starting with a `MANIFEST` entry, which consists of the
+//! `ARCHIVE_HEADER` line followed by one `<hash> <size>` line per object. The objects
+//! follow as `objects/<hash>`, in the order of the manifest. Chunked objects are
+//! reassembled, so archives don't depend on how the objects were stored. `verify` checks an
+//! archive on its own, e.g. a backup.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use serde::Serialize;
+
+use crate::hash::ContentHash;
+use crate::parallel;
+use crate::store::{Store, StoreFileRef, TransferReport};
+
+/// First line of the manifest of an archive.
+const ARCHIVE_HEADER: &str = "git-assets archive v1\n";
+/// Name of the manifest entry.
+const MANIFEST: &str = "MANIFEST";
+/// Directory containing the objects inside the archive.
+const OBJECT_DIR: &str = "objects";
+
+/// Write the given objects of `store` to an archive, sorted by hash and without duplicates.
+///
+/// The archive only depends on the objects, not on when or by whom it was created. Fails with
+/// `NotFound` if any of the objects is not in the store.
+pub fn export<W: Write>(
+    store: &Store,
+    hashes: impl IntoIterator<Item = ContentHash>,
+    out: W,
+) -> io::Result<TransferReport> {
+    let mut hashes: Vec<ContentHash> = hashes.into_iter().collect();
+    hashes.sort();
+    hashes.dedup();
+
+    let mut manifest = ARCHIVE_HEADER.to_string();
+    let mut sizes = Vec::with_capacity(hashes.len());
+    for hash in &hashes {
+        let size = store
+            .object_size(&StoreFileRef::from_hash(hash.clone()))?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not in the store", hash),
+                )
+            })?;
+        manifest.push_str(&format!("{} {}\n", hash, size));
+        sizes.push(size);
+    }
+
+    let mut report = TransferReport::default();
+    let mut builder = tar::Builder::new(out);
+    builder.append(
+        &header(MANIFEST, manifest.len() as u64),
+        manifest.as_bytes(),
+    )?;
+    for (hash, size) in hashes.into_iter().zip(sizes) {
+        let reader = store.open_ref(&StoreFileRef::from_hash(hash.clone()))?;
+        let path = format!("{}/{}", OBJECT_DIR, hash);
+        builder.append(&header(&path, size), reader)?;
+        report.bytes += size;
+        report.transferred.push(hash);
+    }
+    builder.into_inner()?.flush()?;
+    Ok(report)
+}
+
+/// Add the objects of an archive written by `export` to `store`.
+///
+/// Objects that are already present are skipped. All others are verified against their hash
+/// before they are stored; objects that are listed in the manifest but missing from the
+/// archive are reported as missing. The archive is read on the calling thread, while the
+/// objects are hashed and stored on `jobs` threads.
+pub fn import<R: Read>(store: &Store, input: R, jobs: usize) -> io::Result<TransferReport> {
+    let mut archive = tar::Archive::new(input);
+    let mut entries = archive.entries()?;
+    let mut expected = read_manifest(&mut entries)?;
+
+    let mut report = TransferReport::default();
+    let staged = entries.map(|entry| {
+        let mut entry = entry?;
+        let hash = listed_object(&entry, &mut expected)?;
+        if store
+            .object_size(&StoreFileRef::from_hash(hash.clone()))?
+            .is_some()
+        {
+            return Ok((hash, None));
+        }
+        let mut staging_file = store.new_deferred_staging_file_with(hash.algorithm())?;
+        if let Err(err) = io::copy(&mut entry, &mut staging_file) {
+            staging_file.discard()?;
+            return Err(err);
+        }
+        Ok((hash, Some(staging_file)))
+    });
+    parallel::map_ordered(
+        jobs,
+        staged,
+        |(hash, staging_file)| match staging_file {
+            Some(staging_file) => store
+                .make_permanent_verified(staging_file, &hash)
+                .map(|store_ref| (hash, store_ref.size())),
+            None => Ok((hash, None)),
+        },
+        |stored| {
+            match stored? {
+                (hash, Some(size)) => {
+                    report.bytes += size;
+                    report.transferred.push(hash);
+                }
+                (_, None) => report.skipped += 1,
+            }
+            Ok(())
+        },
+    )?;
+
+    report.missing = expected.into_keys().collect();
+    report.missing.sort();
+    Ok(report)
+}
+
+/// The outcome of checking an archive, see `verify`.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct VerifyReport {
+    /// Number of objects whose contents match their hash.
+    pub verified: usize,
+    /// Total size of those objects.
+    pub bytes: u64,
+    /// Objects whose contents don't match their hash.
+    pub corrupt: Vec<ContentHash>,
+    /// Objects that are listed in the manifest, but missing from the archive.
+    pub missing: Vec<ContentHash>,
+}
+
+impl VerifyReport {
+    /// Whether the archive holds every object of its manifest, intact.
+    pub fn is_valid(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Check an archive written by `export` against its manifest, and the objects in it against
+/// their hashes, without a store.
+///
+/// Fails with `InvalidData` if the archive is not laid out like one written by `export`, e.g.
+/// if it contains entries that are not listed in the manifest or whose size differs from it.
+pub fn verify<R: Read>(input: R) -> io::Result<VerifyReport> {
+    let mut archive = tar::Archive::new(input);
+    let mut entries = archive.entries()?;
+    let mut expected = read_manifest(&mut entries)?;
+
+    let mut report = VerifyReport::default();
+    for entry in entries {
+        let mut entry = entry?;
+        let hash = listed_object(&entry, &mut expected)?;
+        let size = entry.size();
+        if ContentHash::hash_stream(hash.algorithm(), &mut entry)? == hash {
+            report.verified += 1;
+            report.bytes += size;
+        } else {
+            report.corrupt.push(hash);
+        }
+    }
+
+    report.missing = expected.into_keys().collect();
+    report.missing.sort();
+    Ok(report)
+}
+
+/// Read the manifest from the first of the `entries` of an archive, see `parse_manifest`.
+fn read_manifest<R: Read>(
+    entries: &mut tar::Entries<'_, R>,
+) -> io::Result<HashMap<ContentHash, u64>> {
+    let mut manifest = String::new();
+    match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.to_str() != Some(MANIFEST) {
+                return Err(invalid(
+                    "archive does not start with a manifest".to_string(),
+                ));
+            }
+            entry.read_to_string(&mut manifest)?;
+        }
+        None => return Err(invalid("archive is empty".to_string())),
+    }
+    parse_manifest(&manifest).ok_or_else(|| invalid("archive has an invalid manifest".to_string()))
+}
+
+/// The hash of the object in `entry`, which is removed from the `expected` objects of the
+/// manifest. Fails if the entry is not an object listed there, or has a different size.
+fn listed_object<R: Read>(
+    entry: &tar::Entry<'_, R>,
+    expected: &mut HashMap<ContentHash, u64>,
+) -> io::Result<ContentHash> {
+    let path = entry.path()?.to_string_lossy().into_owned();
+    let hash = path
+        .strip_prefix(OBJECT_DIR)
+        .and_then(|name| name.strip_prefix('/'))
+        .and_then(|name| ContentHash::from_hex(name.as_bytes()));
+    let size = match hash.as_ref().and_then(|hash| expected.remove(hash)) {
+        Some(size) => size,
+        None => return Err(invalid(format!("unexpected entry in archive: {}", path))),
+    };
+    if entry.size() != size {
+        return Err(invalid(format!(
+            "{} has {} bytes, but the manifest lists {}",
+            path,
+            entry.size(),
+            size
+        )));
+    }
+    Ok(hash.expect("only listed objects have a size"))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parse the manifest of an archive into the sizes of the objects it lists.
+fn parse_manifest(manifest: &str) -> Option<HashMap<ContentHash, u64>> {
+    let mut objects = HashMap::new();
+    for line in manifest.strip_prefix(ARCHIVE_HEADER)?.lines() {
+        let (hash, size) = line.split_once(' ')?;
+        objects.insert(ContentHash::from_hex(hash.as_bytes())?, size.parse().ok()?);
+    }
+    Some(objects)
+}
+
+/// A tar header for a regular file, with all metadata fixed so that archives are reproducible.
+fn header(path: &str, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    // The text form of hashes of all supported algorithms is short enough for the path to fit
+    // into the header without extensions
+    header
+        .set_path(path)
+        .expect("archive paths are short and valid");
+    header.set_cksum();
+    header
+}
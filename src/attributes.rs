@@ -0,0 +1,406 @@
+//! Determining which paths are routed through the git-assets filter.
+//!
+//! All commands that need to know whether a path is "tracked" should go through this module,
+//! so that they agree with git, which is the one actually applying the filter.
+
+use std::io;
+
+use crate::git::Repository;
+
+/// Name of the filter driver as suggested in the README (`filter=assets`).
+pub const DEFAULT_FILTER: &str = "assets";
+
+/// Where git reads `.gitattributes` files from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrSource {
+    /// The attribute files in the working tree (falling back to the index).
+    WorkTree,
+    /// Only the attribute files in the index.
+    Index,
+}
+
+/// Look up the value of the `filter` attribute for each of the given paths.
+///
+/// Returns `None` for paths where the attribute is unspecified or unset.
+pub fn filter_attributes(
+    repo: &Repository,
+    paths: &[&str],
+    source: AttrSource,
+) -> io::Result<Vec<(String, Option<String>)>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = vec!["check-attr", "-z", "--stdin"];
+    if source == AttrSource::Index {
+        args.push("--cached");
+    }
+    args.push("filter");
+
+    let mut input = Vec::new();
+    for path in paths {
+        input.extend_from_slice(path.as_bytes());
+        input.push(0);
+    }
+    let out = repo.run_with_input(&args, input)?;
+
+    // Format: <path> NUL <attribute> NUL <info> NUL
+    let fields: Vec<String> = out
+        .split(|b| *b == 0)
+        .map(|f| String::from_utf8_lossy(f).into_owned())
+        .collect();
+    Ok(fields
+        .chunks_exact(3)
+        .map(|record| {
+            let value = match record[2].as_str() {
+                "unspecified" | "unset" | "set" => None,
+                value => Some(value.to_string()),
+            };
+            (record[0].clone(), value)
+        })
+        .collect())
+}
+
+/// Return those of the given paths that are routed through the filter with the given name.
+pub fn tracked_paths(
+    repo: &Repository,
+    filter: &str,
+    paths: &[&str],
+    source: AttrSource,
+) -> io::Result<Vec<String>> {
+    Ok(filter_attributes(repo, paths, source)?
+        .into_iter()
+        .filter(|(_, value)| value.as_deref() == Some(filter))
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// Check whether a single path is routed through the filter with the given name.
+pub fn is_tracked(repo: &Repository, filter: &str, path: &str) -> io::Result<bool> {
+    Ok(!tracked_paths(repo, filter, &[path], AttrSource::WorkTree)?.is_empty())
+}
+
+/// Check whether a path relative to the root of the repository matches a pattern in
+/// `.gitattributes` syntax.
+///
+/// Patterns without a slash match the file name in any directory, all others match the whole
+/// path. `*` and `?` match anything but a slash, `**` also matches slashes, and a backslash
+/// makes the next character match literally.
+pub fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob_matches(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob_matches(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        // Also matches no directory at all
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob_matches(rest, text)
+                || (0..text.len()).any(|i| text[i] == b'/' && glob_matches(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_matches(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(&c) if c != b'/') && glob_matches(rest, &text[1..])
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            text.first() == Some(c) && glob_matches(rest, &text[1..])
+        }
+    }
+}
+
+/// The pattern matching exactly the given path relative to the root of the repository.
+pub fn path_pattern(path: &str) -> String {
+    let mut pattern = String::from("/");
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// The pattern matching all files with the same extension as the given path, e.g. `*.psd`,
+/// unless its name has no extension that can be used in a pattern.
+pub fn extension_pattern(path: &str) -> Option<String> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let (stem, extension) = name.rsplit_once('.')?;
+    let is_plain = |c: char| !c.is_whitespace() && !matches!(c, '*' | '?' | '[' | '\\' | '"');
+    if stem.is_empty() || extension.is_empty() || !extension.chars().all(is_plain) {
+        return None;
+    }
+    Some(format!("*.{}", extension))
+}
+
+/// Split a `.gitattributes` line into its pattern and the attributes, handling quoted patterns.
+///
+/// Returns `None` for blank lines and comments.
+fn parse_line(line: &str) -> Option<(String, Vec<&str>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (pattern, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            (quoted[..end].to_string(), &quoted[end + 1..])
+        }
+        None => {
+            let end = line.find(char::is_whitespace).unwrap_or(line.len());
+            (line[..end].to_string(), &line[end..])
+        }
+    };
+    Some((pattern, rest.split_whitespace().collect()))
+}
+
+/// Format the line routing a pattern through the filter.
+fn tracking_line(pattern: &str, filter: &str) -> String {
+    format_line(pattern, &tracking_attributes(filter))
+}
+
+/// Format a line assigning the given attributes to a pattern, quoting it if necessary.
+fn format_line<S: AsRef<str>>(pattern: &str, attrs: &[S]) -> String {
+    let mut line = if pattern.contains(char::is_whitespace) {
+        format!("\"{}\"", pattern)
+    } else {
+        pattern.to_string()
+    };
+    for attr in attrs {
+        line.push(' ');
+        line.push_str(attr.as_ref());
+    }
+    line
+}
+
+/// The attributes of a pattern tracked by Git LFS, as written by `git lfs track`.
+pub const LFS_ATTRIBUTES: &[&str] = &["filter=lfs", "diff=lfs", "merge=lfs", "-text"];
+
+/// The attributes of a pattern tracked by git-assets, as written by `add_tracking_patterns`.
+pub fn tracking_attributes(filter: &str) -> Vec<String> {
+    vec![format!("filter={}", filter), "-diff".to_string()]
+}
+
+/// Add lines routing the given patterns through the filter to the contents of a
+/// `.gitattributes` file.
+///
+/// All lines setting the filter are kept together and sorted by pattern, at the place of the
+/// first one. Patterns that are already routed through the filter are not added again, and all
+/// other lines are left alone.
+pub fn add_tracking_patterns(contents: &str, filter: &str, patterns: &[&str]) -> String {
+    let filter_attr = format!("filter={}", filter);
+    let mut other_lines: Vec<&str> = Vec::new();
+    let mut tracking: Vec<(String, String)> = Vec::new();
+    // Where the tracking lines go among the other lines
+    let mut position = None;
+    for line in contents.lines() {
+        match parse_line(line) {
+            Some((pattern, attrs)) if attrs.contains(&filter_attr.as_str()) => {
+                position.get_or_insert(other_lines.len());
+                tracking.push((pattern, line.to_string()));
+            }
+            _ => other_lines.push(line),
+        }
+    }
+    for pattern in patterns {
+        if !tracking.iter().any(|(existing, _)| existing == pattern) {
+            tracking.push((pattern.to_string(), tracking_line(pattern, filter)));
+        }
+    }
+    tracking.sort();
+    tracking.dedup_by(|a, b| a.0 == b.0);
+
+    let position = position.unwrap_or(other_lines.len());
+    let mut result = String::new();
+    for line in &other_lines[..position] {
+        result.push_str(line);
+        result.push('\n');
+    }
+    for (_, line) in &tracking {
+        result.push_str(line);
+        result.push('\n');
+    }
+    for line in &other_lines[position..] {
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+/// Remove the lines routing the given patterns through the filter from the contents of a
+/// `.gitattributes` file.
+///
+/// Returns the new contents and the patterns that were actually removed.
+pub fn remove_tracking_patterns(
+    contents: &str,
+    filter: &str,
+    patterns: &[&str],
+) -> (String, Vec<String>) {
+    let filter_attr = format!("filter={}", filter);
+    let mut result = String::new();
+    let mut removed = Vec::new();
+    for line in contents.lines() {
+        match parse_line(line) {
+            Some((pattern, attrs))
+                if attrs.contains(&filter_attr.as_str())
+                    && patterns.contains(&pattern.as_str()) =>
+            {
+                removed.push(pattern)
+            }
+            _ => {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+    }
+    (result, removed)
+}
+
+/// Remove all lines routing patterns through the given filter from the contents of an
+/// attributes file, e.g. the catch-all `* filter=annex` that git-annex puts into
+/// `.git/info/attributes`.
+pub fn remove_filter(contents: &str, filter: &str) -> String {
+    let filter_attr = format!("filter={}", filter);
+    let mut result = String::new();
+    for line in contents.lines() {
+        match parse_line(line) {
+            Some((_, attrs)) if attrs.contains(&filter_attr.as_str()) => {}
+            _ => {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+    }
+    result
+}
+
+/// Give all patterns that currently use the filter `from` the attributes `to` instead, e.g.
+/// `tracking_attributes` when migrating from Git LFS, or `LFS_ATTRIBUTES` the other way round.
+///
+/// Drivers named after the old filter are removed, and so are settings of the attributes that
+/// `to` assigns; all other attributes are kept. Returns the new contents and the patterns that
+/// were changed.
+pub fn replace_filter<S: AsRef<str>>(
+    contents: &str,
+    from: &str,
+    to: &[S],
+) -> (String, Vec<String>) {
+    let from_filter = format!("filter={}", from);
+    // `-text` sets the attribute `text`, `diff=lfs` sets `diff`
+    let name = |attr: &str| -> String {
+        let attr = attr.trim_start_matches(['-', '!']);
+        attr.split('=').next().unwrap_or(attr).to_string()
+    };
+    let to: Vec<&str> = to.iter().map(AsRef::as_ref).collect();
+    let to_names: Vec<String> = to.iter().map(|attr| name(attr)).collect();
+    let keep = |attr: &&str| {
+        let is_old_driver = attr.split_once('=').is_some_and(|(_, value)| value == from);
+        !is_old_driver && !to_names.contains(&name(attr))
+    };
+    let mut result = String::new();
+    let mut replaced = Vec::new();
+    for line in contents.lines() {
+        match parse_line(line) {
+            Some((pattern, attrs)) if attrs.contains(&from_filter.as_str()) => {
+                let mut new_attrs = to.clone();
+                new_attrs.extend(attrs.into_iter().filter(keep));
+                result.push_str(&format_line(&pattern, &new_attrs));
+                replaced.push(pattern);
+            }
+            _ => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    (result, replaced)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        add_tracking_patterns, extension_pattern, path_pattern, pattern_matches, remove_filter,
+        remove_tracking_patterns, replace_filter, tracking_attributes, LFS_ATTRIBUTES,
+    };
+
+    #[test]
+    fn patterns_match_like_git() {
+        assert!(pattern_matches("*.psd", "a.psd"));
+        assert!(pattern_matches("*.psd", "art/deep/a.psd"));
+        assert!(!pattern_matches("*.psd", "a.psd.txt"));
+        assert!(pattern_matches("art/*.psd", "art/a.psd"));
+        assert!(!pattern_matches("art/*.psd", "art/deep/a.psd"));
+        assert!(pattern_matches("/art/**/*.psd", "art/deep/a.psd"));
+        assert!(pattern_matches("art/**/*.psd", "art/a.psd"));
+        assert!(pattern_matches("image?.png", "image1.png"));
+        assert!(!pattern_matches("/a.psd", "art/a.psd"));
+        assert!(pattern_matches("/what\\?.psd", "what?.psd"));
+        assert!(!pattern_matches("/what\\?.psd", "whatn.psd"));
+    }
+
+    #[test]
+    fn patterns_for_single_paths() {
+        assert_eq!(path_pattern("art/a.psd"), "/art/a.psd");
+        assert_eq!(path_pattern("what?[1].psd"), "/what\\?\\[1].psd");
+        assert!(pattern_matches(&path_pattern("a*b/c.psd"), "a*b/c.psd"));
+        assert!(!pattern_matches(&path_pattern("a*b/c.psd"), "axxb/c.psd"));
+
+        assert_eq!(extension_pattern("art/a.b.psd").as_deref(), Some("*.psd"));
+        assert_eq!(extension_pattern("art.d/README"), None);
+        assert_eq!(extension_pattern(".hidden"), None);
+        assert_eq!(extension_pattern("a.p d"), None);
+    }
+
+    #[test]
+    fn tracking_patterns_are_sorted_and_deduplicated() {
+        let contents = "# assets\n*.xcf filter=assets\n\"*.a b\" filter=assets\n*.txt text\n";
+        assert_eq!(
+            add_tracking_patterns(contents, "assets", &["*.psd", "*.xcf", "my file.png"]),
+            "# assets\n\"*.a b\" filter=assets\n*.psd filter=assets -diff\n*.xcf filter=assets\n\
+             \"my file.png\" filter=assets -diff\n*.txt text\n"
+        );
+        assert_eq!(
+            add_tracking_patterns("", "assets", &["*.psd", "*.psd"]),
+            "*.psd filter=assets -diff\n"
+        );
+    }
+
+    #[test]
+    fn tracking_patterns_are_removed() {
+        let contents = "*.psd filter=assets -diff\n*.psd text\n\"my file\" filter=assets\n";
+        let (contents, removed) =
+            remove_tracking_patterns(contents, "assets", &["*.psd", "my file", "*.bin"]);
+        assert_eq!(contents, "*.psd text\n");
+        assert_eq!(removed, ["*.psd", "my file"]);
+
+        let contents = "* filter=annex\n*.psd filter=assets -diff\n";
+        assert_eq!(
+            remove_filter(contents, "annex"),
+            "*.psd filter=assets -diff\n"
+        );
+    }
+
+    #[test]
+    fn filter_is_replaced() {
+        let contents =
+            "*.psd filter=lfs diff=lfs merge=lfs -text\n\"my file\" filter=lfs\n*.txt text\n";
+        let (contents, replaced) = replace_filter(contents, "lfs", &tracking_attributes("assets"));
+        assert_eq!(
+            contents,
+            "*.psd filter=assets -diff -text\n\"my file\" filter=assets -diff\n*.txt text\n"
+        );
+        assert_eq!(replaced, ["*.psd", "my file"]);
+
+        // And back again
+        let (contents, replaced) = replace_filter(&contents, "assets", LFS_ATTRIBUTES);
+        assert_eq!(
+            contents,
+            "*.psd filter=lfs diff=lfs merge=lfs -text\n\"my file\" filter=lfs diff=lfs merge=lfs -text\n*.txt text\n"
+        );
+        assert_eq!(replaced, ["*.psd", "my file"]);
+    }
+}
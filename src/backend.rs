@@ -0,0 +1,298 @@
+//! Places where the objects of a store are kept.
+//!
+//! A `Store` takes care of staging, hashing and chunking, and hands finished objects to a
+//! `StorageBackend`, which only needs to be able to keep files named after their hash.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config;
+use crate::hash::ContentHash;
+
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+
+/// Object I/O of a store.
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Move the finished object at `source`, a local file with the contents hashing to `hash`,
+    /// into the backend. On success, `source` no longer exists.
+    ///
+    /// Objects that are already present may simply be overwritten, since objects with the
+    /// same hash have the same contents.
+    fn put(&self, hash: &ContentHash, source: &Path) -> io::Result<()>;
+
+    /// Open the contents of an object, failing with `NotFound` if it is not present.
+    fn get(&self, hash: &ContentHash) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Return whether the object is present.
+    fn exists(&self, hash: &ContentHash) -> io::Result<bool> {
+        Ok(self.size(hash)?.is_some())
+    }
+
+    /// Return the size of an object, or `None` if it is not present.
+    fn size(&self, hash: &ContentHash) -> io::Result<Option<u64>>;
+
+    /// List all objects in the backend, and anything else found there.
+    fn list(&self) -> io::Result<Listing>;
+
+    /// Remove an object. Removing an object that is not present is not an error.
+    fn delete(&self, hash: &ContentHash) -> io::Result<()>;
+
+    /// Return when the object was last modified, if the backend knows.
+    fn modified(&self, _hash: &ContentHash) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// Where the object is kept, for messages.
+    fn location(&self, hash: &ContentHash) -> PathBuf;
+
+    /// The local file holding the object, for backends that keep objects as plain files, which
+    /// can then be copied more efficiently than through `get`.
+    fn local_path(&self, _hash: &ContentHash) -> Option<PathBuf> {
+        None
+    }
+
+    /// Hash the contents of a stored object, in order to check them.
+    fn hash_contents(&self, hash: &ContentHash) -> io::Result<ContentHash> {
+        ContentHash::hash_stream(hash.algorithm(), &mut self.get(hash)?)
+    }
+}
+
+/// Environment variable with the time to wait for a connection to a remote backend.
+pub const CONNECT_TIMEOUT_ENV: &str = "GIT_ASSETS_CONNECT_TIMEOUT";
+
+/// Environment variable with the time to wait for a remote backend to send or accept data.
+pub const READ_TIMEOUT_ENV: &str = "GIT_ASSETS_READ_TIMEOUT";
+
+/// Environment variable with the time a single request to a remote backend may take in total.
+pub const TIMEOUT_ENV: &str = "GIT_ASSETS_TIMEOUT";
+
+/// How long remote backends wait, so that a hung server fails a command, e.g. the smudge
+/// filter during a checkout, instead of blocking it forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// For establishing a connection.
+    pub connect: Duration,
+    /// For the other side to send or accept any data at all.
+    pub read: Duration,
+    /// For a whole request, including the contents. None by default, since it depends on the
+    /// size of the objects and the bandwidth.
+    pub overall: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(30),
+            read: Duration::from_secs(60),
+            overall: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// The defaults, overridden by `GIT_ASSETS_CONNECT_TIMEOUT`, `GIT_ASSETS_READ_TIMEOUT` and
+    /// `GIT_ASSETS_TIMEOUT`, each a duration like `30s` or `5m`.
+    pub fn from_env() -> io::Result<Timeouts> {
+        let var = |name: &str| -> io::Result<Option<Duration>> {
+            match std::env::var(name).ok().filter(|value| !value.is_empty()) {
+                None => Ok(None),
+                Some(value) => config::parse_duration(&value).map(Some).map_err(|message| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: {}", name, message),
+                    )
+                }),
+            }
+        };
+        let defaults = Timeouts::default();
+        Ok(Timeouts {
+            connect: var(CONNECT_TIMEOUT_ENV)?.unwrap_or(defaults.connect),
+            read: var(READ_TIMEOUT_ENV)?.unwrap_or(defaults.read),
+            overall: var(TIMEOUT_ENV)?.or(defaults.overall),
+        })
+    }
+
+    /// An HTTP client using these timeouts.
+    #[cfg(any(feature = "http", feature = "serve"))]
+    pub(crate) fn agent(&self) -> ureq::AgentBuilder {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(self.connect)
+            .timeout_read(self.read)
+            .timeout_write(self.read);
+        match self.overall {
+            Some(overall) => agent.timeout(overall),
+            None => agent,
+        }
+    }
+}
+
+/// Contents of a backend.
+#[derive(Debug, Default)]
+pub struct Listing {
+    /// Objects and their sizes.
+    pub objects: Vec<(ContentHash, u64)>,
+    /// Files and directories that don't belong there.
+    pub unexpected: Vec<PathBuf>,
+}
+
+/// Turn an HTTP error status into an `io::Error`, keeping `NotFound` recognizable.
+#[cfg(feature = "http")]
+fn status_error(code: u16, message: String) -> io::Error {
+    let kind = match code {
+        404 => io::ErrorKind::NotFound,
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, message)
+}
+
+/// Objects kept in a local directory, in files named after the hash of their contents.
+///
+/// Files are spread over subdirectories named after the first byte of the hash, see `path`.
+#[derive(Debug)]
+pub struct LocalBackend {
+    dir: PathBuf,
+}
+
+impl LocalBackend {
+    /// Use the given directory, which is created on demand.
+    pub fn new(dir: PathBuf) -> LocalBackend {
+        LocalBackend { dir }
+    }
+
+    /// Path of the file storing the object with the given hash, `<dir>/<first byte>/<rest>`.
+    pub fn path(&self, hash: &ContentHash) -> PathBuf {
+        let (shard, rest) = hash.sharded();
+        self.dir.join(shard).join(rest)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(&self, hash: &ContentHash, source: &Path) -> io::Result<()> {
+        let target = self.path(hash);
+        std::fs::create_dir_all(target.parent().expect("has shard dir"))?;
+        std::fs::rename(source, target)
+    }
+
+    fn get(&self, hash: &ContentHash) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.path(hash))?))
+    }
+
+    fn exists(&self, hash: &ContentHash) -> io::Result<bool> {
+        Ok(self.path(hash).is_file())
+    }
+
+    fn size(&self, hash: &ContentHash) -> io::Result<Option<u64>> {
+        match std::fs::metadata(self.path(hash)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn list(&self) -> io::Result<Listing> {
+        let mut listing = Listing::default();
+        let shards = match self.dir.read_dir() {
+            Ok(shards) => shards,
+            // Nothing was ever put here
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(listing),
+            Err(err) => return Err(err),
+        };
+        for shard_or_error in shards {
+            let shard = shard_or_error?;
+            let shard_name = shard.file_name().to_string_lossy().into_owned();
+            let is_shard = shard_name.len() == 2 && hex::decode(&shard_name).is_ok();
+            if !is_shard || !shard.file_type()?.is_dir() {
+                listing.unexpected.push(shard.path());
+                continue;
+            }
+
+            for entry_or_error in shard.path().read_dir()? {
+                let entry = entry_or_error?;
+                let rest = entry.file_name().to_string_lossy().into_owned();
+                match ContentHash::from_sharded(&shard_name, &rest) {
+                    Some(hash) if entry.file_type()?.is_file() => {
+                        listing.objects.push((hash, entry.metadata()?.len()))
+                    }
+                    _ => listing.unexpected.push(entry.path()),
+                }
+            }
+        }
+        Ok(listing)
+    }
+
+    fn delete(&self, hash: &ContentHash) -> io::Result<()> {
+        match std::fs::remove_file(self.path(hash)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn modified(&self, hash: &ContentHash) -> io::Result<Option<SystemTime>> {
+        Ok(Some(std::fs::metadata(self.path(hash))?.modified()?))
+    }
+
+    fn location(&self, hash: &ContentHash) -> PathBuf {
+        self.path(hash)
+    }
+
+    fn local_path(&self, hash: &ContentHash) -> Option<PathBuf> {
+        Some(self.path(hash))
+    }
+
+    fn hash_contents(&self, hash: &ContentHash) -> io::Result<ContentHash> {
+        ContentHash::hash_store_file(hash.algorithm(), &mut File::open(self.path(hash))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::{LocalBackend, StorageBackend};
+    use crate::hash::{ContentHash, SHA256};
+
+    #[test]
+    fn local_backend_roundtrip() {
+        let dir =
+            std::env::temp_dir().join(format!("git-assets.local_backend.{}", std::process::id()));
+        let backend = LocalBackend::new(dir.join("objects"));
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        assert!(backend.list().unwrap().objects.is_empty());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("source"), b"foo").unwrap();
+        backend.put(&hash, &dir.join("source")).unwrap();
+        assert!(!dir.join("source").exists());
+        assert!(backend.exists(&hash).unwrap());
+        assert_eq!(backend.size(&hash).unwrap(), Some(3));
+        assert_eq!(backend.hash_contents(&hash).unwrap(), hash);
+
+        let mut contents = Vec::new();
+        backend
+            .get(&hash)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"foo");
+
+        std::fs::write(dir.join("objects").join("stray"), b"").unwrap();
+        let listing = backend.list().unwrap();
+        assert_eq!(listing.objects, vec![(hash.clone(), 3)]);
+        assert_eq!(listing.unexpected, vec![dir.join("objects").join("stray")]);
+
+        backend.delete(&hash).unwrap();
+        backend.delete(&hash).unwrap();
+        assert!(!backend.exists(&hash).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
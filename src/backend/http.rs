@@ -0,0 +1,325 @@
+//! Read-only access to a store served by a plain web server, e.g. for CI machines.
+//!
+//! The store directory is expected to be served as is, so that objects are found at
+//! `<base url>/data/<first byte>/<rest>`.
+//!
+//! If `GIT_ASSETS_TOKEN` is set, it is sent as a bearer token with every request, e.g. for
+//! servers started with `git assets serve` that require one. If `GIT_ASSETS_CA_FILE` names a
+//! PEM file, only servers with certificates issued by the certificate authorities in it are
+//! trusted, e.g. for self-signed certificates, instead of the usual ones. Servers that ask for
+//! a client certificate get the one in the PEM files named by `GIT_ASSETS_CLIENT_CERT` and
+//! `GIT_ASSETS_CLIENT_KEY`.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pki_types::pem::{self, PemObject};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use super::{status_error, Listing, StorageBackend, Timeouts};
+use crate::hash::{ContentHash, Hasher};
+
+/// Objects below a base URL, fetched with `GET` requests.
+#[derive(Debug, Clone)]
+pub struct HttpBackend {
+    agent: ureq::Agent,
+    /// URL of the directory containing the shard directories, with trailing slash.
+    base_url: String,
+    /// Whether contents are checked against the hash they are requested by.
+    verify: bool,
+    /// Sent as `Authorization: Bearer <token>`.
+    token: Option<String>,
+}
+
+/// Environment variable with the token sent to servers.
+pub const TOKEN_ENV: &str = "GIT_ASSETS_TOKEN";
+
+/// Environment variable naming a file with the certificate authorities that are trusted.
+pub const CA_FILE_ENV: &str = "GIT_ASSETS_CA_FILE";
+
+/// Environment variable naming a file with the client certificate chain to present.
+pub const CLIENT_CERT_ENV: &str = "GIT_ASSETS_CLIENT_CERT";
+
+/// Environment variable naming a file with the private key of the client certificate.
+pub const CLIENT_KEY_ENV: &str = "GIT_ASSETS_CLIENT_KEY";
+
+impl HttpBackend {
+    /// Use the objects below the given `http://` or `https://` URL.
+    pub fn new(base_url: &str) -> io::Result<HttpBackend> {
+        let mut agent = Timeouts::from_env()?.agent();
+        let path_from_env = |name| {
+            std::env::var_os(name)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        };
+        let ca_file = path_from_env(CA_FILE_ENV);
+        let client_identity = match (
+            path_from_env(CLIENT_CERT_ENV),
+            path_from_env(CLIENT_KEY_ENV),
+        ) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} and {} need to be set together",
+                        CLIENT_CERT_ENV, CLIENT_KEY_ENV
+                    ),
+                ))
+            }
+        };
+        if ca_file.is_some() || client_identity.is_some() {
+            let config = tls_config(ca_file.as_deref(), client_identity)?;
+            agent = agent.tls_config(Arc::new(config));
+        }
+        Ok(HttpBackend {
+            agent: agent.build(),
+            base_url: format!("{}/", base_url.trim_end_matches('/')),
+            verify: true,
+            token: std::env::var(TOKEN_ENV)
+                .ok()
+                .filter(|token| !token.is_empty()),
+        })
+    }
+
+    /// The objects in the given subdirectory.
+    pub fn join(&self, name: &str) -> HttpBackend {
+        HttpBackend {
+            base_url: format!("{}{}/", self.base_url, name),
+            ..self.clone()
+        }
+    }
+
+    /// Check that contents match the hash they are requested by, which is on by default.
+    ///
+    /// Needs to be off for anything that is not named after the hash of its contents,
+    /// such as chunk manifests.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Read the file `name` in this directory, e.g. the `algorithm` file of a store, or return
+    /// `None` if the server doesn't have it.
+    pub fn read_file(&self, name: &str) -> io::Result<Option<String>> {
+        let url = format!("{}{}", self.base_url, name);
+        match self.request_url("GET", &url).call() {
+            Ok(response) => response.into_string().map(Some),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    fn url(&self, hash: &ContentHash) -> String {
+        let (shard, rest) = hash.sharded();
+        format!("{}{}/{}", self.base_url, shard, rest)
+    }
+
+    fn request(&self, method: &str, hash: &ContentHash) -> ureq::Request {
+        self.request_url(method, &self.url(hash))
+    }
+
+    fn request_url(&self, method: &str, url: &str) -> ureq::Request {
+        log::debug!("{} {}", method, url);
+        let request = self.agent.request(method, url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn read_only(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is a read-only store", self.base_url),
+        )
+    }
+}
+
+impl StorageBackend for HttpBackend {
+    fn put(&self, _hash: &ContentHash, _source: &Path) -> io::Result<()> {
+        Err(self.read_only())
+    }
+
+    fn get(&self, hash: &ContentHash) -> io::Result<Box<dyn Read + Send>> {
+        let response = self.request("GET", hash).call().map_err(request_error)?;
+        let reader = response.into_reader();
+        if self.verify {
+            Ok(Box::new(VerifyingReader {
+                inner: reader,
+                hasher: Hasher::new(hash.algorithm()),
+                expected: hash.clone(),
+            }))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    fn size(&self, hash: &ContentHash) -> io::Result<Option<u64>> {
+        match self.request("HEAD", hash).call() {
+            Ok(response) => response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+                .map(Some)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "missing content length")
+                }),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    fn list(&self) -> io::Result<Listing> {
+        Err(io::Error::other(format!(
+            "cannot list the contents of {}",
+            self.base_url
+        )))
+    }
+
+    fn delete(&self, _hash: &ContentHash) -> io::Result<()> {
+        Err(self.read_only())
+    }
+
+    fn location(&self, hash: &ContentHash) -> PathBuf {
+        PathBuf::from(self.url(hash))
+    }
+}
+
+/// A TLS configuration trusting only the certificate authorities in the given PEM file, or
+/// else the usual ones, and presenting the client certificate chain and key in the given PEM
+/// files, if any.
+fn tls_config(
+    ca_file: Option<&Path>,
+    client_identity: Option<(PathBuf, PathBuf)>,
+) -> io::Result<ClientConfig> {
+    let invalid = |path: &Path, variable: &str, message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} ({}): {}", path.display(), variable, message),
+        )
+    };
+    let read = |path: &Path, variable: &str| {
+        std::fs::read(path).map_err(|err| invalid(path, variable, err.to_string()))
+    };
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            for certificate in CertificateDer::pem_slice_iter(&read(ca_file, CA_FILE_ENV)?) {
+                let certificate =
+                    certificate.map_err(|err| invalid(ca_file, CA_FILE_ENV, err.to_string()))?;
+                roots
+                    .add(certificate)
+                    .map_err(|err| invalid(ca_file, CA_FILE_ENV, err.to_string()))?;
+            }
+            if roots.is_empty() {
+                return Err(invalid(ca_file, CA_FILE_ENV, "no certificate found".into()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots);
+    let (cert_file, key_file) = match client_identity {
+        Some(identity) => identity,
+        None => return Ok(builder.with_no_client_auth()),
+    };
+    let certificates = CertificateDer::pem_slice_iter(&read(&cert_file, CLIENT_CERT_ENV)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(&cert_file, CLIENT_CERT_ENV, err.to_string()))?;
+    if certificates.is_empty() {
+        return Err(invalid(
+            &cert_file,
+            CLIENT_CERT_ENV,
+            "no certificate found".into(),
+        ));
+    }
+    let key = match PrivateKeyDer::from_pem_slice(&read(&key_file, CLIENT_KEY_ENV)?) {
+        Ok(key) => key,
+        Err(pem::Error::NoItemsFound) => {
+            return Err(invalid(
+                &key_file,
+                CLIENT_KEY_ENV,
+                "no private key found".into(),
+            ))
+        }
+        Err(err) => return Err(invalid(&key_file, CLIENT_KEY_ENV, err.to_string())),
+    };
+    builder
+        .with_client_auth_cert(certificates, key)
+        .map_err(|err| invalid(&key_file, CLIENT_KEY_ENV, err.to_string()))
+}
+
+/// Passes the contents through and fails at the end if they don't match the expected hash,
+/// so that corrupt downloads are not mistaken for the real thing.
+struct VerifyingReader<R> {
+    inner: R,
+    hasher: Hasher,
+    expected: ContentHash,
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n_read]);
+        if n_read == 0 && !buf.is_empty() {
+            let actual = self.hasher.clone().finish();
+            if actual != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "downloaded contents of {} have hash {}",
+                        self.expected, actual
+                    ),
+                ));
+            }
+        }
+        Ok(n_read)
+    }
+}
+
+/// Turn a failed request into an `io::Error`, keeping `NotFound` recognizable.
+fn request_error(err: ureq::Error) -> io::Error {
+    match err {
+        ureq::Error::Status(code, response) => status_error(
+            code,
+            format!(
+                "{} {}: {}",
+                code,
+                response.get_url(),
+                response.status_text()
+            ),
+        ),
+        ureq::Error::Transport(transport) => io::Error::other(transport),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::VerifyingReader;
+    use crate::hash::{ContentHash, Hasher, SHA256};
+
+    #[test]
+    fn verifying_reader_rejects_wrong_contents() {
+        let read_all = |contents: &'static [u8]| {
+            let mut reader = VerifyingReader {
+                inner: contents,
+                hasher: Hasher::new(SHA256),
+                expected: ContentHash::hash_bytes(SHA256, b"foo"),
+            };
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).map(|_| buf)
+        };
+        assert_eq!(read_all(b"foo").unwrap(), b"foo");
+        assert_eq!(
+            read_all(b"bar").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,475 @@
+//! Objects kept in an S3 compatible object storage, e.g. AWS S3 or MinIO.
+//!
+//! Requests are signed with AWS signature version 4. Credentials, region and endpoint are taken
+//! from the standard AWS environment variables.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{status_error, Listing, StorageBackend, Timeouts};
+use crate::date::civil_date;
+use crate::hash::ContentHash;
+
+/// An S3 bucket, or a prefix within it, holding objects under `<prefix>/<first byte>/<rest>`.
+#[derive(Debug, Clone)]
+pub struct S3Backend {
+    agent: ureq::Agent,
+    /// Base URL of the service, without trailing slash.
+    endpoint: String,
+    /// Value of the host header, which is part of the signature.
+    host: String,
+    region: String,
+    bucket: String,
+    /// Key prefix of all objects, without leading or trailing slash. May be empty.
+    prefix: String,
+    credentials: Credentials,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Never print the secrets
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish()
+    }
+}
+
+impl S3Backend {
+    /// Connect to the bucket and prefix given by an `s3://bucket/prefix` URL.
+    ///
+    /// Reads the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and the
+    /// optional `AWS_SESSION_TOKEN`, and the region from `AWS_REGION` or `AWS_DEFAULT_REGION`
+    /// (defaulting to `us-east-1`). Custom endpoints are given by `AWS_ENDPOINT_URL_S3` or
+    /// `AWS_ENDPOINT_URL`, and are addressed path-style.
+    pub fn from_url(url: &str) -> io::Result<S3Backend> {
+        let path = url.strip_prefix("s3://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not an s3 url: {}", url),
+            )
+        })?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("missing bucket name in {}", url),
+            ));
+        }
+
+        let region = env_var("AWS_REGION")
+            .or_else(|| env_var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = env_var("AWS_ENDPOINT_URL_S3")
+            .or_else(|| env_var("AWS_ENDPOINT_URL"))
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        let missing = |name: &str| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not set", name),
+            )
+        };
+        let credentials = Credentials {
+            access_key_id: env_var("AWS_ACCESS_KEY_ID")
+                .ok_or_else(|| missing("AWS_ACCESS_KEY_ID"))?,
+            secret_access_key: env_var("AWS_SECRET_ACCESS_KEY")
+                .ok_or_else(|| missing("AWS_SECRET_ACCESS_KEY"))?,
+            session_token: env_var("AWS_SESSION_TOKEN"),
+        };
+
+        Ok(S3Backend {
+            agent: Timeouts::from_env()?.agent().build(),
+            endpoint,
+            host,
+            region,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            credentials,
+        })
+    }
+
+    /// The same bucket, with `name` appended to the key prefix.
+    pub fn join(&self, name: &str) -> S3Backend {
+        let prefix = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        };
+        S3Backend {
+            prefix,
+            ..self.clone()
+        }
+    }
+
+    /// Read the object `name` below the key prefix, e.g. the `algorithm` file of a store, or
+    /// return `None` if it doesn't exist.
+    pub fn read_file(&self, name: &str) -> io::Result<Option<String>> {
+        let key = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        };
+        match self.request("GET", &key, &[]).call() {
+            Ok(response) => response.into_string().map(Some),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    /// Key of the object with the given hash.
+    fn key(&self, hash: &ContentHash) -> String {
+        let (shard, rest) = hash.sharded();
+        if self.prefix.is_empty() {
+            format!("{}/{}", shard, rest)
+        } else {
+            format!("{}/{}/{}", self.prefix, shard, rest)
+        }
+    }
+
+    /// Build a signed request for the given key (or the bucket itself, if empty).
+    fn request(&self, method: &str, key: &str, query: &[(&str, &str)]) -> ureq::Request {
+        log::debug!("{} s3://{}/{}", method, self.bucket, key);
+        let path = if key.is_empty() {
+            format!("/{}", uri_encode(&self.bucket, false))
+        } else {
+            format!(
+                "/{}/{}",
+                uri_encode(&self.bucket, false),
+                uri_encode(key, true)
+            )
+        };
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let timestamp = format_timestamp(now);
+        let date = &timestamp[..8];
+
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .flat_map(|(name, value)| [*name, ":", value.trim(), "\n"])
+            .collect();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\nUNSIGNED-PAYLOAD",
+            method, path, query, canonical_headers, signed_headers
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(
+            &self.credentials.secret_access_key,
+            date,
+            &self.region,
+            "s3",
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id, scope, signed_headers, signature
+        );
+
+        let url = if query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, query)
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (name, value) in headers {
+            request = request.set(name, &value);
+        }
+        request
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn put(&self, hash: &ContentHash, source: &Path) -> io::Result<()> {
+        let file = File::open(source)?;
+        let size = file.metadata()?.len();
+        self.request("PUT", &self.key(hash), &[])
+            .set("Content-Length", &size.to_string())
+            .send(file)
+            .map_err(request_error)?;
+        std::fs::remove_file(source)
+    }
+
+    fn get(&self, hash: &ContentHash) -> io::Result<Box<dyn Read + Send>> {
+        let response = self
+            .request("GET", &self.key(hash), &[])
+            .call()
+            .map_err(request_error)?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn size(&self, hash: &ContentHash) -> io::Result<Option<u64>> {
+        match self.request("HEAD", &self.key(hash), &[]).call() {
+            Ok(response) => response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+                .map(Some)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "missing content length")
+                }),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(request_error(err)),
+        }
+    }
+
+    fn list(&self) -> io::Result<Listing> {
+        let mut listing = Listing::default();
+        let prefix = if self.prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.prefix)
+        };
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self
+                .request("GET", "", &query)
+                .call()
+                .map_err(request_error)?
+                .into_string()?;
+
+            let page = parse_list_response(&body)?;
+            for (key, size) in page.objects {
+                let hash = key[prefix.len()..]
+                    .split_once('/')
+                    .and_then(|(shard, rest)| ContentHash::from_sharded(shard, rest));
+                match hash {
+                    Some(hash) => listing.objects.push((hash, size)),
+                    _ => listing
+                        .unexpected
+                        .push(PathBuf::from(format!("s3://{}/{}", self.bucket, key))),
+                }
+            }
+            continuation_token = page.continuation_token;
+            if continuation_token.is_none() {
+                return Ok(listing);
+            }
+        }
+    }
+
+    fn delete(&self, hash: &ContentHash) -> io::Result<()> {
+        // S3 also reports success when deleting objects that don't exist
+        self.request("DELETE", &self.key(hash), &[])
+            .call()
+            .map_err(request_error)?;
+        Ok(())
+    }
+
+    fn location(&self, hash: &ContentHash) -> PathBuf {
+        PathBuf::from(format!("s3://{}/{}", self.bucket, self.key(hash)))
+    }
+}
+
+/// One page of a `ListObjectsV2` response.
+#[derive(Debug, Default, PartialEq)]
+struct ListPage {
+    /// Keys and sizes of the listed objects.
+    objects: Vec<(String, u64)>,
+    /// Token for requesting the next page, if the listing is truncated.
+    continuation_token: Option<String>,
+}
+
+/// Extract the interesting parts of a `ListObjectsV2` response.
+fn parse_list_response(body: &str) -> io::Result<ListPage> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid list response");
+    let mut page = ListPage::default();
+    for contents in xml_elements(body, "Contents") {
+        let key = xml_elements(contents, "Key").next().ok_or_else(invalid)?;
+        let size = xml_elements(contents, "Size").next().ok_or_else(invalid)?;
+        page.objects.push((
+            xml_unescape(key),
+            size.trim().parse().map_err(|_| invalid())?,
+        ));
+    }
+    if xml_elements(body, "IsTruncated").next() == Some("true") {
+        let token = xml_elements(body, "NextContinuationToken")
+            .next()
+            .ok_or_else(invalid)?;
+        page.continuation_token = Some(xml_unescape(token));
+    }
+    Ok(page)
+}
+
+/// Iterate over the contents of all `<tag>` elements, which must not be nested into each other.
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let end = start + rest[start..].find(&close)?;
+        let contents = &rest[start..end];
+        rest = &rest[end + close.len()..];
+        Some(contents)
+    })
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Percent-encode everything but unreserved characters, as required for signing.
+fn uri_encode(text: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Derive the key for signing requests on the given day.
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts keys of any length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Format seconds since the epoch as `YYYYMMDDTHHMMSSZ`.
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Turn a failed request into an `io::Error`, keeping `NotFound` recognizable.
+fn request_error(err: ureq::Error) -> io::Error {
+    match err {
+        ureq::Error::Status(code, response) => {
+            let url = response.get_url().to_string();
+            let body = response.into_string().unwrap_or_default();
+            let message = xml_elements(&body, "Message").next().unwrap_or_default();
+            status_error(code, format!("{} {}: {}", code, url, message))
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_timestamp, parse_list_response, signing_key, uri_encode, ListPage};
+
+    #[test]
+    fn signing_key_matches_aws_example() {
+        // From the AWS documentation on deriving the signing key
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn timestamps_and_encoding() {
+        assert_eq!(format_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_timestamp(1_330_000_000), "20120223T122640Z");
+        assert_eq!(uri_encode("a b/c~", true), "a%20b/c~");
+        assert_eq!(uri_encode("a b/c~", false), "a%20b%2Fc~");
+    }
+
+    #[test]
+    fn list_response_is_parsed() {
+        let body = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <ListBucketResult><Name>bucket</Name><IsTruncated>true</IsTruncated>\
+            <Contents><Key>data/ab/cd</Key><Size>3</Size></Contents>\
+            <Contents><Key>data/a&amp;b</Key><Size>0</Size></Contents>\
+            <NextContinuationToken>token</NextContinuationToken></ListBucketResult>";
+        assert_eq!(
+            parse_list_response(body).unwrap(),
+            ListPage {
+                objects: vec![("data/ab/cd".to_string(), 3), ("data/a&b".to_string(), 0)],
+                continuation_token: Some("token".to_string()),
+            }
+        );
+    }
+}
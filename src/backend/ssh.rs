@@ -0,0 +1,353 @@
+//! Objects kept in a directory on a machine reachable via SSH.
+//!
+//! All operations run the system `ssh` client, so that keys, the SSH agent and the settings of
+//! `~/.ssh/config` are used just like for git itself. Like git, the client command can be
+//! overridden with `GIT_SSH_COMMAND`. The remote side only needs a POSIX shell.
+//!
+//! The default client is told about the `Timeouts`, a command given by `GIT_SSH_COMMAND` is
+//! expected to bring its own options.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use super::{Listing, StorageBackend, Timeouts};
+use crate::git::shell_quote;
+use crate::hash::ContentHash;
+
+/// A remote directory holding objects under `<path>/<first byte>/<rest>`.
+#[derive(Debug, Clone)]
+pub struct SshBackend {
+    /// `[user@]host`, as passed to ssh.
+    destination: String,
+    port: Option<String>,
+    /// Absolute path of the directory on the remote machine, without trailing slash.
+    path: String,
+    timeouts: Timeouts,
+}
+
+impl SshBackend {
+    /// Use the directory given by an `ssh://[user@]host[:port]/path` URL.
+    pub fn from_url(url: &str) -> io::Result<SshBackend> {
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} in {}", message, url),
+            )
+        };
+        let rest = url
+            .strip_prefix("ssh://")
+            .ok_or_else(|| invalid("not an ssh url"))?;
+        let (authority, path) = rest
+            .find('/')
+            .map(|slash| rest.split_at(slash))
+            .ok_or_else(|| invalid("missing path"))?;
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) => (destination, Some(port.to_string())),
+            None => (authority, None),
+        };
+        if destination.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        // Like git, refuse anything ssh would take for an option (CVE-2017-1000117)
+        if destination.starts_with('-') {
+            return Err(invalid("invalid host"));
+        }
+        if let Some(port) = &port {
+            if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid("invalid port"));
+            }
+        }
+        Ok(SshBackend {
+            destination: destination.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+            timeouts: Timeouts::from_env()?,
+        })
+    }
+
+    /// The subdirectory `name` of this directory.
+    pub fn join(&self, name: &str) -> SshBackend {
+        SshBackend {
+            path: format!("{}/{}", self.path, name),
+            ..self.clone()
+        }
+    }
+
+    /// Read the file `name` in this directory, e.g. the `algorithm` file of a store, or return
+    /// `None` if it doesn't exist.
+    pub fn read_file(&self, name: &str) -> io::Result<Option<String>> {
+        // The first line tells apart missing files from empty ones
+        let script = format!(
+            "if [ -f {path} ]; then echo found && cat {path}; fi",
+            path = shell_quote(&format!("{}/{}", self.path, name))
+        );
+        let output = String::from_utf8_lossy(&self.run(&script, Stdio::null())?).into_owned();
+        Ok(output.strip_prefix("found\n").map(str::to_string))
+    }
+
+    fn remote_path(&self, hash: &ContentHash) -> (String, String) {
+        let (shard, rest) = hash.sharded();
+        (format!("{}/{}", self.path, shard), rest)
+    }
+
+    /// Prepare running `script` with the remote shell.
+    fn command(&self, script: &str) -> Command {
+        let custom = std::env::var("GIT_SSH_COMMAND").ok();
+        let ssh = custom.as_deref().unwrap_or("ssh");
+        // Like git, leave splitting the command into arguments to the shell
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("{} \"$@\"", ssh)).arg("ssh");
+        if custom.is_none() {
+            // Give up after `read` without an answer to keepalive messages sent every third
+            let interval = (self.timeouts.read.as_secs() / 3).max(1);
+            command
+                .arg("-o")
+                .arg(format!(
+                    "ConnectTimeout={}",
+                    self.timeouts.connect.as_secs().max(1)
+                ))
+                .arg("-o")
+                .arg(format!("ServerAliveInterval={}", interval))
+                .arg("-o")
+                .arg("ServerAliveCountMax=3");
+        }
+        if let Some(port) = &self.port {
+            command.arg("-p").arg(port);
+        }
+        command.arg("--").arg(&self.destination).arg(script);
+        log::debug!("ssh {}: {}", self.destination, script);
+        command
+    }
+
+    /// Run `script` remotely and return its output, failing if it does not succeed.
+    fn run(&self, script: &str, input: Stdio) -> io::Result<Vec<u8>> {
+        let output = self
+            .command(script)
+            .stdin(input)
+            .stderr(Stdio::piped())
+            .output()?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = match stderr.trim() {
+                "" => format!("ssh {} failed with {}", self.destination, output.status),
+                stderr => format!("ssh {}: {}", self.destination, stderr),
+            };
+            Err(io::Error::other(message))
+        }
+    }
+
+    fn url(&self, relative: &str) -> PathBuf {
+        let port = self
+            .port
+            .as_ref()
+            .map_or(String::new(), |p| format!(":{}", p));
+        PathBuf::from(format!(
+            "ssh://{}{}{}/{}",
+            self.destination, port, self.path, relative
+        ))
+    }
+}
+
+impl StorageBackend for SshBackend {
+    fn put(&self, hash: &ContentHash, source: &Path) -> io::Result<()> {
+        let (dir, name) = self.remote_path(hash);
+        let target = shell_quote(&format!("{}/{}", dir, name));
+        // Upload under a temporary name first, so that the object never appears half-written
+        let temp = shell_quote(&format!("{}/.{}.tmp", dir, name));
+        let script = format!(
+            "mkdir -p {dir} && if cat > {temp}.$$; then mv -f {temp}.$$ {target}; \
+             else rm -f {temp}.$$; exit 1; fi",
+            dir = shell_quote(&dir),
+            temp = temp,
+            target = target,
+        );
+        self.run(&script, File::open(source)?.into())?;
+        std::fs::remove_file(source)
+    }
+
+    fn get(&self, hash: &ContentHash) -> io::Result<Box<dyn Read + Send>> {
+        let (dir, name) = self.remote_path(hash);
+        let path = shell_quote(&format!("{}/{}", dir, name));
+        // The first line tells apart missing objects from other failures
+        let script = format!(
+            "if [ -f {path} ]; then echo found && exec cat {path}; else echo missing; fi",
+            path = path
+        );
+        let mut child = self
+            .command(&script)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut status = String::new();
+        stdout.read_line(&mut status)?;
+        let reader = RemoteReader {
+            child,
+            stdout,
+            destination: self.destination.clone(),
+        };
+        match status.as_str() {
+            "found\n" => Ok(Box::new(reader)),
+            "missing\n" => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", self.location(hash).display()),
+            )),
+            _ => Err(reader.finish().err().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unexpected output of ssh")
+            })),
+        }
+    }
+
+    fn size(&self, hash: &ContentHash) -> io::Result<Option<u64>> {
+        let (dir, name) = self.remote_path(hash);
+        let path = shell_quote(&format!("{}/{}", dir, name));
+        let output = self.run(
+            &format!("if [ -f {path} ]; then wc -c < {path}; fi", path = path),
+            Stdio::null(),
+        )?;
+        let output = String::from_utf8_lossy(&output);
+        if output.trim().is_empty() {
+            return Ok(None);
+        }
+        output
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unexpected output of wc"))
+    }
+
+    fn list(&self) -> io::Result<Listing> {
+        // Everything but regular files, then the sizes of all regular files. All paths start
+        // with `./`, which tells them apart from the totals printed by `wc`.
+        let script = format!(
+            "cd {} 2>/dev/null || exit 0; find . -mindepth 1 ! -type f | sed 's/^/- /'; \
+             find . -type f -exec wc -c {{}} +",
+            shell_quote(&self.path)
+        );
+        let output = self.run(&script, Stdio::null())?;
+        let mut listing = Listing::default();
+        for line in String::from_utf8_lossy(&output).lines() {
+            if let Some(path) = line.strip_prefix("- ./") {
+                let is_shard = path.len() == 2 && hex::decode(path).is_ok();
+                if !is_shard {
+                    listing.unexpected.push(self.url(path));
+                }
+                continue;
+            }
+            let (size, path) = match line.trim_start().split_once(" ./") {
+                Some((size, path)) => (size, path),
+                None => continue,
+            };
+            let hash = path
+                .split_once('/')
+                .and_then(|(shard, rest)| ContentHash::from_sharded(shard, rest));
+            match (hash, size.parse()) {
+                (Some(hash), Ok(size)) => listing.objects.push((hash, size)),
+                _ => listing.unexpected.push(self.url(path)),
+            }
+        }
+        Ok(listing)
+    }
+
+    fn delete(&self, hash: &ContentHash) -> io::Result<()> {
+        let (dir, name) = self.remote_path(hash);
+        self.run(
+            &format!("rm -f {}", shell_quote(&format!("{}/{}", dir, name))),
+            Stdio::null(),
+        )?;
+        Ok(())
+    }
+
+    fn location(&self, hash: &ContentHash) -> PathBuf {
+        let (shard, rest) = hash.sharded();
+        self.url(&format!("{}/{}", shard, rest))
+    }
+}
+
+/// Streams the output of a remote `cat`, failing at the end if it did not succeed.
+struct RemoteReader {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    destination: String,
+}
+
+impl RemoteReader {
+    fn finish(mut self) -> io::Result<()> {
+        let status = self.child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "ssh {} failed with {}",
+                self.destination, status
+            )))
+        }
+    }
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.stdout.read(buf)?;
+        if n_read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "ssh {} failed with {}",
+                    self.destination, status
+                )));
+            }
+        }
+        Ok(n_read)
+    }
+}
+
+impl Drop for RemoteReader {
+    fn drop(&mut self) {
+        // Don't leave the connection behind when not reading until the end
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::SshBackend;
+    use crate::backend::StorageBackend;
+    use crate::hash::{ContentHash, SHA512_256};
+
+    #[test]
+    fn parse_ssh_urls() {
+        let backend = SshBackend::from_url("ssh://git@example.com:2222/srv/assets/").unwrap();
+        assert_eq!(backend.destination, "git@example.com");
+        assert_eq!(backend.port.as_deref(), Some("2222"));
+        assert_eq!(backend.join("data").path, "/srv/assets/data");
+
+        let backend = SshBackend::from_url("ssh://example.com/assets").unwrap();
+        assert_eq!(backend.destination, "example.com");
+        assert_eq!(backend.port, None);
+
+        assert!(SshBackend::from_url("ssh://example.com").is_err());
+        assert!(SshBackend::from_url("ssh:///assets").is_err());
+        assert!(SshBackend::from_url("ssh://-oProxyCommand=touch%20x/assets").is_err());
+        assert!(SshBackend::from_url("ssh://example.com:-oProxyCommand=x/assets").is_err());
+    }
+
+    #[test]
+    fn locations_use_the_sharded_name() {
+        let backend = SshBackend::from_url("ssh://example.com/assets").unwrap();
+        let hash = ContentHash::hash_bytes(SHA512_256, b"foo");
+        let (shard, rest) = hash.sharded();
+        assert_eq!(
+            backend.join("data").location(&hash),
+            PathBuf::from(format!("ssh://example.com/assets/data/{}/{}", shard, rest))
+        );
+    }
+}
@@ -0,0 +1,727 @@
+//! Settings that apply to every invocation, so that they don't need to be passed as flags.
+//!
+//! Settings are read from four layers, each overriding the ones before:
+//!
+//! 1. the user's `~/.config/git-assets/config.toml` (or below `$XDG_CONFIG_HOME`),
+//! 2. `.gitassets.toml` at the root of the repository, which can be committed to share the
+//!    settings with everyone working on it,
+//! 3. the `assets` section of the git config, e.g. `assets.minSize`,
+//! 4. the file named by the environment variable `GIT_ASSETS_CONFIG`, e.g. one set up by CI.
+//!
+//! Flags on the command line override all of them. In the files, keys are written in kebab
+//! case, e.g. `min-size = "4KiB"`, and relative paths are relative to the directory containing
+//! the file.
+//!
+//! Since anyone who can commit to a repository can change `.gitassets.toml`, it may only set
+//! settings that can't send objects or tokens elsewhere, unless `assets.trustRepoConfig` is set
+//! in the git config. Unknown keys in it are skipped with a warning, so that a key added by a
+//! newer version doesn't break older ones for everyone sharing the file. `allowed-remotes` is
+//! never read from it, since it restricts where the other settings may send objects, and
+//! neither is `sign`, which uses the user's own key.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::git::Repository;
+use crate::store::PointerFormat;
+
+/// Name of the configuration file at the root of a repository.
+pub const REPO_CONFIG_FILE: &str = ".gitassets.toml";
+
+/// Environment variable naming a configuration file that overrides all other layers.
+pub const CONFIG_ENV: &str = "GIT_ASSETS_CONFIG";
+
+/// Every key of the files, see `Config`.
+const KEYS: &[&str] = &[
+    "store",
+    "remote",
+    "alternates",
+    "chunked",
+    "min-size",
+    "pointer",
+    "jobs",
+    "verify-reads",
+    "read-tokens",
+    "write-tokens",
+    "token-file",
+    "tls-cert",
+    "tls-key",
+    "tls-client-ca",
+    "webhooks",
+    "allowed-remotes",
+    "sign",
+    "verify-signatures",
+    "gc-grace-period",
+];
+
+/// Keys that `.gitassets.toml` may not set, even in trusted repositories.
+const USER_KEYS: &[&str] = &["allowed-remotes", "sign"];
+
+/// Keys that `.gitassets.toml` may only set in trusted repositories, because they choose where
+/// objects and tokens go.
+const SENSITIVE_KEYS: &[&str] = &[
+    "store",
+    "remote",
+    "alternates",
+    "read-tokens",
+    "write-tokens",
+    "token-file",
+    "tls-cert",
+    "tls-key",
+    "tls-client-ca",
+    "webhooks",
+];
+
+/// Settings from any number of layers, where `None` means that none of them set it.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// The store, a local directory or the URL of a remote store.
+    pub store: Option<PathBuf>,
+    /// The remote store that objects are pushed to and fetched from.
+    pub remote: Option<PathBuf>,
+    /// Other stores on this machine, e.g. of sibling clones, or URLs of peers running `serve`,
+    /// that objects missing in the store are copied from before trying the remote.
+    pub alternates: Option<Vec<PathBuf>>,
+    /// Whether `store-file` splits large files into content defined chunks.
+    pub chunked: Option<bool>,
+    /// Size in bytes below which `store-file` passes contents through unchanged. Either a
+    /// number or a string with a unit, e.g. `"4KiB"`.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub min_size: Option<u64>,
+    /// Format of the pointers that are written.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub pointer: Option<PointerFormat>,
+    /// Number of threads used by commands that work in parallel.
+    pub jobs: Option<usize>,
+    /// Whether objects are hashed again while they are retrieved, so that corrupt ones fail
+    /// instead of being checked out.
+    pub verify_reads: Option<bool>,
+    /// Tokens that `serve` accepts for reading objects.
+    pub read_tokens: Option<Vec<String>>,
+    /// Tokens that `serve` accepts for reading and uploading objects.
+    pub write_tokens: Option<Vec<String>>,
+    /// File with more tokens for `serve`, see `server::read_token_file`.
+    pub token_file: Option<PathBuf>,
+    /// PEM file with the certificate chain that `serve` presents, for HTTPS.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the certificate.
+    pub tls_key: Option<PathBuf>,
+    /// PEM file with the certificate authorities that issue the client certificates `serve`
+    /// requires.
+    pub tls_client_ca: Option<PathBuf>,
+    /// URLs that `serve` notifies of every upload.
+    pub webhooks: Option<Vec<String>>,
+    /// The remote stores that may be used, see `remote_allowed`. If unset, any may be used.
+    pub allowed_remotes: Option<Vec<String>>,
+    /// Whether `store-file` signs new objects with the key git signs commits with, see
+    /// `signing`.
+    pub sign: Option<bool>,
+    /// Whether objects need a valid signature by a trusted signer to be retrieved or fetched.
+    pub verify_signatures: Option<bool>,
+    /// How long `gc` keeps unreferenced objects after they were stored, e.g. `"12h"`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub gc_grace_period: Option<Duration>,
+    /// Whether `.gitassets.toml` may set the keys that choose where objects and tokens go. Only
+    /// read from the git config, `assets.trustRepoConfig`.
+    #[serde(skip)]
+    pub trust_repo_config: Option<bool>,
+    /// Whether the smudge filter passes pointers through. Only read from the git config,
+    /// `assets.skipSmudge`.
+    #[serde(skip)]
+    pub skip_smudge: Option<bool>,
+    /// The git identity of the user, `Name <email>`, recorded as who stored new objects, see
+    /// `metadata`. Only read from the git config, `user.name` and `user.email`.
+    #[serde(skip)]
+    pub user: Option<String>,
+}
+
+impl Config {
+    /// Read all layers that apply in the given repository, whose working tree is at
+    /// `work_tree`. Outside of a repository, this is the user's file and the global git config.
+    pub fn load(repo: &Repository, work_tree: Option<&Path>) -> io::Result<Config> {
+        let mut config = Config::default();
+        if let Some(path) = user_config_path() {
+            config = config.merge(Config::from_file(&path)?.unwrap_or_default());
+        }
+        let git_config = Config::from_git_config(repo)?;
+        if let Some(root) = work_tree {
+            let path = root.join(REPO_CONFIG_FILE);
+            let trusted = git_config.trust_repo_config == Some(true);
+            config = config.merge(Config::from_repo_file(&path, trusted)?.unwrap_or_default());
+        }
+        config = config.merge(git_config);
+        if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+            // Unlike the other files, this one was asked for explicitly
+            let path = PathBuf::from(path);
+            let env_config = Config::from_file(&path)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} (from {}) does not exist", path.display(), CONFIG_ENV),
+                )
+            })?;
+            config = config.merge(env_config);
+        }
+        Ok(config)
+    }
+
+    /// Read a configuration file, or return `None` if it doesn't exist.
+    pub fn from_file(path: &Path) -> io::Result<Option<Config>> {
+        Config::parse_file(path, |_| None)
+    }
+
+    /// Read the configuration file of a repository, or return `None` if it doesn't exist.
+    ///
+    /// Unknown keys are skipped with a warning, and so are the keys that choose where objects
+    /// and tokens go, unless the repository is `trusted`.
+    ///
+    /// Without the `config-files` feature, the file is ignored with a warning rather than
+    /// failing, since the filters would otherwise fail for everyone once someone commits it.
+    pub fn from_repo_file(path: &Path, trusted: bool) -> io::Result<Option<Config>> {
+        if cfg!(not(feature = "config-files")) && path.exists() {
+            log::warn!(
+                "{}: ignored, reading it needs git-assets built with the `config-files` feature",
+                path.display()
+            );
+            return Ok(None);
+        }
+        Config::parse_file(path, |key| {
+            if !KEYS.contains(&key) {
+                Some("unknown setting, maybe from a newer version")
+            } else if USER_KEYS.contains(&key) {
+                Some("only allowed in the user's configuration file or the git config")
+            } else if !trusted && SENSITIVE_KEYS.contains(&key) {
+                Some(
+                    "not allowed in a committed file, unless `git config assets.trustRepoConfig \
+                     true`",
+                )
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Read a configuration file, skipping the keys that `skip` gives a reason for.
+    #[cfg(feature = "config-files")]
+    fn parse_file(
+        path: &Path,
+        skip: impl Fn(&str) -> Option<&'static str>,
+    ) -> io::Result<Option<Config>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let invalid = |err: toml::de::Error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        };
+        let mut table: toml::value::Table = toml::from_str(&contents).map_err(invalid)?;
+        let keys: Vec<String> = table.keys().cloned().collect();
+        for key in keys {
+            if let Some(reason) = skip(&key) {
+                log::warn!("{}: skipping {}: {}", path.display(), key, reason);
+                table.remove(&key);
+            }
+        }
+        let mut config = Config::deserialize(toml::Value::Table(table)).map_err(invalid)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        config.store = config.store.map(|store| resolve(dir, store));
+        config.remote = config.remote.map(|remote| resolve(dir, remote));
+        config.alternates = config.alternates.map(|alternates| {
+            alternates
+                .into_iter()
+                .map(|alternate| resolve(dir, alternate))
+                .collect()
+        });
+        config.token_file = config.token_file.map(|file| dir.join(file));
+        config.tls_cert = config.tls_cert.map(|file| dir.join(file));
+        config.tls_key = config.tls_key.map(|file| dir.join(file));
+        config.tls_client_ca = config.tls_client_ca.map(|file| dir.join(file));
+        Ok(Some(config))
+    }
+
+    /// Without TOML support, configuration files can't be read, which is only an error if
+    /// there is one, since its settings would silently be ignored otherwise. The repository's
+    /// file never gets here, see `from_repo_file`.
+    #[cfg(not(feature = "config-files"))]
+    fn parse_file(
+        path: &Path,
+        _skip: impl Fn(&str) -> Option<&'static str>,
+    ) -> io::Result<Option<Config>> {
+        match std::fs::metadata(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{}: reading configuration files needs git-assets built with the \
+                     `config-files` feature",
+                    path.display()
+                ),
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read the settings from the `assets` section of the git config, and the identity of the
+    /// user from the `user` section. Other variables in the sections are ignored.
+    pub fn from_git_config(repo: &Repository) -> io::Result<Config> {
+        let mut config = Config::default();
+        let (mut user_name, mut user_email) = (None, None);
+        for (section, name, value) in repo.config_sections(&["assets", "user"])? {
+            if section == "user" {
+                match name.as_str() {
+                    "name" => user_name = value,
+                    "email" => user_email = value,
+                    _ => {}
+                }
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("assets.{}: {}", name, message),
+                )
+            };
+            // Only booleans may be given without a value
+            let text = || {
+                value
+                    .as_deref()
+                    .ok_or_else(|| invalid("missing value".into()))
+            };
+            let boolean = || match value.as_deref() {
+                None => Ok(true),
+                Some(value) => {
+                    parse_bool(value).ok_or_else(|| invalid(format!("invalid boolean: {}", value)))
+                }
+            };
+            match name.as_str() {
+                "store" => config.store = Some(PathBuf::from(text()?)),
+                "remote" => config.remote = Some(PathBuf::from(text()?)),
+                // Like tokens, alternates can be given multiple times
+                "alternate" => config
+                    .alternates
+                    .get_or_insert_with(Vec::new)
+                    .push(PathBuf::from(text()?)),
+                "chunked" => config.chunked = Some(boolean()?),
+                "verifyreads" => config.verify_reads = Some(boolean()?),
+                "sign" => config.sign = Some(boolean()?),
+                "verifysignatures" => config.verify_signatures = Some(boolean()?),
+                "trustrepoconfig" => config.trust_repo_config = Some(boolean()?),
+                "skipsmudge" => config.skip_smudge = Some(boolean()?),
+                "minsize" => config.min_size = Some(parse_size(text()?).map_err(invalid)?),
+                "gcgraceperiod" => {
+                    config.gc_grace_period = Some(parse_duration(text()?).map_err(invalid)?)
+                }
+                "pointer" => config.pointer = Some(text()?.parse().map_err(invalid)?),
+                // Tokens can be given multiple times
+                "readtoken" => config
+                    .read_tokens
+                    .get_or_insert_with(Vec::new)
+                    .push(text()?.to_string()),
+                "writetoken" => config
+                    .write_tokens
+                    .get_or_insert_with(Vec::new)
+                    .push(text()?.to_string()),
+                "tokenfile" => config.token_file = Some(PathBuf::from(text()?)),
+                "tlscert" => config.tls_cert = Some(PathBuf::from(text()?)),
+                "tlskey" => config.tls_key = Some(PathBuf::from(text()?)),
+                "tlsclientca" => config.tls_client_ca = Some(PathBuf::from(text()?)),
+                "webhook" => config
+                    .webhooks
+                    .get_or_insert_with(Vec::new)
+                    .push(text()?.to_string()),
+                "allowedremote" => config
+                    .allowed_remotes
+                    .get_or_insert_with(Vec::new)
+                    .push(text()?.to_string()),
+                "jobs" => {
+                    let jobs = text()?;
+                    config.jobs = Some(
+                        jobs.parse()
+                            .map_err(|_| invalid(format!("invalid number: {}", jobs)))?,
+                    );
+                }
+                _ => {}
+            }
+        }
+        config.user = match (user_name, user_email) {
+            (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+            (Some(name), None) => Some(name),
+            (None, Some(email)) => Some(format!("<{}>", email)),
+            (None, None) => None,
+        };
+        Ok(config)
+    }
+
+    /// Combine two layers, where the settings of `over` take precedence.
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            store: over.store.or(self.store),
+            remote: over.remote.or(self.remote),
+            alternates: over.alternates.or(self.alternates),
+            chunked: over.chunked.or(self.chunked),
+            min_size: over.min_size.or(self.min_size),
+            pointer: over.pointer.or(self.pointer),
+            jobs: over.jobs.or(self.jobs),
+            verify_reads: over.verify_reads.or(self.verify_reads),
+            read_tokens: over.read_tokens.or(self.read_tokens),
+            write_tokens: over.write_tokens.or(self.write_tokens),
+            token_file: over.token_file.or(self.token_file),
+            tls_cert: over.tls_cert.or(self.tls_cert),
+            tls_key: over.tls_key.or(self.tls_key),
+            tls_client_ca: over.tls_client_ca.or(self.tls_client_ca),
+            webhooks: over.webhooks.or(self.webhooks),
+            allowed_remotes: over.allowed_remotes.or(self.allowed_remotes),
+            sign: over.sign.or(self.sign),
+            verify_signatures: over.verify_signatures.or(self.verify_signatures),
+            gc_grace_period: over.gc_grace_period.or(self.gc_grace_period),
+            trust_repo_config: over.trust_repo_config.or(self.trust_repo_config),
+            skip_smudge: over.skip_smudge.or(self.skip_smudge),
+            user: over.user.or(self.user),
+        }
+    }
+}
+
+/// Whether the URL of a remote store matches one of the `allowed` patterns.
+///
+/// A pattern is a host, e.g. `assets.example.com`, which matches that host with any scheme, or
+/// a scheme and a host, e.g. `https://assets.example.com`. The host may be `*` for any host,
+/// or start with `*.` for any subdomain, e.g. `s3://*` or `*.example.com`. Users and ports in
+/// the URL are ignored. The host of an S3 URL is its bucket.
+pub fn remote_allowed(url: &str, allowed: &[String]) -> bool {
+    let (scheme, rest) = match url.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let host_port = authority.rsplit('@').next().unwrap_or_default();
+    let host = match host_port.strip_prefix('[') {
+        // IPv6 addresses contain colons themselves
+        Some(address) => address.split(']').next().unwrap_or_default(),
+        None => host_port.split(':').next().unwrap_or_default(),
+    };
+    allowed.iter().any(|pattern| {
+        let host_pattern = match pattern.split_once("://") {
+            Some((allowed_scheme, host_pattern)) => {
+                if !allowed_scheme.eq_ignore_ascii_case(scheme) {
+                    return false;
+                }
+                host_pattern.trim_end_matches('/')
+            }
+            None => pattern.as_str(),
+        };
+        match host_pattern.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => {
+                host.len() > suffix.len()
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+            _ => host.eq_ignore_ascii_case(host_pattern),
+        }
+    })
+}
+
+/// Location of the user's configuration file, or `None` if the home directory is unknown.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("git-assets").join("config.toml"))
+}
+
+/// Directory for staging objects that are stored in remote stores, below the user's
+/// `~/.cache` (or `$XDG_CACHE_HOME`), or `None` if the home directory is unknown.
+pub fn user_staging_dir() -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join("git-assets").join("staging"))
+}
+
+/// Parse a size like `512`, `500K`, `20M` or `1GiB` into bytes, using binary units.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let exponent = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(format!("invalid size unit in {}, use K, M, G or T", s)),
+    };
+    number
+        .checked_mul(1u64 << (10 * exponent))
+        .ok_or_else(|| format!("size too large: {}", s))
+}
+
+/// Parse a duration given as a number followed by a unit, one of `s`, `m`, `h`, `d` or `w`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {}, use s, m, h, d or w",
+                s
+            ))
+        }
+    };
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration too large: {}", s))
+}
+
+/// Parse a boolean the way git does, or return `None` if git wouldn't accept it either.
+pub fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "" | "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Make a path from a configuration file relative to its directory, leaving URLs as they are.
+#[cfg(feature = "config-files")]
+fn resolve(dir: &Path, path: PathBuf) -> PathBuf {
+    if crate::store::is_url(&path) {
+        path
+    } else {
+        dir.join(path)
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "config-files")]
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{
+        parse_duration, parse_size, remote_allowed, Config, KEYS, SENSITIVE_KEYS, USER_KEYS,
+    };
+    #[cfg(feature = "config-files")]
+    use crate::store::PointerFormat;
+
+    #[test]
+    #[cfg(feature = "config-files")]
+    fn parse_config_files() {
+        let dir = std::env::temp_dir().join(format!("git-assets-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "store = \"store\"\nremote = \"https://example.com/assets\"\nmin-size = \"4KiB\"\n\
+             pointer = \"v2\"\njobs = 3\nverify-reads = true\nwrite-tokens = [\"abc\"]\n\
+             token-file = \"tokens\"\ntls-cert = \"cert.pem\"\ntls-client-ca = \"ca.pem\"\n\
+             allowed-remotes = [\"s3://*\"]\nalternates = [\"../other/x-assets\"]\n\
+             gc-grace-period = \"12h\"\n",
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap().unwrap();
+        assert_eq!(
+            config,
+            Config {
+                store: Some(dir.join("store")),
+                remote: Some(PathBuf::from("https://example.com/assets")),
+                alternates: Some(vec![dir.join("../other/x-assets")]),
+                chunked: None,
+                min_size: Some(4096),
+                pointer: Some(PointerFormat::V2),
+                jobs: Some(3),
+                verify_reads: Some(true),
+                read_tokens: None,
+                write_tokens: Some(vec!["abc".to_string()]),
+                token_file: Some(dir.join("tokens")),
+                tls_cert: Some(dir.join("cert.pem")),
+                tls_key: None,
+                tls_client_ca: Some(dir.join("ca.pem")),
+                webhooks: None,
+                allowed_remotes: Some(vec!["s3://*".to_string()]),
+                sign: None,
+                verify_signatures: None,
+                gc_grace_period: Some(Duration::from_secs(12 * 60 * 60)),
+                trust_repo_config: None,
+                skip_smudge: None,
+                user: None,
+            }
+        );
+
+        std::fs::write(&path, "min-size = 100\n").unwrap();
+        assert_eq!(
+            Config::from_file(&path).unwrap().unwrap().min_size,
+            Some(100)
+        );
+        for invalid in ["min-size = \"4X\"\n", "pointer = \"v3\"\n", "unknown = 1\n"] {
+            std::fs::write(&path, invalid).unwrap();
+            assert!(Config::from_file(&path).is_err(), "{}", invalid);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "config-files")]
+    fn repository_files_are_limited() {
+        let dir =
+            std::env::temp_dir().join(format!("git-assets-repo-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".gitassets.toml");
+        std::fs::write(
+            &path,
+            "remote = \"ssh://elsewhere/assets\"\nmin-size = 100\nfrom-the-future = 1\n\
+             allowed-remotes = [\"*\"]\nsign = true\nverify-signatures = true\n",
+        )
+        .unwrap();
+        let expected = Config {
+            min_size: Some(100),
+            verify_signatures: Some(true),
+            ..Config::default()
+        };
+        assert_eq!(
+            Config::from_repo_file(&path, false).unwrap(),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            Config::from_repo_file(&path, true).unwrap(),
+            Some(Config {
+                remote: Some(PathBuf::from("ssh://elsewhere/assets")),
+                ..expected
+            })
+        );
+        // Other files still reject keys they don't know
+        assert!(Config::from_file(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restricted_keys_are_known() {
+        assert!(SENSITIVE_KEYS.iter().all(|key| KEYS.contains(key)));
+        assert!(USER_KEYS.iter().all(|key| KEYS.contains(key)));
+    }
+
+    #[test]
+    fn allowed_remotes() {
+        let allowed = [
+            "https://assets.example.com".to_string(),
+            "s3://*".to_string(),
+            "*.internal".to_string(),
+            "::1".to_string(),
+        ];
+        for url in [
+            "https://assets.example.com/team",
+            "HTTPS://user@Assets.Example.com:8443/",
+            "s3://any-bucket/prefix",
+            "ssh://git@store.internal/srv/assets",
+            "http://[::1]:8080",
+        ] {
+            assert!(remote_allowed(url, &allowed), "{}", url);
+        }
+        for url in [
+            "http://assets.example.com/team",
+            "https://example.com/assets",
+            "https://assets.example.com.evil.net/",
+            "ssh://internal/srv/assets",
+            "assets.example.com",
+        ] {
+            assert!(!remote_allowed(url, &allowed), "{}", url);
+        }
+        assert!(!remote_allowed("https://example.com", &[]));
+    }
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let user = Config {
+            min_size: Some(1),
+            jobs: Some(2),
+            ..Config::default()
+        };
+        let repo = Config {
+            min_size: Some(3),
+            chunked: Some(false),
+            ..Config::default()
+        };
+        assert_eq!(
+            user.merge(repo),
+            Config {
+                min_size: Some(3),
+                jobs: Some(2),
+                chunked: Some(false),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("500K"), Ok(500 << 10));
+        assert_eq!(parse_size("20MiB"), Ok(20 << 20));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1w"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+}
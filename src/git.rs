@@ -0,0 +1,737 @@
+//! Reading pointer files from a git repository by invoking the `git` command line tool.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::hash::ContentHash;
+use crate::store::StoreFileRef;
+
+/// Blobs larger than this can't be pointer files, so their contents are never inspected.
+pub(crate) const MAX_POINTER_SIZE: u64 = 1024;
+
+/// Environment variables that select the repository git works on, as listed by
+/// `git rev-parse --local-env-vars`.
+const REPO_ENV_VARS: &[&str] = &[
+    "GIT_ALTERNATE_OBJECT_DIRECTORIES",
+    "GIT_CONFIG",
+    "GIT_CONFIG_PARAMETERS",
+    "GIT_CONFIG_COUNT",
+    "GIT_OBJECT_DIRECTORY",
+    "GIT_DIR",
+    "GIT_WORK_TREE",
+    "GIT_IMPLICIT_WORK_TREE",
+    "GIT_GRAFT_FILE",
+    "GIT_INDEX_FILE",
+    "GIT_NO_REPLACE_OBJECTS",
+    "GIT_REPLACE_REF_BASE",
+    "GIT_PREFIX",
+    "GIT_INTERNAL_SUPER_PREFIX",
+    "GIT_SHALLOW_FILE",
+    "GIT_COMMON_DIR",
+];
+
+/// A git repository, accessed through the `git` binary.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    /// Directory in which git commands are run.
+    dir: PathBuf,
+    /// Whether git commands ignore `REPO_ENV_VARS`, so that they find the repository from
+    /// `dir` even when these variables point at another one.
+    ignore_repo_env: bool,
+}
+
+/// Where a repository is, as found by `Repository::locate`.
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// Root directory of the working tree, or `None` for bare repositories.
+    pub work_tree: Option<PathBuf>,
+    /// Absolute path of the git directory shared by all worktrees, see
+    /// `Repository::common_dir`.
+    pub common_dir: PathBuf,
+    /// The superproject if the repository is checked out as its submodule, see
+    /// `Repository::superproject`.
+    pub superproject: Option<Repository>,
+}
+
+/// A file in a git tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    /// Path of the file relative to the root of the repository.
+    pub path: String,
+    /// Hex encoded git object id of the blob.
+    pub blob: String,
+    /// Size of the blob in bytes.
+    pub size: u64,
+}
+
+/// A file staged in the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// File mode in octal, e.g. `100644`.
+    pub mode: String,
+    /// Hex encoded git object id of the blob.
+    pub blob: String,
+    /// Path of the file relative to the root of the repository.
+    pub path: String,
+}
+
+/// A pointer file in a git tree, referencing an object in the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PointerEntry {
+    /// Path of the pointer file relative to the root of the repository.
+    pub path: String,
+    /// Hex encoded git object id of the pointer blob.
+    pub blob: String,
+    /// The store object the pointer refers to.
+    pub store_ref: StoreFileRef,
+}
+
+/// Remembers which blobs are pointer files, so that walking many commits reads every blob only once.
+pub type PointerCache = HashMap<String, Option<StoreFileRef>>;
+
+/// A commit together with some of its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// Full hex encoded commit id.
+    pub id: String,
+    /// Author time as unix timestamp.
+    pub time: i64,
+    /// Name of the author.
+    pub author: String,
+}
+
+/// How an asset differs between two revisions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointerChange {
+    Added(PointerEntry),
+    Removed(PointerEntry),
+    Changed {
+        old: PointerEntry,
+        new: PointerEntry,
+    },
+}
+
+impl PointerChange {
+    /// Path of the affected pointer file.
+    pub fn path(&self) -> &str {
+        match self {
+            PointerChange::Added(entry) | PointerChange::Removed(entry) => &entry.path,
+            PointerChange::Changed { new, .. } => &new.path,
+        }
+    }
+}
+
+/// Compare two sets of pointer files by path, returning the changes sorted by path.
+///
+/// Pointers that refer to the same object in both sets are not reported.
+pub fn diff_pointers(old: Vec<PointerEntry>, new: Vec<PointerEntry>) -> Vec<PointerChange> {
+    let mut old: BTreeMap<String, PointerEntry> =
+        old.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let mut changes = Vec::new();
+
+    for new_entry in new {
+        match old.remove(&new_entry.path) {
+            None => changes.push(PointerChange::Added(new_entry)),
+            Some(old_entry) => {
+                if old_entry.store_ref != new_entry.store_ref {
+                    changes.push(PointerChange::Changed {
+                        old: old_entry,
+                        new: new_entry,
+                    });
+                }
+            }
+        }
+    }
+    changes.extend(old.into_values().map(PointerChange::Removed));
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+impl Repository {
+    /// Access the repository containing the given directory.
+    pub fn new(dir: PathBuf) -> Repository {
+        Repository {
+            dir,
+            ignore_repo_env: false,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Build a git command that runs inside the repository.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new("git");
+        cmd.current_dir(&self.dir);
+        if self.ignore_repo_env {
+            for var in REPO_ENV_VARS {
+                cmd.env_remove(var);
+            }
+        }
+        cmd
+    }
+
+    /// Run a git command to completion and return its stdout.
+    pub fn run(&self, args: &[&str]) -> io::Result<Vec<u8>> {
+        let start = Instant::now();
+        let output = self.command().args(args).stderr(Stdio::piped()).output()?;
+        log_command(args, start);
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(git_error(args, &output.stderr))
+        }
+    }
+
+    /// Run a git command to completion, feeding it the given input on stdin, and return its stdout.
+    pub fn run_with_input(&self, args: &[&str], input: Vec<u8>) -> io::Result<Vec<u8>> {
+        let start = Instant::now();
+        let mut child = self
+            .command()
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Feed the input from a separate thread so that neither side blocks on a full pipe.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output()?;
+        writer.join().expect("git input writer thread panicked")?;
+        log_command(args, start);
+
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(git_error(args, &output.stderr))
+        }
+    }
+
+    /// Read a single git config value, or `None` if it is not set.
+    pub fn config_get(&self, key: &str) -> io::Result<Option<String>> {
+        self.config_value(&["config", "--get", key])
+    }
+
+    /// Read a single boolean git config value, or `None` if it is not set. Fails for values
+    /// that git doesn't accept as booleans.
+    pub fn config_get_bool(&self, key: &str) -> io::Result<Option<bool>> {
+        let value = self.config_value(&["config", "--bool", "--get", key])?;
+        Ok(value.map(|value| value == "true"))
+    }
+
+    /// Read all git config values in the given sections, e.g. `assets`, with a single call of
+    /// git, as triples of the section, the variable name, both of which git lowercases, and the
+    /// value. Variables given without a value, which git takes as true, have no value.
+    pub fn config_sections(
+        &self,
+        sections: &[&str],
+    ) -> io::Result<Vec<(String, String, Option<String>)>> {
+        let pattern = format!("^({})\\.", sections.join("|"));
+        let args = ["config", "--null", "--get-regexp", &pattern];
+        let output = self.command().args(args).stderr(Stdio::piped()).output()?;
+        match output.status.code() {
+            Some(0) => {}
+            Some(1) => return Ok(Vec::new()),
+            _ => return Err(git_error(&args, &output.stderr)),
+        }
+        // Format: <section>.<name> [LF <value>] NUL
+        Ok(output
+            .stdout
+            .split(|b| *b == 0)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let entry = String::from_utf8_lossy(entry);
+                let (key, value) = match entry.split_once('\n') {
+                    Some((key, value)) => (key, Some(value.to_string())),
+                    None => (&*entry, None),
+                };
+                let (section, name) = key.split_once('.').unwrap_or(("", key));
+                (section.to_string(), name.to_string(), value)
+            })
+            .collect())
+    }
+
+    fn config_value(&self, args: &[&str]) -> io::Result<Option<String>> {
+        let output = self.command().args(args).stderr(Stdio::piped()).output()?;
+        match output.status.code() {
+            Some(0) => Ok(Some(
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            )),
+            // git uses this exit code for keys that are not set
+            Some(1) => Ok(None),
+            _ => Err(git_error(args, &output.stderr)),
+        }
+    }
+
+    /// Return the absolute path of the git directory, e.g. `.git` in the working tree.
+    pub fn git_dir(&self) -> io::Result<PathBuf> {
+        let out = self.run(&["rev-parse", "--absolute-git-dir"])?;
+        Ok(PathBuf::from(String::from_utf8_lossy(&out).trim()))
+    }
+
+    /// Return the absolute path of the git directory shared by all worktrees of the repository,
+    /// or `None` if the directory is not inside a git repository.
+    ///
+    /// This is the git directory itself, except in linked worktrees. Unlike looking for a
+    /// `.git` directory, this also works where `.git` is a file pointing elsewhere, as in
+    /// worktrees and submodules.
+    pub fn common_dir(&self) -> io::Result<Option<PathBuf>> {
+        let output = self
+            .command()
+            .args(["rev-parse", "--git-common-dir"])
+            .stderr(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        // The path is relative to the directory git ran in, unless it is elsewhere
+        let common_dir = self
+            .dir
+            .join(String::from_utf8_lossy(&output.stdout).trim());
+        common_dir.canonicalize().map(Some)
+    }
+
+    /// Find the working tree, the common git directory and the superproject at once, or return
+    /// `None` if the directory is not inside a git repository.
+    ///
+    /// This runs a single git command, unlike asking for each of them, which matters for the
+    /// filters that git runs once per file.
+    pub fn locate(&self) -> io::Result<Option<Location>> {
+        let args = [
+            "rev-parse",
+            "--show-toplevel",
+            "--git-common-dir",
+            "--show-superproject-working-tree",
+        ];
+        let start = Instant::now();
+        let output = self.command().args(args).stderr(Stdio::null()).output()?;
+        log_command(&args, start);
+        if !output.status.success() {
+            // Bare repositories have no working tree to show, and aren't submodules either
+            return Ok(self.common_dir()?.map(|common_dir| Location {
+                work_tree: None,
+                common_dir,
+                superproject: None,
+            }));
+        }
+        // One line for each, except for the superproject, which only submodules have
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut lines = output.lines();
+        let (work_tree, common_dir) = match (lines.next(), lines.next()) {
+            (Some(work_tree), Some(common_dir)) => (work_tree, common_dir),
+            _ => return Err(git_error(&args, b"unexpected output")),
+        };
+        Ok(Some(Location {
+            work_tree: Some(PathBuf::from(work_tree)),
+            // The path is relative to the directory git ran in, unless it is elsewhere
+            common_dir: self.dir.join(common_dir).canonicalize()?,
+            superproject: lines
+                .next()
+                .filter(|line| !line.is_empty())
+                .map(|work_tree| Repository {
+                    dir: PathBuf::from(work_tree),
+                    ignore_repo_env: true,
+                }),
+        }))
+    }
+
+    /// Return the directory that git runs hooks from, which `core.hooksPath` may move.
+    pub fn hooks_dir(&self) -> io::Result<PathBuf> {
+        let out = self.run(&["rev-parse", "--git-path", "hooks"])?;
+        // The path is relative to the directory git ran in, unless it is elsewhere
+        Ok(self.dir.join(String::from_utf8_lossy(&out).trim()))
+    }
+
+    /// Return the superproject if this repository is checked out as its submodule.
+    pub fn superproject(&self) -> io::Result<Option<Repository>> {
+        let out = self.run(&["rev-parse", "--show-superproject-working-tree"])?;
+        let work_tree = String::from_utf8_lossy(&out).trim().to_string();
+        if work_tree.is_empty() {
+            return Ok(None);
+        }
+        // The environment of git running inside the submodule refers to the submodule
+        Ok(Some(Repository {
+            dir: PathBuf::from(work_tree),
+            ignore_repo_env: true,
+        }))
+    }
+
+    /// Return the root directory of the working tree.
+    pub fn work_tree(&self) -> io::Result<PathBuf> {
+        let out = self.run(&["rev-parse", "--show-toplevel"])?;
+        Ok(PathBuf::from(String::from_utf8_lossy(&out).trim()))
+    }
+
+    /// List all files in the index, relative to the root of the working tree.
+    ///
+    /// With `untracked`, files in the working tree that are neither in the index nor ignored
+    /// are listed as well.
+    pub fn ls_files(&self, untracked: bool) -> io::Result<Vec<String>> {
+        let mut args = vec!["ls-files", "-z", "--full-name"];
+        if untracked {
+            args.extend(&["--cached", "--others", "--exclude-standard"]);
+        }
+        args.extend(&["--", ":/"]);
+        let out = self.run(&args)?;
+        Ok(out
+            .split(|b| *b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| String::from_utf8_lossy(path).into_owned())
+            .collect())
+    }
+
+    /// Find the files in the working tree that currently contain a pointer instead of the
+    /// contents, e.g. because smudging was skipped. Returns paths relative to the root.
+    pub fn worktree_pointers(&self) -> io::Result<Vec<(String, StoreFileRef)>> {
+        let root = self.work_tree()?;
+        let mut pointers = Vec::new();
+        for path in self.ls_files(false)? {
+            let full_path = root.join(&path);
+            match std::fs::symlink_metadata(&full_path) {
+                Ok(meta) if meta.is_file() && meta.len() <= MAX_POINTER_SIZE => {}
+                // Deleted files are not there to be replaced
+                _ => continue,
+            }
+            let contents = std::fs::read(&full_path)?;
+            if let Ok(store_ref) = StoreFileRef::parse_from_stream(&mut contents.as_slice()) {
+                pointers.push((path, store_ref));
+            }
+        }
+        Ok(pointers)
+    }
+
+    /// Resolve a revision to the full id of the commit it refers to.
+    pub fn resolve_commit(&self, rev: &str) -> io::Result<String> {
+        let spec = format!("{}^{{commit}}", rev);
+        let out = self.run(&["rev-parse", "--verify", "--quiet", &spec])?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    /// List all files (recursively) in the tree of the given revision.
+    pub fn ls_tree(&self, rev: &str) -> io::Result<Vec<TreeEntry>> {
+        let out = self.run(&["ls-tree", "-r", "-z", "--long", "--full-tree", rev])?;
+        let mut entries = Vec::new();
+        for record in out.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+            // Format: <mode> SP <type> SP <object> SP+ <size> TAB <path>
+            let record = String::from_utf8_lossy(record);
+            let (meta, path) = record
+                .split_once('\t')
+                .ok_or_else(|| invalid_output("ls-tree", &record))?;
+            let fields: Vec<&str> = meta.split_whitespace().collect();
+            if fields.len() != 4 || fields[1] != "blob" {
+                // Submodules (commits) don't have contents we could look at
+                continue;
+            }
+            entries.push(TreeEntry {
+                path: path.to_string(),
+                blob: fields[2].to_string(),
+                size: fields[3]
+                    .parse()
+                    .map_err(|_| invalid_output("ls-tree", &record))?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// List all pointer files in the tree of the given revision.
+    pub fn pointers(&self, rev: &str) -> io::Result<Vec<PointerEntry>> {
+        self.pointers_cached(rev, &mut PointerCache::new())
+    }
+
+    /// List all pointer files in the tree of the given revision, reusing and
+    /// extending the knowledge about previously inspected blobs.
+    pub fn pointers_cached(
+        &self,
+        rev: &str,
+        cache: &mut PointerCache,
+    ) -> io::Result<Vec<PointerEntry>> {
+        let candidates: Vec<TreeEntry> = self
+            .ls_tree(rev)?
+            .into_iter()
+            .filter(|entry| entry.size <= MAX_POINTER_SIZE)
+            .collect();
+
+        let mut unknown: Vec<&str> = candidates
+            .iter()
+            .map(|e| e.blob.as_str())
+            .filter(|blob| !cache.contains_key(*blob))
+            .collect();
+        unknown.sort_unstable();
+        unknown.dedup();
+        let contents = self.cat_blobs(&unknown)?;
+        for (blob, content) in unknown.into_iter().zip(contents) {
+            let store_ref = StoreFileRef::parse_from_stream(&mut content.as_slice()).ok();
+            cache.insert(blob.to_string(), store_ref);
+        }
+
+        Ok(candidates
+            .into_iter()
+            .filter_map(|entry| {
+                let store_ref = cache.get(&entry.blob)?.clone()?;
+                Some(PointerEntry {
+                    path: entry.path,
+                    blob: entry.blob,
+                    store_ref,
+                })
+            })
+            .collect())
+    }
+
+    /// Collect the objects referenced by any pointer that is reachable from a ref (including
+    /// reflogs), or that is currently staged in the index of any worktree.
+    pub fn reachable_objects(&self) -> io::Result<HashSet<ContentHash>> {
+        // `--indexed-objects` covers the indices of all linked worktrees, which share the store
+        let objects = self.rev_list_objects(&["--all", "--reflog", "--indexed-objects"])?;
+        self.pointed_to_objects(objects)
+    }
+
+    /// Collect the objects referenced by any pointer in the commits selected by the given
+    /// `git rev-list` arguments, e.g. `[<new>, "--not", <old>]` for those in `<new>` but not
+    /// in `<old>`.
+    pub fn referenced_objects(&self, revs: &[&str]) -> io::Result<HashSet<ContentHash>> {
+        let objects = self.rev_list_objects(revs)?;
+        self.pointed_to_objects(objects)
+    }
+
+    /// List the ids of all objects in the commits selected by the given `git rev-list`
+    /// arguments, except for blobs that are too large to be pointers.
+    fn rev_list_objects(&self, revs: &[&str]) -> io::Result<Vec<String>> {
+        // Let git do the history walk, skipping all blobs that are too large to be pointers
+        let limit = format!("--filter=blob:limit={}", MAX_POINTER_SIZE + 1);
+        let mut args = vec!["rev-list", "--objects", &limit];
+        args.extend(revs);
+        let out = self.run(&args)?;
+        Ok(String::from_utf8_lossy(&out)
+            .lines()
+            .filter_map(|line| line.split(' ').next())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Collect the objects referenced by those of the given git objects that are pointers.
+    fn pointed_to_objects(&self, mut objects: Vec<String>) -> io::Result<HashSet<ContentHash>> {
+        objects.sort_unstable();
+        objects.dedup();
+
+        let requests: Vec<&str> = objects.iter().map(String::as_str).collect();
+        let blobs: Vec<&str> = self
+            .object_types(&requests)?
+            .into_iter()
+            .zip(requests)
+            .filter(|(object_type, _)| object_type == "blob")
+            .map(|(_, object)| object)
+            .collect();
+        let contents = self.cat_blobs(&blobs)?;
+
+        Ok(contents
+            .iter()
+            .filter_map(|content| StoreFileRef::parse_from_stream(&mut content.as_slice()).ok())
+            .map(|store_ref| store_ref.hash().clone())
+            .collect())
+    }
+
+    /// Look up the type (`blob`, `tree`, ...) of each of the given objects.
+    pub fn object_types(&self, objects: &[&str]) -> io::Result<Vec<String>> {
+        let input: String = objects.iter().flat_map(|o| [*o, "\n"]).collect();
+        let out = self.run_with_input(
+            &["cat-file", "--batch-check=%(objecttype)"],
+            input.into_bytes(),
+        )?;
+        let types: Vec<String> = String::from_utf8_lossy(&out)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        if types.len() != objects.len() {
+            return Err(invalid_output("cat-file", &types.join("\n")));
+        }
+        Ok(types)
+    }
+
+    /// List commits as selected by the given `git log` arguments.
+    pub fn log(&self, args: &[&str]) -> io::Result<Vec<CommitInfo>> {
+        let mut log_args = vec!["log", "-z", "--format=%H %at %aN"];
+        log_args.extend_from_slice(args);
+        let out = self.run(&log_args)?;
+
+        let mut commits = Vec::new();
+        for record in out.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+            let record = String::from_utf8_lossy(record);
+            let mut fields = record.trim_start_matches('\n').splitn(3, ' ');
+            let id = fields.next().unwrap_or_default();
+            let time = fields.next().and_then(|t| t.parse().ok());
+            let author = fields.next();
+            match (time, author) {
+                (Some(time), Some(author)) => commits.push(CommitInfo {
+                    id: id.to_string(),
+                    time,
+                    author: author.to_string(),
+                }),
+                _ => return Err(invalid_output("log", &record)),
+            }
+        }
+        Ok(commits)
+    }
+
+    /// List the files staged in the index. Files with merge conflicts are left out.
+    pub fn index_entries(&self) -> io::Result<Vec<IndexEntry>> {
+        let out = self.run(&["ls-files", "--stage", "-z"])?;
+        let mut entries = Vec::new();
+        for record in out.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+            // Format: <mode> SP <object> SP <stage> TAB <path>
+            let record = String::from_utf8_lossy(record);
+            let (meta, path) = record
+                .split_once('\t')
+                .ok_or_else(|| invalid_output("ls-files", &record))?;
+            let fields: Vec<&str> = meta.split(' ').collect();
+            if fields.len() != 3 {
+                return Err(invalid_output("ls-files", &record));
+            }
+            if fields[2] == "0" {
+                entries.push(IndexEntry {
+                    mode: fields[0].to_string(),
+                    blob: fields[1].to_string(),
+                    path: path.to_string(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Add a blob with the given contents to the repository and return its object id.
+    pub fn write_blob(&self, contents: &[u8]) -> io::Result<String> {
+        let out = self.run_with_input(&["hash-object", "-w", "--stdin"], contents.to_vec())?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    /// Compute the object id that a blob with the given contents would have, without adding it.
+    pub fn hash_blob(&self, contents: &[u8]) -> io::Result<String> {
+        let out = self.run_with_input(&["hash-object", "--stdin"], contents.to_vec())?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    /// Stage the given entries, replacing whatever is staged for their paths.
+    pub fn update_index(&self, entries: &[IndexEntry]) -> io::Result<()> {
+        let mut input = Vec::new();
+        for entry in entries {
+            input.extend_from_slice(
+                format!("{} {}\t{}", entry.mode, entry.blob, entry.path).as_bytes(),
+            );
+            input.push(0);
+        }
+        self.run_with_input(&["update-index", "-z", "--index-info"], input)?;
+        Ok(())
+    }
+
+    /// Read the contents of several blobs with a single `git cat-file --batch` process.
+    pub fn cat_blobs(&self, blobs: &[&str]) -> io::Result<Vec<Vec<u8>>> {
+        let mut child = self
+            .command()
+            .args(["cat-file", "--batch"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Feed the requests from a separate thread so that neither side blocks on a full pipe.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let request: String = blobs.iter().flat_map(|b| [*b, "\n"]).collect();
+        let writer = std::thread::spawn(move || stdin.write_all(request.as_bytes()));
+
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut contents = Vec::with_capacity(blobs.len());
+        for _ in blobs {
+            let mut header = String::new();
+            stdout.read_line(&mut header)?;
+            // Format: <object> SP <type> SP <size> LF <contents> LF
+            let size: usize = header
+                .split_whitespace()
+                .nth(2)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid_output("cat-file", &header))?;
+            let mut buf = vec![0; size + 1];
+            stdout.read_exact(&mut buf)?;
+            buf.pop();
+            contents.push(buf);
+        }
+
+        writer.join().expect("cat-file writer thread panicked")?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(git_error(&["cat-file", "--batch"], b""));
+        }
+        Ok(contents)
+    }
+}
+
+/// Quote a string for the shell, which runs the filter and hook commands of git as well as
+/// commands over SSH.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Log how long a git command took, since slow git commands make for slow checkouts.
+fn log_command(args: &[&str], start: Instant) {
+    log::debug!(
+        "git {} took {:.3}s",
+        args.join(" "),
+        start.elapsed().as_secs_f64()
+    );
+}
+
+pub(crate) fn git_error(args: &[&str], stderr: &[u8]) -> io::Error {
+    io::Error::other(format!(
+        "git {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(stderr).trim()
+    ))
+}
+
+fn invalid_output(command: &str, output: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected output from git {}: {:?}", command, output),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_pointers, shell_quote, PointerChange, PointerEntry};
+    use crate::hash::{ContentHash, SHA256};
+    use crate::store::StoreFileRef;
+
+    fn entry(path: &str, contents: &[u8]) -> PointerEntry {
+        PointerEntry {
+            path: path.to_string(),
+            blob: String::new(),
+            store_ref: StoreFileRef::from_hash(ContentHash::hash_bytes(SHA256, contents)),
+        }
+    }
+
+    #[test]
+    fn diff_pointers_by_path() {
+        let old = vec![entry("b", b"b"), entry("c", b"c"), entry("d", b"d")];
+        let new = vec![entry("a", b"a"), entry("c", b"c2"), entry("d", b"d")];
+
+        let changes = diff_pointers(old, new);
+        assert_eq!(
+            changes,
+            vec![
+                PointerChange::Added(entry("a", b"a")),
+                PointerChange::Removed(entry("b", b"b")),
+                PointerChange::Changed {
+                    old: entry("c", b"c"),
+                    new: entry("c", b"c2"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_for_shell() {
+        assert_eq!(shell_quote("/srv/it's here"), r"'/srv/it'\''s here'");
+    }
+}
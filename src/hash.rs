@@ -1,73 +1,550 @@
-//! A convenience wrapper around a byte array representing a SHA256 hash.
+//! Content hashes, which address the objects in a store, and the algorithms computing them.
+//!
+//! Every `ContentHash` knows the `HashAlgorithm` it was computed with, so that stores, pointers
+//! and validation work with any algorithm. In text, hashes are written as the hex digest,
+//! prefixed by `<algorithm>-` for all algorithms but SHA-256, which predates the prefix.
 
+use std::cmp::Ordering;
 use std::fmt;
-use std::io::Read;
+use std::fs::File;
+use std::io::{self, Read, Write};
 
-use sha2::{Digest, Sha256};
+use serde::{Serialize, Serializer};
+use sha2::Digest;
 
-/// Length of a SHA-256 hash in bytes.
-const SHA256_BYTES: usize = 32;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
-/// A SHA-256 hash of some data.
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Sha256Hash([u8; SHA256_BYTES]);
+/// Length of the longest digest of any algorithm in bytes.
+const MAX_DIGEST_BYTES: usize = 64;
 
-impl Sha256Hash {
-    /// Parse a byte slice as SHA-256 hash.
-    pub fn from_bytes(hash: &[u8]) -> Option<Sha256Hash> {
-        if hash.len() == SHA256_BYTES {
-            let mut sha256 = Sha256Hash([0; SHA256_BYTES]);
-            sha256.0.copy_from_slice(hash);
-            Some(sha256)
-        } else {
-            None
+/// Upper bound for the length of the text form of a hash, for algorithm names of up to 31 bytes.
+pub const MAX_TEXT_LEN: usize = 32 + 2 * MAX_DIGEST_BYTES;
+
+/// Files below this size are hashed with plain reads, mapping them isn't worth the setup cost.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// A hash function that objects can be addressed by.
+pub trait HashAlgorithm: fmt::Debug + Send + Sync {
+    /// Short name identifying the algorithm in the text form of hashes, e.g. `sha256`. It must
+    /// not contain `-` and be shorter than 32 bytes.
+    fn name(&self) -> &'static str;
+
+    /// Length of a digest in bytes, at most 64.
+    fn digest_len(&self) -> usize;
+
+    /// Start hashing incrementally.
+    fn start(&self) -> Box<dyn HashState>;
+
+    /// Compute the digest of `bytes` using up to `jobs` threads, or return `None` if the
+    /// algorithm cannot be parallelized. The result is the same as when hashing incrementally.
+    fn digest_parallel(&self, _bytes: &[u8], _jobs: usize) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Whether `digest_parallel` is supported, so that hashing the complete contents at once
+    /// pays off.
+    fn is_parallel(&self) -> bool {
+        false
+    }
+}
+
+/// The state of an incremental hash computation, see `Hasher`.
+pub trait HashState: Send {
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Return the digest of everything passed to `update`.
+    fn finish(self: Box<Self>) -> Vec<u8>;
+
+    fn box_clone(&self) -> Box<dyn HashState>;
+}
+
+impl<D: Digest + Clone + Send + 'static> HashState for D {
+    fn update(&mut self, bytes: &[u8]) {
+        self.input(bytes);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.result().to_vec()
+    }
+
+    fn box_clone(&self) -> Box<dyn HashState> {
+        Box::new(self.clone())
+    }
+}
+
+/// SHA-256, the algorithm of all stores created before others were supported.
+#[derive(Debug)]
+pub struct Sha256;
+
+impl HashAlgorithm for Sha256 {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(sha2::Sha256::new())
+    }
+}
+
+/// SHA-512/256, i.e. SHA-512 with a different initial state truncated to 256 bits, for
+/// environments that mandate SHA-512 based digests. It is also faster than SHA-256 on 64-bit
+/// machines without SHA extensions.
+#[derive(Debug)]
+pub struct Sha512_256;
+
+impl HashAlgorithm for Sha512_256 {
+    fn name(&self) -> &'static str {
+        "sha512_256"
+    }
+
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(sha2::Sha512Trunc256::new())
+    }
+}
+
+/// A tree of SHA-256 hashes, which can be computed by many threads at once for huge files.
+///
+/// The contents are split into blocks of `TREE_BLOCK_SIZE` bytes, the last one possibly shorter.
+/// The digest is the SHA-256 hash of the SHA-256 hashes of all blocks, followed by the total
+/// length as 64-bit little endian number. It does not depend on how many threads computed it.
+#[derive(Debug)]
+pub struct Sha256Tree;
+
+/// Size of the blocks hashed independently by `Sha256Tree`.
+const TREE_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+impl HashAlgorithm for Sha256Tree {
+    fn name(&self) -> &'static str {
+        "sha256_tree"
+    }
+
+    fn digest_len(&self) -> usize {
+        32
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(TreeState {
+            block: sha2::Sha256::new(),
+            block_len: 0,
+            root: sha2::Sha256::new(),
+            len: 0,
+        })
+    }
+
+    fn digest_parallel(&self, bytes: &[u8], jobs: usize) -> Option<Vec<u8>> {
+        let blocks: Vec<&[u8]> = bytes.chunks(TREE_BLOCK_SIZE).collect();
+        let mut leaves = vec![[0u8; 32]; blocks.len()];
+        // Blocks have the same size, so splitting them evenly keeps all threads equally busy
+        let jobs = std::cmp::max(1, jobs);
+        let per_job = std::cmp::max(1, blocks.len().div_ceil(jobs));
+        crossbeam_utils::thread::scope(|scope| {
+            for (blocks, leaves) in blocks.chunks(per_job).zip(leaves.chunks_mut(per_job)) {
+                scope.spawn(move |_| {
+                    for (block, leaf) in blocks.iter().zip(leaves) {
+                        leaf.copy_from_slice(&sha2::Sha256::digest(block));
+                    }
+                });
+            }
+        })
+        .expect("hashing thread panicked");
+
+        let mut root = sha2::Sha256::new();
+        for leaf in &leaves {
+            root.input(leaf);
         }
+        root.input((bytes.len() as u64).to_le_bytes());
+        Some(root.result().to_vec())
+    }
+
+    fn is_parallel(&self) -> bool {
+        true
     }
+}
+
+/// Incremental state of `Sha256Tree`.
+#[derive(Clone)]
+struct TreeState {
+    /// Hashes the current block.
+    block: sha2::Sha256,
+    block_len: usize,
+    /// Hashes the hashes of all finished blocks.
+    root: sha2::Sha256,
+    /// Total length so far.
+    len: u64,
+}
+
+impl HashState for TreeState {
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = std::cmp::min(bytes.len(), TREE_BLOCK_SIZE - self.block_len);
+            self.block.input(&bytes[..n]);
+            self.block_len += n;
+            bytes = &bytes[n..];
+            if self.block_len == TREE_BLOCK_SIZE {
+                self.root.input(self.block.result_reset());
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        let TreeState {
+            block,
+            block_len,
+            mut root,
+            len,
+        } = *self;
+        if block_len > 0 {
+            root.input(block.result());
+        }
+        root.input(len.to_le_bytes());
+        root.result().to_vec()
+    }
+
+    fn box_clone(&self) -> Box<dyn HashState> {
+        Box::new(self.clone())
+    }
+}
+
+pub static SHA256: &dyn HashAlgorithm = &Sha256;
+pub static SHA512_256: &dyn HashAlgorithm = &Sha512_256;
+pub static SHA256_TREE: &dyn HashAlgorithm = &Sha256Tree;
+
+/// All supported algorithms.
+static ALGORITHMS: &[&dyn HashAlgorithm] = &[&Sha256, &Sha512_256, &Sha256Tree];
+
+/// Look up a supported algorithm by its name.
+pub fn algorithm(name: &str) -> Option<&'static dyn HashAlgorithm> {
+    ALGORITHMS
+        .iter()
+        .copied()
+        .find(|algorithm| algorithm.name() == name)
+}
+
+/// Computes a `ContentHash` incrementally.
+pub struct Hasher {
+    algorithm: &'static dyn HashAlgorithm,
+    state: Box<dyn HashState>,
+}
+
+impl Hasher {
+    pub fn new(algorithm: &'static dyn HashAlgorithm) -> Hasher {
+        Hasher {
+            algorithm,
+            state: algorithm.start(),
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static dyn HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.state.update(bytes);
+    }
+
+    pub fn finish(self) -> ContentHash {
+        ContentHash::from_digest(self.algorithm, &self.state.finish())
+            .expect("algorithm produced a digest of the wrong length")
+    }
+}
+
+impl Clone for Hasher {
+    fn clone(&self) -> Hasher {
+        Hasher {
+            algorithm: self.algorithm,
+            state: self.state.box_clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Hasher {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Hasher({})", self.algorithm.name())
+    }
+}
+
+/// The hash of some data, together with the algorithm it was computed with.
+#[derive(Clone)]
+pub struct ContentHash {
+    algorithm: &'static dyn HashAlgorithm,
+    /// The digest, padded with zeros after `algorithm.digest_len()` bytes.
+    digest: [u8; MAX_DIGEST_BYTES],
+}
 
-    /// Parse a hex string as SHA-256 hash.
-    pub fn from_hex(hash_hex: &[u8]) -> Option<Sha256Hash> {
-        if hash_hex.len() == SHA256_BYTES * 2 {
-            let mut bytes = [0; SHA256_BYTES];
-            hex::decode_to_slice(hash_hex, &mut bytes).ok()?;
-            Some(Sha256Hash(bytes))
+impl ContentHash {
+    /// Wrap a digest computed with `algorithm`, if it has the right length.
+    pub fn from_digest(
+        algorithm: &'static dyn HashAlgorithm,
+        digest: &[u8],
+    ) -> Option<ContentHash> {
+        if digest.len() == algorithm.digest_len() {
+            let mut hash = ContentHash {
+                algorithm,
+                digest: [0; MAX_DIGEST_BYTES],
+            };
+            hash.digest[..digest.len()].copy_from_slice(digest);
+            Some(hash)
         } else {
             None
         }
     }
 
+    /// Parse the text form of a hash, see the module documentation.
+    pub fn from_hex(hash_hex: &[u8]) -> Option<ContentHash> {
+        let text = std::str::from_utf8(hash_hex).ok()?;
+        let (algorithm, digest_hex) = match text.split_once('-') {
+            Some((name, digest_hex)) if name != SHA256.name() => (algorithm(name)?, digest_hex),
+            Some(_) => return None,
+            None => (SHA256, text),
+        };
+        if digest_hex.len() != algorithm.digest_len() * 2 {
+            return None;
+        }
+        let mut digest = [0; MAX_DIGEST_BYTES];
+        hex::decode_to_slice(digest_hex, &mut digest[..algorithm.digest_len()]).ok()?;
+        Some(ContentHash { algorithm, digest })
+    }
+
     pub fn to_hex_string(&self) -> String {
         format!("{}", self)
     }
 
+    /// Whether the hash starts with `prefix`, a lowercase hex digest prefix that may be preceded
+    /// by `<algorithm>-`, as accepted by `Store::resolve_hash`.
+    pub fn matches_prefix(&self, prefix: &str) -> bool {
+        let (algorithm, digest_prefix) = match prefix.split_once('-') {
+            Some((algorithm, digest_prefix)) => (Some(algorithm), digest_prefix),
+            None => (None, prefix),
+        };
+        algorithm.map_or(true, |algorithm| self.algorithm.name() == algorithm)
+            && hex::encode(self.as_bytes()).starts_with(digest_prefix)
+    }
+
+    /// Split the text form for keeping the object in a directory named after the first byte
+    /// of the digest, i.e. into `ab` and `cdef...` for SHA-256 and `<name>-cdef...` otherwise.
+    pub fn sharded(&self) -> (String, String) {
+        let hex = hex::encode(self.as_bytes());
+        let rest = if self.algorithm.name() == SHA256.name() {
+            hex[2..].to_string()
+        } else {
+            format!("{}-{}", self.algorithm.name(), &hex[2..])
+        };
+        (hex[..2].to_string(), rest)
+    }
+
+    /// Parse the output of `sharded`, rejecting anything that `sharded` would not produce.
+    pub fn from_sharded(shard: &str, rest: &str) -> Option<ContentHash> {
+        let text = match rest.split_once('-') {
+            Some((name, digest_rest)) => format!("{}-{}{}", name, shard, digest_rest),
+            None => format!("{}{}", shard, rest),
+        };
+        let hash = ContentHash::from_hex(text.as_bytes())?;
+        if hash.sharded() != (shard.to_string(), rest.to_string()) {
+            return None;
+        }
+        Some(hash)
+    }
+
+    pub fn algorithm(&self) -> &'static dyn HashAlgorithm {
+        self.algorithm
+    }
+
+    /// The digest without the algorithm.
     pub fn as_bytes(&self) -> &[u8] {
-        &self.0
+        &self.digest[..self.algorithm.digest_len()]
+    }
+
+    /// Hash a byte array.
+    pub fn hash_bytes(algorithm: &'static dyn HashAlgorithm, bytes: &[u8]) -> ContentHash {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(bytes);
+        hasher.finish()
     }
 
-    /// Compute the SHA-256 hash of a byte array.
-    pub fn hash_bytes(bytes: &[u8]) -> Sha256Hash {
-        let mut hasher = sha2::Sha256::new();
-        hasher.input(bytes);
-        Sha256Hash::from_bytes(&hasher.result()).expect("SHA-256 is broken")
+    /// Hash a byte array with up to `jobs` threads if the algorithm supports it, see
+    /// `HashAlgorithm::digest_parallel`.
+    pub fn hash_bytes_parallel(
+        algorithm: &'static dyn HashAlgorithm,
+        bytes: &[u8],
+        jobs: usize,
+    ) -> ContentHash {
+        match algorithm.digest_parallel(bytes, jobs) {
+            Some(digest) => ContentHash::from_digest(algorithm, &digest)
+                .expect("algorithm produced a digest of the wrong length"),
+            None => ContentHash::hash_bytes(algorithm, bytes),
+        }
     }
 
-    /// Compute the SHA-256 hash of an arbitrary stream.
-    pub fn hash_stream<R: Read>(reader: &mut R) -> std::io::Result<Sha256Hash> {
-        let mut buf = vec![0u8; 4096];
-        let mut hasher = sha2::Sha256::new();
+    /// Hash an arbitrary stream.
+    pub fn hash_stream<R: Read>(
+        algorithm: &'static dyn HashAlgorithm,
+        reader: &mut R,
+    ) -> io::Result<ContentHash> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut hasher = Hasher::new(algorithm);
         loop {
             let n_read = reader.read(&mut buf)?;
             if n_read > 0 {
-                hasher.input(&buf[0..n_read]);
+                hasher.update(&buf[0..n_read]);
             } else {
-                return Ok(hasher.into());
+                return Ok(hasher.finish());
             }
         }
     }
+
+    /// Hash a file on disk.
+    ///
+    /// The file is read as a stream, so it may be any file, even one that is modified
+    /// meanwhile. With the `io-uring` feature on Linux, large files are read through io_uring
+    /// instead, unless the kernel doesn't support it.
+    pub fn hash_file(
+        algorithm: &'static dyn HashAlgorithm,
+        file: &mut File,
+    ) -> io::Result<ContentHash> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            let metadata = file.metadata()?;
+            if metadata.is_file() && metadata.len() >= MMAP_THRESHOLD {
+                if let Ok(ring) = io_uring::IoUring::new(uring::QUEUE_DEPTH) {
+                    return uring::hash_file(ring, algorithm, file, metadata.len());
+                }
+            }
+        }
+        ContentHash::hash_stream(algorithm, file)
+    }
+
+    /// Hash a file of the store, like `hash_file`, but memory-map it if it is large so that the
+    /// OS can take care of readahead.
+    ///
+    /// Only for files that the store owns, which are never truncated or modified in place.
+    pub(crate) fn hash_store_file(
+        algorithm: &'static dyn HashAlgorithm,
+        file: &mut File,
+    ) -> io::Result<ContentHash> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            let metadata = file.metadata()?;
+            if metadata.is_file() && metadata.len() >= MMAP_THRESHOLD {
+                if let Ok(ring) = io_uring::IoUring::new(uring::QUEUE_DEPTH) {
+                    return uring::hash_file(ring, algorithm, file, metadata.len());
+                }
+            }
+        }
+        if let Some(map) = map_store_file(file)? {
+            return Ok(ContentHash::hash_bytes(algorithm, &map));
+        }
+        ContentHash::hash_stream(algorithm, file)
+    }
+}
+
+/// Memory-map `file` if it is a regular file large enough for mapping to pay off.
+///
+/// Returns `None` for pipes, small files and files that cannot be mapped, which should be read
+/// as a stream instead. Only for files that the store owns: truncating a mapped file crashes
+/// the process, and modifying it changes the contents under the hands of the caller.
+pub(crate) fn map_store_file(file: &File) -> io::Result<Option<memmap2::Mmap>> {
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() < MMAP_THRESHOLD {
+        return Ok(None);
+    }
+    // Safety: objects and staging files of the store are never modified in place once they
+    // are hashed, only replaced by renames or removed.
+    Ok(unsafe { memmap2::Mmap::map(file) }.ok())
+}
+
+/// Copy all of the freshly opened `source` to `target`, starting at the current position of
+/// `target`, while feeding it to `hasher`. Returns the number of bytes copied.
+///
+/// With the `io-uring` feature on Linux, large files are copied through io_uring, so that
+/// reading, hashing and writing overlap.
+pub(crate) fn copy_file(
+    source: &mut File,
+    target: &mut File,
+    hasher: &mut Hasher,
+) -> io::Result<u64> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        let metadata = source.metadata()?;
+        if metadata.is_file() && metadata.len() >= MMAP_THRESHOLD {
+            if let Ok(ring) = io_uring::IoUring::new(uring::QUEUE_DEPTH) {
+                use std::io::{Seek, SeekFrom};
+                // The reads and writes have explicit offsets and leave the positions alone
+                let offset = target.stream_position()?;
+                uring::copy_file(ring, source, metadata.len(), target, offset, hasher)?;
+                target.seek(SeekFrom::Start(offset + metadata.len()))?;
+                return Ok(metadata.len());
+            }
+        }
+    }
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n_read = match source.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n_read) => n_read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buf[..n_read]);
+        target.write_all(&buf[..n_read])?;
+        copied += n_read as u64;
+    }
 }
 
-impl fmt::Display for Sha256Hash {
+impl PartialEq for ContentHash {
+    fn eq(&self, other: &ContentHash) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ContentHash {}
+
+impl PartialOrd for ContentHash {
+    fn partial_cmp(&self, other: &ContentHash) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ContentHash {
+    /// Hashes are ordered by digest first, so that sorting by hash sorts by the hex digest.
+    fn cmp(&self, other: &ContentHash) -> Ordering {
+        self.as_bytes()
+            .cmp(other.as_bytes())
+            .then_with(|| self.algorithm.name().cmp(other.algorithm.name()))
+    }
+}
+
+impl std::hash::Hash for ContentHash {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write(self.algorithm.name().as_bytes());
+        state.write(self.as_bytes());
+    }
+}
+
+impl fmt::Debug for ContentHash {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "ContentHash({})", self)
+    }
+}
+
+impl fmt::Display for ContentHash {
+    /// The precision limits the number of digest bytes that are written.
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        let num_bytes = formatter.precision().unwrap_or(std::usize::MAX);
+        if self.algorithm.name() != SHA256.name() {
+            write!(formatter, "{}-", self.algorithm.name())?;
+        }
+        let num_bytes = formatter.precision().unwrap_or(usize::MAX);
         for b in self.as_bytes().iter().take(num_bytes) {
             write!(formatter, "{:02x}", b)?;
         }
@@ -75,23 +552,24 @@ impl fmt::Display for Sha256Hash {
     }
 }
 
-impl From<Sha256> for Sha256Hash {
-    fn from(hasher: Sha256) -> Sha256Hash {
-        Sha256Hash::from_bytes(&hasher.result()).expect("SHA 256 is broken")
+impl Serialize for ContentHash {
+    /// Hashes are serialized in their text form.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Sha256Hash;
-    use sha2::{Digest, Sha256};
+    use super::{ContentHash, Hasher, SHA256, SHA256_TREE, SHA512_256, TREE_BLOCK_SIZE};
 
     #[test]
     fn sha256hash() {
-        let mut hasher = Sha256::new();
-        hasher.input(b"foo");
-        // check that conversion works
-        let hash: Sha256Hash = hasher.into();
+        let mut hasher = Hasher::new(SHA256);
+        hasher.update(b"f");
+        hasher.update(b"oo");
+        let hash = hasher.finish();
+        assert_eq!(hash, ContentHash::hash_bytes(SHA256, b"foo"));
 
         // check that pretty printing works
         assert_eq!(
@@ -101,12 +579,26 @@ mod test {
         assert_eq!(format!("{:.8}", hash), "2c26b46b68ffc68f"); // 8 bytes
     }
 
+    #[test]
+    fn hash_prefixes() {
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        assert!(hash.matches_prefix("2c26b46b"));
+        assert!(hash.matches_prefix("sha256-2c26"));
+        assert!(!hash.matches_prefix("sha512_256-2c26"));
+        assert!(!hash.matches_prefix("2c27"));
+        let hash = ContentHash::hash_bytes(SHA512_256, b"foo");
+        let hex = hex::encode(hash.as_bytes());
+        assert!(hash.matches_prefix(&hex[..6]));
+        assert!(hash.matches_prefix(&format!("sha512_256-{}", &hex[..6])));
+    }
+
     #[test]
     fn sha256hash_hex_roundtrip() {
-        let hash: Sha256Hash = Sha256Hash::from_hex(
+        let hash = ContentHash::from_hex(
             b"2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae",
         )
         .unwrap();
+        assert_eq!(hash.algorithm().name(), "sha256");
 
         // check that pretty printing works
         assert_eq!(
@@ -114,5 +606,122 @@ mod test {
             "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
         );
         assert_eq!(format!("{:.8}", hash), "2c26b46b68ffc68f"); // 8 bytes
+
+        // SHA-256 hashes never carry a prefix, and unknown algorithms are rejected
+        assert!(ContentHash::from_hex(
+            b"sha256-2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        )
+        .is_none());
+        assert!(ContentHash::from_hex(
+            b"md5-2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        )
+        .is_none());
+        assert!(ContentHash::from_hex(b"2c26b46b").is_none());
+    }
+
+    #[test]
+    fn sha512_256() {
+        let hash = ContentHash::hash_bytes(SHA512_256, b"abc");
+        // Test vector from FIPS 180-4
+        assert_eq!(
+            hash.to_hex_string(),
+            "sha512_256-53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"
+        );
+        assert_eq!(
+            ContentHash::from_hex(hash.to_hex_string().as_bytes()),
+            Some(hash.clone())
+        );
+        assert_ne!(hash, ContentHash::hash_bytes(SHA256, b"abc"));
+    }
+
+    #[test]
+    fn sha256_tree_is_independent_of_threads() {
+        let contents: Vec<u8> = (0..2 * TREE_BLOCK_SIZE + 12345).map(|i| i as u8).collect();
+        let expected = ContentHash::hash_bytes(SHA256_TREE, &contents);
+
+        // Incremental updates of all sizes, crossing block boundaries
+        let mut hasher = Hasher::new(SHA256_TREE);
+        for piece in contents.chunks(1_000_003) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finish(), expected);
+        for jobs in [1, 2, 3, 16] {
+            assert_eq!(
+                ContentHash::hash_bytes_parallel(SHA256_TREE, &contents, jobs),
+                expected
+            );
+        }
+        for jobs in [0, 4] {
+            assert_eq!(
+                ContentHash::hash_bytes_parallel(SHA256_TREE, b"", jobs),
+                ContentHash::hash_bytes(SHA256_TREE, b"")
+            );
+        }
+
+        // One block is the hash of its hash and the length
+        let mut root = Hasher::new(SHA256);
+        root.update(ContentHash::hash_bytes(SHA256, b"foo").as_bytes());
+        root.update(&3u64.to_le_bytes());
+        assert_eq!(
+            ContentHash::hash_bytes(SHA256_TREE, b"foo").as_bytes(),
+            root.finish().as_bytes()
+        );
+    }
+
+    #[test]
+    fn sharded_roundtrip() {
+        for algorithm in [SHA256, SHA512_256] {
+            let hash = ContentHash::hash_bytes(algorithm, b"foo");
+            let (shard, rest) = hash.sharded();
+            assert_eq!(shard.len(), 2);
+            assert_eq!(ContentHash::from_sharded(&shard, &rest), Some(hash));
+            assert_eq!(
+                ContentHash::from_sharded(&shard.to_uppercase(), &rest),
+                None
+            );
+            assert_eq!(ContentHash::from_sharded(&rest[..2], &rest[2..]), None);
+        }
+    }
+
+    #[test]
+    fn sha256hash_file_matches_bytes() {
+        // large enough to take the memory-mapped path for store files
+        let contents: Vec<u8> = (0..3 * super::MMAP_THRESHOLD).map(|i| i as u8).collect();
+        let path =
+            std::env::temp_dir().join(format!("git-assets.hash_file.{}", std::process::id()));
+        std::fs::write(&path, &contents).unwrap();
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let hash = ContentHash::hash_file(SHA256, &mut file).unwrap();
+        let mut file = std::fs::File::open(&path).unwrap();
+        let store_hash = ContentHash::hash_store_file(SHA256, &mut file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(hash, ContentHash::hash_bytes(SHA256, &contents));
+        assert_eq!(store_hash, hash);
+    }
+
+    #[test]
+    fn copy_file_hashes_contents() {
+        let contents: Vec<u8> = (0..3 * super::MMAP_THRESHOLD).map(|i| i as u8).collect();
+        let dir = std::env::temp_dir().join(format!("git-assets.copy_file.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("source"), &contents).unwrap();
+
+        let mut target = std::fs::File::create(dir.join("target")).unwrap();
+        std::io::Write::write_all(&mut target, b"head").unwrap();
+        let mut hasher = super::Hasher::new(SHA256);
+        let mut source = std::fs::File::open(dir.join("source")).unwrap();
+        let copied = super::copy_file(&mut source, &mut target, &mut hasher).unwrap();
+        std::io::Write::write_all(&mut target, b"tail").unwrap();
+        drop(target);
+
+        assert_eq!(copied, contents.len() as u64);
+        assert_eq!(hasher.finish(), ContentHash::hash_bytes(SHA256, &contents));
+        let copy = std::fs::read(dir.join("target")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&copy[..4], b"head");
+        assert!(copy[4..copy.len() - 4] == contents[..]);
+        assert_eq!(&copy[copy.len() - 4..], b"tail");
     }
 }
@@ -0,0 +1,322 @@
+//! Hashing and copying of large files via io_uring, keeping several reads and writes in flight
+//! while hashing.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use super::{ContentHash, HashAlgorithm, Hasher};
+use io_uring::{opcode, types, IoUring};
+
+/// Number of chunks that are kept in flight at the same time.
+pub const QUEUE_DEPTH: u32 = 4;
+
+/// Size of a single read or write request.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A buffer together with the part of the file it is responsible for.
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    len: usize,
+    filled: usize,
+    written: usize,
+    hashed: bool,
+    busy: bool,
+}
+
+/// Which kind of request a completion belongs to, stored in the lowest bit of its user data.
+const READ: u64 = 0;
+const WRITE: u64 = 1;
+
+/// Hash the first `file_len` bytes of `file` using the given ring.
+///
+/// Reads are submitted ahead of the hasher so that the disk is kept busy while
+/// the previous chunk is being hashed. Chunks are hashed strictly in file order.
+pub fn hash_file(
+    ring: IoUring,
+    algorithm: &'static dyn HashAlgorithm,
+    file: &File,
+    file_len: u64,
+) -> io::Result<ContentHash> {
+    let mut hasher = Hasher::new(algorithm);
+    run(ring, file, file_len, None, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+/// Copy the first `file_len` bytes of `source` to `target`, starting at `target_offset`, and
+/// feed them to `hasher`.
+///
+/// Every chunk is written as soon as it has been read, so reading, hashing and writing overlap.
+pub fn copy_file(
+    ring: IoUring,
+    source: &File,
+    file_len: u64,
+    target: &File,
+    target_offset: u64,
+    hasher: &mut Hasher,
+) -> io::Result<()> {
+    run(
+        ring,
+        source,
+        file_len,
+        Some((target, target_offset)),
+        hasher,
+    )
+}
+
+fn run(
+    mut ring: IoUring,
+    source: &File,
+    file_len: u64,
+    target: Option<(&File, u64)>,
+    hasher: &mut Hasher,
+) -> io::Result<()> {
+    let source_fd = types::Fd(source.as_raw_fd());
+    let target = target.map(|(file, offset)| (types::Fd(file.as_raw_fd()), offset));
+    let mut slots: Vec<Slot> = (0..QUEUE_DEPTH)
+        .map(|_| Slot {
+            buf: vec![0; CHUNK_SIZE],
+            offset: 0,
+            len: 0,
+            filled: 0,
+            written: 0,
+            hashed: false,
+            busy: false,
+        })
+        .collect();
+    let mut next_read = 0u64;
+    let mut next_hash = 0u64;
+    let mut in_flight = 0usize;
+    let mut failure = None;
+
+    while failure.is_none() && (next_hash < file_len || in_flight > 0) {
+        for (index, slot) in slots.iter_mut().enumerate() {
+            if !slot.busy && next_read < file_len {
+                slot.offset = next_read;
+                slot.len = std::cmp::min(CHUNK_SIZE as u64, file_len - next_read) as usize;
+                slot.filled = 0;
+                slot.written = 0;
+                slot.hashed = false;
+                slot.busy = true;
+                next_read += slot.len as u64;
+                push_read(&mut ring, source_fd, slot, index);
+                in_flight += 1;
+            }
+        }
+
+        if let Err(err) = submit_and_wait(&ring) {
+            failure = Some(err);
+            break;
+        }
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+
+        for (user_data, result) in completed {
+            in_flight -= 1;
+            let index = (user_data >> 1) as usize;
+            let slot = &mut slots[index];
+            if result < 0 {
+                failure = Some(io::Error::from_raw_os_error(-result));
+            } else if result == 0 && user_data & 1 == READ {
+                failure = Some(io::ErrorKind::UnexpectedEof.into());
+            } else if result == 0 {
+                failure = Some(io::ErrorKind::WriteZero.into());
+            } else if failure.is_some() {
+                // Don't start anything new, only wait for the pending requests
+            } else if user_data & 1 == READ {
+                slot.filled += result as usize;
+                if slot.filled < slot.len {
+                    // Short read, request the remaining part of the chunk
+                    push_read(&mut ring, source_fd, slot, index);
+                    in_flight += 1;
+                } else if let Some((target_fd, target_offset)) = target {
+                    push_write(&mut ring, target_fd, target_offset, slot, index);
+                    in_flight += 1;
+                }
+            } else {
+                slot.written += result as usize;
+                if slot.written < slot.len {
+                    let (target_fd, target_offset) = target.expect("write without a target");
+                    push_write(&mut ring, target_fd, target_offset, slot, index);
+                    in_flight += 1;
+                }
+            }
+        }
+
+        while let Some(slot) = slots.iter_mut().find(|slot| {
+            slot.busy && !slot.hashed && slot.offset == next_hash && slot.filled == slot.len
+        }) {
+            hasher.update(&slot.buf[..slot.len]);
+            next_hash += slot.len as u64;
+            slot.hashed = true;
+        }
+        for slot in &mut slots {
+            if slot.busy && slot.hashed && (target.is_none() || slot.written == slot.len) {
+                slot.busy = false;
+            }
+        }
+    }
+
+    // The kernel may still write into our buffers, so they must outlive all pending requests.
+    while in_flight > 0 {
+        if let Err(err) = submit_and_wait(&ring) {
+            // Without knowing when the pending requests are done, the buffers can never be
+            // freed safely, so they are leaked together with the ring.
+            std::mem::forget(slots);
+            std::mem::forget(ring);
+            return Err(failure.unwrap_or(err));
+        }
+        in_flight -= ring.completion().count();
+    }
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Submit the queued requests and wait for at least one to complete, retrying if a signal
+/// interrupts the wait.
+fn submit_and_wait(ring: &IoUring) -> io::Result<()> {
+    loop {
+        match ring.submit_and_wait(1) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+/// Queue a read for the part of `slot` that hasn't been filled yet.
+fn push_read(ring: &mut IoUring, fd: types::Fd, slot: &mut Slot, index: usize) {
+    let remaining = &mut slot.buf[slot.filled..slot.len];
+    let entry = opcode::Read::new(fd, remaining.as_mut_ptr(), remaining.len() as u32)
+        .offset64((slot.offset + slot.filled as u64) as i64)
+        .build()
+        .user_data((index as u64) << 1 | READ);
+    // Safety: the buffer is owned by `slot`, which is kept alive until the read has completed.
+    // There is at most one request per slot in flight, so the queue cannot overflow.
+    unsafe { ring.submission().push(&entry) }.expect("io_uring submission queue overflow");
+}
+
+/// Queue a write for the part of `slot` that hasn't been written yet.
+fn push_write(
+    ring: &mut IoUring,
+    fd: types::Fd,
+    target_offset: u64,
+    slot: &mut Slot,
+    index: usize,
+) {
+    let remaining = &slot.buf[slot.written..slot.len];
+    let entry = opcode::Write::new(fd, remaining.as_ptr(), remaining.len() as u32)
+        .offset64((target_offset + slot.offset + slot.written as u64) as i64)
+        .build()
+        .user_data((index as u64) << 1 | WRITE);
+    // Safety: as for reads, and the slot isn't reused before the write has completed.
+    unsafe { ring.submission().push(&entry) }.expect("io_uring submission queue overflow");
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io;
+    use std::path::PathBuf;
+
+    use io_uring::IoUring;
+
+    use super::{copy_file, hash_file, CHUNK_SIZE, QUEUE_DEPTH};
+    use crate::hash::{ContentHash, Hasher, SHA256};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("git-assets.uring.{}.{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// More chunks than slots, and a partial one at the end.
+    fn contents() -> Vec<u8> {
+        (0..(QUEUE_DEPTH as usize * 2 + 1) * CHUNK_SIZE + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn ring() -> Option<IoUring> {
+        // Some kernels and sandboxes don't allow io_uring, the callers fall back to plain reads then
+        IoUring::new(QUEUE_DEPTH).ok()
+    }
+
+    #[test]
+    fn hash_file_matches_bytes() {
+        let Some(ring) = ring() else { return };
+        let dir = temp_dir("hash");
+        let contents = contents();
+        std::fs::write(dir.join("file"), &contents).unwrap();
+        let file = File::open(dir.join("file")).unwrap();
+
+        let hash = hash_file(ring, SHA256, &file, contents.len() as u64).unwrap();
+        assert_eq!(hash, ContentHash::hash_bytes(SHA256, &contents));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copy_file_writes_at_offset() {
+        let Some(ring) = ring() else { return };
+        let dir = temp_dir("copy");
+        let contents = contents();
+        std::fs::write(dir.join("source"), &contents).unwrap();
+        std::fs::write(dir.join("target"), b"head").unwrap();
+        let source = File::open(dir.join("source")).unwrap();
+        let target = File::options()
+            .write(true)
+            .open(dir.join("target"))
+            .unwrap();
+
+        let mut hasher = Hasher::new(SHA256);
+        copy_file(
+            ring,
+            &source,
+            contents.len() as u64,
+            &target,
+            4,
+            &mut hasher,
+        )
+        .unwrap();
+        assert_eq!(hasher.finish(), ContentHash::hash_bytes(SHA256, &contents));
+        let copied = std::fs::read(dir.join("target")).unwrap();
+        assert_eq!(&copied[..4], b"head");
+        assert!(copied[4..] == contents[..]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_file_fails() {
+        let Some(ring) = ring() else { return };
+        let dir = temp_dir("truncated");
+        let contents = contents();
+        std::fs::write(dir.join("file"), &contents).unwrap();
+        let file = File::open(dir.join("file")).unwrap();
+
+        // The read of the last chunk comes up short and then hits the end of the file while
+        // the other reads are still pending
+        let err = hash_file(ring, SHA256, &file, contents.len() as u64 + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_errors_are_reported() {
+        let Some(ring) = ring() else { return };
+        let dir = temp_dir("unreadable");
+        let contents = contents();
+        std::fs::write(dir.join("file"), &contents).unwrap();
+        // Reading from a file that is only open for writing fails on every request
+        let file = File::options().write(true).open(dir.join("file")).unwrap();
+
+        let err = hash_file(ring, SHA256, &file, contents.len() as u64).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(9 /* EBADF */));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
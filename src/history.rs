@@ -0,0 +1,441 @@
+//! Rewriting the history of a repository so that files are committed as pointers.
+//!
+//! The history is streamed from `git fast-export` to `git fast-import`. With `--no-data` and
+//! `--full-tree`, every commit lists all of its files by blob id, so files can be swapped for
+//! pointers by replacing the blob id, and the `.gitattributes` file of every commit can be
+//! made to track the same patterns. Each blob is only stored and converted once.
+//!
+//! Since the rewritten stream needs the id of every pointer blob in place, blobs can only be
+//! stored in parallel ahead of time: a first pass over the stream collects the blobs to
+//! convert, which are stored on several threads before the second pass rewrites the history.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::process::Stdio;
+
+use serde::Serialize;
+
+use crate::attributes;
+use crate::git::{self, Repository};
+use crate::hash::ContentHash;
+use crate::parallel;
+use crate::store::{PointerFormat, Store, StoreFileRef};
+
+/// Path of the attributes file that is made to track the patterns.
+const ATTRIBUTES_PATH: &[u8] = b".gitattributes";
+
+/// What `rewrite` did.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RewriteReport {
+    /// Number of commits in the rewritten history.
+    pub commits: u64,
+    /// Objects that were stored for blobs that are now pointers.
+    pub objects: Vec<ContentHash>,
+    /// Total size of these blobs.
+    pub bytes: u64,
+}
+
+/// Rewrite all refs so that files matching any of `patterns` are committed as pointers in
+/// the given format, with their contents in `store`, and so that the `.gitattributes` file at
+/// the root of every commit tracks the patterns.
+///
+/// Patterns use the syntax of `.gitattributes`, see `attributes::pattern_matches`. Files that
+/// already are pointers are left alone. Refs are only updated if the whole history could be
+/// rewritten; the index and working tree are not touched. With more than one of `jobs`, the
+/// blobs are stored on that many threads before the history is rewritten. In a dry run of
+/// `store`, neither the refs nor the store are changed, but the report is the same.
+pub fn rewrite(
+    repo: &Repository,
+    store: &Store,
+    patterns: &[&str],
+    format: PointerFormat,
+    jobs: usize,
+) -> io::Result<RewriteReport> {
+    let export_args = [
+        "fast-export",
+        "--all",
+        "--no-data",
+        "--full-tree",
+        "--signed-tags=strip",
+        "--use-done-feature",
+    ];
+    let import_args = ["fast-import", "--force", "--quiet", "--done"];
+    let mut rewriter = Rewriter {
+        repo,
+        store,
+        patterns,
+        format,
+        pointer_blobs: HashMap::new(),
+        attribute_blobs: HashMap::new(),
+        report: RewriteReport::default(),
+    };
+    if jobs > 1 {
+        rewriter.store_blobs(&export_args, jobs)?;
+    }
+
+    let mut export = repo
+        .command()
+        .args(export_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut import = if store.dry_run() {
+        None
+    } else {
+        let import = repo
+            .command()
+            .args(import_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        Some(import)
+    };
+
+    let input = BufReader::new(export.stdout.take().expect("stdout is piped"));
+    let output: Box<dyn Write> = match &mut import {
+        Some(import) => Box::new(BufWriter::new(import.stdin.take().expect("stdin is piped"))),
+        None => Box::new(io::sink()),
+    };
+    // On failure, the stream lacks the final `done`, so fast-import leaves the refs alone
+    let rewritten = rewriter.process(input, output);
+
+    let export_output = export.wait_with_output()?;
+    let import_output = import.map(|import| import.wait_with_output()).transpose()?;
+    rewritten?;
+    if !export_output.status.success() {
+        return Err(git::git_error(&export_args, &export_output.stderr));
+    }
+    if let Some(import_output) = import_output.filter(|output| !output.status.success()) {
+        return Err(git::git_error(&import_args, &import_output.stderr));
+    }
+    Ok(rewriter.report)
+}
+
+struct Rewriter<'a> {
+    repo: &'a Repository,
+    store: &'a Store,
+    patterns: &'a [&'a str],
+    format: PointerFormat,
+    /// Blob ids of the pointers that replace the blob with the given id.
+    pointer_blobs: HashMap<String, String>,
+    /// Blob ids of the attribute files that replace the one with the given id, or the missing one.
+    attribute_blobs: HashMap<Option<String>, String>,
+    report: RewriteReport,
+}
+
+impl Rewriter<'_> {
+    /// Copy the fast-import stream from `input` to `output`, rewriting the files of all commits.
+    fn process<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        let mut line = Vec::new();
+        let mut in_commit = false;
+        let mut has_attributes = false;
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if copy_data(&line, &mut input, &mut output)? {
+                continue;
+            }
+
+            if line.starts_with(b"commit ") {
+                in_commit = true;
+                has_attributes = false;
+                self.report.commits += 1;
+            } else if in_commit && line == b"\n" {
+                // The end of the commit, which may still need an attributes file
+                if !has_attributes {
+                    let blob = self.attributes_blob(None)?;
+                    output.write_all(format!("M 100644 {} .gitattributes\n", blob).as_bytes())?;
+                }
+                in_commit = false;
+            } else if in_commit && line.starts_with(b"M ") {
+                let (new_line, is_attributes) = self.rewrite_modify(&line)?;
+                has_attributes |= is_attributes;
+                output.write_all(&new_line)?;
+                continue;
+            }
+            output.write_all(&line)?;
+        }
+        output.flush()
+    }
+
+    /// Store the blobs that the history rewritten from `export_args` will have pointers to on
+    /// `jobs` threads, so that `pointer_blob` finds them later.
+    fn store_blobs(&mut self, export_args: &[&str], jobs: usize) -> io::Result<()> {
+        let mut export = self
+            .repo
+            .command()
+            .args(export_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let input = BufReader::new(export.stdout.take().expect("stdout is piped"));
+        let blobs = self.converted_blobs(input);
+        let export_output = export.wait_with_output()?;
+        let blobs = blobs?;
+        if !export_output.status.success() {
+            return Err(git::git_error(export_args, &export_output.stderr));
+        }
+
+        let (repo, store, format) = (self.repo, self.store, self.format);
+        let (pointer_blobs, report) = (&mut self.pointer_blobs, &mut self.report);
+        parallel::map_ordered(
+            jobs,
+            blobs.into_iter().map(Ok),
+            |blob| {
+                let converted = convert_blob(repo, store, format, &blob);
+                (blob, converted)
+            },
+            |(blob, converted)| {
+                let (pointer_blob, store_ref) = converted?;
+                if let Some(store_ref) = store_ref {
+                    report.bytes += store_ref.size().unwrap_or(0);
+                    report.objects.push(store_ref.hash().clone());
+                }
+                pointer_blobs.insert(blob, pointer_blob);
+                Ok(())
+            },
+        )
+    }
+
+    /// The blobs of the files in the fast-import stream from `input` that `rewrite_modify`
+    /// converts, in the order they first appear.
+    fn converted_blobs<R: BufRead>(&self, mut input: R) -> io::Result<Vec<String>> {
+        let mut blobs = Vec::new();
+        let mut seen = HashSet::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if copy_data(&line, &mut input, &mut io::sink())? || !line.starts_with(b"M ") {
+                continue;
+            }
+            let (mode, blob, path_field) = parse_modify(&line)?;
+            if self.converts(mode, &unquote(path_field)) && seen.insert(blob.clone()) {
+                blobs.push(blob);
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Whether a file with the given mode and path is replaced by a pointer.
+    fn converts(&self, mode: &[u8], path: &[u8]) -> bool {
+        (mode == b"100644" || mode == b"100755")
+            && self
+                .patterns
+                .iter()
+                .any(|pattern| attributes::pattern_matches(pattern, &String::from_utf8_lossy(path)))
+    }
+
+    /// Rewrite a `M <mode> <blob> <path>` line, returning it and whether it modifies the
+    /// attributes file.
+    fn rewrite_modify(&mut self, line: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        let (mode, blob, path_field) = parse_modify(line)?;
+        let path = unquote(path_field);
+
+        let new_blob = if path == ATTRIBUTES_PATH {
+            self.attributes_blob(Some(&blob))?
+        } else if self.converts(mode, &path) {
+            self.pointer_blob(&blob)?
+        } else {
+            return Ok((line.to_vec(), false));
+        };
+
+        let mut new_line = Vec::with_capacity(line.len());
+        new_line.extend_from_slice(b"M ");
+        new_line.extend_from_slice(mode);
+        new_line.extend_from_slice(format!(" {} ", new_blob).as_bytes());
+        new_line.extend_from_slice(path_field);
+        new_line.push(b'\n');
+        Ok((new_line, path == ATTRIBUTES_PATH))
+    }
+
+    /// Store the contents of a blob and return the id of the blob with a pointer to them.
+    fn pointer_blob(&mut self, blob: &str) -> io::Result<String> {
+        if let Some(pointer_blob) = self.pointer_blobs.get(blob) {
+            return Ok(pointer_blob.clone());
+        }
+        let (pointer_blob, store_ref) = convert_blob(self.repo, self.store, self.format, blob)?;
+        if let Some(store_ref) = store_ref {
+            self.report.bytes += store_ref.size().unwrap_or(0);
+            self.report.objects.push(store_ref.hash().clone());
+        }
+        self.pointer_blobs
+            .insert(blob.to_string(), pointer_blob.clone());
+        Ok(pointer_blob)
+    }
+
+    /// Return the id of the blob replacing the given attributes file, or the missing one.
+    fn attributes_blob(&mut self, blob: Option<&str>) -> io::Result<String> {
+        let key = blob.map(str::to_string);
+        if let Some(new_blob) = self.attribute_blobs.get(&key) {
+            return Ok(new_blob.clone());
+        }
+        let contents = match blob {
+            Some(blob) => {
+                let contents = self.repo.cat_blobs(&[blob])?;
+                String::from_utf8_lossy(&contents[0]).into_owned()
+            }
+            None => String::new(),
+        };
+        let contents =
+            attributes::add_tracking_patterns(&contents, attributes::DEFAULT_FILTER, self.patterns);
+        let new_blob = write_blob(self.repo, self.store, contents.as_bytes())?;
+        self.attribute_blobs.insert(key, new_blob.clone());
+        Ok(new_blob)
+    }
+}
+
+/// Store the contents of a blob, and return the id of the blob with a pointer to them in the
+/// given format along with the stored object. Blobs that already are pointers are returned
+/// as they are.
+fn convert_blob(
+    repo: &Repository,
+    store: &Store,
+    format: PointerFormat,
+    blob: &str,
+) -> io::Result<(String, Option<StoreFileRef>)> {
+    let args = ["cat-file", "blob", blob];
+    let mut child = repo
+        .command()
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut contents = child.stdout.take().expect("stdout is piped");
+    let mut head = Vec::new();
+    (&mut contents)
+        .take(git::MAX_POINTER_SIZE + 1)
+        .read_to_end(&mut head)?;
+
+    let is_pointer = head.len() as u64 <= git::MAX_POINTER_SIZE
+        && StoreFileRef::parse_from_stream(&mut head.as_slice()).is_ok();
+    let converted = if is_pointer {
+        (blob.to_string(), None)
+    } else {
+        let mut staging_file = store.new_staging_file()?;
+        let copied = staging_file
+            .write_all(&head)
+            .and_then(|()| io::copy(&mut contents, &mut staging_file));
+        if let Err(err) = copied {
+            staging_file.discard()?;
+            return Err(err);
+        }
+        let store_ref = store.make_permanent(staging_file)?;
+        let pointer = format!("{}\n", store_ref.to_pointer(format));
+        (
+            write_blob(repo, store, pointer.as_bytes())?,
+            Some(store_ref),
+        )
+    };
+    drop(contents);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(git::git_error(&args, &output.stderr));
+    }
+    Ok(converted)
+}
+
+/// Add a blob to the repository, or only compute its id in a dry run of `store`.
+fn write_blob(repo: &Repository, store: &Store, contents: &[u8]) -> io::Result<String> {
+    if store.dry_run() {
+        repo.hash_blob(contents)
+    } else {
+        repo.write_blob(contents)
+    }
+}
+
+/// If `line` starts a `data <count>` section, copy it and the section to `output`, and return
+/// whether it did. Messages are copied verbatim, whatever they contain.
+fn copy_data<R: BufRead, W: Write>(line: &[u8], input: &mut R, output: &mut W) -> io::Result<bool> {
+    let count = match line.strip_prefix(b"data ") {
+        Some(count) => count,
+        None => return Ok(false),
+    };
+    let count: u64 = std::str::from_utf8(count)
+        .ok()
+        .and_then(|count| count.trim_end().parse().ok())
+        .ok_or_else(|| invalid_stream(line))?;
+    output.write_all(line)?;
+    if io::copy(&mut input.take(count), output)? != count {
+        return Err(invalid_stream(line));
+    }
+    Ok(true)
+}
+
+/// Split a `M <mode> <blob> <path>` line into the mode, the blob and the possibly quoted path.
+fn parse_modify(line: &[u8]) -> io::Result<(&[u8], String, &[u8])> {
+    let fields = line
+        .strip_suffix(b"\n")
+        .unwrap_or(line)
+        .splitn(4, |&byte| byte == b' ')
+        .collect::<Vec<&[u8]>>();
+    match fields[..] {
+        [_, mode, blob, path] => Ok((mode, String::from_utf8_lossy(blob).into_owned(), path)),
+        _ => Err(invalid_stream(line)),
+    }
+}
+
+/// Undo the C-style quoting of paths in the fast-import format.
+fn unquote(path: &[u8]) -> Vec<u8> {
+    let quoted = match path.strip_prefix(b"\"").and_then(|p| p.strip_suffix(b"\"")) {
+        Some(quoted) => quoted,
+        None => return path.to_vec(),
+    };
+    let mut unquoted = Vec::with_capacity(quoted.len());
+    let mut bytes = quoted.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            unquoted.push(byte);
+            continue;
+        }
+        let escaped = match bytes.next() {
+            Some(b'a') => 0x07,
+            Some(b'b') => 0x08,
+            Some(b't') => b'\t',
+            Some(b'n') => b'\n',
+            Some(b'v') => 0x0b,
+            Some(b'f') => 0x0c,
+            Some(b'r') => b'\r',
+            // Three octal digits
+            Some(digit @ b'0'..=b'7') => bytes
+                .by_ref()
+                .take(2)
+                .fold(u32::from(digit - b'0'), |value, digit| {
+                    value * 8 + u32::from(digit.wrapping_sub(b'0'))
+                }) as u8,
+            Some(other) => other,
+            None => break,
+        };
+        unquoted.push(escaped);
+    }
+    unquoted
+}
+
+fn invalid_stream(line: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "unexpected line in fast-export output: {}",
+            String::from_utf8_lossy(line).trim_end()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::unquote;
+
+    #[test]
+    fn unquote_paths() {
+        assert_eq!(unquote(b"a.bin"), b"a.bin");
+        assert_eq!(unquote(b"\"d/b c.bin\""), b"d/b c.bin");
+        assert_eq!(unquote(b"\"tab\\there \\\"\\\\\""), b"tab\there \"\\");
+        assert_eq!(unquote(b"\"gr\\303\\274n.png\""), "grün.png".as_bytes());
+    }
+}
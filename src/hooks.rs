@@ -0,0 +1,93 @@
+//! Installing git hooks that run git-assets.
+//!
+//! Hooks written here carry a marker line, so that they can be told apart from hooks written
+//! by hand or by other tools, which are never overwritten or removed without being asked to.
+
+use std::io;
+use std::path::Path;
+
+/// Line identifying the hooks written by `install`.
+pub const MARKER: &str = "# Installed by git-assets";
+
+/// The contents of a hook running the given shell command.
+pub fn script(command: &str) -> String {
+    format!("#!/bin/sh\n{}\n{}\n", MARKER, command)
+}
+
+/// Whether the hook in the given file was written by `install`.
+pub fn is_installed(path: &Path) -> io::Result<bool> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().any(|line| line == MARKER)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        // Not even text, so certainly not ours
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the hook `name` into `hooks_dir` as an executable script running `command`.
+///
+/// Fails with `AlreadyExists` if there is a hook that was not written by `install`, unless
+/// `force` is given.
+pub fn install(hooks_dir: &Path, name: &str, command: &str, force: bool) -> io::Result<()> {
+    let path = hooks_dir.join(name);
+    if !force && path.exists() && !is_installed(&path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already exists and was not installed by git-assets",
+                path.display()
+            ),
+        ));
+    }
+    std::fs::create_dir_all(hooks_dir)?;
+    std::fs::write(&path, script(command))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Remove the hook `name` from `hooks_dir` if it was written by `install`, returning whether
+/// it was.
+pub fn uninstall(hooks_dir: &Path, name: &str) -> io::Result<bool> {
+    let path = hooks_dir.join(name);
+    if !is_installed(&path)? {
+        return Ok(false);
+    }
+    std::fs::remove_file(path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{install, is_installed, uninstall};
+
+    #[test]
+    fn install_and_uninstall_hooks() {
+        let hooks_dir =
+            std::env::temp_dir().join(format!("git-assets.hooks.{}", std::process::id()));
+        install(&hooks_dir, "post-merge", "git-assets checkout", false).unwrap();
+        let contents = std::fs::read_to_string(hooks_dir.join("post-merge")).unwrap();
+        assert!(contents.starts_with("#!/bin/sh\n"));
+        assert!(contents.ends_with("\ngit-assets checkout\n"));
+        // Our own hooks are updated
+        install(&hooks_dir, "post-merge", "git-assets checkout", false).unwrap();
+
+        // Other hooks are left alone
+        std::fs::write(hooks_dir.join("post-checkout"), "#!/bin/sh\nmake\n").unwrap();
+        assert!(install(&hooks_dir, "post-checkout", "git-assets checkout", false).is_err());
+        assert!(!uninstall(&hooks_dir, "post-checkout").unwrap());
+        assert!(hooks_dir.join("post-checkout").exists());
+        install(&hooks_dir, "post-checkout", "git-assets checkout", true).unwrap();
+        assert!(is_installed(&hooks_dir.join("post-checkout")).unwrap());
+
+        assert!(uninstall(&hooks_dir, "post-merge").unwrap());
+        assert!(!hooks_dir.join("post-merge").exists());
+        assert!(!uninstall(&hooks_dir, "post-merge").unwrap());
+
+        std::fs::remove_dir_all(hooks_dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+//! Moving objects between git-assets stores and the local object storage of Git LFS.
+//!
+//! Git LFS keeps every object in `.git/lfs/objects/<oid[0..2]>/<oid[2..4]>/<oid>`, where the
+//! oid is the hex encoded SHA-256 hash of the contents, i.e. the same hash that identifies
+//! the object in a store using `sha256`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{self, ContentHash};
+use crate::parallel;
+use crate::store::{Store, StoreFileRef, TransferReport};
+
+/// The directory containing the objects of Git LFS, given the git directory of a repository.
+pub fn objects_dir(git_dir: &Path) -> PathBuf {
+    git_dir.join("lfs").join("objects")
+}
+
+/// Where Git LFS keeps the object with the given hash inside `objects_dir`.
+pub fn object_path(objects_dir: &Path, hash: &ContentHash) -> PathBuf {
+    let oid = hash.to_hex_string();
+    objects_dir.join(&oid[0..2]).join(&oid[2..4]).join(oid)
+}
+
+/// Add all objects in the Git LFS object directory `objects_dir` to `store`.
+///
+/// Objects that are already present are skipped, all others are verified against their oid
+/// before they are stored, on `jobs` threads. Files that are not named like objects, e.g.
+/// temporary files of an interrupted download, are ignored. A missing directory contains no
+/// objects.
+pub fn import_objects(
+    store: &Store,
+    objects_dir: &Path,
+    jobs: usize,
+) -> io::Result<TransferReport> {
+    let mut paths = Vec::new();
+    for first in read_dir_names(objects_dir)? {
+        for second in read_dir_names(&objects_dir.join(&first))? {
+            for oid in read_dir_names(&objects_dir.join(&first).join(&second))? {
+                let hash = match ContentHash::from_digest(
+                    hash::SHA256,
+                    &hex::decode(&oid).unwrap_or_default(),
+                ) {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                let path = objects_dir.join(&first).join(&second).join(&oid);
+                if path == object_path(objects_dir, &hash) && path.is_file() {
+                    paths.push((hash, path));
+                }
+            }
+        }
+    }
+    paths.sort();
+
+    let mut report = TransferReport::default();
+    parallel::map_ordered(
+        jobs,
+        paths.into_iter().map(Ok),
+        |(hash, path)| import_object(store, hash, &path),
+        |imported| {
+            match imported? {
+                (hash, Some(size)) => {
+                    report.bytes += size;
+                    report.transferred.push(hash);
+                }
+                (_, None) => report.skipped += 1,
+            }
+            Ok(())
+        },
+    )?;
+    Ok(report)
+}
+
+/// Store the object with the given hash from the file at `path`, returning its size, or
+/// `None` if the store already has it.
+fn import_object(
+    store: &Store,
+    hash: ContentHash,
+    path: &Path,
+) -> io::Result<(ContentHash, Option<u64>)> {
+    if store
+        .object_size(&StoreFileRef::from_hash(hash.clone()))?
+        .is_some()
+    {
+        return Ok((hash, None));
+    }
+    let mut staging_file = store.new_staging_file_with(hash::SHA256)?;
+    let copied =
+        std::fs::File::open(path).and_then(|mut file| io::copy(&mut file, &mut staging_file));
+    let size = match copied {
+        Ok(size) => size,
+        Err(err) => {
+            staging_file.discard()?;
+            return Err(err);
+        }
+    };
+    store.make_permanent_verified(staging_file, &hash)?;
+    Ok((hash, Some(size)))
+}
+
+/// Copy the given objects of `store` into the Git LFS object directory `objects_dir`.
+///
+/// Objects that Git LFS already has are skipped, and objects missing from the store are
+/// reported as missing. Since Git LFS only knows SHA-256, this fails with `InvalidInput` before
+/// copying anything if any of the objects is addressed by another algorithm. Objects are
+/// copied on `jobs` threads. In a dry run of `store`, nothing is copied, but the report is the
+/// same.
+pub fn export_objects(
+    store: &Store,
+    hashes: impl IntoIterator<Item = ContentHash>,
+    objects_dir: &Path,
+    jobs: usize,
+) -> io::Result<TransferReport> {
+    let mut hashes: Vec<ContentHash> = hashes.into_iter().collect();
+    hashes.sort();
+    hashes.dedup();
+    if let Some(hash) = hashes
+        .iter()
+        .find(|hash| hash.algorithm().name() != hash::SHA256.name())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Git LFS only supports sha256 objects, not {}", hash),
+        ));
+    }
+
+    let mut report = TransferReport::default();
+    parallel::map_ordered(
+        jobs,
+        hashes.into_iter().map(Ok),
+        |hash| export_object(store, hash, objects_dir),
+        |exported| {
+            match exported? {
+                Exported::Copied(hash, size) => {
+                    report.bytes += size;
+                    report.transferred.push(hash);
+                }
+                Exported::Present => report.skipped += 1,
+                Exported::Missing(hash) => report.missing.push(hash),
+            }
+            Ok(())
+        },
+    )?;
+    Ok(report)
+}
+
+/// What became of an object passed to `export_objects`.
+enum Exported {
+    /// Copied, or would have been in a dry run, with its size.
+    Copied(ContentHash, u64),
+    /// Git LFS already has it.
+    Present,
+    /// The store doesn't have it.
+    Missing(ContentHash),
+}
+
+/// Copy the object with the given hash from `store` into `objects_dir`.
+fn export_object(store: &Store, hash: ContentHash, objects_dir: &Path) -> io::Result<Exported> {
+    let path = object_path(objects_dir, &hash);
+    if path.is_file() {
+        return Ok(Exported::Present);
+    }
+    if store.dry_run() {
+        return Ok(
+            match store.object_size(&StoreFileRef::from_hash(hash.clone()))? {
+                Some(size) => Exported::Copied(hash, size),
+                None => Exported::Missing(hash),
+            },
+        );
+    }
+    let mut reader = match store.open_ref(&StoreFileRef::from_hash(hash.clone())) {
+        Ok(reader) => reader,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Exported::Missing(hash)),
+        Err(err) => return Err(err),
+    };
+    std::fs::create_dir_all(path.parent().expect("objects are in a directory"))?;
+    // Git LFS must never see a partially written object
+    let temp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+    let written = std::fs::File::create(&temp_path).and_then(|mut file| {
+        let size = io::copy(&mut reader, &mut file)?;
+        file.sync_all()?;
+        Ok(size)
+    });
+    match written.and_then(|size| std::fs::rename(&temp_path, &path).map(|()| size)) {
+        Ok(size) => Ok(Exported::Copied(hash, size)),
+        Err(err) => {
+            let _ = std::fs::remove_file(&temp_path);
+            Err(err)
+        }
+    }
+}
+
+/// List the names of the entries of a directory, treating one that doesn't exist like an
+/// empty one. Names that are not valid UTF-8 cannot be oids and are left out.
+fn read_dir_names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        if let Ok(name) = entry?.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export_objects, import_objects, object_path};
+    use crate::hash::{ContentHash, SHA256, SHA512_256};
+    use crate::store::{Store, StoreFileRef};
+
+    #[test]
+    fn import_lfs_objects() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.lfs_import.{}", std::process::id()));
+        let objects_dir = base_dir.join("lfs").join("objects");
+        std::fs::create_dir_all(&base_dir).unwrap();
+        let store = Store::open_or_create(base_dir.join("store")).unwrap();
+
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        let path = object_path(&objects_dir, &hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"foo").unwrap();
+        // Neither an object, nor at the right place
+        std::fs::write(path.with_file_name("tmp"), b"bar").unwrap();
+
+        let report = import_objects(&store, &objects_dir, 2).unwrap();
+        assert_eq!(report.transferred, std::slice::from_ref(&hash));
+        assert_eq!(report.bytes, 3);
+        let store_ref = StoreFileRef::from_hash(hash.clone());
+        assert_eq!(store.object_size(&store_ref).unwrap(), Some(3));
+
+        let report = import_objects(&store, &objects_dir, 2).unwrap();
+        assert_eq!((report.transferred.len(), report.skipped), (0, 1));
+
+        // Corrupted objects are not imported
+        let corrupt = ContentHash::hash_bytes(SHA256, b"baz");
+        let path = object_path(&objects_dir, &corrupt);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"qux").unwrap();
+        assert!(import_objects(&store, &objects_dir, 2).is_err());
+        assert!(store.validate().unwrap().is_valid());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn export_lfs_objects() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.lfs_export.{}", std::process::id()));
+        let objects_dir = base_dir.join("lfs").join("objects");
+        std::fs::create_dir_all(&base_dir).unwrap();
+        let store = Store::open_or_create(base_dir.join("store")).unwrap();
+
+        let mut staging_file = store.new_staging_file().unwrap();
+        std::io::Write::write_all(&mut staging_file, b"foo").unwrap();
+        let hash = store.make_permanent(staging_file).unwrap().hash().clone();
+        let missing = ContentHash::hash_bytes(SHA256, b"bar");
+
+        let report =
+            export_objects(&store, [hash.clone(), missing.clone()], &objects_dir, 2).unwrap();
+        assert_eq!(report.transferred, std::slice::from_ref(&hash));
+        assert_eq!(report.missing, [missing]);
+        assert_eq!(
+            std::fs::read(object_path(&objects_dir, &hash)).unwrap(),
+            b"foo"
+        );
+        let report = export_objects(&store, [hash.clone()], &objects_dir, 2).unwrap();
+        assert_eq!((report.transferred.len(), report.skipped), (0, 1));
+
+        let other = ContentHash::hash_bytes(SHA512_256, b"foo");
+        assert!(export_objects(&store, [other], &objects_dir, 2).is_err());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -1,2 +1,18 @@
+pub mod annex;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod attributes;
+pub mod backend;
+pub mod config;
+pub mod date;
+pub mod git;
 pub mod hash;
+pub mod history;
+pub mod hooks;
+pub mod lfs;
+pub mod media_type;
+pub mod metadata;
+pub mod parallel;
+pub mod server;
+pub mod signing;
 pub mod store;
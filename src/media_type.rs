@@ -0,0 +1,88 @@
+//! Guessing the media type of object contents, as recorded in `git-assets v2` pointers.
+//!
+//! Detection only looks at the first `SNIFF_LEN` bytes and knows the signatures of common
+//! asset formats. It is a hint for tools looking at pointers, not something to rely on.
+
+/// Number of bytes at the start of the contents that `detect` looks at.
+pub const SNIFF_LEN: usize = 512;
+
+/// Media type of contents that are neither recognized nor text.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Signatures at the start of the contents, most specific first.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"BM", "image/bmp"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"8BPS", "image/vnd.adobe.photoshop"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\0", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"glTF", "model/gltf-binary"),
+    (b"\x1a\x45\xdf\xa3", "video/x-matroska"),
+];
+
+/// Guess the media type of contents starting with `prefix`.
+pub fn detect(prefix: &[u8]) -> &'static str {
+    if let Some((_, media_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| prefix.starts_with(signature))
+    {
+        return media_type;
+    }
+    // Containers whose format is only given after the size of the first box or chunk
+    match (prefix.get(..4), prefix.get(4..8), prefix.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => return "image/webp",
+        (Some(b"RIFF"), _, Some(b"WAVE")) => return "audio/wav",
+        (Some(b"RIFF"), _, Some(b"AVI ")) => return "video/x-msvideo",
+        (_, Some(b"ftyp"), Some(b"qt  ")) => return "video/quicktime",
+        (_, Some(b"ftyp"), _) => return "video/mp4",
+        _ => {}
+    }
+    if is_text(prefix) {
+        "text/plain"
+    } else {
+        OCTET_STREAM
+    }
+}
+
+/// Whether the contents look like UTF-8 text. The prefix may end within a character.
+fn is_text(prefix: &[u8]) -> bool {
+    if prefix.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(prefix) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none() && prefix.len() - err.valid_up_to() < 4,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::detect;
+
+    #[test]
+    fn detect_media_types() {
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(detect(b"RIFF\x24\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(detect(b"\0\0\0\x18ftypmp42"), "video/mp4");
+        assert_eq!(detect(b"hello world\n"), "text/plain");
+        // Cut off in the middle of a multi-byte character
+        assert_eq!(detect("grüße".as_bytes().split_at(3).0), "text/plain");
+        assert_eq!(detect(b""), "text/plain");
+        assert_eq!(detect(b"\0\x01\x02"), "application/octet-stream");
+    }
+}
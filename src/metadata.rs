@@ -0,0 +1,176 @@
+//! What a store records about objects besides their contents: who stored them first, from
+//! which repository, and when, and labels like `license=cc0` that users tag them with.
+//!
+//! Local stores keep it as one JSON file per object in `metadata/`, named after the hash, see
+//! `Store::metadata`. Remote stores record nothing.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Everything recorded about an object. Objects without a file have the default, i.e. nothing.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObjectMetadata {
+    /// Where the object came from, unless it was stored before this was recorded.
+    pub provenance: Option<Provenance>,
+    /// Labels by their key, see `parse_label`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ObjectMetadata {
+    /// Whether nothing is recorded, so that the file can go.
+    pub fn is_empty(&self) -> bool {
+        self.provenance.is_none() && self.labels.is_empty()
+    }
+}
+
+/// Who stored an object first, from which repository, and when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The git identity of the user, `Name <email>`, if it is configured.
+    pub user: Option<String>,
+    /// The working tree, or the git directory of bare repositories, it was stored from.
+    pub repo: Option<PathBuf>,
+    /// When it was stored, in seconds since the unix epoch.
+    pub time: i64,
+}
+
+impl Provenance {
+    /// Provenance of objects stored just now.
+    pub fn new(user: Option<String>, repo: Option<PathBuf>) -> Provenance {
+        Provenance {
+            user,
+            repo,
+            time: now(),
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+/// Split a label given as `key=value` into its key and value.
+///
+/// Keys consist of letters, digits, `-`, `_` and `.`, so that they can't be mistaken for
+/// anything else in filters. Values may be anything, including empty.
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("not a label of the form key=value: {}", label))?;
+    check_key(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Fail unless `key` is a valid key of a label.
+pub fn check_key(key: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if key.is_empty() || !key.chars().all(valid) {
+        return Err(format!(
+            "invalid label key {:?}, only letters, digits, `-`, `_` and `.` are allowed",
+            key
+        ));
+    }
+    Ok(())
+}
+
+/// Selects objects by their labels. Written as one of
+///
+/// - `key=value`: objects that have the label with that value,
+/// - `key!=value`: objects that don't, including those without the label,
+/// - `key`: objects that have the label, with any value,
+/// - `!key`: objects that don't have the label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelFilter {
+    key: String,
+    /// The value that the label must have, or any value if `None`.
+    value: Option<String>,
+    /// Whether the objects that don't match are selected instead.
+    negated: bool,
+}
+
+impl LabelFilter {
+    /// Whether an object with the given labels is selected.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        let matches = match &self.value {
+            Some(value) => labels.get(&self.key) == Some(value),
+            None => labels.contains_key(&self.key),
+        };
+        matches != self.negated
+    }
+}
+
+impl FromStr for LabelFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value, negated) = if let Some((key, value)) = s.split_once("!=") {
+            (key, Some(value), true)
+        } else if let Some((key, value)) = s.split_once('=') {
+            (key, Some(value), false)
+        } else if let Some(key) = s.strip_prefix('!') {
+            (key, None, true)
+        } else {
+            (s, None, false)
+        };
+        check_key(key)?;
+        Ok(LabelFilter {
+            key: key.to_string(),
+            value: value.map(str::to_string),
+            negated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{parse_label, LabelFilter};
+
+    #[test]
+    fn labels_are_parsed() {
+        assert_eq!(
+            parse_label("license=cc0"),
+            Ok(("license".to_string(), "cc0".to_string()))
+        );
+        assert_eq!(
+            parse_label("a.b_c-1=x=y"),
+            Ok(("a.b_c-1".to_string(), "x=y".to_string()))
+        );
+        assert_eq!(
+            parse_label("empty="),
+            Ok(("empty".to_string(), String::new()))
+        );
+        assert!(parse_label("license").is_err());
+        assert!(parse_label("=cc0").is_err());
+        assert!(parse_label("bad key=1").is_err());
+    }
+
+    #[test]
+    fn label_filters_match() {
+        let labels: BTreeMap<String, String> = [("license", "cc0"), ("temporary", "true")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let matches = |filter: &str| filter.parse::<LabelFilter>().unwrap().matches(&labels);
+        assert!(matches("license=cc0"));
+        assert!(!matches("license=proprietary"));
+        assert!(!matches("license!=cc0"));
+        assert!(matches("license!=proprietary"));
+        assert!(matches("source!=vendorX"));
+        assert!(matches("temporary"));
+        assert!(!matches("!temporary"));
+        assert!(!matches("source"));
+        assert!(matches("!source"));
+        assert!("bad key".parse::<LabelFilter>().is_err());
+        assert!("!".parse::<LabelFilter>().is_err());
+    }
+}
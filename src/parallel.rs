@@ -0,0 +1,180 @@
+//! Spreading work over several threads while keeping the order of the results.
+//!
+//! Storing many objects is both IO and CPU bound: one thread reads the inputs, e.g. a stream
+//! that can only be read in order, while workers hash and store what was read. Since only a
+//! bounded number of inputs is in flight at any time, memory and open files stay bounded no
+//! matter how many inputs there are.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{mpsc, Mutex};
+
+/// Run `work` on each of the `inputs` on `jobs` threads, and hand the results to `done` in
+/// the order of the inputs.
+///
+/// The inputs are taken on the calling thread, and `done` runs on a thread of its own, so it
+/// may well be called while the next input is still awaited, e.g. in an interactive protocol.
+/// Only `2 * jobs` inputs are taken before the result of the first of them was handed to
+/// `done`. If `inputs` yields an error, the inputs before it are still finished before it is
+/// returned. If `done` fails, no more inputs are taken and its error is returned.
+pub fn map_ordered<I, O>(
+    jobs: usize,
+    mut inputs: impl Iterator<Item = io::Result<I>>,
+    work: impl Fn(I) -> O + Sync,
+    mut done: impl FnMut(O) -> io::Result<()> + Send,
+) -> io::Result<()>
+where
+    I: Send,
+    O: Send,
+{
+    let jobs = jobs.max(1);
+    let (input_sender, input_receiver) = mpsc::sync_channel::<(usize, I)>(jobs);
+    let input_receiver = Mutex::new(input_receiver);
+    let (output_sender, output_receiver) = mpsc::channel::<(usize, O)>();
+    // One ticket for every input that was taken, but whose result wasn't handed to `done` yet
+    let (ticket_sender, ticket_receiver) = mpsc::sync_channel::<()>(2 * jobs);
+
+    crossbeam_utils::thread::scope(|scope| {
+        for _ in 0..jobs {
+            let output_sender = output_sender.clone();
+            let input_receiver = &input_receiver;
+            let work = &work;
+            scope.spawn(move |_| loop {
+                let next = input_receiver.lock().expect("worker panicked").recv();
+                let (index, input) = match next {
+                    Ok(next) => next,
+                    Err(_) => return,
+                };
+                if output_sender.send((index, work(input))).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(output_sender);
+
+        let finisher = scope.spawn(move |_| {
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, output) in output_receiver {
+                pending.insert(index, output);
+                while let Some(output) = pending.remove(&next) {
+                    done(output)?;
+                    next += 1;
+                    let _ = ticket_receiver.try_recv();
+                }
+            }
+            Ok(())
+        });
+
+        let mut taken = Ok(());
+        for index in 0.. {
+            // Fails once `done` gave up
+            if ticket_sender.send(()).is_err() {
+                break;
+            }
+            match inputs.next() {
+                Some(Ok(input)) => {
+                    if input_sender.send((index, input)).is_err() {
+                        break;
+                    }
+                }
+                Some(Err(err)) => {
+                    taken = Err(err);
+                    break;
+                }
+                None => break,
+            }
+        }
+        drop(input_sender);
+        let finished = finisher.join().expect("finisher panicked");
+        finished.and(taken)
+    })
+    .expect("worker panicked")
+}
+
+#[cfg(test)]
+mod test {
+    use super::map_ordered;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn results_keep_the_order_of_the_inputs() {
+        for jobs in [0, 1, 4] {
+            let mut results = Vec::new();
+            map_ordered(
+                jobs,
+                (0..100u64).map(Ok),
+                |n| {
+                    // Later inputs tend to finish first
+                    std::thread::sleep(std::time::Duration::from_micros(100 - n));
+                    n * n
+                },
+                |square| {
+                    results.push(square);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(results, (0..100).map(|n| n * n).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn inputs_in_flight_are_bounded() {
+        let taken = AtomicUsize::new(0);
+        let mut most = 0;
+        map_ordered(
+            2,
+            (0..50).map(|n| {
+                taken.fetch_add(1, Ordering::SeqCst);
+                Ok(n)
+            }),
+            |n| n,
+            |n| {
+                // Inputs taken so far, minus the ones finished before this one
+                most = most.max(taken.load(Ordering::SeqCst) - n);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(most <= 4, "{} inputs in flight", most);
+    }
+
+    #[test]
+    fn errors_stop_taking_inputs() {
+        let mut results = Vec::new();
+        let err = map_ordered(
+            3,
+            (0..10).map(|n| match n {
+                5 => Err(io::Error::other("broken input")),
+                n => Ok(n),
+            }),
+            |n| n,
+            |n| {
+                results.push(n);
+                Ok(())
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "broken input");
+        assert_eq!(results, [0, 1, 2, 3, 4]);
+
+        let taken = AtomicUsize::new(0);
+        let err = map_ordered(
+            1,
+            (0..1000).map(|n| {
+                taken.fetch_add(1, Ordering::SeqCst);
+                Ok(n)
+            }),
+            |n| n,
+            |n| match n {
+                3 => Err(io::Error::other("broken output")),
+                _ => Ok(()),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "broken output");
+        assert!(taken.load(Ordering::SeqCst) < 10);
+    }
+}
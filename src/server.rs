@@ -0,0 +1,224 @@
+//! A small HTTP server for sharing a store, e.g. within a team, without setting up anything else.
+//!
+//! Objects are read with `GET /objects/<hash>`, checked for with `HEAD /objects/<hash>`, and
+//! uploaded with `PUT /objects/<hash>`, which only stores contents that match the hash. The
+//! directories of the store are served read-only as well, e.g. `GET /data/<shard>/<rest>`, so
+//! that the server can be used like any other store served over HTTP, see `HttpBackend`. The
+//! store is accessed through its backends, so it may itself be remote, e.g. in an S3 bucket.
+//!
+//! More stores can be served as namespaces, below `/<namespace>/`, e.g.
+//! `GET /confidential/objects/<hash>`, so that one server can host projects that are not
+//! meant for the same people. The served store itself is the namespace named `default`.
+//!
+//! Without tokens, anyone who can reach the server may read and upload objects. Once tokens are
+//! added, every request needs one as `Authorization: Bearer <token>`, and only tokens with the
+//! write scope may upload. Tokens may be limited to some namespaces, and the token `*` grants
+//! requests without a valid token access, e.g. to read a public namespace.
+//!
+//! Large objects can be uploaded in parts instead, and resumed after the connection broke off,
+//! see `upload`.
+//!
+//! After every upload, the server posts a JSON notification with the hash, size and namespace
+//! of the object and the id of the uploader's token to the webhooks, see `add_webhook`.
+//!
+//! With a certificate and private key, see `bind_tls`, the server only accepts HTTPS
+//! connections. Given certificate authorities for clients as well, it only accepts clients
+//! with a certificate issued by one of them, in addition to checking tokens.
+//!
+//! The server itself needs the `serve` feature. Token files and namespace names are handled
+//! here without it, so that they can be managed anywhere.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::hash::{ContentHash, SHA256};
+
+#[cfg(feature = "serve")]
+mod http;
+
+#[cfg(feature = "serve")]
+pub use http::Server;
+
+/// Name of the namespace of the store that is served at the root, e.g. in token files.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The token whose grants apply to every request, including those without a valid token.
+pub const ANONYMOUS_TOKEN: &str = "*";
+
+/// Names that can't be namespaces, since they are taken by the routes of a store.
+const RESERVED_NAMESPACES: &[&str] = &[
+    DEFAULT_NAMESPACE,
+    "objects",
+    "data",
+    "manifests",
+    "signatures",
+    "uploads",
+];
+
+/// What a token allows its bearer to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Reading and checking for objects.
+    Read,
+    /// Uploading objects, besides everything `Read` allows.
+    Write,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scope, String> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            _ => Err(format!("invalid scope {:?}, use read or write", s)),
+        }
+    }
+}
+
+/// Whether the name can be given to a namespace, see `Server::add_namespace`.
+pub fn is_valid_namespace(name: &str) -> bool {
+    !name.is_empty()
+        && !RESERVED_NAMESPACES.contains(&name)
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+/// A line of a token file, see `read_token_file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenGrant {
+    pub token: String,
+    pub scope: Scope,
+    /// The namespaces the token may be used in, or every one if empty.
+    pub namespaces: Vec<String>,
+}
+
+impl TokenGrant {
+    /// An identifier of the token that doesn't give it away, see `token_id`.
+    pub fn id(&self) -> String {
+        token_id(&self.token)
+    }
+}
+
+impl fmt::Display for TokenGrant {
+    /// Formats the grant as a line of a token file, without the newline.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.token, self.scope)?;
+        for namespace in &self.namespaces {
+            write!(f, " {}", namespace)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first 12 hex digits of the SHA-256 hash of a token, which identify it without giving
+/// it away, e.g. when listing tokens.
+pub fn token_id(token: &str) -> String {
+    let mut id = ContentHash::hash_bytes(SHA256, token.as_bytes()).to_hex_string();
+    id.truncate(12);
+    id
+}
+
+/// Read the tokens from a file with a token, its scope and optionally the namespaces it is
+/// limited to on each line, e.g. `s3cr3t write` or `s3cr3t read default public`. Empty lines
+/// and lines starting with `#` are skipped.
+pub fn read_token_file(path: &Path) -> io::Result<Vec<TokenGrant>> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_tokens(&contents).map_err(|message| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    })
+}
+
+fn parse_tokens(contents: &str) -> Result<Vec<TokenGrant>, String> {
+    let mut tokens = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        match parse_token_line(line) {
+            Ok(Some(grant)) => tokens.push(grant),
+            Ok(None) => {}
+            Err(message) => return Err(format!("line {}: {}", index + 1, message)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse a line of a token file, see `read_token_file`, or return `None` if it is empty or a
+/// comment.
+pub fn parse_token_line(line: &str) -> Result<Option<TokenGrant>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut fields = line.split_whitespace();
+    let (token, scope) = match (fields.next(), fields.next()) {
+        (Some(token), Some(scope)) => (token, scope),
+        _ => return Err("expected a token and its scope".to_string()),
+    };
+    let namespaces: Vec<String> = fields.map(str::to_string).collect();
+    if let Some(invalid) = namespaces
+        .iter()
+        .find(|name| name.as_str() != DEFAULT_NAMESPACE && !is_valid_namespace(name))
+    {
+        return Err(format!("invalid namespace {:?}", invalid));
+    }
+    Ok(Some(TokenGrant {
+        token: token.to_string(),
+        scope: scope.parse()?,
+        namespaces,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid_namespace, parse_tokens, Scope, TokenGrant};
+
+    #[test]
+    fn token_files() {
+        let grant = |token: &str, scope, namespaces: &[&str]| TokenGrant {
+            token: token.to_string(),
+            scope,
+            namespaces: namespaces.iter().map(|name| name.to_string()).collect(),
+        };
+        assert_eq!(
+            parse_tokens("# comment\n\nabc read\n  def write  \n* read default public\n"),
+            Ok(vec![
+                grant("abc", Scope::Read, &[]),
+                grant("def", Scope::Write, &[]),
+                grant("*", Scope::Read, &["default", "public"]),
+            ])
+        );
+        assert_eq!(
+            grant("*", Scope::Read, &["default", "public"]).to_string(),
+            "* read default public"
+        );
+        assert!(parse_tokens("abc\n").is_err());
+        assert!(parse_tokens("abc admin\n").is_err());
+        assert!(parse_tokens("abc read objects\n").is_err());
+        assert!(parse_tokens("abc read ../up\n").is_err());
+        assert!(Scope::Write > Scope::Read);
+    }
+
+    #[test]
+    fn namespaces() {
+        assert!(is_valid_namespace("team-1.confidential_x"));
+        for invalid in ["", "default", "objects", "data", "..", "a/b", "ü"] {
+            assert!(!is_valid_namespace(invalid), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Serving stores over HTTP, see the documentation of `server`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response};
+
+use super::{is_valid_namespace, token_id, Scope, ANONYMOUS_TOKEN, DEFAULT_NAMESPACE};
+use crate::backend::{StorageBackend, Timeouts};
+use crate::hash::ContentHash;
+use crate::store::{Store, StoreFileRef};
+
+#[cfg(unix)]
+mod tls;
+mod upload;
+
+/// Serves a store over HTTP, see the module documentation.
+pub struct Server {
+    http: tiny_http::Server,
+    /// `http` or `https`.
+    scheme: &'static str,
+    /// Accepts the HTTPS connections and forwards them to `http`.
+    #[cfg(unix)]
+    frontend: Option<tls::Frontend>,
+    store: Store,
+    /// The stores served below `/<namespace>/`, by namespace.
+    namespaces: HashMap<String, Store>,
+    /// The tokens that are accepted, with what they allow in which namespace, where `None`
+    /// stands for every namespace. Empty if no token is needed.
+    tokens: HashMap<String, HashMap<Option<String>, Scope>>,
+    /// URLs that are notified of uploads, see `add_webhook`.
+    webhooks: Vec<String>,
+    /// The client for the webhooks, once there is one.
+    agent: Option<ureq::Agent>,
+    /// The staging files of the resumable uploads that a request is working on.
+    uploads: Mutex<HashSet<String>>,
+}
+
+/// The notification posted to the webhooks after an upload.
+#[derive(Debug, Serialize)]
+struct Upload<'a> {
+    hash: &'a ContentHash,
+    size: u64,
+    namespace: &'a str,
+    /// The id of the token the object was uploaded with, see `token_id`.
+    uploader: Option<String>,
+}
+
+/// What a request refers to.
+enum Route {
+    /// The contents of an object, reassembled if it is stored in chunks.
+    Object(ContentHash),
+    /// A file in the `data/` directory of the store.
+    Data(ContentHash),
+    /// A file in the `manifests/` directory of the store.
+    Manifest(ContentHash),
+    /// A file in the `signatures/` directory of the store.
+    Signature(ContentHash),
+    /// A resumable upload of an object, with its size once it was started, see `upload`.
+    Upload(ContentHash, Option<u64>),
+    /// The name of the algorithm new objects are addressed by, like the `algorithm` file of a
+    /// local store, so that clients address objects they upload in the same way.
+    Algorithm,
+}
+
+impl Route {
+    /// The namespace a path is in, if it is not in the default one, and what it refers to.
+    fn parse(path: &str) -> Option<(Option<&str>, Route)> {
+        let path = path.trim_start_matches('/');
+        match Route::parse_in_namespace(path) {
+            Some(route) => Some((None, route)),
+            None => {
+                let (namespace, rest) = path.split_once('/')?;
+                Some((Some(namespace), Route::parse_in_namespace(rest)?))
+            }
+        }
+    }
+
+    fn parse_in_namespace(path: &str) -> Option<Route> {
+        let components: Vec<&str> = path.split('/').collect();
+        match components.as_slice() {
+            ["objects", hash] => ContentHash::from_hex(hash.as_bytes()).map(Route::Object),
+            ["data", shard, rest] => ContentHash::from_sharded(shard, rest).map(Route::Data),
+            ["manifests", shard, rest] => {
+                ContentHash::from_sharded(shard, rest).map(Route::Manifest)
+            }
+            ["signatures", shard, rest] => {
+                ContentHash::from_sharded(shard, rest).map(Route::Signature)
+            }
+            ["algorithm"] => Some(Route::Algorithm),
+            ["uploads", hash] => {
+                ContentHash::from_hex(hash.as_bytes()).map(|hash| Route::Upload(hash, None))
+            }
+            ["uploads", hash, length] => Some(Route::Upload(
+                ContentHash::from_hex(hash.as_bytes())?,
+                Some(length.parse().ok()?),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Server {
+    /// Listen on the given address, e.g. `127.0.0.1:8080`. With port 0, a free port is
+    /// picked, see `addr`.
+    pub fn bind(store: Store, addr: &str) -> io::Result<Server> {
+        let http = tiny_http::Server::http(addr)
+            .map_err(|err| io::Error::new(io::ErrorKind::AddrNotAvailable, err.to_string()))?;
+        Ok(Server {
+            http,
+            scheme: "http",
+            #[cfg(unix)]
+            frontend: None,
+            store,
+            namespaces: HashMap::new(),
+            tokens: HashMap::new(),
+            webhooks: Vec::new(),
+            agent: None,
+            uploads: Mutex::default(),
+        })
+    }
+
+    /// Listen for HTTPS connections on the given address, presenting the certificate chain
+    /// and using the private key read from the given PEM files.
+    ///
+    /// With `client_ca`, a PEM file with certificate authorities, only clients presenting a
+    /// certificate issued by one of them may connect. Only supported on Unix.
+    pub fn bind_tls(
+        store: Store,
+        addr: &str,
+        certificate: &Path,
+        private_key: &Path,
+        client_ca: Option<&Path>,
+    ) -> io::Result<Server> {
+        #[cfg(unix)]
+        {
+            let config = tls::server_config(certificate, private_key, client_ca)?;
+            let (frontend, http) = tls::Frontend::start(addr, config)?;
+            Ok(Server {
+                http,
+                scheme: "https",
+                frontend: Some(frontend),
+                store,
+                namespaces: HashMap::new(),
+                tokens: HashMap::new(),
+                webhooks: Vec::new(),
+                agent: None,
+                uploads: Mutex::default(),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (store, addr, certificate, private_key, client_ca);
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HTTPS is only supported on Unix",
+            ))
+        }
+    }
+
+    /// Serve another store below `/<name>/`. Fails if the name is taken, or is not made of
+    /// ASCII letters, digits, `-`, `_` and `.`.
+    pub fn add_namespace(&mut self, name: String, store: Store) -> io::Result<()> {
+        if !is_valid_namespace(&name) || self.namespaces.contains_key(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} can't be the name of a namespace", name),
+            ));
+        }
+        self.namespaces.insert(name, store);
+        Ok(())
+    }
+
+    /// Post a notification to the URL after every upload, once the client got its answer.
+    ///
+    /// The notification is a JSON object with the `hash` of the object, its `size`, the
+    /// `namespace` it was uploaded to and the `uploader`, the id of the token that was used
+    /// (see `token_id`), or `null` if none was. Failing to deliver it is logged, but doesn't
+    /// fail the upload.
+    pub fn add_webhook(&mut self, url: String) -> io::Result<()> {
+        if self.agent.is_none() {
+            self.agent = Some(Timeouts::from_env()?.agent().build());
+        }
+        self.webhooks.push(url);
+        Ok(())
+    }
+
+    /// Accept requests bearing the given token in the given namespace, or in every one if
+    /// `None`, for what the scope allows. Once a token is added, requests without a valid one
+    /// are rejected, unless `ANONYMOUS_TOKEN` is added as well.
+    pub fn add_token(&mut self, token: String, scope: Scope, namespace: Option<String>) {
+        let grants = self.tokens.entry(token).or_default();
+        let scope = grants.get(&namespace).map_or(scope, |old| scope.max(*old));
+        grants.insert(namespace, scope);
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        #[cfg(unix)]
+        if let Some(frontend) = &self.frontend {
+            return Some(frontend.addr);
+        }
+        self.http.server_addr().to_ip()
+    }
+
+    /// The URL the server is reached at, using the address it listens on.
+    pub fn url(&self) -> Option<String> {
+        self.addr()
+            .map(|addr| format!("{}://{}", self.scheme, addr))
+    }
+
+    /// Whether connections are encrypted.
+    pub fn is_tls(&self) -> bool {
+        self.scheme == "https"
+    }
+
+    /// Answer requests with `jobs` threads, until receiving requests fails.
+    ///
+    /// Failing to answer a single request, e.g. because the client went away, is logged but
+    /// doesn't stop the server.
+    pub fn run(&self, jobs: usize) -> io::Result<()> {
+        crossbeam_utils::thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs.max(1))
+                .map(|_| {
+                    scope.spawn(|_| loop {
+                        let request = self.http.recv()?;
+                        let description = format!("{} {}", request.method(), request.url());
+                        match self.handle(request) {
+                            Ok(status) => log::info!("{} -> {}", description, status),
+                            Err(err) => log::warn!("{} failed: {}", description, err),
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("server worker panicked"))
+        })
+        .expect("server worker panicked")
+    }
+
+    /// Answer a single request, returning the status code it was answered with.
+    fn handle(&self, mut request: Request) -> io::Result<u16> {
+        let path = request.url().split('?').next().unwrap_or_default();
+        let (namespace, route) = match Route::parse(path) {
+            Some(parsed) => parsed,
+            None => return respond_error(request, 404, "no such object"),
+        };
+        let (namespace, store) = match namespace {
+            None => (DEFAULT_NAMESPACE.to_string(), &self.store),
+            Some(name) => match self.namespaces.get(name) {
+                Some(store) => (name.to_string(), store),
+                None => return respond_error(request, 404, "no such namespace"),
+            },
+        };
+        let needed = match (request.method(), &route) {
+            (_, Route::Upload(..)) => Scope::Write,
+            (Method::Get | Method::Head, _) => Scope::Read,
+            _ => Scope::Write,
+        };
+        match self.authorize(&request, &namespace) {
+            Ok(Some(scope)) if scope >= needed => {}
+            Ok(Some(_)) => return respond_error(request, 403, "token is not allowed to upload"),
+            Ok(None) => {
+                return respond_error(request, 403, "token is not allowed in this namespace")
+            }
+            Err(message) => {
+                let challenge = Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+                    .expect("header is valid");
+                request.respond(
+                    Response::from_string(format!("{}\n", message))
+                        .with_status_code(401)
+                        .with_header(challenge),
+                )?;
+                return Ok(401);
+            }
+        }
+        let (data, manifests, signatures) = store.backends();
+        let uploader = bearer_token(&request)
+            .filter(|_| !self.tokens.is_empty())
+            .map(token_id);
+        match (request.method().clone(), route) {
+            (Method::Head, Route::Object(hash)) => {
+                match store.object_size(&StoreFileRef::from_hash(hash))? {
+                    Some(size) => respond(request, 200, size, io::empty()),
+                    None => respond_error(request, 404, "no such object"),
+                }
+            }
+            (Method::Get, Route::Object(hash)) => {
+                match store.open_ref(&StoreFileRef::from_hash(hash)) {
+                    Ok(contents) => respond(request, 200, contents.size(), contents),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        respond_error(request, 404, "no such object")
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            (Method::Put, Route::Object(hash)) => {
+                let mut staging_file = store.new_staging_file_with(hash.algorithm())?;
+                let size = match io::copy(request.as_reader(), &mut staging_file) {
+                    Ok(size) => size,
+                    Err(err) => {
+                        staging_file.discard()?;
+                        return Err(err);
+                    }
+                };
+                match store.make_permanent_verified(staging_file, &hash) {
+                    Ok(_) => {
+                        let status = respond(request, 201, 0, io::empty())?;
+                        self.notify(&Upload {
+                            hash: &hash,
+                            size,
+                            namespace: &namespace,
+                            uploader,
+                        });
+                        Ok(status)
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                        respond_error(request, 400, &err.to_string())
+                    }
+                    Err(err) => {
+                        respond_error(request, 500, &err.to_string())?;
+                        Err(err)
+                    }
+                }
+            }
+            (Method::Get | Method::Head, Route::Data(hash)) => serve_file(request, data, &hash),
+            (Method::Get | Method::Head, Route::Manifest(hash)) => {
+                serve_file(request, manifests, &hash)
+            }
+            (Method::Get | Method::Head, Route::Signature(hash)) => {
+                serve_file(request, signatures, &hash)
+            }
+            (Method::Get | Method::Head, Route::Algorithm) => {
+                let name = format!("{}\n", store.algorithm().name());
+                if request.method() == &Method::Head {
+                    respond(request, 200, name.len() as u64, io::empty())
+                } else {
+                    respond(request, 200, name.len() as u64, name.as_bytes())
+                }
+            }
+            (_, Route::Upload(hash, length)) => {
+                self.handle_upload(request, store, &namespace, hash, length, uploader)
+            }
+            _ => respond_error(request, 405, "method not allowed"),
+        }
+    }
+
+    /// What the request is allowed to do in the namespace, which is nothing if `None`, or why
+    /// it needs a valid token.
+    fn authorize(&self, request: &Request, namespace: &str) -> Result<Option<Scope>, &'static str> {
+        if self.tokens.is_empty() {
+            return Ok(Some(Scope::Write));
+        }
+        let scope_in = |grants: &HashMap<Option<String>, Scope>| {
+            let everywhere = grants.get(&None).copied();
+            everywhere.max(grants.get(&Some(namespace.to_string())).copied())
+        };
+        let anonymous = self.tokens.get(ANONYMOUS_TOKEN).and_then(scope_in);
+        let token = match bearer_token(request) {
+            Some(token) => token,
+            None if anonymous.is_some() => return Ok(anonymous),
+            None => return Err("missing token"),
+        };
+        // Compare against every token without stopping early, so that the time taken doesn't
+        // tell how much of a token was guessed correctly
+        self.tokens
+            .iter()
+            .filter(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+            .fold(None, |_, (_, grants)| Some(scope_in(grants).max(anonymous)))
+            .ok_or("invalid token")
+    }
+
+    /// Post the notification of an upload to every webhook.
+    fn notify(&self, upload: &Upload) {
+        let agent = match &self.agent {
+            Some(agent) => agent,
+            None => return,
+        };
+        let body = serde_json::to_string(upload).expect("notifications can be serialized");
+        for url in &self.webhooks {
+            let sent = agent
+                .post(url)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            if let Err(err) = sent {
+                log::warn!("notifying {} of {} failed: {}", url, upload.hash, err);
+            }
+        }
+    }
+}
+
+/// The token a request bears as `Authorization: Bearer <token>`, if any.
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Whether two byte strings are equal, taking the same time wherever they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answer with a file of the store as it is stored in `backend`.
+fn serve_file(
+    request: Request,
+    backend: &dyn StorageBackend,
+    hash: &ContentHash,
+) -> io::Result<u16> {
+    let size = match backend.size(hash)? {
+        Some(size) => size,
+        None => return respond_error(request, 404, "no such object"),
+    };
+    if request.method() == &Method::Head {
+        respond(request, 200, size, io::empty())
+    } else {
+        respond(request, 200, size, backend.get(hash)?)
+    }
+}
+
+/// Answer with the given contents of `size` bytes. For `HEAD` requests, only the size is sent.
+fn respond<R: Read>(request: Request, status: u16, size: u64, contents: R) -> io::Result<u16> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..])
+        .expect("header is valid");
+    request.respond(Response::new(
+        status.into(),
+        vec![content_type],
+        contents,
+        Some(size as usize),
+        None,
+    ))?;
+    Ok(status)
+}
+
+/// Answer with an error status and a message explaining it.
+fn respond_error(request: Request, status: u16, message: &str) -> io::Result<u16> {
+    request.respond(Response::from_string(format!("{}\n", message)).with_status_code(status))?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{constant_time_eq, Route};
+
+    #[test]
+    fn tokens_are_compared_in_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn routes() {
+        let hash = "2".repeat(64);
+        assert!(matches!(
+            Route::parse(&format!("/objects/{}", hash)),
+            Some((None, Route::Object(_)))
+        ));
+        assert!(matches!(
+            Route::parse(&format!("/public/data/22/{}", &hash[2..])),
+            Some((Some("public"), Route::Data(_)))
+        ));
+        assert!(matches!(
+            Route::parse("/public/algorithm"),
+            Some((Some("public"), Route::Algorithm))
+        ));
+        assert!(Route::parse(&format!("/a/b/objects/{}", hash)).is_none());
+    }
+}
@@ -0,0 +1,341 @@
+//! HTTPS for the server, with TLS terminated in front of the HTTP library.
+//!
+//! The HTTP library only supports an old version of rustls and can't ask clients for
+//! certificates. Instead, the HTTP server listens on a Unix socket in a directory that only the
+//! current user can access, and every connection accepted on the public address is forwarded to
+//! it in plain text, once its handshake, including the check of the client's certificate, has
+//! succeeded.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use rustls_pki_types::pem::{self, PemObject};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+/// How long a client may take for the handshake, so that idle connections don't keep threads
+/// around forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How old the directory of a server must be before it may be removed if nothing listens on
+/// its socket.
+const STALE_DIR_AGE: Duration = Duration::from_secs(60);
+
+/// Size of the buffers for forwarding data in either direction.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Accepts TLS connections and forwards them to the HTTP server, see the module documentation.
+pub struct Frontend {
+    /// The address clients connect to.
+    pub addr: SocketAddr,
+    /// Private directory with the socket of the HTTP server, removed when dropped.
+    socket_dir: PathBuf,
+}
+
+impl Frontend {
+    /// Listen on the given address and start accepting connections in the background, returning
+    /// the HTTP server they are forwarded to.
+    pub fn start(
+        addr: &str,
+        config: Arc<ServerConfig>,
+    ) -> io::Result<(Frontend, tiny_http::Server)> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|err| io::Error::new(io::ErrorKind::AddrNotAvailable, err.to_string()))?;
+        remove_stale_dirs();
+        let socket_dir = private_dir()?;
+        let socket = socket_dir.join("http.sock");
+        let frontend = Frontend {
+            addr: listener.local_addr()?,
+            socket_dir,
+        };
+        let http = tiny_http::Server::http_unix(&socket).map_err(io::Error::other)?;
+        std::thread::spawn(move || accept(listener, config, socket));
+        Ok((frontend, http))
+    }
+}
+
+impl Drop for Frontend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.socket_dir);
+    }
+}
+
+/// Read the certificate chain and private key to present, and the certificate authorities that
+/// client certificates must be issued by, if clients need one, from PEM files.
+pub fn server_config(
+    certificate: &Path,
+    private_key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certificates = read_certificates(certificate)?;
+    let key = match PrivateKeyDer::from_pem_slice(&read_file(private_key)?) {
+        Ok(key) => key,
+        Err(pem::Error::NoItemsFound) => {
+            return Err(invalid(private_key, "no private key found".into()))
+        }
+        Err(err) => return Err(invalid(private_key, err.to_string())),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for root in read_certificates(client_ca)? {
+                roots
+                    .add(root)
+                    .map_err(|err| invalid(client_ca, err.to_string()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|err| invalid(client_ca, err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certificates, key)
+        .map_err(|err| invalid(private_key, err.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// Read all certificates from a PEM file, of which there must be at least one.
+fn read_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(&read_file(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(path, err.to_string()))?;
+    if certificates.is_empty() {
+        return Err(invalid(path, "no certificate found".into()));
+    }
+    Ok(certificates)
+}
+
+/// Read a file, naming it in the error.
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn invalid(path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+/// Prefix of the private directories in the temporary directory.
+const DIR_PREFIX: &str = "git-assets-serve.";
+
+/// Remove the directories left behind by servers that were killed, which is how they usually
+/// stop. Directories are only removed once nothing listens on their socket any more, and not
+/// right after their creation, before the server had a chance to listen.
+fn remove_stale_dirs() {
+    let entries = match std::fs::read_dir(std::env::temp_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(DIR_PREFIX) {
+            continue;
+        }
+        let dir = entry.path();
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if age.map_or(true, |age| age < STALE_DIR_AGE) {
+            continue;
+        }
+        if UnixStream::connect(dir.join("http.sock")).is_err() {
+            // Fails for directories of other users, which is fine
+            if std::fs::remove_dir_all(&dir).is_ok() {
+                log::debug!("removed {} of a server that is gone", dir.display());
+            }
+        }
+    }
+}
+
+/// Create a directory that only the current user can access, under a name that nobody else
+/// has taken.
+fn private_dir() -> io::Result<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    let mut attempt = 0;
+    loop {
+        let dir = std::env::temp_dir().join(format!(
+            "{}{}.{}.{}",
+            DIR_PREFIX,
+            std::process::id(),
+            nanos,
+            attempt
+        ));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            result => return result.map(|()| dir),
+        }
+    }
+}
+
+/// Forward every connection accepted by `listener` to the HTTP server at `socket`, each in its
+/// own threads.
+fn accept(listener: TcpListener, config: Arc<ServerConfig>, socket: PathBuf) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("accepting a connection failed: {}", err);
+                continue;
+            }
+        };
+        let config = config.clone();
+        let socket = socket.clone();
+        std::thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown address".to_string(), |addr| addr.to_string());
+            let connection = match handshake(&stream, config) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log::warn!("TLS handshake with {} failed: {}", peer, err);
+                    return;
+                }
+            };
+            if let Err(err) = forward(stream, connection, &socket) {
+                log::debug!("connection from {} ended: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Complete the handshake, which fails for clients without an acceptable certificate if they
+/// need one.
+fn handshake(stream: &TcpStream, config: Arc<ServerConfig>) -> io::Result<ServerConnection> {
+    let mut connection = ServerConnection::new(config).map_err(io::Error::other)?;
+    let mut io = stream;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut io)?;
+    }
+    while connection.wants_write() {
+        connection.write_tls(&mut io)?;
+    }
+    stream.set_read_timeout(None)?;
+    Ok(connection)
+}
+
+/// Pass the decrypted requests to the HTTP server and its encrypted responses back, until
+/// either side closes the connection.
+fn forward(stream: TcpStream, connection: ServerConnection, socket: &Path) -> io::Result<()> {
+    let upstream = UnixStream::connect(socket)?;
+    let connection = Arc::new(Mutex::new(connection));
+    let responses = {
+        let connection = connection.clone();
+        let stream = stream.try_clone()?;
+        let upstream = upstream.try_clone()?;
+        std::thread::spawn(move || {
+            let result = send_responses(&connection, &upstream, &stream);
+            // Also stops waiting for requests, since the client can't get any more responses
+            let _ = stream.shutdown(Shutdown::Both);
+            result
+        })
+    };
+    let result = receive_requests(&connection, &stream, &upstream);
+    if result.is_err() {
+        let _ = upstream.shutdown(Shutdown::Both);
+    }
+    let sent = responses.join().expect("TLS forwarding thread panicked");
+    result.and(sent)
+}
+
+/// Decrypt what the client sends and pass it on to the HTTP server.
+fn receive_requests(
+    connection: &Mutex<ServerConnection>,
+    stream: &TcpStream,
+    upstream: &UnixStream,
+) -> io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut plaintext = Vec::new();
+    let (mut reader, mut writer) = (stream, stream);
+    let mut upstream = upstream;
+    // Nothing was read yet, but the handshake may already have received the first request
+    let mut n_read = 0;
+    let mut closed = false;
+    loop {
+        {
+            let mut connection = connection.lock().expect("TLS forwarding thread panicked");
+            let mut incoming = &buf[..n_read];
+            loop {
+                if !incoming.is_empty() {
+                    connection.read_tls(&mut incoming)?;
+                }
+                let state = match connection.process_new_packets() {
+                    Ok(state) => state,
+                    Err(err) => {
+                        // Tell the client what went wrong, if possible
+                        let _ = connection.write_tls(&mut writer);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                };
+                let start = plaintext.len();
+                plaintext.resize(start + state.plaintext_bytes_to_read(), 0);
+                connection.reader().read_exact(&mut plaintext[start..])?;
+                closed |= state.peer_has_closed();
+                if incoming.is_empty() {
+                    break;
+                }
+            }
+            while connection.wants_write() {
+                connection.write_tls(&mut writer)?;
+            }
+        }
+        // Without holding the lock, since the HTTP server may only read more once it has
+        // sent its response
+        upstream.write_all(&plaintext)?;
+        plaintext.clear();
+        if closed {
+            return upstream.shutdown(Shutdown::Write);
+        }
+        n_read = reader.read(&mut buf)?;
+        closed = n_read == 0;
+    }
+}
+
+/// Encrypt what the HTTP server answers and send it to the client.
+fn send_responses(
+    connection: &Mutex<ServerConnection>,
+    upstream: &UnixStream,
+    stream: &TcpStream,
+) -> io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let (mut upstream, mut writer) = (upstream, stream);
+    loop {
+        let n_read = upstream.read(&mut buf)?;
+        let mut connection = connection.lock().expect("TLS forwarding thread panicked");
+        if n_read == 0 {
+            connection.send_close_notify();
+        }
+        let mut outgoing = &buf[..n_read];
+        loop {
+            let n_written = connection.writer().write(outgoing)?;
+            outgoing = &outgoing[n_written..];
+            while connection.wants_write() {
+                connection.write_tls(&mut writer)?;
+            }
+            if outgoing.is_empty() {
+                break;
+            }
+        }
+        if n_read == 0 {
+            return Ok(());
+        }
+    }
+}
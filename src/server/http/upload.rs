@@ -0,0 +1,199 @@
+//! Uploads that can be resumed after the connection was lost, in the style of the tus protocol.
+//!
+//! `POST /uploads/<hash>` with the size of the object in `Upload-Length` starts an upload, or
+//! finds the one that was started before, and answers with its URL in `Location` and the number
+//! of bytes received so far in `Upload-Offset`. `PATCH <location>` appends the body to the
+//! upload, given the current offset in `Upload-Offset`, and `HEAD <location>` tells the offset
+//! again, e.g. after a connection broke off. Once all bytes are there, the contents are checked
+//! against the hash and the object is stored. `DELETE <location>` gives up on an upload.
+//!
+//! The received bytes are kept in the staging directory of the store, so that an upload can
+//! even be resumed after the server was restarted.
+
+use std::io::{self, Read};
+
+use tiny_http::{Header, Method, Request, Response};
+
+use super::{respond_error, Server, Upload};
+use crate::hash::ContentHash;
+use crate::store::{StagingFile, Store, StoreFileRef};
+
+/// The version of the tus protocol that the uploads follow.
+const TUS_VERSION: &str = "1.0.0";
+
+impl Server {
+    /// Answer a request for the upload of `hash` to the `store` of the `namespace`, whose size
+    /// is part of the URL once the upload was started.
+    pub(super) fn handle_upload(
+        &self,
+        mut request: Request,
+        store: &Store,
+        namespace: &str,
+        hash: ContentHash,
+        length: Option<u64>,
+        uploader: Option<String>,
+    ) -> io::Result<u16> {
+        let method = request.method().clone();
+        let length = match (&method, length) {
+            (Method::Post, None) => {
+                match header(&request, "Upload-Length").and_then(|value| value.parse().ok()) {
+                    Some(length) => {
+                        return self.start_upload(request, store, namespace, hash, length)
+                    }
+                    None => return respond_error(request, 400, "missing Upload-Length"),
+                }
+            }
+            (Method::Head | Method::Patch | Method::Delete, Some(length)) => length,
+            _ => return respond_error(request, 405, "method not allowed"),
+        };
+
+        // Only one request at a time may work on an upload, so that appends don't interleave
+        let name = staging_name(namespace, &hash, length);
+        if !self
+            .uploads
+            .lock()
+            .expect("not poisoned")
+            .insert(name.clone())
+        {
+            return respond_error(request, 409, "the upload is busy with another request");
+        }
+        let result = match store.resumable_staging_file(&name, hash.algorithm(), false) {
+            Ok(staging_file) => match method {
+                Method::Head => staging_file
+                    .size()
+                    .and_then(|offset| respond(request, 200, offset, Some(length), None, None)),
+                Method::Delete => staging_file
+                    .discard()
+                    .and_then(|()| respond(request, 204, 0, None, None, None)),
+                _ => {
+                    let upload = Upload {
+                        hash: &hash,
+                        size: length,
+                        namespace,
+                        uploader,
+                    };
+                    self.append(&mut request, store, staging_file, upload)
+                        .and_then(|(status, offset, message)| {
+                            respond(request, status, offset, None, None, message)
+                        })
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                respond_error(request, 404, "no such upload")
+            }
+            Err(err) => Err(err),
+        };
+        self.uploads.lock().expect("not poisoned").remove(&name);
+        result
+    }
+
+    /// Answer a `POST`, which starts the upload unless it was started before.
+    fn start_upload(
+        &self,
+        request: Request,
+        store: &Store,
+        namespace: &str,
+        hash: ContentHash,
+        length: u64,
+    ) -> io::Result<u16> {
+        if store
+            .object_size(&StoreFileRef::from_hash(hash.clone()))?
+            .is_some()
+        {
+            return respond(request, 200, length, Some(length), None, None);
+        }
+        let name = staging_name(namespace, &hash, length);
+        let offset = store
+            .resumable_staging_file(&name, hash.algorithm(), true)?
+            .size()?;
+        let prefix = match namespace {
+            super::DEFAULT_NAMESPACE => String::new(),
+            namespace => format!("/{}", namespace),
+        };
+        let location = format!("{}/uploads/{}/{}", prefix, hash, length);
+        respond(request, 201, offset, Some(length), Some(location), None)
+    }
+
+    /// Append the body of a `PATCH` to the upload, and store the object once it is complete.
+    /// Returns the status, the new offset and a message to answer with.
+    fn append(
+        &self,
+        request: &mut Request,
+        store: &Store,
+        mut staging_file: StagingFile,
+        upload: Upload,
+    ) -> io::Result<(u16, u64, Option<String>)> {
+        let offset = staging_file.size()?;
+        match header(request, "Upload-Offset").and_then(|value| value.parse::<u64>().ok()) {
+            Some(claimed) if claimed == offset => {}
+            Some(_) => return Ok((409, offset, Some("Upload-Offset doesn't match".into()))),
+            None => return Ok((400, offset, Some("missing Upload-Offset".into()))),
+        }
+        let body = request.as_reader();
+        // Bytes that arrived before the connection broke off are kept, that's the point
+        let appended = io::copy(&mut body.take(upload.size - offset), &mut staging_file)?;
+        let offset = offset + appended;
+        if body.read(&mut [0])? != 0 {
+            let message = "the body is longer than Upload-Length".to_string();
+            return Ok((400, offset, Some(message)));
+        }
+        if offset < upload.size {
+            return Ok((204, offset, None));
+        }
+        match store.make_permanent_verified(staging_file, upload.hash) {
+            Ok(_) => {
+                self.notify(&upload);
+                Ok((201, offset, None))
+            }
+            // The staging file is gone, and so is the upload
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Ok((400, offset, Some(err.to_string())))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Name of the staging file of an upload. Stores may share their staging directory, e.g. if
+/// they are remote, so the namespace is part of it.
+fn staging_name(namespace: &str, hash: &ContentHash, length: u64) -> String {
+    format!("upload.{}.{}.{}", namespace, hash, length)
+}
+
+/// The value of a header of the request, if it has one.
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().trim())
+}
+
+/// Answer with the offset of an upload, and optionally its length, its location and a message
+/// explaining the status.
+fn respond(
+    request: Request,
+    status: u16,
+    offset: u64,
+    length: Option<u64>,
+    location: Option<String>,
+    message: Option<String>,
+) -> io::Result<u16> {
+    let header = |name: &str, value: &str| {
+        Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header is valid")
+    };
+    let body = message.map_or(String::new(), |message| message + "\n");
+    let mut response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Tus-Resumable", TUS_VERSION))
+        .with_header(header("Upload-Offset", &offset.to_string()))
+        .with_header(header("Cache-Control", "no-store"));
+    if let Some(length) = length {
+        response.add_header(header("Upload-Length", &length.to_string()));
+    }
+    if let Some(location) = location {
+        response.add_header(header("Location", &location));
+    }
+    request.respond(response)?;
+    Ok(status)
+}
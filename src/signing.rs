@@ -0,0 +1,216 @@
+//! Signatures of objects, so that whoever retrieves an object can tell who introduced it.
+//!
+//! Signing an object means signing the line `git-assets object <hash>`, which identifies its
+//! contents, with the key that git signs commits with: `user.signingKey`, which is used by
+//! `ssh-keygen -Y sign` if `gpg.format` is `ssh`, and by gpg otherwise. A store keeps one
+//! signature per object, made by whoever stored it first, see `Store::add_signature`.
+//!
+//! Like `git verify-commit`, SSH signatures are checked against the allowed signers file named
+//! by `gpg.ssh.allowedSignersFile`, and OpenPGP signatures against the keys gpg knows.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::git::Repository;
+use crate::hash::ContentHash;
+
+/// What SSH signatures of objects are made for, so that they can't pass for signatures of
+/// anything else, e.g. of commits.
+pub const SSH_NAMESPACE: &str = "git-assets";
+
+const SSH_SIGNATURE_HEADER: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+const PGP_SIGNATURE_HEADER: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+/// What a signature says about an object, see `verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The object was signed by a trusted signer, known by the given name, e.g. an email
+    /// address.
+    Trusted(String),
+    /// The signature wasn't made for this object or with a trusted key, for the given reason.
+    Untrusted(String),
+}
+
+/// The data that is signed for an object.
+pub fn payload(hash: &ContentHash) -> String {
+    format!("git-assets object {}\n", hash)
+}
+
+/// Sign an object with the signing key configured for git in `repo`, returning the armored
+/// signature.
+///
+/// Fails with `NotFound` if SSH signing is configured without a key, and with `InvalidInput`
+/// for signature formats other than `ssh` and `openpgp`.
+pub fn sign(repo: &Repository, hash: &ContentHash) -> io::Result<Vec<u8>> {
+    let key = repo.config_get("user.signingKey")?;
+    let format = repo.config_get("gpg.format")?;
+    let output = match format.as_deref() {
+        Some("ssh") => {
+            let key = key.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "signing objects with ssh needs a key, see `git config user.signingKey`",
+                )
+            })?;
+            // Like git, the public key may be given inline, with the private key in the agent
+            let (key_file, _temp) = match key.strip_prefix("key::") {
+                Some(public_key) => {
+                    let temp = TempFile::with_contents(format!("{}\n", public_key).as_bytes())?;
+                    (temp.path.clone(), Some(temp))
+                }
+                None => (expand_home(&key), None),
+            };
+            let program = repo.config_get("gpg.ssh.program")?;
+            let mut command = Command::new(program.as_deref().unwrap_or("ssh-keygen"));
+            command
+                .args(["-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+                .arg(key_file);
+            run(command, payload(hash).as_bytes())?
+        }
+        Some("openpgp") | None => {
+            let program = repo.config_get("gpg.program")?;
+            let mut command = Command::new(program.as_deref().unwrap_or("gpg"));
+            command.arg("-bsa");
+            if let Some(key) = key {
+                command.args(["-u", &key]);
+            }
+            run(command, payload(hash).as_bytes())?
+        }
+        Some(other) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot sign objects with gpg.format {}", other),
+            ))
+        }
+    };
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "signing {} failed: {}",
+            hash,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Check a signature of an object against the signers that git trusts in `repo`.
+pub fn verify(repo: &Repository, hash: &ContentHash, signature: &[u8]) -> io::Result<Verification> {
+    if signature.starts_with(SSH_SIGNATURE_HEADER) {
+        let allowed_signers = match repo.config_get("gpg.ssh.allowedSignersFile")? {
+            Some(file) => expand_home(&file),
+            None => {
+                return Ok(Verification::Untrusted(
+                    "no signer is trusted, see `git config gpg.ssh.allowedSignersFile`".into(),
+                ))
+            }
+        };
+        let program = repo.config_get("gpg.ssh.program")?;
+        let program = program.as_deref().unwrap_or("ssh-keygen");
+        let signature_file = TempFile::with_contents(signature)?;
+
+        let mut command = Command::new(program);
+        command
+            .args(["-Y", "find-principals", "-s"])
+            .arg(&signature_file.path)
+            .arg("-f")
+            .arg(&allowed_signers);
+        let output = run(command, b"")?;
+        let principals = String::from_utf8_lossy(&output.stdout);
+        let principal = match principals.lines().next() {
+            Some(principal) if output.status.success() => principal.to_string(),
+            _ => {
+                return Ok(Verification::Untrusted(
+                    "not signed by an allowed signer".into(),
+                ))
+            }
+        };
+
+        let mut command = Command::new(program);
+        command
+            .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-I", &principal, "-f"])
+            .arg(&allowed_signers)
+            .arg("-s")
+            .arg(&signature_file.path);
+        let output = run(command, payload(hash).as_bytes())?;
+        Ok(if output.status.success() {
+            Verification::Trusted(principal)
+        } else {
+            Verification::Untrusted(format!("not a signature of {} by {}", hash, principal))
+        })
+    } else if signature.starts_with(PGP_SIGNATURE_HEADER) {
+        let program = repo.config_get("gpg.program")?;
+        let signature_file = TempFile::with_contents(signature)?;
+        let mut command = Command::new(program.as_deref().unwrap_or("gpg"));
+        command
+            .args(["--status-fd=1", "--verify"])
+            .arg(&signature_file.path)
+            .arg("-");
+        let output = run(command, payload(hash).as_bytes())?;
+        // Signatures by expired or revoked keys are reported differently
+        let signer = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+            .and_then(|rest| rest.split_once(' '))
+            .map(|(_, user)| user.to_string());
+        Ok(match signer {
+            Some(signer) if output.status.success() => Verification::Trusted(signer),
+            _ => Verification::Untrusted(format!("not a valid signature of {}", hash)),
+        })
+    } else {
+        Ok(Verification::Untrusted("unknown kind of signature".into()))
+    }
+}
+
+/// Run a signing program with `input` on stdin.
+fn run(mut command: Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // The payload is far smaller than a pipe buffer, so this can't block on a full stdout
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input)?;
+    child.wait_with_output()
+}
+
+/// Paths in the git config may start with `~/`, like in `core.excludesFile`.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A file for handing data to the signing programs, which is removed when dropped.
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn with_contents(contents: &[u8]) -> io::Result<TempFile> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "git-assets-signing.{}.{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(contents)?;
+        Ok(TempFile { path })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
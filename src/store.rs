@@ -1,23 +1,122 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 
-use crate::hash::Sha256Hash;
+#[cfg(feature = "http")]
+use crate::backend::http::HttpBackend;
+#[cfg(feature = "s3")]
+use crate::backend::s3::S3Backend;
+#[cfg(feature = "ssh")]
+use crate::backend::ssh::SshBackend;
+use crate::backend::{LocalBackend, StorageBackend};
+use crate::hash::{self, ContentHash, HashAlgorithm, Hasher};
+use crate::media_type;
+use crate::metadata::{ObjectMetadata, Provenance};
+
+#[cfg(feature = "async")]
+mod async_store;
+mod verified;
+
+#[cfg(feature = "async")]
+pub use async_store::AsyncStore;
+use verified::VerificationCache;
 
 #[derive(Debug)]
 pub struct Store {
     /// Root directory of the store
     base_dir: PathBuf,
-    /// Directory where the actual data is stored, in files named after the sha256 hash of their contents
-    data_dir: PathBuf,
+    /// Where the actual data is stored, see `LocalBackend` for the default layout in `data/`.
+    data: Box<dyn StorageBackend>,
     /// Directory for temp files created while storing files in the data directory.
     staging_dir: PathBuf,
     /// Directory for keeping references to the repositories that make use of this store.
+    ///
+    /// Every registered repository has a file named after the sha256 hash of the absolute path
+    /// of its git directory, containing that path followed by a newline. See `register_repo`.
     ref_dir: PathBuf,
+    /// Where the chunk manifests of objects stored in chunks are kept, by default in `manifests/`.
+    manifests: Box<dyn StorageBackend>,
+    /// Where the signatures of objects are kept, by default in `signatures/`, see `signing`.
+    signatures: Box<dyn StorageBackend>,
+    /// Whether large objects are split into chunks when they are made permanent.
+    chunked: bool,
+    /// Where objects missing in this store are downloaded from when they are read.
+    remote: Option<Box<Store>>,
+    /// The algorithm that new objects are addressed by.
+    algorithm: &'static dyn HashAlgorithm,
+    /// Whether removing objects and repairs only report what they would do, see `set_dry_run`.
+    dry_run: bool,
+    /// Whether objects are hashed again while they are read, see `set_verify_reads`.
+    verify_reads: bool,
+    /// Recorded for objects that are new to the store, see `set_provenance`.
+    provenance: Option<Provenance>,
+    /// Held from checking the quota until the object is stored, so that objects stored on
+    /// several threads at once don't exceed it together.
+    quota_lock: Mutex<()>,
 }
 
+/// Version of the store layout, recorded in the `version` file of the store.
+///
+/// Stores without that file use the original layout (version 1), where all objects are
+/// directly inside the data directory.
+const LAYOUT_VERSION: u32 = 2;
+
+/// Objects of at least this size are split into chunks when chunking is enabled.
+const CHUNKING_THRESHOLD: u64 = 8 * 1024 * 1024;
+/// Size bounds passed to the content defined chunker.
+const CHUNK_MIN_SIZE: u32 = 256 * 1024;
+const CHUNK_AVG_SIZE: u32 = 1024 * 1024;
+const CHUNK_MAX_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Unreferenced objects stored less than this long ago are kept by `collect_garbage`.
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Staging files that are older than this are removed by `clean_stale_staging`.
+const STALE_STAGING_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// `clean_stale_staging` looks for stale staging files at most this often.
+const STALE_STAGING_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of objects listed in `StoreStats::largest`.
+const LARGEST_OBJECTS: usize = 10;
+
+/// File in the store remembering which objects passed validation, see `verified`.
+const VERIFICATION_CACHE: &str = "verified";
+
+/// File in the store caching the total size of the objects in `data/`, so that checking the
+/// quota doesn't need to list them all, see `used_bytes`.
+const USAGE_FILE: &str = "usage";
+
+/// File in the store holding its `StoreConfig`.
+const CONFIG_FILE: &str = "config.json";
+
+/// Directory in the store with an empty file, named after the hash, for every pinned object.
+const PIN_DIR: &str = "pins";
+
+/// Directory in the store with a JSON file for every object that something is recorded about,
+/// named after the hash, see `metadata`.
+const METADATA_DIR: &str = "metadata";
+
+/// Directory in the store with an empty file for every object that was read, whose modification
+/// time is when the object was last read.
+const ACCESS_DIR: &str = "access";
+
+/// File in the store naming the algorithm that new objects are addressed by, see
+/// `Store::open_or_create_with`. Stores without it use SHA-256.
+const ALGORITHM_FILE: &str = "algorithm";
+
+/// Directory in the store that `Store::repair` moves corrupt objects into.
+const LOST_AND_FOUND: &str = "lost+found";
+
+/// First line of a chunk manifest. It is followed by one `<chunk-hash> <size>` line per chunk.
+const MANIFEST_HEADER: &str = "git-assets chunks v1\n";
+
 macro_rules! may_already_exist {
     ($ioresult:expr) => {
         match $ioresult {
@@ -37,178 +136,2588 @@ impl Store {
         let data_dir = base_dir.join("data");
         let staging_dir = base_dir.join("staging");
         let ref_dir = base_dir.join("ref");
+        let manifest_dir = base_dir.join("manifests");
+        let signature_dir = base_dir.join("signatures");
 
         may_already_exist!(std::fs::create_dir(&base_dir))?;
         may_already_exist!(std::fs::create_dir(&data_dir))?;
         may_already_exist!(std::fs::create_dir(&staging_dir))?;
         may_already_exist!(std::fs::create_dir(&ref_dir))?;
+        may_already_exist!(std::fs::create_dir(&manifest_dir))?;
+        may_already_exist!(std::fs::create_dir(&signature_dir))?;
 
-        Ok(Store {
+        let store = Store {
+            algorithm: read_algorithm(&base_dir)?,
             base_dir,
-            data_dir,
+            data: Box::new(LocalBackend::new(data_dir)),
             staging_dir,
             ref_dir,
+            manifests: Box::new(LocalBackend::new(manifest_dir)),
+            signatures: Box::new(LocalBackend::new(signature_dir)),
+            chunked: false,
+            remote: None,
+            dry_run: false,
+            verify_reads: false,
+            provenance: None,
+            quota_lock: Mutex::default(),
+        };
+        store.upgrade_layout()?;
+        Ok(store)
+    }
+
+    /// Like `open_or_create`, but address new objects by `algorithm` instead of SHA-256.
+    ///
+    /// The algorithm can only be chosen while the store is empty, i.e. when it is created.
+    /// Opening a store that already holds objects addressed by another algorithm fails with
+    /// `AlreadyExists`.
+    pub fn open_or_create_with(
+        base_dir: PathBuf,
+        algorithm: &'static dyn HashAlgorithm,
+    ) -> io::Result<Store> {
+        let mut store = Store::open_or_create(base_dir)?;
+        if store.algorithm.name() == algorithm.name() {
+            return Ok(store);
+        }
+        if !store.data.list()?.objects.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} already holds objects addressed by {}",
+                    store.base_dir.display(),
+                    store.algorithm.name()
+                ),
+            ));
+        }
+        std::fs::write(
+            store.base_dir.join(ALGORITHM_FILE),
+            format!("{}\n", algorithm.name()),
+        )?;
+        store.algorithm = algorithm;
+        Ok(store)
+    }
+
+    /// Open an existing store without creating anything, e.g. a store belonging to another repository.
+    pub fn open(base_dir: PathBuf) -> io::Result<Store> {
+        let data_dir = base_dir.join("data");
+        if !data_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("not a git-assets store: {}", base_dir.display()),
+            ));
+        }
+        let store = Store {
+            data: Box::new(LocalBackend::new(data_dir)),
+            staging_dir: base_dir.join("staging"),
+            ref_dir: base_dir.join("ref"),
+            manifests: Box::new(LocalBackend::new(base_dir.join("manifests"))),
+            signatures: Box::new(LocalBackend::new(base_dir.join("signatures"))),
+            chunked: false,
+            remote: None,
+            dry_run: false,
+            verify_reads: false,
+            provenance: None,
+            quota_lock: Mutex::default(),
+            algorithm: read_algorithm(&base_dir)?,
+            base_dir,
+        };
+        store.upgrade_layout()?;
+        Ok(store)
+    }
+
+    /// Use the given backends for objects, chunk manifests and signatures, e.g. on a remote
+    /// server.
+    ///
+    /// `location` only describes the store in messages. New objects are addressed by
+    /// `algorithm`, which must be the one recorded by the store on the server, and staged in
+    /// `staging_dir` before being handed to the backend, which is created as accessible only by
+    /// the current user if it doesn't exist yet.
+    pub fn with_backends(
+        location: PathBuf,
+        staging_dir: PathBuf,
+        algorithm: &'static dyn HashAlgorithm,
+        data: Box<dyn StorageBackend>,
+        manifests: Box<dyn StorageBackend>,
+        signatures: Box<dyn StorageBackend>,
+    ) -> io::Result<Store> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&staging_dir)?;
+        Ok(Store {
+            ref_dir: location.join("ref"),
+            base_dir: location,
+            data,
+            staging_dir,
+            manifests,
+            signatures,
+            chunked: false,
+            remote: None,
+            dry_run: false,
+            verify_reads: false,
+            provenance: None,
+            quota_lock: Mutex::default(),
+            algorithm,
         })
     }
 
+    /// Open a remote store given by a URL such as `s3://bucket/prefix`.
+    ///
+    /// Objects, manifests and signatures are laid out below the URL just like in a local
+    /// store, and new objects are addressed by the algorithm named in its `algorithm` file, or
+    /// SHA-256 without one. Stores given by `http://` or `https://` URLs are read-only. New objects are staged
+    /// in `staging_dir`, which must not be shared with other users, see `with_backends`.
+    ///
+    /// Each kind of URL needs the cargo feature of the same name, e.g. `s3`, and fails with
+    /// `Unsupported` without it.
+    #[cfg_attr(not(any(feature = "http", feature = "ssh")), allow(unused_variables))]
+    pub fn open_url(url: &str, staging_dir: PathBuf) -> io::Result<Store> {
+        match url.split_once("://").map(|(scheme, _)| scheme) {
+            #[cfg(feature = "s3")]
+            Some("s3") => {
+                let bucket = S3Backend::from_url(url)?;
+                let algorithm =
+                    parse_algorithm(bucket.read_file(ALGORITHM_FILE)?.as_deref(), Path::new(url))?;
+                Store::with_backends(
+                    PathBuf::from(url),
+                    staging_dir,
+                    algorithm,
+                    Box::new(bucket.join("data")),
+                    Box::new(bucket.join("manifests")),
+                    Box::new(bucket.join("signatures")),
+                )
+            }
+            #[cfg(feature = "ssh")]
+            Some("ssh") => {
+                let dir = SshBackend::from_url(url)?;
+                let algorithm =
+                    parse_algorithm(dir.read_file(ALGORITHM_FILE)?.as_deref(), Path::new(url))?;
+                Store::with_backends(
+                    PathBuf::from(url),
+                    staging_dir,
+                    algorithm,
+                    Box::new(dir.join("data")),
+                    Box::new(dir.join("manifests")),
+                    Box::new(dir.join("signatures")),
+                )
+            }
+            #[cfg(feature = "http")]
+            Some("http") | Some("https") => {
+                let server = HttpBackend::new(url)?;
+                let mut manifests = server.join("manifests");
+                manifests.set_verify(false);
+                let mut signatures = server.join("signatures");
+                signatures.set_verify(false);
+                let algorithm =
+                    parse_algorithm(server.read_file(ALGORITHM_FILE)?.as_deref(), Path::new(url))?;
+                Store::with_backends(
+                    PathBuf::from(url),
+                    staging_dir,
+                    algorithm,
+                    Box::new(server.join("data")),
+                    Box::new(manifests),
+                    Box::new(signatures),
+                )
+            }
+            // Only reached for the kinds of URLs whose features are off
+            #[allow(unreachable_patterns)]
+            Some(scheme @ ("s3" | "ssh" | "http" | "https")) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{}:// stores need git-assets built with the `{}` feature",
+                    scheme,
+                    if scheme == "https" { "http" } else { scheme }
+                ),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported store url: {}", url),
+            )),
+        }
+    }
+
+    /// Bring a store using an older layout up to date.
+    ///
+    /// Objects of flat (version 1) stores are moved into their shard directories. This is safe
+    /// to run concurrently, since every object is moved by a single atomic rename.
+    fn upgrade_layout(&self) -> io::Result<()> {
+        let version_file = self.base_dir.join("version");
+        match std::fs::read_to_string(&version_file) {
+            Ok(contents) => {
+                return match contents.trim().parse::<u32>() {
+                    Ok(version) if version <= LAYOUT_VERSION => Ok(()),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "unsupported store version {:?} in {}",
+                            contents.trim(),
+                            self.base_dir.display()
+                        ),
+                    )),
+                };
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        for entry_or_error in self.base_dir.join("data").read_dir()? {
+            let entry = entry_or_error?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(hash) = hash_from_file_name(&entry.path()) {
+                match self.data.put(&hash, &entry.path()) {
+                    // Another process was faster
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+        }
+
+        std::fs::write(version_file, format!("{}\n", LAYOUT_VERSION))
+    }
+
+    /// Record that the repository with the given git directory uses this store.
+    ///
+    /// Every repository gets a file in `ref/`, named after the hash of its path and containing
+    /// that path. Registering a repository again does nothing.
+    pub fn register_repo(&self, git_dir: &Path) -> io::Result<()> {
+        self.ensure_local("repositories can only be registered with local stores")?;
+        let git_dir = git_dir.canonicalize()?;
+        std::fs::create_dir_all(&self.ref_dir)?;
+        std::fs::write(
+            self.ref_dir.join(ref_name(&git_dir)?),
+            format!("{}\n", git_dir.display()),
+        )
+    }
+
+    /// Remove the registration of a repository, returning whether it was registered.
+    ///
+    /// The git directory does not need to exist anymore, but then it has to be given by the
+    /// same absolute path it was registered with.
+    pub fn unregister_repo(&self, git_dir: &Path) -> io::Result<bool> {
+        self.ensure_local("repositories can only be registered with local stores")?;
+        let git_dir = git_dir
+            .canonicalize()
+            .unwrap_or_else(|_| git_dir.to_path_buf());
+        match std::fs::remove_file(self.ref_dir.join(ref_name(&git_dir)?)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return the git directories of all repositories registered with this store, sorted.
+    ///
+    /// Files in `ref/` that are not in the expected format are skipped, see `validate`.
+    pub fn registered_repos(&self) -> io::Result<Vec<PathBuf>> {
+        if is_url(&self.base_dir) {
+            return Ok(Vec::new());
+        }
+        let mut repos = Vec::new();
+        for entry in read_dir_if_exists(&self.ref_dir)? {
+            if let Ok(Some(git_dir)) = read_ref(&entry?.path()) {
+                repos.push(git_dir);
+            }
+        }
+        repos.sort();
+        Ok(repos)
+    }
+
+    /// Protect an object from garbage collection, even when nothing references it anymore.
+    /// Returns whether the object was not pinned before.
+    pub fn pin(&self, hash: &ContentHash) -> io::Result<bool> {
+        self.ensure_local("objects can only be pinned in local stores")?;
+        let pin_dir = self.base_dir.join(PIN_DIR);
+        std::fs::create_dir_all(&pin_dir)?;
+        let created = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(pin_dir.join(hash.to_hex_string()));
+        match created {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Remove the pin of an object, returning whether it was pinned.
+    pub fn unpin(&self, hash: &ContentHash) -> io::Result<bool> {
+        self.ensure_local("objects can only be pinned in local stores")?;
+        let pin = self.base_dir.join(PIN_DIR).join(hash.to_hex_string());
+        match std::fs::remove_file(pin) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return all pinned objects, sorted.
+    pub fn pinned(&self) -> io::Result<Vec<ContentHash>> {
+        if is_url(&self.base_dir) {
+            return Ok(Vec::new());
+        }
+        let mut pinned = Vec::new();
+        for entry in read_dir_if_exists(&self.base_dir.join(PIN_DIR))? {
+            if let Some(hash) = hash_from_file_name(&entry?.path()) {
+                pinned.push(hash);
+            }
+        }
+        pinned.sort();
+        Ok(pinned)
+    }
+
+    fn ensure_local(&self, message: &str) -> io::Result<()> {
+        if is_url(&self.base_dir) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        Ok(())
+    }
+
+    /// Split objects of at least 8 MiB into content defined chunks when making them permanent.
+    ///
+    /// Chunks are stored like any other object, so revisions of a large file that differ only
+    /// slightly share most of their chunks. Off by default.
+    pub fn set_chunked(&mut self, chunked: bool) {
+        self.chunked = chunked;
+    }
+
+    /// Record who stores objects in this store and from which repository, for the objects it
+    /// doesn't have yet, see `metadata`. The time is replaced by when each object is stored.
+    /// Remote stores record nothing.
+    pub fn set_provenance(&mut self, provenance: Provenance) {
+        self.provenance = Some(provenance);
+    }
+
+    /// Only report what `remove`, `collect_garbage`, `prune`, `evict`, `clean_staging` and
+    /// `repair` would delete, without changing anything. Off by default.
+    ///
+    /// Objects made permanent are still hashed and checked, but then discarded, so that
+    /// e.g. a migration can report what it would store.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether this store only reports what it would delete, see `set_dry_run`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Hash the contents of objects again while they are read with `open_ref`, so that
+    /// corruption of the store, e.g. by bit rot, is noticed. Off by default.
+    ///
+    /// Reading the end of a corrupt object then fails with `InvalidData` instead of returning
+    /// the end of the contents.
+    pub fn set_verify_reads(&mut self, verify_reads: bool) {
+        self.verify_reads = verify_reads;
+    }
+
+    /// The backends holding the objects, the chunk manifests and the signatures, e.g. for
+    /// serving them as they are.
+    #[cfg(feature = "serve")]
+    pub(crate) fn backends(
+        &self,
+    ) -> (
+        &dyn StorageBackend,
+        &dyn StorageBackend,
+        &dyn StorageBackend,
+    ) {
+        (&*self.data, &*self.manifests, &*self.signatures)
+    }
+
+    /// Use this store as a local cache of `remote`: objects that are missing here are
+    /// downloaded from `remote` when they are opened, and kept here afterwards. So are
+    /// signatures, see `signature`.
+    pub fn set_remote(&mut self, remote: Store) {
+        self.remote = Some(Box::new(remote));
+    }
+
+    /// Read the settings of the store. Stores without settings, including remote stores, use
+    /// the defaults.
+    pub fn config(&self) -> io::Result<StoreConfig> {
+        if is_url(&self.base_dir) {
+            return Ok(StoreConfig::default());
+        }
+        match std::fs::read(self.base_dir.join(CONFIG_FILE)) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid {}: {}",
+                        self.base_dir.join(CONFIG_FILE).display(),
+                        err
+                    ),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(StoreConfig::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replace the settings of the store.
+    pub fn set_config(&self, config: &StoreConfig) -> io::Result<()> {
+        self.ensure_local("only local stores have settings")?;
+        let contents = serde_json::to_vec_pretty(config)?;
+        let (temp_path, mut file) = new_temp_file(&self.staging_dir, "config", "json")?;
+        let written = file
+            .write_all(&contents)
+            .and_then(|()| file.sync_all())
+            .and_then(|()| std::fs::rename(&temp_path, self.base_dir.join(CONFIG_FILE)));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        written
+    }
+
+    /// Read the list of chunks, with their sizes, that the object with the given hash consists of.
+    fn read_manifest(&self, hash: &ContentHash) -> io::Result<Vec<(ContentHash, u64)>> {
+        let mut contents = String::new();
+        self.manifests.get(hash)?.read_to_string(&mut contents)?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid chunk manifest for {}", hash),
+            )
+        };
+        let lines = contents.strip_prefix(MANIFEST_HEADER).ok_or_else(invalid)?;
+        lines
+            .lines()
+            .map(|line| {
+                let (chunk, size) = line.split_once(' ').ok_or_else(invalid)?;
+                let chunk = ContentHash::from_hex(chunk.as_bytes()).ok_or_else(invalid)?;
+                let size = size.parse().map_err(|_| invalid())?;
+                Ok((chunk, size))
+            })
+            .collect()
+    }
+
+    /// Split the staged file into chunks, store the ones that are new and write the manifest
+    /// for the object with the given hash.
+    fn store_chunked(&self, staged: &Path, hash: &ContentHash) -> io::Result<()> {
+        let mut manifest = String::from(MANIFEST_HEADER);
+        let chunker = fastcdc::v2020::StreamCDC::new(
+            File::open(staged)?,
+            CHUNK_MIN_SIZE,
+            CHUNK_AVG_SIZE,
+            CHUNK_MAX_SIZE,
+        );
+        for chunk_or_error in chunker {
+            let chunk = chunk_or_error?;
+            let chunk_hash = ContentHash::hash_bytes(hash.algorithm(), &chunk.data);
+            // Unchanged parts of earlier revisions are already there
+            if !self.data.exists(&chunk_hash)? {
+                self.put_bytes(&*self.data, &chunk_hash, &chunk.data)?;
+            }
+            manifest.push_str(&format!("{} {}\n", chunk_hash, chunk.length));
+        }
+        // The manifest comes last, so that it never refers to chunks that are not there yet.
+        self.put_bytes(&*self.manifests, hash, manifest.as_bytes())
+    }
+
+    /// Put an object with the given contents into a backend, going through the staging directory.
+    fn put_bytes(
+        &self,
+        backend: &dyn StorageBackend,
+        hash: &ContentHash,
+        contents: &[u8],
+    ) -> io::Result<()> {
+        let (temp_path, mut file) = new_temp_file(&self.staging_dir, "install", "")?;
+        let result = file
+            .write_all(contents)
+            .and_then(|()| backend.put(hash, &temp_path));
+        drop(file);
+        if result.is_err() {
+            std::fs::remove_file(temp_path)?;
+        }
+        result
+    }
+
+    /// Root directory of the store.
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// The algorithm that new objects are addressed by.
+    pub fn algorithm(&self) -> &'static dyn HashAlgorithm {
+        self.algorithm
+    }
+
     pub fn new_staging_file(&self) -> io::Result<StagingFile> {
+        self.new_staging_file_with(self.algorithm)
+    }
+
+    /// Like `new_staging_file`, but if the algorithm of the store supports it, the contents are
+    /// only hashed once they are complete, by all cores at once, e.g. for contents arriving on
+    /// a pipe faster than a single core can hash them.
+    pub fn new_staging_file_parallel(&self) -> io::Result<StagingFile> {
+        let mut staging_file = self.new_staging_file()?;
+        staging_file.deferred = self.algorithm.is_parallel();
+        Ok(staging_file)
+    }
+
+    /// Like `new_staging_file`, but hash the contents with the given algorithm, e.g. for
+    /// copying an object of another store whose hash is already known.
+    pub fn new_staging_file_with(
+        &self,
+        algorithm: &'static dyn HashAlgorithm,
+    ) -> io::Result<StagingFile> {
         let (path, file) = new_temp_file(&self.staging_dir, "smudge", "")?;
-        Ok(StagingFile::new(path, file))
+        Ok(StagingFile::new(path, file, algorithm))
+    }
+
+    /// Like `new_staging_file_with`, but the contents are only hashed once they are complete,
+    /// by the thread making them permanent, e.g. so that one thread reading a stream can stage
+    /// objects while others hash them.
+    pub fn new_deferred_staging_file_with(
+        &self,
+        algorithm: &'static dyn HashAlgorithm,
+    ) -> io::Result<StagingFile> {
+        let mut staging_file = self.new_staging_file_with(algorithm)?;
+        staging_file.deferred = true;
+        Ok(staging_file)
+    }
+
+    /// Open the staging file of an upload that can be resumed, e.g. once a client reconnects.
+    ///
+    /// Unlike other staging files, it is named `name`, so that it is found again until it is
+    /// made permanent, discarded or removed as stale, see `clean_stale_staging`. Contents are
+    /// appended to it, and only hashed once they are complete. Unless `create` is set, fails
+    /// with `NotFound` if it doesn't exist yet.
+    pub fn resumable_staging_file(
+        &self,
+        name: &str,
+        algorithm: &'static dyn HashAlgorithm,
+        create: bool,
+    ) -> io::Result<StagingFile> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid name for a staging file: {:?}", name),
+            ));
+        }
+        let filename = self.staging_dir.join(name);
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(create)
+            .open(&filename)?;
+        let mut staging_file = StagingFile::new(filename, file, algorithm);
+        staging_file.deferred = true;
+        Ok(staging_file)
     }
 
     pub fn make_permanent(&self, staging_file: StagingFile) -> io::Result<StoreFileRef> {
+        let hash = staging_file.hash()?;
+        self.make_permanent_as(staging_file, hash)
+    }
+
+    /// Make the staging file permanent under the given hash, which must be the hash of its
+    /// contents, e.g. because it was computed in parallel instead of while writing.
+    fn make_permanent_as(
+        &self,
+        staging_file: StagingFile,
+        hash: ContentHash,
+    ) -> io::Result<StoreFileRef> {
         drop(staging_file.file); // close the file
-        let hash: Sha256Hash = staging_file.hasher.into();
-        let final_path = self.data_dir.join(format!("{}", hash));
 
-        // If the file already exists, we can still safely overwrite it because
-        // if they have the same name, they will have the same contents.
-        std::fs::rename(staging_file.filename, &final_path)?;
+        let filename = staging_file.filename;
+        let quota = match self.check_quota(&hash, &filename) {
+            Ok(quota) => quota,
+            Err(err) => {
+                std::fs::remove_file(&filename)?;
+                return Err(err);
+            }
+        };
+
+        let size = std::fs::metadata(&filename)?.len();
+        let provenance = match &self.provenance {
+            Some(provenance)
+                if !self.dry_run
+                    && !is_url(&self.base_dir)
+                    && !self.data.exists(&hash)?
+                    && !self.manifests.exists(&hash)? =>
+            {
+                Some(Provenance::new(
+                    provenance.user.clone(),
+                    provenance.repo.clone(),
+                ))
+            }
+            _ => None,
+        };
+        let mut prefix = Vec::with_capacity(media_type::SNIFF_LEN);
+        File::open(&filename)?
+            .take(media_type::SNIFF_LEN as u64)
+            .read_to_end(&mut prefix)?;
 
-        let store_file = StoreFileRef { hash };
+        if self.dry_run {
+            std::fs::remove_file(&filename)?;
+        } else if self.chunked && size >= CHUNKING_THRESHOLD {
+            let result = self.store_chunked(&filename, &hash);
+            std::fs::remove_file(&filename)?;
+            result?;
+        } else {
+            // If the object already exists, we can still safely overwrite it because
+            // if they have the same name, they will have the same contents.
+            self.data.put(&hash, &filename)?;
+        }
+
+        if !self.dry_run {
+            log::debug!("stored object {} ({} bytes)", hash, size);
+            match &quota {
+                Some((_guard, added)) => self.adjust_usage(*added)?,
+                // Nothing keeps the cached usage up to date without a quota
+                None => self.invalidate_usage()?,
+            }
+        }
+        if let Some(provenance) = provenance {
+            // Whoever stored it first is recorded, even if another one came in between
+            self.update_metadata(&hash, |metadata| {
+                metadata.provenance.get_or_insert(provenance);
+            })?;
+        }
+        let store_file = StoreFileRef {
+            hash,
+            size: Some(size),
+            media_type: Some(media_type::detect(&prefix).to_string()),
+        };
 
         Ok(store_file)
     }
 
-    /// Open a file in the store's data directory based on a reference.
-    pub fn open_ref(&self, store_ref: &StoreFileRef) -> io::Result<File> {
-        let path = self.data_dir.join(format!("{}", store_ref.hash));
-        File::open(path)
-    }
+    /// Store the contents of the file at `path`.
+    ///
+    /// The file is copied to the staging directory first and only hashed there, so that the
+    /// stored contents always match their hash, even if the file is modified meanwhile. If the
+    /// algorithm of the store supports it, large files are hashed by all cores at once.
+    pub fn store_path(&self, path: &Path) -> io::Result<StoreFileRef> {
+        let mut file = File::open(path)?;
+        let mut staging_file = self.new_staging_file_parallel()?;
+        if let Err(err) = staging_file.copy_from(&mut file) {
+            staging_file.discard()?;
+            return Err(err);
+        }
+        self.make_permanent(staging_file)
+    }
+
+    /// Like `make_permanent`, but fail with `InvalidData` and discard the staging file if its
+    /// contents do not hash to `expected`.
+    pub fn make_permanent_verified(
+        &self,
+        staging_file: StagingFile,
+        expected: &ContentHash,
+    ) -> io::Result<StoreFileRef> {
+        let actual_hash = staging_file.hash()?;
+        if actual_hash != *expected {
+            staging_file.discard()?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected contents hashing to {}, got {}",
+                    expected, actual_hash
+                ),
+            ));
+        }
+        self.make_permanent_as(staging_file, actual_hash)
+    }
+
+    /// Open the contents of an object in the store based on a reference.
+    ///
+    /// Objects stored in chunks are reassembled while reading. If any of their chunks is
+    /// missing, this fails with `NotFound` just like for a missing object. Missing objects are
+    /// downloaded from the remote store first, if one was set with `set_remote`. With
+    /// `set_verify_reads`, the contents are checked against the hash while they are read.
+    pub fn open_ref(&self, store_ref: &StoreFileRef) -> io::Result<ObjectReader<'_>> {
+        let (size, parts) = self.object_parts(&store_ref.hash)?;
+        self.reader(store_ref, size, parts)
+    }
+
+    /// Write the contents of the referenced object to the file at `target`, replacing it, like
+    /// copying them from `open_ref`. Returns their size.
+    ///
+    /// The contents are written to a temporary file next to `target` first, which only replaces
+    /// it once all of them were copied and, with `set_verify_reads`, found to be intact. Objects
+    /// kept as local files are copied with `hash::copy_file`, which uses io_uring where
+    /// available.
+    pub fn write_to_file(&self, store_ref: &StoreFileRef, target: &Path) -> io::Result<u64> {
+        let (size, parts) = self.object_parts(&store_ref.hash)?;
+        let temp_path = target.with_extension(format!("tmp.{}", std::process::id()));
+        // Keep e.g. the executable bit of the file that is replaced, but not the permissions of
+        // whatever a symlink that is replaced points to
+        let permissions = std::fs::symlink_metadata(target)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.permissions());
+        let written = self.copy_parts(store_ref, size, parts, &temp_path, permissions);
+        match written.and_then(|size| std::fs::rename(&temp_path, target).map(|()| size)) {
+            Ok(size) => Ok(size),
+            Err(err) => {
+                let _ = std::fs::remove_file(&temp_path);
+                Err(err)
+            }
+        }
+    }
+
+    /// Copy the given parts of an object into the new file `target`, for `write_to_file`.
+    ///
+    /// An object stored in one piece is cloned instead where the file system supports it, see
+    /// `clone_file`, so that its data is shared with the store rather than copied.
+    fn copy_parts(
+        &self,
+        store_ref: &StoreFileRef,
+        size: u64,
+        parts: Vec<ContentHash>,
+        target: &Path,
+        permissions: Option<std::fs::Permissions>,
+    ) -> io::Result<u64> {
+        let create = || {
+            let file = File::create(target)?;
+            if let Some(permissions) = permissions.clone() {
+                file.set_permissions(permissions)?;
+            }
+            Ok::<_, io::Error>(file)
+        };
+        let paths: Option<Vec<PathBuf>> = parts
+            .iter()
+            .map(|part| self.data.local_path(part))
+            .collect();
+        let paths = match paths {
+            Some(paths) => paths,
+            None => return io::copy(&mut self.reader(store_ref, size, parts)?, &mut create()?),
+        };
+
+        let mut hasher = Hasher::new(store_ref.hash.algorithm());
+        let (copied, actual) = match &paths[..] {
+            [source] if clone_file(source, target) => {
+                // The clone has the permissions of the object otherwise
+                if let Some(permissions) = permissions.clone() {
+                    std::fs::set_permissions(target, permissions)?;
+                }
+                let actual = if self.verify_reads {
+                    ContentHash::hash_file(store_ref.hash.algorithm(), &mut File::open(target)?)?
+                } else {
+                    store_ref.hash.clone()
+                };
+                (size, actual)
+            }
+            _ => {
+                let mut file = create()?;
+                let mut copied = 0;
+                for path in &paths {
+                    copied += hash::copy_file(&mut File::open(path)?, &mut file, &mut hasher)?;
+                }
+                (copied, hasher.finish())
+            }
+        };
+        if self.verify_reads && actual != store_ref.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "object {} is corrupt, its contents hash to {}",
+                    store_ref.hash, actual
+                ),
+            ));
+        }
+        Ok(copied)
+    }
+
+    fn reader(
+        &self,
+        store_ref: &StoreFileRef,
+        size: u64,
+        parts: Vec<ContentHash>,
+    ) -> io::Result<ObjectReader<'_>> {
+        let mut remaining = parts.into_iter();
+        let current = match remaining.next() {
+            Some(part) => Some(self.data.get(&part)?),
+            None => None,
+        };
+        let verifier = if self.verify_reads {
+            Some((
+                Hasher::new(store_ref.hash.algorithm()),
+                store_ref.hash.clone(),
+            ))
+        } else {
+            None
+        };
+        Ok(ObjectReader {
+            data: &*self.data,
+            size,
+            current,
+            remaining,
+            verifier,
+        })
+    }
+
+    /// Return the size of an object and the objects in `data` that make up its contents, i.e.
+    /// either the object itself or its chunks. Counts as reading the object, see `open_ref`.
+    fn object_parts(&self, hash: &ContentHash) -> io::Result<(u64, Vec<ContentHash>)> {
+        match (self.local_object_parts(hash), &self.remote) {
+            (Err(err), Some(remote)) if err.kind() == io::ErrorKind::NotFound => {
+                log::info!("fetching {} from {}", hash, remote.base_dir.display());
+                let report = self.fetch_from_remote(remote, std::iter::once(hash.clone()))?;
+                if report.transferred.is_empty() {
+                    return Err(err);
+                }
+                self.local_object_parts(hash)
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn local_object_parts(&self, hash: &ContentHash) -> io::Result<(u64, Vec<ContentHash>)> {
+        if let Some(size) = self.data.size(hash)? {
+            self.record_access(hash);
+            return Ok((size, vec![hash.clone()]));
+        }
+
+        let chunks = self.read_manifest(hash)?;
+        for (chunk, _) in &chunks {
+            if !self.data.exists(chunk)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("chunk {} of object {} is missing", chunk, hash),
+                ));
+            }
+        }
+        self.record_access(hash);
+        Ok((
+            chunks.iter().map(|(_, size)| size).sum(),
+            chunks.into_iter().map(|(chunk, _)| chunk).collect(),
+        ))
+    }
+
+    /// Fail if storing the staged file would make the store exceed its quota.
+    ///
+    /// Objects that are already present never exceed it. Chunked objects count with their full
+    /// size, although chunks they share with other objects take no additional space. If the
+    /// store has a quota, the returned guard must be held until the object is stored, and the
+    /// returned size added to the usage then, see `adjust_usage`.
+    fn check_quota(
+        &self,
+        hash: &ContentHash,
+        staged: &Path,
+    ) -> io::Result<Option<(MutexGuard<'_, ()>, u64)>> {
+        let quota = match self.config()?.quota {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        let guard = self.quota_lock.lock().expect("quota lock is not poisoned");
+        if self.data.exists(hash)? || self.manifests.exists(hash)? {
+            return Ok(Some((guard, 0)));
+        }
+        let size = std::fs::metadata(staged)?.len();
+        let used = self.used_bytes()?;
+        if used + size > quota {
+            return Err(io::Error::other(
+                format!(
+                    "storing {} ({} bytes) would exceed the quota of {} bytes of {}, which already holds {} bytes",
+                    hash,
+                    size,
+                    quota,
+                    self.base_dir.display(),
+                    used
+                ),
+            ));
+        }
+        Ok(Some((guard, size)))
+    }
+
+    /// The total size of the objects in the data directory, as cached in the `usage` file.
+    ///
+    /// Storing objects while the store has a quota keeps the cache up to date, anything else
+    /// that changes the data directory drops it, so that it is listed again the next time.
+    /// Since other processes may store objects at the same time, it can still drift, which
+    /// `collect_garbage` and `validate` correct by recomputing it.
+    fn used_bytes(&self) -> io::Result<u64> {
+        match self.cached_usage()? {
+            Some(used) => Ok(used),
+            None => {
+                let used = self.data.list()?.objects.iter().map(|(_, size)| size).sum();
+                self.write_usage(used)?;
+                Ok(used)
+            }
+        }
+    }
+
+    fn cached_usage(&self) -> io::Result<Option<u64>> {
+        if is_url(&self.base_dir) {
+            return Ok(None);
+        }
+        match std::fs::read_to_string(self.base_dir.join(USAGE_FILE)) {
+            // A garbled cache is simply recomputed
+            Ok(contents) => Ok(contents.trim().parse().ok()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_usage(&self, used: u64) -> io::Result<()> {
+        if is_url(&self.base_dir) || self.dry_run {
+            return Ok(());
+        }
+        let (temp_path, mut file) = new_temp_file(&self.staging_dir, "usage", "")?;
+        let written = file
+            .write_all(format!("{}\n", used).as_bytes())
+            .and_then(|()| std::fs::rename(&temp_path, self.base_dir.join(USAGE_FILE)));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        written
+    }
+
+    /// Add newly stored bytes to the cached usage, if there is one, see `used_bytes`.
+    fn adjust_usage(&self, added: u64) -> io::Result<()> {
+        match self.cached_usage()? {
+            Some(used) if added > 0 => self.write_usage(used + added),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop the cached usage after the data directory changed, see `used_bytes`.
+    fn invalidate_usage(&self) -> io::Result<()> {
+        if is_url(&self.base_dir) || self.dry_run {
+            return Ok(());
+        }
+        match std::fs::remove_file(self.base_dir.join(USAGE_FILE)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remember that an object was read just now, see `evict`.
+    ///
+    /// This is best effort: the store may well be read-only, e.g. when used as an alternate.
+    fn record_access(&self, hash: &ContentHash) {
+        if is_url(&self.base_dir) {
+            return;
+        }
+        let access_dir = self.base_dir.join(ACCESS_DIR);
+        let _ = std::fs::create_dir_all(&access_dir)
+            .and_then(|()| std::fs::write(access_dir.join(hash.to_hex_string()), b""));
+    }
+
+    /// Return when an object was last read, or stored if it was never read.
+    fn last_access(&self, object: &StoredObject) -> Option<SystemTime> {
+        let access_file = self
+            .base_dir
+            .join(ACCESS_DIR)
+            .join(object.hash.to_hex_string());
+        let accessed = std::fs::metadata(access_file)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        accessed.max(object.modified)
+    }
+
+    /// Forget what is recorded about a deleted object besides its contents, i.e. its signature,
+    /// its metadata and when it was last read.
+    fn forget(&self, hash: &ContentHash) -> io::Result<()> {
+        self.signatures.delete(hash)?;
+        if is_url(&self.base_dir) {
+            return Ok(());
+        }
+        for dir in [METADATA_DIR, ACCESS_DIR] {
+            match std::fs::remove_file(self.base_dir.join(dir).join(hash.to_hex_string())) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// What is recorded about an object, see `metadata`. Objects that nothing is recorded about,
+    /// including those of remote stores, have the default.
+    pub fn metadata(&self, hash: &ContentHash) -> io::Result<ObjectMetadata> {
+        if is_url(&self.base_dir) {
+            return Ok(ObjectMetadata::default());
+        }
+        let path = self.base_dir.join(METADATA_DIR).join(hash.to_hex_string());
+        match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {}: {}", path.display(), err),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(ObjectMetadata::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Change what is recorded about an object, returning the result. The file is replaced
+    /// atomically, and removed once nothing is recorded anymore.
+    pub fn update_metadata(
+        &self,
+        hash: &ContentHash,
+        update: impl FnOnce(&mut ObjectMetadata),
+    ) -> io::Result<ObjectMetadata> {
+        self.ensure_local("only local stores record metadata")?;
+        let mut metadata = self.metadata(hash)?;
+        update(&mut metadata);
+        let metadata_dir = self.base_dir.join(METADATA_DIR);
+        let path = metadata_dir.join(hash.to_hex_string());
+        if metadata.is_empty() {
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => return Ok(metadata),
+            }
+        }
+        std::fs::create_dir_all(&metadata_dir)?;
+        let contents = serde_json::to_vec_pretty(&metadata)?;
+        let (temp_path, mut file) = new_temp_file(&self.staging_dir, "metadata", "json")?;
+        let written = file
+            .write_all(&contents)
+            .and_then(|()| std::fs::rename(&temp_path, &path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        written.map(|()| metadata)
+    }
+
+    /// The signature of an object, if it has one, see `signing`. Signatures missing here are
+    /// fetched from the remote, see `set_remote`.
+    pub fn signature(&self, hash: &ContentHash) -> io::Result<Option<Vec<u8>>> {
+        match (self.local_signature(hash)?, &self.remote) {
+            (None, Some(remote)) if self.fetch_signature(remote, hash)? => {
+                self.local_signature(hash)
+            }
+            (signature, _) => Ok(signature),
+        }
+    }
+
+    fn local_signature(&self, hash: &ContentHash) -> io::Result<Option<Vec<u8>>> {
+        let mut signature = Vec::new();
+        match self.signatures.get(hash) {
+            Ok(mut reader) => reader.read_to_end(&mut signature)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(Some(signature))
+    }
+
+    /// Record the signature of an object, replacing the one it had.
+    pub fn add_signature(&self, hash: &ContentHash, signature: &[u8]) -> io::Result<()> {
+        self.put_bytes(&*self.signatures, hash, signature)
+    }
+
+    /// Copy the signature of an object from `other`, unless this store has one already.
+    /// Returns whether this store has one now.
+    pub fn fetch_signature(&self, other: &Store, hash: &ContentHash) -> io::Result<bool> {
+        if self.signatures.exists(hash)? {
+            return Ok(true);
+        }
+        match other.signature(hash)? {
+            Some(signature) => {
+                self.add_signature(hash, &signature)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Copy an object from another store into this one, verifying its hash on the way, along
+    /// with its signature.
+    ///
+    /// Returns `Ok(false)` if the other store doesn't contain the object either.
+    pub fn fetch_from(&self, other: &Store, store_ref: &StoreFileRef) -> io::Result<bool> {
+        let mut source = match other.open_ref(store_ref) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let mut staging_file = self.new_staging_file_with(store_ref.hash.algorithm())?;
+        if let Err(err) = io::copy(&mut source, &mut staging_file) {
+            staging_file.discard()?;
+            return Err(err);
+        }
+        let actual_hash = staging_file.hasher.clone().finish();
+        if actual_hash != store_ref.hash {
+            staging_file.discard()?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "object {} in {} is corrupt",
+                    store_ref.hash,
+                    other.base_dir.display()
+                ),
+            ));
+        }
+        self.make_permanent(staging_file)?;
+        self.fetch_signature(other, &store_ref.hash)?;
+        Ok(true)
+    }
+
+    /// Copy objects to another store, e.g. from the store of a repository to a shared store.
+    ///
+    /// With `hashes`, only those objects are copied (including their chunks), otherwise all of
+    /// them. Objects that `target` already has are skipped, and all others are verified against
+    /// their hash on the way.
+    ///
+    /// `progress` is called while copying, see `push_to_with`.
+    pub fn copy_to(
+        &self,
+        target: &Store,
+        hashes: Option<Vec<ContentHash>>,
+        progress: &mut dyn FnMut(&TransferProgress),
+    ) -> io::Result<TransferReport> {
+        match hashes {
+            Some(hashes) => target.fetch_from_remote_with(self, hashes.into_iter(), progress),
+            None => self.push_to_with(target, progress),
+        }
+    }
+
+    /// Upload all objects, chunk manifests and signatures that `remote` does not have yet.
+    ///
+    /// Objects are verified against their hash before being uploaded, so that corrupt objects
+    /// don't spread. Manifests are uploaded after the chunks they refer to.
+    pub fn push_to(&self, remote: &Store) -> io::Result<TransferReport> {
+        self.push_to_with(remote, &mut |_| {})
+    }
+
+    /// Like `push_to`, but call `progress` repeatedly while uploading, e.g. to show how far
+    /// the upload has come.
+    pub fn push_to_with(
+        &self,
+        remote: &Store,
+        progress: &mut dyn FnMut(&TransferProgress),
+    ) -> io::Result<TransferReport> {
+        let mut report = TransferReport::default();
+        let pairs: [(&dyn StorageBackend, &dyn StorageBackend, bool); 2] = [
+            (&*self.data, &*remote.data, true),
+            (&*self.manifests, &*remote.manifests, false),
+        ];
+        // List everything first, so that the progress can be given relative to the total
+        let mut pending = Vec::new();
+        for (local, target, verify) in pairs {
+            let present: HashSet<ContentHash> = target
+                .list()?
+                .objects
+                .into_iter()
+                .map(|(hash, _)| hash)
+                .collect();
+            for (hash, size) in local.list()?.objects {
+                if present.contains(&hash) {
+                    report.skipped += 1;
+                } else {
+                    pending.push((local, target, verify, hash, size));
+                }
+            }
+        }
+        let total = Some((
+            pending.len(),
+            pending.iter().map(|(_, _, _, _, size)| size).sum(),
+        ));
+        for (local, target, verify, hash, _) in pending {
+            let done = report.progress(total);
+            report.bytes += remote.copy_object(local, target, &hash, verify, &mut |bytes| {
+                progress(&done.advanced(bytes))
+            })?;
+            report.transferred.push(hash);
+        }
+        // Signatures are small and go along with the objects, so they aren't reported
+        let signed: HashSet<ContentHash> = remote
+            .signatures
+            .list()?
+            .objects
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        for (hash, _) in self.signatures.list()?.objects {
+            if !signed.contains(&hash) {
+                remote.copy_object(
+                    &*self.signatures,
+                    &*remote.signatures,
+                    &hash,
+                    false,
+                    &mut |_| {},
+                )?;
+            }
+        }
+        progress(&report.progress(total));
+        Ok(report)
+    }
+
+    /// Download the given objects from `remote` unless they are already present, including
+    /// all chunks of objects stored in chunks and the signatures of the objects.
+    ///
+    /// Objects that the remote store does not have either are reported as missing.
+    pub fn fetch_from_remote(
+        &self,
+        remote: &Store,
+        hashes: impl Iterator<Item = ContentHash>,
+    ) -> io::Result<TransferReport> {
+        self.fetch_from_remote_with(remote, hashes, &mut |_| {})
+    }
+
+    /// Like `fetch_from_remote`, but call `progress` repeatedly while downloading. The total
+    /// is not known in advance.
+    pub fn fetch_from_remote_with(
+        &self,
+        remote: &Store,
+        hashes: impl Iterator<Item = ContentHash>,
+        progress: &mut dyn FnMut(&TransferProgress),
+    ) -> io::Result<TransferReport> {
+        let mut report = TransferReport::default();
+        for hash in hashes {
+            if self
+                .object_size(&StoreFileRef::from_hash(hash.clone()))?
+                .is_some()
+            {
+                report.skipped += 1;
+            } else if remote.data.exists(&hash)? {
+                let done = report.progress(None);
+                report.bytes +=
+                    self.copy_object(&*remote.data, &*self.data, &hash, true, &mut |bytes| {
+                        progress(&done.advanced(bytes))
+                    })?;
+                self.fetch_signature(remote, &hash)?;
+                report.transferred.push(hash);
+            } else {
+                let chunks = match remote.read_manifest(&hash) {
+                    Ok(chunks) => chunks,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        report.missing.push(hash);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                for (chunk, _) in chunks {
+                    if !self.data.exists(&chunk)? {
+                        let done = report.progress(None);
+                        report.bytes += self.copy_object(
+                            &*remote.data,
+                            &*self.data,
+                            &chunk,
+                            true,
+                            &mut |bytes| progress(&done.advanced(bytes)),
+                        )?;
+                        report.transferred.push(chunk);
+                    }
+                }
+                let done = report.progress(None);
+                report.bytes += self.copy_object(
+                    &*remote.manifests,
+                    &*self.manifests,
+                    &hash,
+                    false,
+                    &mut |bytes| progress(&done.advanced(bytes)),
+                )?;
+                self.fetch_signature(remote, &hash)?;
+                report.transferred.push(hash);
+            }
+        }
+        progress(&report.progress(None));
+        Ok(report)
+    }
+
+    /// Copy a single object between two backends, going through the staging directory of this
+    /// store. With `verify`, the contents must match the hash. Returns the size of the object.
+    ///
+    /// `progress` is called with the number of bytes copied so far after every read.
+    fn copy_object(
+        &self,
+        source: &dyn StorageBackend,
+        target: &dyn StorageBackend,
+        hash: &ContentHash,
+        verify: bool,
+        progress: &mut dyn FnMut(u64),
+    ) -> io::Result<u64> {
+        let start = Instant::now();
+        let mut staging_file = self.new_staging_file_with(hash.algorithm())?;
+        let copied = match source.local_path(hash) {
+            Some(path) => File::open(path)
+                .and_then(|mut file| staging_file.copy_from(&mut file))
+                .map(|size| {
+                    progress(size);
+                    size
+                }),
+            None => source.get(hash).and_then(|inner| {
+                let mut reader = ProgressReader {
+                    inner,
+                    read: 0,
+                    progress,
+                };
+                io::copy(&mut reader, &mut staging_file)
+            }),
+        };
+        let size = match copied {
+            Ok(size) => size,
+            Err(err) => {
+                staging_file.discard()?;
+                return Err(err);
+            }
+        };
+        let actual_hash = staging_file.hasher.clone().finish();
+        if verify && actual_hash != *hash {
+            staging_file.discard()?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupt", source.location(hash).display()),
+            ));
+        }
+        drop(staging_file.file);
+        let result = target.put(hash, &staging_file.filename);
+        if result.is_err() {
+            std::fs::remove_file(&staging_file.filename)?;
+        }
+        result?;
+        // The target is one of the backends of this store, see `push_to_with` and
+        // `fetch_from_remote_with`
+        self.invalidate_usage()?;
+        log::debug!(
+            "copied {} ({} bytes) from {} in {:.3}s",
+            hash,
+            size,
+            source.location(hash).display(),
+            start.elapsed().as_secs_f64()
+        );
+        Ok(size)
+    }
+
+    /// Return the size of the referenced object, or `None` if it is not in the store.
+    pub fn object_size(&self, store_ref: &StoreFileRef) -> io::Result<Option<u64>> {
+        if let Some(size) = self.data.size(&store_ref.hash)? {
+            return Ok(Some(size));
+        }
+        match self.read_manifest(&store_ref.hash) {
+            Ok(chunks) => Ok(Some(chunks.iter().map(|(_, size)| size).sum())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return where the referenced object is kept, or `None` if it is not in the store. For
+    /// objects stored in chunks, this is their chunk manifest.
+    pub fn object_location(&self, store_ref: &StoreFileRef) -> io::Result<Option<PathBuf>> {
+        for backend in [&self.data, &self.manifests] {
+            if backend.size(&store_ref.hash)?.is_some() {
+                return Ok(Some(backend.location(&store_ref.hash)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete all objects from the data directory that are neither contained in `reachable`
+    /// nor pinned, and were stored longer ago than `GC_GRACE_PERIOD`, see
+    /// `collect_garbage_with`.
+    pub fn collect_garbage(
+        &self,
+        reachable: impl Iterator<Item = ContentHash>,
+    ) -> io::Result<GarbageReport> {
+        self.collect_garbage_with(reachable, GC_GRACE_PERIOD)
+    }
+
+    /// Delete all objects from the data directory that are neither contained in `reachable`
+    /// nor pinned, unless they were stored less than `grace_period` before the scan started.
+    ///
+    /// The grace period protects objects that a filter stored while `reachable` was collected,
+    /// which are not in the index yet, like `git gc --prune=<date>` does for loose objects.
+    /// Objects of unknown age count as new. Chunks are kept as long as the manifest of a kept
+    /// object refers to them, and manifests of deleted objects are deleted as well. Files in the
+    /// data directory that are not objects are left alone, `validate` reports those.
+    pub fn collect_garbage_with(
+        &self,
+        reachable: impl Iterator<Item = ContentHash>,
+        grace_period: Duration,
+    ) -> io::Result<GarbageReport> {
+        let cutoff = SystemTime::now()
+            .checked_sub(grace_period)
+            .unwrap_or(UNIX_EPOCH);
+        let recent = |modified: Option<SystemTime>| modified.map_or(true, |time| time > cutoff);
+        let mut reachable: HashSet<ContentHash> = reachable.collect();
+        reachable.extend(self.pinned()?);
+        let mut report = GarbageReport::default();
+
+        for (hash, size) in self.manifests.list()?.objects {
+            if reachable.contains(&hash) || recent(self.manifests.modified(&hash)?) {
+                let chunks = self.read_manifest(&hash)?;
+                reachable.extend(chunks.into_iter().map(|(chunk, _)| chunk));
+            } else {
+                if !self.dry_run {
+                    self.manifests.delete(&hash)?;
+                    self.forget(&hash)?;
+                }
+                report.freed_bytes += size;
+                report.removed.push(hash);
+            }
+        }
+
+        let mut used = 0;
+        for (hash, size) in self.data.list()?.objects {
+            if reachable.contains(&hash) || recent(self.data.modified(&hash)?) {
+                report.kept += 1;
+                used += size;
+            } else {
+                if !self.dry_run {
+                    self.data.delete(&hash)?;
+                    self.forget(&hash)?;
+                }
+                report.freed_bytes += size;
+                report.removed.push(hash);
+            }
+        }
+        self.write_usage(used)?;
+
+        Ok(report)
+    }
+
+    /// Delete objects that are neither contained in `reachable` nor pinned, as far as the
+    /// retention policy allows.
+    ///
+    /// Unlike `collect_garbage`, which deletes every unreferenced object, this keeps the
+    /// `keep_recent` most recently stored unreferenced objects, and all that are younger than
+    /// `older_than`. Chunks are deleted together with the last object that refers to them.
+    pub fn prune(
+        &self,
+        reachable: impl Iterator<Item = ContentHash>,
+        policy: &RetentionPolicy,
+    ) -> io::Result<GarbageReport> {
+        let mut reachable: HashSet<ContentHash> = reachable.collect();
+        reachable.extend(self.pinned()?);
+
+        let mut candidates = Vec::new();
+        let mut report = GarbageReport::default();
+        for object in self.top_level_objects()? {
+            if reachable.contains(&object.hash) {
+                report.kept += 1;
+            } else {
+                candidates.push(object);
+            }
+        }
+        // Most recent first, objects of unknown age count as new
+        let now = SystemTime::now();
+        candidates.sort_by(|a, b| {
+            let newest_first = b.modified.unwrap_or(now).cmp(&a.modified.unwrap_or(now));
+            newest_first.then_with(|| a.hash.cmp(&b.hash))
+        });
+
+        for (index, object) in candidates.into_iter().enumerate() {
+            let age = object
+                .modified
+                .map(|modified| now.duration_since(modified).unwrap_or_default());
+            let recent = policy.keep_recent.is_some_and(|keep| index < keep);
+            let young = match (policy.older_than(), age) {
+                (Some(older_than), Some(age)) => age < older_than,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if recent || young {
+                report.kept += 1;
+            } else if self.remove(&object.hash)? {
+                report.freed_bytes += object.size;
+                report.removed.push(object.hash);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Delete the least recently read objects until the store is no larger than `max_size`.
+    ///
+    /// This is meant for stores that are a local cache of `backup`, so only objects that
+    /// `backup` has are deleted, and never pinned ones. Objects that were never read count as
+    /// read when they were stored.
+    pub fn evict(&self, max_size: u64, backup: &Store) -> io::Result<GarbageReport> {
+        let mut report = GarbageReport::default();
+        let data = self.data.list()?;
+        let mut total: u64 = data.objects.iter().map(|(_, size)| size).sum();
+        if total <= max_size {
+            report.kept = data.objects.len() as u64;
+            return Ok(report);
+        }
+
+        let pinned: HashSet<ContentHash> = self.pinned()?.into_iter().collect();
+        let backed_up: HashSet<ContentHash> = backup
+            .data
+            .list()?
+            .objects
+            .into_iter()
+            .chain(backup.manifests.list()?.objects)
+            .map(|(hash, _)| hash)
+            .collect();
+
+        let mut candidates: Vec<(Option<SystemTime>, StoredObject)> = self
+            .top_level_objects()?
+            .into_iter()
+            .map(|object| (self.last_access(&object), object))
+            .collect();
+        // Least recently read first
+        candidates.sort_by(|(a_access, a), (b_access, b)| {
+            a_access.cmp(b_access).then_with(|| a.hash.cmp(&b.hash))
+        });
+
+        for (_, object) in candidates {
+            let evictable = !pinned.contains(&object.hash) && backed_up.contains(&object.hash);
+            if total <= max_size || !evictable {
+                report.kept += 1;
+                continue;
+            }
+            if self.remove(&object.hash)? {
+                if object.chunked && !self.dry_run {
+                    // Only chunks that no other object shares are gone
+                    let remaining: u64 =
+                        self.data.list()?.objects.iter().map(|(_, size)| size).sum();
+                    report.freed_bytes += total.saturating_sub(remaining);
+                    total = remaining;
+                } else {
+                    // In a dry run, shared chunks are counted as if they were freed as well
+                    report.freed_bytes += object.size;
+                    total -= object.size;
+                }
+                report.removed.push(object.hash);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Remove staging files older than a week, which are certainly abandoned, at most once a
+    /// day. Meant for commands that write to the store anyway, so that the staging directory
+    /// doesn't grow forever without anyone running `clean_staging`.
+    ///
+    /// Does nothing in dry runs. Failures are logged, since they are not worth failing for.
+    pub fn clean_stale_staging(&self) {
+        if self.dry_run || is_url(&self.base_dir) {
+            return;
+        }
+        // The modification time of this file tells when the last cleanup was
+        let marker = self.base_dir.join("staging-cleaned");
+        let last_cleanup = std::fs::metadata(&marker).and_then(|metadata| metadata.modified());
+        if let Ok(Ok(elapsed)) = last_cleanup.map(|time| time.elapsed()) {
+            if elapsed < STALE_STAGING_INTERVAL {
+                return;
+            }
+        }
+        let result =
+            std::fs::write(&marker, b"").and_then(|()| self.clean_staging(STALE_STAGING_AGE));
+        match result {
+            Ok(report) if !report.removed.is_empty() => log::info!(
+                "removed {} stale staging files ({} bytes)",
+                report.removed.len(),
+                report.freed_bytes
+            ),
+            Ok(_) => {}
+            Err(err) => log::warn!("cleaning up stale staging files failed: {}", err),
+        }
+    }
+
+    /// Remove files from the staging directory that were last modified longer ago than
+    /// `older_than`, e.g. because the filter writing them was killed.
+    ///
+    /// Files that are younger might still be written to, and are left alone.
+    pub fn clean_staging(&self, older_than: Duration) -> io::Result<StagingReport> {
+        let mut report = StagingReport::default();
+        let now = SystemTime::now();
+        for entry in read_dir_if_exists(&self.staging_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if !metadata.is_file() || age < older_than {
+                report.kept += 1;
+                continue;
+            }
+            if !self.dry_run {
+                match std::fs::remove_file(entry.path()) {
+                    // Some other process was faster
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            report.removed.push(entry.path());
+            report.freed_bytes += metadata.len();
+        }
+        Ok(report)
+    }
+
+    /// Iterate over all objects in the store, including the individual chunks of objects stored
+    /// in chunks.
+    ///
+    /// Objects stored in chunks are listed with the total size of their contents.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<StoredObject>> + '_> {
+        let objects = self
+            .data
+            .list()?
+            .objects
+            .into_iter()
+            .map(move |(hash, size)| {
+                Ok(StoredObject {
+                    modified: self.data.modified(&hash)?,
+                    hash,
+                    size,
+                    chunked: false,
+                })
+            });
+        let chunked = self
+            .manifests
+            .list()?
+            .objects
+            .into_iter()
+            .map(move |(hash, _)| {
+                Ok(StoredObject {
+                    size: self
+                        .read_manifest(&hash)?
+                        .iter()
+                        .map(|(_, size)| size)
+                        .sum(),
+                    modified: self.manifests.modified(&hash)?,
+                    hash,
+                    chunked: true,
+                })
+            });
+        Ok(objects.chain(chunked))
+    }
+
+    /// List all objects that were stored, sorted by hash. Unlike `iter`, this leaves out the
+    /// chunks of objects stored in chunks.
+    pub fn top_level_objects(&self) -> io::Result<Vec<StoredObject>> {
+        let mut chunks = HashSet::new();
+        for (hash, _) in self.manifests.list()?.objects {
+            chunks.extend(
+                self.read_manifest(&hash)?
+                    .into_iter()
+                    .map(|(chunk, _)| chunk),
+            );
+        }
+        let mut objects = Vec::new();
+        for object in self.iter()? {
+            let object = object?;
+            if object.chunked || !chunks.contains(&object.hash) {
+                objects.push(object);
+            }
+        }
+        objects.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(objects)
+    }
+
+    /// Find the objects whose hash starts with the given prefix, either of the text form of the
+    /// hash or of the hex digest alone.
+    ///
+    /// A complete hash is returned as is, without checking whether the object exists. Shorter
+    /// prefixes need to be at least 4 characters long.
+    pub fn resolve_hash(&self, prefix: &str) -> io::Result<Vec<ContentHash>> {
+        let prefix = prefix.to_ascii_lowercase();
+        if let Some(hash) = ContentHash::from_hex(prefix.as_bytes()) {
+            // A plain digest is read as SHA-256, but may also be a digest of the store's algorithm
+            if prefix.contains('-') || hash.algorithm().name() == self.algorithm.name() {
+                return Ok(vec![hash]);
+            }
+        }
+        let digest_prefix = prefix
+            .split_once('-')
+            .map_or(prefix.as_str(), |(_, rest)| rest);
+        if digest_prefix.len() < 4 || !digest_prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not a hash or hash prefix of at least 4 characters: {}",
+                    prefix
+                ),
+            ));
+        }
+        let mut matches: Vec<ContentHash> = self
+            .data
+            .list()?
+            .objects
+            .into_iter()
+            .chain(self.manifests.list()?.objects)
+            .map(|(hash, _)| hash)
+            .filter(|hash| hash.matches_prefix(&prefix))
+            .collect();
+        matches.sort();
+        matches.dedup();
+        Ok(matches)
+    }
+
+    /// Delete an object from the store, returning whether it was there.
+    ///
+    /// For objects stored in chunks, the manifest and all chunks that no other manifest refers
+    /// to are deleted, so that none of the contents remain.
+    pub fn remove(&self, hash: &ContentHash) -> io::Result<bool> {
+        if self.dry_run {
+            return Ok(self.data.exists(hash)? || self.manifests.exists(hash)?);
+        }
+        if self.data.exists(hash)? {
+            self.data.delete(hash)?;
+            self.forget(hash)?;
+            self.invalidate_usage()?;
+            return Ok(true);
+        }
+        let chunks = match self.read_manifest(hash) {
+            Ok(chunks) => chunks,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let mut shared = HashSet::new();
+        for (other, _) in self.manifests.list()?.objects {
+            if other != *hash {
+                shared.extend(
+                    self.read_manifest(&other)?
+                        .into_iter()
+                        .map(|(chunk, _)| chunk),
+                );
+            }
+        }
+        // The manifest goes first, so that it never refers to chunks that are gone
+        self.manifests.delete(hash)?;
+        for (chunk, _) in chunks {
+            if !shared.contains(&chunk) {
+                self.data.delete(&chunk)?;
+            }
+        }
+        self.forget(hash)?;
+        self.invalidate_usage()?;
+        Ok(true)
+    }
+
+    /// Compute summary statistics about the objects in the store, and the state of its
+    /// staging and `ref/` directories.
+    pub fn stats(&self) -> io::Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        let mut objects = self.data.list()?.objects;
+        for (_, size) in &objects {
+            stats.objects += 1;
+            stats.bytes += size;
+        }
+        objects.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        stats.largest = objects
+            .into_iter()
+            .take(LARGEST_OBJECTS)
+            .map(|(hash, size)| ObjectSize { hash, size })
+            .collect();
+        stats.chunked_objects = self.manifests.list()?.objects.len() as u64;
+
+        for entry in read_dir_if_exists(&self.staging_dir)? {
+            stats.staging_files += 1;
+            stats.staging_bytes += entry?.metadata()?.len();
+        }
+        if !is_url(&self.base_dir) {
+            stats.registered_repos = self.registered_repos()?.len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Check all entries in the data store for consistency, using one thread per CPU.
+    ///
+    /// Objects that passed an earlier validation and have not been modified since are not
+    /// hashed again.
+    pub fn validate(&self) -> io::Result<ValidationReport> {
+        let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+        self.validate_with(jobs, false)
+    }
+
+    /// Check all entries in the data store for consistency, hashing objects on `jobs` threads.
+    ///
+    /// Unless `full` is set, objects whose size and modification time are still the same as
+    /// when they were last verified are skipped.
+    pub fn validate_with(&self, jobs: usize, full: bool) -> io::Result<ValidationReport> {
+        let listing = self.data.list()?;
+        let manifests = self.manifests.list()?;
+        let mut report = ValidationReport {
+            unexpected_files: listing.unexpected,
+            ..ValidationReport::default()
+        };
+        report.unexpected_files.extend(manifests.unexpected);
+
+        // Chunks are checked like all other objects below, so it suffices that they are present.
+        for (hash, _) in manifests.objects {
+            let complete = match self.read_manifest(&hash) {
+                Ok(chunks) => chunks
+                    .iter()
+                    .map(|(chunk, _)| self.data.exists(chunk))
+                    .collect::<io::Result<Vec<bool>>>()?
+                    .into_iter()
+                    .all(|exists| exists),
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => false,
+                Err(err) => return Err(err),
+            };
+            if !complete {
+                report.broken_manifests.push(self.manifests.location(&hash));
+            }
+        }
+
+        if !is_url(&self.base_dir) {
+            self.check_refs(&mut report)?;
+        }
+
+        // Remote stores have no place for the cache
+        let cache_path = self.base_dir.join(VERIFICATION_CACHE);
+        let use_cache = !is_url(&self.base_dir);
+        let cache = if use_cache && !full {
+            VerificationCache::load(&cache_path)?
+        } else {
+            VerificationCache::default()
+        };
+        let mut new_cache = VerificationCache::default();
+
+        // Workers take objects from a shared queue and send back what they found out about them
+        if use_cache {
+            self.write_usage(listing.objects.iter().map(|(_, size)| size).sum())?;
+        }
+        let queue = Mutex::new(listing.objects.into_iter());
+        let (sender, receiver) = mpsc::channel();
+        crossbeam_utils::thread::scope(|scope| {
+            for _ in 0..jobs.max(1) {
+                let sender = sender.clone();
+                let queue = &queue;
+                let cache = &cache;
+                scope.spawn(move |_| loop {
+                    let next = queue.lock().expect("validation worker panicked").next();
+                    let (expected_hash, size) = match next {
+                        Some(object) => object,
+                        None => return,
+                    };
+                    let result = self.check_object(cache, &expected_hash, size);
+                    let failed = result.is_err();
+                    if sender.send((expected_hash, size, result)).is_err() || failed {
+                        return;
+                    }
+                });
+            }
+            drop(sender);
+
+            for (expected_hash, size, result) in receiver {
+                match result? {
+                    ObjectCheck::Unchanged(verified) => {
+                        report.skipped += 1;
+                        new_cache.keep(expected_hash, verified);
+                    }
+                    ObjectCheck::Hashed(actual_hash, modified) if actual_hash == expected_hash => {
+                        new_cache.insert(expected_hash, size, modified);
+                    }
+                    ObjectCheck::Hashed(actual_hash, _) => {
+                        report.hash_mismatches.push(HashMismatch {
+                            file_name: self.data.location(&expected_hash),
+                            expected_hash,
+                            actual_hash,
+                        });
+                    }
+                }
+            }
+            Ok::<(), io::Error>(())
+        })
+        .expect("validation worker panicked")?;
+        if use_cache {
+            new_cache.save(&cache_path)?;
+        }
+        report
+            .hash_mismatches
+            .sort_by(|a, b| a.expected_hash.cmp(&b.expected_hash));
+
+        Ok(report)
+    }
+    /// Check the registrations in `ref/` for files that are not in the expected format, and for
+    /// repositories that no longer exist.
+    fn check_refs(&self, report: &mut ValidationReport) -> io::Result<()> {
+        for entry in read_dir_if_exists(&self.ref_dir)? {
+            let path = entry?.path();
+            match read_ref(&path)? {
+                None => report.malformed_refs.push(path),
+                Some(git_dir) if !git_dir.is_dir() => report.stale_refs.push(path),
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Repair the problems found by `validate` in a local store.
+    ///
+    /// Corrupt objects and broken chunk manifests are moved into `lost+found/`, so that they
+    /// can be recovered by hand, and unexpected files as well as malformed and stale
+    /// registrations are deleted. Staging files older than `stale_staging` are removed as well.
+    pub fn repair(
+        &self,
+        report: &ValidationReport,
+        stale_staging: Duration,
+    ) -> io::Result<RepairReport> {
+        if is_url(&self.base_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only local stores can be repaired",
+            ));
+        }
+        let lost_and_found = self.base_dir.join(LOST_AND_FOUND);
+        let mut repair = RepairReport::default();
+        let dry_run = self.dry_run;
+        let mut quarantine = |path: &Path, name: String| -> io::Result<()> {
+            let target = lost_and_found.join(name);
+            if dry_run {
+                repair.quarantined.push(target);
+                return Ok(());
+            }
+            std::fs::create_dir_all(&lost_and_found)?;
+            match std::fs::rename(path, &target) {
+                // Some other process removed it in the meantime
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => {
+                    result?;
+                    repair.quarantined.push(target);
+                    Ok(())
+                }
+            }
+        };
+        for mismatch in &report.hash_mismatches {
+            quarantine(&mismatch.file_name, mismatch.expected_hash.to_hex_string())?;
+        }
+        for manifest in &report.broken_manifests {
+            // Manifests are sharded like objects, so the name is split over two components
+            let shard_and_name: Vec<&str> = manifest
+                .iter()
+                .rev()
+                .take(2)
+                .filter_map(|component| component.to_str())
+                .collect();
+            let name: String = shard_and_name.into_iter().rev().collect();
+            quarantine(manifest, format!("{}.manifest", name))?;
+        }
+
+        let files = report
+            .unexpected_files
+            .iter()
+            .chain(&report.malformed_refs)
+            .chain(&report.stale_refs);
+        for path in files {
+            let removed = if self.dry_run {
+                Ok(())
+            } else if path.is_dir() {
+                std::fs::remove_dir_all(path)
+            } else {
+                std::fs::remove_file(path)
+            };
+            match removed {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+            repair.removed.push(path.clone());
+        }
+
+        repair.staging = self.clean_staging(stale_staging)?;
+        // Corrupt objects were moved out of the data directory
+        self.invalidate_usage()?;
+        Ok(repair)
+    }
+
+    /// Hash an object, unless the cache shows that it is unchanged since it was last verified.
+    fn check_object(
+        &self,
+        cache: &VerificationCache,
+        hash: &ContentHash,
+        size: u64,
+    ) -> io::Result<ObjectCheck> {
+        // Taken before hashing, so that modifications while hashing are noticed next time
+        let modified = self.data.modified(hash)?;
+        if let Some(verified) = cache.get(hash) {
+            if verified.matches(size, modified) {
+                return Ok(ObjectCheck::Unchanged(*verified));
+            }
+        }
+        Ok(ObjectCheck::Hashed(
+            self.data.hash_contents(hash)?,
+            modified,
+        ))
+    }
+}
+
+/// What validation found out about a single object.
+enum ObjectCheck {
+    /// The object was verified before and has not been modified since.
+    Unchanged(verified::Verified),
+    /// The actual hash of the object, and when it was modified before hashing.
+    Hashed(ContentHash, Option<SystemTime>),
+}
+
+/// Settings of a store, kept in `config.json` inside the store.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// Which unreferenced objects `Store::prune` keeps.
+    pub retention: RetentionPolicy,
+    /// The size in bytes above which `Store::evict` deletes the least recently read objects.
+    pub max_size: Option<u64>,
+    /// The size in bytes that `Store::make_permanent` refuses to grow the store beyond.
+    pub quota: Option<u64>,
+}
+
+/// Rules for keeping objects that are no longer referenced, see `Store::prune`.
+///
+/// Without any rules, all unreferenced objects are deleted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Keep objects that were stored less than this many seconds ago.
+    pub older_than_secs: Option<u64>,
+    /// Keep this many of the most recently stored objects.
+    pub keep_recent: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn older_than(&self) -> Option<Duration> {
+        self.older_than_secs.map(Duration::from_secs)
+    }
+}
+
+/// The outcome of a garbage collection run.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GarbageReport {
+    /// Objects that were deleted.
+    pub removed: Vec<ContentHash>,
+    /// Total size of the deleted objects.
+    pub freed_bytes: u64,
+    /// Number of objects that are still referenced.
+    pub kept: u64,
+}
+
+/// The outcome of transferring objects between two stores.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct TransferReport {
+    /// Objects, chunks and manifests that were copied.
+    pub transferred: Vec<ContentHash>,
+    /// Total size of the copied files.
+    pub bytes: u64,
+    /// Number of files that were already present at the destination.
+    pub skipped: u64,
+    /// Objects that were requested, but are not present at the source.
+    pub missing: Vec<ContentHash>,
+}
+
+impl TransferReport {
+    fn progress(&self, total: Option<(usize, u64)>) -> TransferProgress {
+        TransferProgress {
+            files: self.transferred.len(),
+            bytes: self.bytes,
+            total,
+        }
+    }
+}
+
+/// How far a transfer between two stores has come, see `Store::push_to_with`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Number of objects, chunks and manifests that were copied completely.
+    pub files: usize,
+    /// Bytes copied so far, including those of the file that is being copied.
+    pub bytes: u64,
+    /// Number of files and bytes that are copied in total, if known in advance.
+    pub total: Option<(usize, u64)>,
+}
+
+impl TransferProgress {
+    fn advanced(mut self, bytes: u64) -> TransferProgress {
+        self.bytes += bytes;
+        self
+    }
+}
+
+/// Calls `progress` with the number of bytes read so far after every read.
+struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    progress: &'a mut dyn FnMut(u64),
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.read += n_read as u64;
+        (self.progress)(self.read);
+        Ok(n_read)
+    }
+}
+
+/// An object in the store, see `Store::iter`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredObject {
+    pub hash: ContentHash,
+    /// Size of the contents in bytes.
+    pub size: u64,
+    /// When the object was last modified, if the storage backend knows.
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub modified: Option<SystemTime>,
+    /// Whether the object is stored as a manifest of chunks.
+    pub chunked: bool,
+}
+
+/// Serialize a point in time as seconds since the unix epoch.
+fn serialize_timestamp<S: serde::Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs())
+        .serialize(serializer)
+}
+
+/// The outcome of cleaning up the staging directory.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StagingReport {
+    /// Files that were deleted.
+    pub removed: Vec<PathBuf>,
+    /// Total size of the deleted files.
+    pub freed_bytes: u64,
+    /// Number of files that are too recent to be deleted.
+    pub kept: u64,
+}
 
-    /// Check all entries in the data store for consistency.
-    pub fn validate(&self) -> io::Result<ValidationReport> {
-        let mut report = ValidationReport::default();
+/// The outcome of `Store::repair`.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RepairReport {
+    /// Where corrupt objects and broken manifests have been moved to.
+    pub quarantined: Vec<PathBuf>,
+    /// Unexpected files and directories, and registrations in `ref/`, that were deleted.
+    pub removed: Vec<PathBuf>,
+    /// The staging files that were cleaned up.
+    pub staging: StagingReport,
+}
 
-        for entry_or_error in self.data_dir.read_dir()? {
-            let entry = entry_or_error?;
-            if entry.file_type()?.is_dir() {
-                report.unexpected_files.push(entry.path());
-            } else {
-                let path = entry.path();
-                // try to extract the hash from the filename
-                if let Some(expected_hash) = path
-                    .file_name()
-                    .and_then(std::ffi::OsStr::to_str)
-                    .map(str::as_bytes)
-                    .and_then(Sha256Hash::from_hex)
-                {
-                    let mut file = File::open(&path)?;
-                    let actual_hash = Sha256Hash::hash_stream(&mut file)?;
-                    if actual_hash != expected_hash {
-                        report.hash_mismatches.push(HashMismatch {
-                            file_name: path,
-                            expected_hash,
-                            actual_hash,
-                        });
-                    }
-                } else {
-                    // if the filename doesn't look like a hash, the file doesn't belong here
-                    report.unexpected_files.push(path);
-                }
-            }
-        }
+/// Summary statistics about the data store.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StoreStats {
+    /// Number of objects in the store, counting every chunk of chunked objects.
+    pub objects: u64,
+    /// Total size of all objects in bytes.
+    pub bytes: u64,
+    /// Number of objects stored in chunks.
+    pub chunked_objects: u64,
+    /// The largest objects, largest first.
+    pub largest: Vec<ObjectSize>,
+    /// Number of files in the staging directory, e.g. left behind by interrupted filters.
+    pub staging_files: u64,
+    /// Total size of the files in the staging directory.
+    pub staging_bytes: u64,
+    /// Number of repositories registered with the store.
+    pub registered_repos: u64,
+}
 
-        Ok(report)
-    }
+/// An object and its size.
+#[derive(Debug, Clone, Serialize)]
+pub struct ObjectSize {
+    pub hash: ContentHash,
+    pub size: u64,
 }
 
 /// Contains a report of running a validation on the data store.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize)]
 pub struct ValidationReport {
     pub hash_mismatches: Vec<HashMismatch>,
     /// List of files that were found inside the store that don't belong there
     pub unexpected_files: Vec<PathBuf>,
+    /// Chunk manifests that cannot be parsed or refer to missing chunks
+    pub broken_manifests: Vec<PathBuf>,
+    /// Files in `ref/` that are not registrations of a repository
+    pub malformed_refs: Vec<PathBuf>,
+    /// Registrations of repositories whose git directory no longer exists
+    pub stale_refs: Vec<PathBuf>,
+    /// Number of objects that were not hashed, because they are unchanged since they were last verified
+    pub skipped: u64,
 }
 
 impl ValidationReport {
     /// Return whether the data store is valid, i.e. it doesn't contain any faulty entries.
     pub fn is_valid(&self) -> bool {
-        self.hash_mismatches.is_empty() && self.unexpected_files.is_empty()
+        self.hash_mismatches.is_empty()
+            && self.unexpected_files.is_empty()
+            && self.broken_manifests.is_empty()
+            && self.malformed_refs.is_empty()
+            && self.stale_refs.is_empty()
     }
 }
 
 /// The content hash didn't match the file name.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct HashMismatch {
     /// Affected file in the store.
     pub file_name: PathBuf,
     /// Expected content hash based on the filename
-    pub expected_hash: Sha256Hash,
+    pub expected_hash: ContentHash,
     /// Actual content hash based on the contents
-    pub actual_hash: Sha256Hash,
+    pub actual_hash: ContentHash,
+}
+
+/// Reads the contents of an object, see `Store::open_ref`.
+pub struct ObjectReader<'a> {
+    /// Where the chunks are read from.
+    data: &'a dyn StorageBackend,
+    /// Total size of the contents.
+    size: u64,
+    /// Object or chunk that is currently being read.
+    current: Option<Box<dyn Read + Send>>,
+    /// Chunks that still need to be read after the current one.
+    remaining: std::vec::IntoIter<ContentHash>,
+    /// Hash of the contents read so far and the hash they must end up with, if verifying.
+    verifier: Option<(Hasher, ContentHash)>,
+}
+
+impl ObjectReader<'_> {
+    /// Total size of the object in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl ObjectReader<'_> {
+    fn read_parts(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(reader) = &mut self.current {
+                let n_read = reader.read(buf)?;
+                if n_read > 0 || buf.is_empty() {
+                    return Ok(n_read);
+                }
+            }
+            match self.remaining.next() {
+                Some(chunk) => self.current = Some(self.data.get(&chunk)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Read for ObjectReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.read_parts(buf)?;
+        if let Some((hasher, expected)) = &mut self.verifier {
+            hasher.update(&buf[..n_read]);
+            if n_read == 0 && !buf.is_empty() {
+                let actual = hasher.clone().finish();
+                if actual != *expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "object {} is corrupt, its contents hash to {}",
+                            expected, actual
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(n_read)
+    }
 }
 
 /// A reference to a data file stored in the `Store`.
+///
+/// Besides the hash, which identifies the object, a reference may know the size and media type
+/// of the contents. References returned by the store always do, while those parsed from a
+/// `v1` pointer or created with `from_hash` don't.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct StoreFileRef {
-    hash: Sha256Hash,
+    hash: ContentHash,
+    size: Option<u64>,
+    media_type: Option<String>,
 }
 
+/// Upper bound for the length of pointers that `parse_from_stream` reads.
+pub const MAX_POINTER_LEN: u64 = 1024;
+/// First line of Git LFS pointers.
+const LFS_VERSION_LINE: &str = "version https://git-lfs.github.com/spec/v1\n";
+
 impl StoreFileRef {
-    pub fn from_hash(hash: Sha256Hash) -> StoreFileRef {
-        Self { hash }
+    pub fn from_hash(hash: ContentHash) -> StoreFileRef {
+        Self {
+            hash,
+            size: None,
+            media_type: None,
+        }
     }
 
-    pub fn hash(&self) -> &Sha256Hash {
+    pub fn hash(&self) -> &ContentHash {
         &self.hash
     }
 
-    /// Convert this reference to its string representation in this format:
+    /// The same reference, knowing the size of the contents, e.g. as looked up with
+    /// `Store::object_size`.
+    pub fn with_size(mut self, size: u64) -> StoreFileRef {
+        self.size = Some(size);
+        self
+    }
+
+    /// Size of the contents in bytes, if known.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Detected media type of the contents, if known, e.g. `image/png`.
+    pub fn media_type(&self) -> Option<&str> {
+        self.media_type.as_deref()
+    }
+
+    /// Parse a pointer in any of the formats described by `PointerFormat`, detecting which
+    /// one it is.
+    pub fn parse_from_stream<R: Read>(reader: &mut R) -> io::Result<StoreFileRef> {
+        let mut pointer = Vec::new();
+        reader.take(MAX_POINTER_LEN).read_to_end(&mut pointer)?;
+        if pointer.starts_with(b"{") {
+            return Self::parse_json(&pointer).ok_or_else(|| io::ErrorKind::InvalidData.into());
+        }
+        if let Some(rest) = pointer.strip_prefix(LFS_VERSION_LINE.as_bytes()) {
+            return Self::parse_lfs(rest).ok_or_else(|| io::ErrorKind::InvalidData.into());
+        }
+
+        // The line formats start with the magic string "git-assets", a space, the version and
+        // a newline, which ensures that we don't accidentally try to parse something else.
+        let (format, rest) = if let Some(rest) = pointer.strip_prefix(b"git-assets v1\n") {
+            (PointerFormat::V1, rest)
+        } else if let Some(rest) = pointer.strip_prefix(b"git-assets v2\n") {
+            (PointerFormat::V2, rest)
+        } else {
+            return Err(io::ErrorKind::InvalidData.into());
+        };
+        let mut lines = rest
+            .split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        // The hash in its text form, e.g. 64 bytes for the hex encoded sha256
+        let hash_hex = lines.next().unwrap_or_default();
+        let mut store_ref =
+            Self::from_hash(ContentHash::from_hex(hash_hex).ok_or(io::ErrorKind::InvalidData)?);
+        if format == PointerFormat::V1 {
+            // Anything after the hash is ignored
+            return Ok(store_ref);
+        }
+
+        for line in lines.filter(|line| !line.is_empty()) {
+            let line = std::str::from_utf8(line).map_err(|_| io::ErrorKind::InvalidData)?;
+            let (key, value) = line.split_once(' ').ok_or(io::ErrorKind::InvalidData)?;
+            match key {
+                "size" => {
+                    store_ref.size = Some(value.parse().map_err(|_| io::ErrorKind::InvalidData)?)
+                }
+                "type" => store_ref.media_type = Some(value.to_string()),
+                // Leave room for adding more fields without a new version
+                _ => {}
+            }
+        }
+        if store_ref.size.is_none() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(store_ref)
+    }
+
+    /// Parse a pointer in the `Json` format.
+    fn parse_json(pointer: &[u8]) -> Option<StoreFileRef> {
+        let value: serde_json::Value = serde_json::from_slice(pointer).ok()?;
+        let fields = value.as_object()?;
+        if fields.get("version")?.as_u64()? != 2 {
+            return None;
+        }
+        // Exactly one of the fields is named after the hash algorithm
+        let mut hashes = fields.iter().filter_map(|(key, value)| {
+            let algorithm = hash::algorithm(key)?;
+            let digest = hex::decode(value.as_str()?).ok()?;
+            Some(ContentHash::from_digest(algorithm, &digest))
+        });
+        let hash = hashes.next()??;
+        if hashes.next().is_some() {
+            return None;
+        }
+        let media_type = match fields.get("type") {
+            Some(media_type) => Some(media_type.as_str()?.to_string()),
+            None => None,
+        };
+        Some(StoreFileRef {
+            hash,
+            size: Some(fields.get("size")?.as_u64()?),
+            media_type,
+        })
+    }
+
+    /// Parse the lines after the version line of a Git LFS pointer.
+    fn parse_lfs(rest: &[u8]) -> Option<StoreFileRef> {
+        let mut hash = None;
+        let mut size = None;
+        for line in std::str::from_utf8(rest).ok()?.lines() {
+            match line.split_once(' ')? {
+                ("oid", oid) => {
+                    let digest = hex::decode(oid.strip_prefix("sha256:")?).ok()?;
+                    hash = Some(ContentHash::from_digest(hash::SHA256, &digest)?);
+                }
+                ("size", value) => size = Some(value.parse().ok()?),
+                // Extensions change what the oid refers to, so the contents would be wrong
+                (key, _) if key.starts_with("ext-") => return None,
+                _ => {}
+            }
+        }
+        Some(StoreFileRef {
+            hash: hash?,
+            size: Some(size?),
+            media_type: None,
+        })
+    }
+
+    /// Format this reference as a pointer in the given format.
+    ///
+    /// References whose size is unknown can only be written as `v1` pointers, so they are,
+    /// whatever format is asked for. The same goes for `Lfs` pointers to objects that are
+    /// not addressed by SHA-256.
+    pub fn to_pointer(&self, format: PointerFormat) -> String {
+        let is_sha256 = self.hash.algorithm().name() == hash::SHA256.name();
+        match (format, self.size) {
+            (PointerFormat::V1, _) | (_, None) => self.to_string(),
+            (PointerFormat::Lfs, Some(_)) if !is_sha256 => self.to_string(),
+            (PointerFormat::Lfs, Some(size)) => format!(
+                "{}oid sha256:{}\nsize {}",
+                LFS_VERSION_LINE, self.hash, size
+            ),
+            (PointerFormat::V2, Some(size)) => {
+                let mut pointer = format!("git-assets v2\n{}\nsize {}", self.hash, size);
+                if let Some(media_type) = &self.media_type {
+                    pointer.push_str(&format!("\ntype {}", media_type));
+                }
+                pointer
+            }
+            (PointerFormat::Json, Some(size)) => {
+                // Written by hand to keep the fields in the documented order
+                let mut pointer = format!(
+                    "{{\"version\":2,\"{}\":\"{}\",\"size\":{}",
+                    self.hash.algorithm().name(),
+                    hex::encode(self.hash.as_bytes()),
+                    size
+                );
+                if let Some(media_type) = &self.media_type {
+                    let media_type = serde_json::to_string(media_type).expect("strings serialize");
+                    pointer.push_str(&format!(",\"type\":{}", media_type));
+                }
+                pointer.push('}');
+                pointer
+            }
+        }
+    }
+}
+
+impl fmt::Display for StoreFileRef {
+    /// Format this reference in its string representation:
     ///
     /// ```text
     /// git-assets <format-version>
-    /// <file-sha256-hash>
+    /// <file-hash>
     /// ```
     ///
-    /// where `<format-version>` is currently `v1` and will be increased when
-    /// the reference format changes, and <file-sha256-hash> is the sha 256
-    /// hash of the file contents that are pointed to by this reference.
-    pub fn to_string(&self) -> String {
-        format!("git-assets v1\n{}", self.hash)
+    /// where `<format-version>` is `v1`, and <file-hash> is the text form of the
+    /// hash of the file contents that are pointed to by this reference. See
+    /// `to_pointer` for the other formats.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "git-assets v1\n{}", self.hash)
     }
+}
 
-    pub fn parse_from_stream<R: Read>(reader: &mut R) -> io::Result<StoreFileRef> {
-        // The current format takes exactly 78 bytes:
-        // - 10 bytes for the magic string "git-assets"
-        // - 1 byte for a space
-        // - 2 bytes for "v1"
-        // - 1 byte for the newline
-        // - 64 bytes for the hex encoded sha256
-        // First read magic to ensure that we don't accidentally try to parse something else
-        let mut buf = [0; 78];
-        reader.read_exact(&mut buf)?;
-        if &buf[0..14] != b"git-assets v1\n" {
-            return Err(io::ErrorKind::InvalidData.into());
-        }
+/// The formats in which pointers can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerFormat {
+    /// Only the hash:
+    ///
+    /// ```text
+    /// git-assets v1
+    /// <file-hash>
+    /// ```
+    V1,
+    /// The hash followed by the size in bytes and the detected media type of the contents:
+    ///
+    /// ```text
+    /// git-assets v2
+    /// <file-hash>
+    /// size <bytes>
+    /// type <media-type>
+    /// ```
+    ///
+    /// The `type` line is optional, and lines with unknown keys are ignored.
+    V2,
+    /// The same information as `V2`, as a JSON object on a single line for tools that
+    /// already know how to parse JSON:
+    ///
+    /// ```text
+    /// {"version":2,"sha256":"<hex-digest>","size":<bytes>,"type":"<media-type>"}
+    /// ```
+    ///
+    /// The digest is given in a field named after the hash algorithm, e.g. `sha512_256`.
+    /// The `type` field is optional, and unknown fields are ignored.
+    Json,
+    /// A Git LFS pointer, so that repositories can switch between Git LFS and git-assets
+    /// without rewriting their history:
+    ///
+    /// ```text
+    /// version https://git-lfs.github.com/spec/v1
+    /// oid sha256:<hex-digest>
+    /// size <bytes>
+    /// ```
+    ///
+    /// Only objects addressed by SHA-256 can be referenced this way, and pointers using Git
+    /// LFS extensions are rejected.
+    Lfs,
+}
 
-        let hash = Sha256Hash::from_hex(&buf[14..]).ok_or(io::ErrorKind::InvalidData)?;
+impl std::str::FromStr for PointerFormat {
+    type Err = String;
 
-        Ok(Self { hash })
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(PointerFormat::V1),
+            "v2" => Ok(PointerFormat::V2),
+            "json" => Ok(PointerFormat::Json),
+            "lfs" => Ok(PointerFormat::Lfs),
+            _ => Err(format!("unknown pointer format: {}", s)),
+        }
     }
 }
 
 pub struct StagingFile {
     filename: PathBuf,
     file: File,
-    hasher: Sha256,
+    hasher: Hasher,
+    /// Whether the contents are hashed in parallel once complete instead of while writing.
+    deferred: bool,
 }
 
 impl StagingFile {
-    fn new(filename: PathBuf, file: File) -> StagingFile {
+    fn new(filename: PathBuf, file: File, algorithm: &'static dyn HashAlgorithm) -> StagingFile {
         StagingFile {
             filename,
             file,
-            hasher: Sha256::new(),
+            hasher: Hasher::new(algorithm),
+            deferred: false,
+        }
+    }
+}
+
+impl StagingFile {
+    /// The hash of the contents written so far.
+    fn hash(&self) -> io::Result<ContentHash> {
+        if !self.deferred {
+            return Ok(self.hasher.clone().finish());
+        }
+        let algorithm = self.hasher.algorithm();
+        let mut file = File::open(&self.filename)?;
+        match hash::map_store_file(&file)? {
+            Some(map) => {
+                let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+                Ok(ContentHash::hash_bytes_parallel(algorithm, &map, jobs))
+            }
+            None => ContentHash::hash_store_file(algorithm, &mut file),
         }
     }
+
+    /// Append the contents of `source` like `io::copy`, using io_uring where available.
+    fn copy_from(&mut self, source: &mut File) -> io::Result<u64> {
+        if self.deferred {
+            return io::copy(source, &mut self.file);
+        }
+        hash::copy_file(source, &mut self.file, &mut self.hasher)
+    }
+
+    /// The number of bytes staged so far.
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Throw away the staged contents.
+    pub fn discard(self) -> io::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(self.filename)
+    }
 }
 
 impl Write for StagingFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n_written = self.file.write(buf)?;
         // Only hash the parts that we managed to write
-        self.hasher.input(&buf[0..n_written]);
+        if !self.deferred {
+            self.hasher.update(&buf[0..n_written]);
+        }
 
         Ok(n_written)
     }
@@ -218,6 +2727,144 @@ impl Write for StagingFile {
     }
 }
 
+/// Return whether a store location is the URL of a remote store rather than a local directory.
+pub fn is_url(location: &Path) -> bool {
+    location
+        .to_str()
+        .is_some_and(|location| location.contains("://"))
+}
+
+/// Create `target` as a copy-on-write clone of `source`, with `clonefile(2)` on APFS or the
+/// `FICLONE` ioctl on e.g. Btrfs and XFS, so that no data is copied. Returns `false` if that is
+/// not possible, e.g. on other file systems or across them, in which case `target` doesn't
+/// exist afterwards and the caller copies instead, which reports any actual problem.
+#[cfg_attr(
+    not(any(target_os = "macos", target_os = "linux")),
+    allow(unused_variables)
+)]
+fn clone_file(source: &Path, target: &Path) -> bool {
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let (source, target) = match (
+            CString::new(source.as_os_str().as_bytes()),
+            CString::new(target.as_os_str().as_bytes()),
+        ) {
+            (Ok(source), Ok(target)) => (source, target),
+            _ => return false,
+        };
+        // Safety: both are valid, NUL terminated paths
+        unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) == 0 }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let source = match File::open(source) {
+            Ok(source) => source,
+            Err(_) => return false,
+        };
+        let file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(target)
+        {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        // Safety: both file descriptors are open for as long as the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+            return true;
+        }
+        drop(file);
+        let _ = std::fs::remove_file(target);
+        false
+    }
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        false
+    }
+}
+
+/// Name of the file in `ref/` registering the repository with the given git directory.
+fn ref_name(git_dir: &Path) -> io::Result<String> {
+    let git_dir = git_dir.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path is not valid UTF-8: {}", git_dir.display()),
+        )
+    })?;
+    Ok(ContentHash::hash_bytes(hash::SHA256, git_dir.as_bytes()).to_hex_string())
+}
+
+/// Read the git directory registered by a file in `ref/`, or `None` if the file is malformed.
+fn read_ref(path: &Path) -> io::Result<Option<PathBuf>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        // Most likely a directory
+        Err(_) if !path.is_file() => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(String::from_utf8(contents).ok().and_then(|contents| {
+        let git_dir = contents.strip_suffix('\n')?;
+        let name = ref_name(Path::new(git_dir)).ok();
+        let valid = !git_dir.contains('\n')
+            && Path::new(git_dir).is_absolute()
+            && path.file_name().and_then(|name| name.to_str()) == name.as_deref();
+        valid.then(|| PathBuf::from(git_dir))
+    }))
+}
+
+/// List a directory, treating one that doesn't exist like an empty one.
+fn read_dir_if_exists(
+    dir: &Path,
+) -> io::Result<impl Iterator<Item = io::Result<std::fs::DirEntry>>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => Some(entries),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    Ok(entries.into_iter().flatten())
+}
+
+/// Read which algorithm new objects of the local store in `base_dir` are addressed by.
+fn read_algorithm(base_dir: &Path) -> io::Result<&'static dyn HashAlgorithm> {
+    match std::fs::read_to_string(base_dir.join(ALGORITHM_FILE)) {
+        Ok(name) => parse_algorithm(Some(&name), base_dir),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => parse_algorithm(None, base_dir),
+        Err(err) => Err(err),
+    }
+}
+
+/// The algorithm named by the `algorithm` file of the store at `location`, if it has one.
+fn parse_algorithm(
+    contents: Option<&str>,
+    location: &Path,
+) -> io::Result<&'static dyn HashAlgorithm> {
+    let name = match contents {
+        Some(contents) => contents.trim(),
+        None => return Ok(hash::SHA256),
+    };
+    hash::algorithm(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported hash algorithm {:?} in {}",
+                name,
+                location.display()
+            ),
+        )
+    })
+}
+
+/// Parse the name of a file in the data directory as hash of its contents.
+fn hash_from_file_name(path: &Path) -> Option<ContentHash> {
+    path.file_name()
+        .and_then(std::ffi::OsStr::to_str)
+        .map(str::as_bytes)
+        .and_then(ContentHash::from_hex)
+}
+
 fn new_temp_file(dir: &Path, base_name: &str, suffix: &str) -> io::Result<(PathBuf, File)> {
     let mut counter = 0;
     loop {
@@ -242,13 +2889,399 @@ fn new_temp_file(dir: &Path, base_name: &str, suffix: &str) -> io::Result<(PathB
 
 #[cfg(test)]
 mod test {
-    use super::StoreFileRef;
-    use crate::hash::Sha256Hash;
+    use std::io::{Read, Write};
+    use std::time::{Duration, SystemTime};
+
+    use super::{PointerFormat, Store, StoreFileRef, GC_GRACE_PERIOD, STALE_STAGING_AGE};
+    use crate::hash::{ContentHash, SHA256, SHA256_TREE, SHA512_256};
+
+    #[test]
+    fn flat_store_is_sharded_on_open() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_upgrade.{}", std::process::id()));
+        let hash = ContentHash::hash_bytes(SHA256, b"foo").to_hex_string();
+        std::fs::create_dir_all(base_dir.join("data")).unwrap();
+        std::fs::write(base_dir.join("data").join(&hash), b"foo").unwrap();
+
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let sharded = base_dir.join("data").join(&hash[..2]).join(&hash[2..]);
+        assert_eq!(std::fs::read(sharded).unwrap(), b"foo");
+        assert!(!base_dir.join("data").join(&hash).exists());
+        assert!(store.validate().unwrap().is_valid());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    /// Deterministic, incompressible test data.
+    fn pseudo_random_bytes(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn store_bytes(store: &Store, contents: &[u8]) -> StoreFileRef {
+        let mut staging_file = store.new_staging_file().unwrap();
+        staging_file.write_all(contents).unwrap();
+        store.make_permanent(staging_file).unwrap()
+    }
+
+    #[test]
+    fn store_path_matches_stream() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_path.{}", std::process::id()));
+        let path = base_dir.with_extension("input");
+
+        // SHA-256 trees are hashed in parallel once the file is staged
+        for algorithm in [SHA256, SHA256_TREE] {
+            let store = Store::open_or_create_with(base_dir.clone(), algorithm).unwrap();
+            // One staged file large enough to be memory-mapped, one that is read as a stream
+            for len in [9 * 1024 * 1024, 100] {
+                let contents = pseudo_random_bytes(len);
+                std::fs::write(&path, &contents).unwrap();
+                let store_ref = store.store_path(&path).unwrap();
+                assert_eq!(
+                    store_ref.hash(),
+                    &ContentHash::hash_bytes(algorithm, &contents)
+                );
+                assert_eq!(read_ref(&store, &store_ref), contents);
+
+                // The same for contents from a pipe, which are hashed once staged
+                let mut staging_file = store.new_staging_file_parallel().unwrap();
+                staging_file.write_all(&contents).unwrap();
+                assert_eq!(store.make_permanent(staging_file).unwrap(), store_ref);
+            }
+            assert!(store.store_path(&base_dir.join("missing")).is_err());
+            assert!(store.validate().unwrap().is_valid());
+            std::fs::remove_dir_all(&base_dir).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn algorithm_is_chosen_at_creation() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_algorithm.{}", std::process::id()));
+        let store = Store::open_or_create_with(base_dir.clone(), SHA512_256).unwrap();
+        let store_ref = store_bytes(&store, b"foo");
+        assert_eq!(
+            store_ref.hash(),
+            &ContentHash::hash_bytes(SHA512_256, b"foo")
+        );
+        let (shard, rest) = store_ref.hash().sharded();
+        assert!(base_dir.join("data").join(shard).join(rest).is_file());
+        assert!(store.validate().unwrap().is_valid());
+
+        let digest = hex::encode(store_ref.hash().as_bytes());
+        assert_eq!(
+            store.resolve_hash(&digest).unwrap(),
+            [store_ref.hash().clone()]
+        );
+        assert_eq!(
+            store.resolve_hash(&digest[..6]).unwrap(),
+            [store_ref.hash().clone()]
+        );
+
+        // Reopening keeps the algorithm, which cannot be changed anymore
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        assert_eq!(store.algorithm().name(), "sha512_256");
+        assert_eq!(read_ref(&store, &store_ref), b"foo");
+        let err = Store::open_or_create_with(base_dir.clone(), SHA256).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    fn read_ref(store: &Store, store_ref: &StoreFileRef) -> Vec<u8> {
+        let mut reader = store.open_ref(store_ref).unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(reader.size(), contents.len() as u64);
+        contents
+    }
+
+    #[test]
+    fn chunked_objects_share_chunks() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_chunked.{}", std::process::id()));
+        let mut store = Store::open_or_create(base_dir.clone()).unwrap();
+        store.set_chunked(true);
+
+        let old = pseudo_random_bytes(12 * 1024 * 1024);
+        let mut new = old.clone();
+        new[6 * 1024 * 1024..6 * 1024 * 1024 + 100].copy_from_slice(&[0; 100]);
+
+        let old_ref = store_bytes(&store, &old);
+        let chunks_after_old = store.stats().unwrap().objects;
+        let new_ref = store_bytes(&store, &new);
+        let chunks_after_new = store.stats().unwrap().objects;
+        assert!(chunks_after_old > 1);
+        // Only the chunks around the modification are new
+        assert!(chunks_after_new - chunks_after_old <= 2);
+
+        assert_eq!(old_ref.hash(), &ContentHash::hash_bytes(SHA256, &old));
+        assert_eq!(read_ref(&store, &old_ref), old);
+        assert_eq!(read_ref(&store, &new_ref), new);
+        assert_eq!(store.object_size(&new_ref).unwrap(), Some(new.len() as u64));
+        let checkout = base_dir.join("checkout");
+        let size = store.write_to_file(&new_ref, &checkout).unwrap();
+        assert_eq!(size, new.len() as u64);
+        assert!(std::fs::read(&checkout).unwrap() == new);
+        std::fs::remove_file(checkout).unwrap();
+        assert!(store.validate().unwrap().is_valid());
+
+        let report = store
+            .collect_garbage_with(std::iter::once(new_ref.hash().clone()), Duration::ZERO)
+            .unwrap();
+        assert!(report.removed.contains(old_ref.hash()));
+        assert_eq!(store.object_size(&old_ref).unwrap(), None);
+        assert_eq!(read_ref(&store, &new_ref), new);
+        assert!(store.validate().unwrap().is_valid());
+
+        // Removing an object keeps the chunks it shares with others
+        store_bytes(&store, &old);
+        assert!(store.remove(old_ref.hash()).unwrap());
+        assert!(!store.remove(old_ref.hash()).unwrap());
+        assert_eq!(read_ref(&store, &new_ref), new);
+        assert!(store.validate().unwrap().is_valid());
+        assert!(store.remove(new_ref.hash()).unwrap());
+        assert_eq!(store.stats().unwrap().objects, 0);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn garbage_collection_keeps_recent_objects() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_grace.{}", std::process::id()));
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let old_ref = store_bytes(&store, b"stored long ago");
+        let new_ref = store_bytes(&store, b"stored just now");
+        let old_path = store.object_location(&old_ref).unwrap().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(old_path)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * GC_GRACE_PERIOD)
+            .unwrap();
+
+        let report = store.collect_garbage(std::iter::empty()).unwrap();
+        assert_eq!(report.removed, [old_ref.hash().clone()]);
+        assert_eq!(report.kept, 1);
+        assert_eq!(store.object_size(&new_ref).unwrap(), Some(15));
+
+        let report = store
+            .collect_garbage_with(std::iter::empty(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(report.removed, [new_ref.hash().clone()]);
+        assert_eq!(store.stats().unwrap().objects, 0);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn transfers_report_progress() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_progress.{}", std::process::id()));
+        std::fs::create_dir_all(&base_dir).unwrap();
+        let local = Store::open_or_create(base_dir.join("local")).unwrap();
+        let remote = Store::open_or_create(base_dir.join("remote")).unwrap();
+        let refs: Vec<_> = [&b"abc"[..], b"defgh"]
+            .iter()
+            .map(|contents| store_bytes(&local, contents))
+            .collect();
+
+        let mut updates = Vec::new();
+        let report = local
+            .push_to_with(&remote, &mut |progress| updates.push(*progress))
+            .unwrap();
+        assert_eq!(report.bytes, 8);
+        assert!(updates
+            .iter()
+            .all(|progress| progress.total == Some((2, 8))));
+        assert!(updates
+            .windows(2)
+            .all(|pair| pair[0].bytes <= pair[1].bytes && pair[0].files <= pair[1].files));
+        assert_eq!(
+            updates
+                .last()
+                .map(|progress| (progress.files, progress.bytes)),
+            Some((2, 8))
+        );
+
+        let fetched = Store::open_or_create(base_dir.join("fetched")).unwrap();
+        let mut last = None;
+        fetched
+            .fetch_from_remote_with(
+                &remote,
+                refs.iter().map(|store_ref| store_ref.hash().clone()),
+                &mut |progress| last = Some(*progress),
+            )
+            .unwrap();
+        let last = last.unwrap();
+        assert_eq!((last.files, last.bytes, last.total), (2, 8, None));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn validation_finds_all_mismatches() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_validate.{}", std::process::id()));
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let refs: Vec<StoreFileRef> = (0..20u8)
+            .map(|i| store_bytes(&store, &pseudo_random_bytes(1000 + i as usize)))
+            .collect();
+        assert!(store.validate_with(4, true).unwrap().is_valid());
+        // Nothing changed since, so the second run can rely on the first
+        assert_eq!(store.validate_with(4, false).unwrap().skipped, 20);
+        assert_eq!(store.validate_with(4, true).unwrap().skipped, 0);
+
+        let mut corrupted: Vec<ContentHash> =
+            refs.iter().step_by(3).map(|r| r.hash().clone()).collect();
+        for hash in &corrupted {
+            std::fs::write(store.data.location(hash), b"corrupted").unwrap();
+        }
+        corrupted.sort();
+        for jobs in [1, 4] {
+            let report = store.validate_with(jobs, false).unwrap();
+            let mismatches: Vec<ContentHash> = report
+                .hash_mismatches
+                .iter()
+                .map(|mismatch| mismatch.expected_hash.clone())
+                .collect();
+            assert_eq!(mismatches, corrupted);
+        }
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn verified_reads_reject_corrupt_objects() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_verify.{}", std::process::id()));
+        let mut store = Store::open_or_create(base_dir.clone()).unwrap();
+        let intact = store_bytes(&store, b"intact");
+        let corrupt = store_bytes(&store, b"corrupt");
+        std::fs::write(store.data.location(corrupt.hash()), b"bitrot").unwrap();
+        // Without verification, the corruption goes unnoticed
+        assert_eq!(read_ref(&store, &corrupt), b"bitrot");
+
+        store.set_verify_reads(true);
+        assert_eq!(read_ref(&store, &intact), b"intact");
+        let mut contents = Vec::new();
+        let err = store
+            .open_ref(&corrupt)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = store
+            .write_to_file(&corrupt, &base_dir.join("checkout"))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn written_files_are_cloned_or_copied() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_clone.{}", std::process::id()));
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let store_ref = store_bytes(&store, b"contents");
+        let object = store.data.location(store_ref.hash());
+
+        // Whether cloning works depends on the file system of the temporary directory
+        let clone = base_dir.join("clone");
+        if super::clone_file(&object, &clone) {
+            assert_eq!(std::fs::read(&clone).unwrap(), b"contents");
+        } else {
+            assert!(!clone.exists());
+        }
+
+        let target = base_dir.join("checkout");
+        assert_eq!(store.write_to_file(&store_ref, &target).unwrap(), 8);
+        assert_eq!(std::fs::read(&target).unwrap(), b"contents");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let executable = std::fs::Permissions::from_mode(0o755);
+            std::fs::set_permissions(&target, executable).unwrap();
+            store.write_to_file(&store_ref, &target).unwrap();
+            let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn stale_staging_files_are_removed_once_a_day() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_stale.{}", std::process::id()));
+        let mut store = Store::open_or_create(base_dir.clone()).unwrap();
+        let stale = |name: &str| {
+            let path = base_dir.join("staging").join(name);
+            let file = std::fs::File::create(&path).unwrap();
+            let modified = SystemTime::now() - 2 * STALE_STAGING_AGE;
+            file.set_modified(modified).unwrap();
+            path
+        };
+
+        // Opening the store leaves them alone, and so do dry runs
+        let first = stale("smudge.1.");
+        drop(Store::open_or_create(base_dir.clone()).unwrap());
+        store.set_dry_run(true);
+        store.clean_stale_staging();
+        assert!(first.exists());
+
+        store.set_dry_run(false);
+        store.clean_stale_staging();
+        assert!(!first.exists());
+        // Until a day has passed, there is no need to look again
+        let second = stale("smudge.2.");
+        store.clean_stale_staging();
+        assert!(second.exists());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn validation_checks_registered_repos() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.store_refs.{}", std::process::id()));
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let git_dir = base_dir.join("repo.git");
+        std::fs::create_dir(&git_dir).unwrap();
+        store.register_repo(&git_dir).unwrap();
+        assert!(store.validate().unwrap().is_valid());
+
+        std::fs::write(base_dir.join("ref").join("garbage"), b"/nowhere\n").unwrap();
+        std::fs::remove_dir(&git_dir).unwrap();
+        let report = store.validate().unwrap();
+        assert_eq!(
+            report.malformed_refs,
+            [base_dir.join("ref").join("garbage")]
+        );
+        assert_eq!(report.stale_refs.len(), 1);
+
+        let repair = store.repair(&report, STALE_STAGING_AGE).unwrap();
+        assert_eq!(repair.removed.len(), 2);
+        assert!(store.validate().unwrap().is_valid());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
 
     #[test]
     fn store_file_ref_roundtrip() {
         let r = StoreFileRef::from_hash(
-            Sha256Hash::from_hex(
+            ContentHash::from_hex(
                 b"2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae",
             )
             .unwrap(),
@@ -262,5 +3295,75 @@ mod test {
 
         let r2 = StoreFileRef::parse_from_stream(&mut std::io::Cursor::new(serialized)).unwrap();
         assert_eq!(r2, r);
+        // Without a size there is nothing to put into a v2 pointer
+        assert_eq!(r.to_pointer(PointerFormat::V2), r.to_string());
+    }
+
+    #[test]
+    fn store_file_ref_v2_roundtrip() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.pointer_v2.{}", std::process::id()));
+        let store = Store::open_or_create(base_dir.clone()).unwrap();
+        let r = store_bytes(&store, b"%PDF-1.7\n");
+        assert_eq!(r.size(), Some(9));
+        assert_eq!(r.media_type(), Some("application/pdf"));
+
+        let serialized = r.to_pointer(PointerFormat::V2);
+        assert_eq!(
+            serialized,
+            format!("git-assets v2\n{}\nsize 9\ntype application/pdf", r.hash())
+        );
+        let r2 = StoreFileRef::parse_from_stream(&mut serialized.as_bytes()).unwrap();
+        assert_eq!(r2, r);
+
+        // Unknown fields are skipped, but the size is required
+        let pointer = format!("git-assets v2\n{}\ncolor blue\nsize 9\n", r.hash());
+        let r3 = StoreFileRef::parse_from_stream(&mut pointer.as_bytes()).unwrap();
+        assert_eq!(
+            (r3.hash(), r3.size(), r3.media_type()),
+            (r.hash(), Some(9), None)
+        );
+        let pointer = format!("git-assets v2\n{}\ntype text/plain\n", r.hash());
+        assert!(StoreFileRef::parse_from_stream(&mut pointer.as_bytes()).is_err());
+
+        let serialized = r.to_pointer(PointerFormat::Json);
+        assert_eq!(
+            serialized,
+            format!(
+                "{{\"version\":2,\"sha256\":\"{}\",\"size\":9,\"type\":\"application/pdf\"}}",
+                r.hash()
+            )
+        );
+        let r4 = StoreFileRef::parse_from_stream(&mut serialized.as_bytes()).unwrap();
+        assert_eq!(r4, r);
+        for pointer in [
+            format!("{{\"version\":1,\"sha256\":\"{}\",\"size\":9}}", r.hash()),
+            format!("{{\"version\":2,\"sha256\":\"{}\"}}", r.hash()),
+            format!(
+                "{{\"version\":2,\"sha256\":\"{0}\",\"sha512_256\":\"{0}\",\"size\":9}}",
+                r.hash()
+            ),
+            "{\"version\":2,\"sha256\":\"00\",\"size\":9}".to_string(),
+        ] {
+            assert!(StoreFileRef::parse_from_stream(&mut pointer.as_bytes()).is_err());
+        }
+
+        let serialized = r.to_pointer(PointerFormat::Lfs);
+        assert_eq!(
+            serialized,
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 9",
+                r.hash()
+            )
+        );
+        let r5 = StoreFileRef::parse_from_stream(&mut serialized.as_bytes()).unwrap();
+        assert_eq!((r5.hash(), r5.size()), (r.hash(), Some(9)));
+        let pointer = format!(
+            "version https://git-lfs.github.com/spec/v1\next-0-foo sha256:{0}\noid sha256:{0}\nsize 9\n",
+            r.hash()
+        );
+        assert!(StoreFileRef::parse_from_stream(&mut pointer.as_bytes()).is_err());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
     }
 }
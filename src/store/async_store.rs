@@ -0,0 +1,165 @@
+//! Access to a `Store` from async code, enabled by the `async` feature.
+//!
+//! Contents are streamed through `tokio::io::AsyncRead` and `AsyncWrite`, so that services
+//! can store and serve objects without dedicating a thread to every request. Blocking file
+//! system calls run on tokio's blocking thread pool one at a time, just like `tokio::fs` does.
+
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Store, StoreFileRef};
+
+/// Size of the buffer that contents are streamed through.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A `Store` that can be used from async code. Cloning it is cheap and shares the store.
+#[derive(Debug, Clone)]
+pub struct AsyncStore {
+    store: Arc<Store>,
+}
+
+impl AsyncStore {
+    pub fn new(store: Store) -> AsyncStore {
+        AsyncStore {
+            store: Arc::new(store),
+        }
+    }
+
+    /// The underlying store, e.g. for operations that have no async variant.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Store everything that can be read from `reader`, like `Store::make_permanent` does for
+    /// a staging file.
+    pub async fn put<R: AsyncRead + Unpin>(&self, reader: &mut R) -> io::Result<StoreFileRef> {
+        let store = Arc::clone(&self.store);
+        let mut staging_file = blocking(move || store.new_staging_file()).await?;
+        let mut file = tokio::fs::File::from_std(staging_file.file.try_clone()?);
+
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let copied = async {
+            loop {
+                let n_read = reader.read(&mut buf).await?;
+                if n_read == 0 {
+                    // Wait for the last write to finish before the file is closed
+                    return file.flush().await;
+                }
+                file.write_all(&buf[..n_read]).await?;
+                staging_file.hasher.update(&buf[..n_read]);
+            }
+        }
+        .await;
+        drop(file);
+
+        let store = Arc::clone(&self.store);
+        blocking(move || match copied {
+            Ok(()) => store.make_permanent(staging_file),
+            Err(err) => {
+                staging_file.discard()?;
+                Err(err)
+            }
+        })
+        .await
+    }
+
+    /// Write the contents of an object to `writer` and return their size, like reading from
+    /// `Store::open_ref` would.
+    pub async fn get<W: AsyncWrite + Unpin>(
+        &self,
+        store_ref: &StoreFileRef,
+        writer: &mut W,
+    ) -> io::Result<u64> {
+        let store = Arc::clone(&self.store);
+        let hash = store_ref.hash.clone();
+        let (_, parts) = blocking(move || store.object_parts(&hash)).await?;
+
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut written = 0;
+        for part in parts {
+            let store = Arc::clone(&self.store);
+            let mut reader = blocking(move || store.data.get(&part)).await?;
+            loop {
+                let (returned_reader, returned_buf, n_read) = blocking(move || {
+                    let n_read = reader.read(&mut buf)?;
+                    Ok((reader, buf, n_read))
+                })
+                .await?;
+                reader = returned_reader;
+                buf = returned_buf;
+                if n_read == 0 {
+                    break;
+                }
+                writer.write_all(&buf[..n_read]).await?;
+                written += n_read as u64;
+            }
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    /// Return the size of an object, or `None` if it is not in the store.
+    pub async fn object_size(&self, store_ref: &StoreFileRef) -> io::Result<Option<u64>> {
+        let store = Arc::clone(&self.store);
+        let store_ref = store_ref.clone();
+        blocking(move || store.object_size(&store_ref)).await
+    }
+}
+
+/// Run a blocking operation on tokio's blocking thread pool.
+async fn blocking<T, F>(operation: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(operation)
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncStore;
+    use crate::hash::{ContentHash, SHA256};
+    use crate::store::Store;
+
+    #[test]
+    fn put_get_roundtrip() {
+        let base_dir =
+            std::env::temp_dir().join(format!("git-assets.async_store.{}", std::process::id()));
+        let mut store = Store::open_or_create(base_dir.clone()).unwrap();
+        store.set_chunked(true);
+        let store = AsyncStore::new(store);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // Large enough to be stored in chunks
+        let contents: Vec<u8> = (0..20 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        runtime.block_on(async {
+            let store_ref = store.put(&mut contents.as_slice()).await.unwrap();
+            assert_eq!(
+                store_ref.hash(),
+                &ContentHash::hash_bytes(SHA256, &contents)
+            );
+            assert_eq!(
+                store.object_size(&store_ref).await.unwrap(),
+                Some(contents.len() as u64)
+            );
+
+            let mut read = Vec::new();
+            let size = store.get(&store_ref, &mut read).await.unwrap();
+            assert_eq!(size, contents.len() as u64);
+            assert!(read == contents);
+
+            let missing =
+                crate::store::StoreFileRef::from_hash(ContentHash::hash_bytes(SHA256, b"x"));
+            assert!(store.get(&missing, &mut Vec::new()).await.is_err());
+        });
+        assert!(store.store().validate().unwrap().is_valid());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+//! Remembers which objects passed validation, so that unchanged objects need not be hashed again.
+//!
+//! The cache is a text file with one `<hash> <size> <mtime> <verified>` line per object,
+//! where both times are given in nanoseconds since the unix epoch.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hash::ContentHash;
+
+/// The state of an object when it was last found to be intact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verified {
+    pub size: u64,
+    /// Modification time of the object file.
+    pub modified: u128,
+    /// When the object was hashed.
+    pub verified: u128,
+}
+
+impl Verified {
+    /// Return whether an object with the given size and modification time is still the
+    /// object that was verified.
+    pub fn matches(&self, size: u64, modified: Option<SystemTime>) -> bool {
+        self.size == size && modified.and_then(nanos_since_epoch) == Some(self.modified)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerificationCache {
+    entries: HashMap<ContentHash, Verified>,
+}
+
+impl VerificationCache {
+    /// Read the cache from `path`. A missing file is an empty cache, and so are files that
+    /// cannot be parsed, since the cache can always be rebuilt by hashing everything again.
+    pub fn load(path: &Path) -> io::Result<VerificationCache> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => String::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(VerificationCache::parse(&contents).unwrap_or_default())
+    }
+
+    fn parse(contents: &str) -> Option<VerificationCache> {
+        let mut entries = HashMap::new();
+        for line in contents.lines() {
+            let mut fields = line.split(' ');
+            let hash = ContentHash::from_hex(fields.next()?.as_bytes())?;
+            let verified = Verified {
+                size: fields.next()?.parse().ok()?,
+                modified: fields.next()?.parse().ok()?,
+                verified: fields.next()?.parse().ok()?,
+            };
+            if fields.next().is_some() {
+                return None;
+            }
+            entries.insert(hash, verified);
+        }
+        Some(VerificationCache { entries })
+    }
+
+    pub fn get(&self, hash: &ContentHash) -> Option<&Verified> {
+        self.entries.get(hash)
+    }
+
+    /// Record that the object was just found to be intact. Objects whose modification time
+    /// is unknown cannot be recognized later, so they are not recorded.
+    pub fn insert(&mut self, hash: ContentHash, size: u64, modified: Option<SystemTime>) {
+        let modified = match modified.and_then(nanos_since_epoch) {
+            Some(modified) => modified,
+            None => return,
+        };
+        let verified = nanos_since_epoch(SystemTime::now()).unwrap_or(0);
+        self.entries.insert(
+            hash,
+            Verified {
+                size,
+                modified,
+                verified,
+            },
+        );
+    }
+
+    /// Keep an entry of another cache, e.g. of an object that was skipped because of it.
+    pub fn keep(&mut self, hash: ContentHash, verified: Verified) {
+        self.entries.insert(hash, verified);
+    }
+
+    /// Write the cache to `path`, replacing the previous one atomically.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(hash, verified)| {
+                format!(
+                    "{} {} {} {}\n",
+                    hash, verified.size, verified.modified, verified.verified
+                )
+            })
+            .collect();
+        lines.sort();
+
+        let temp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+        let mut file = std::fs::File::create(&temp_path)?;
+        let written = file
+            .write_all(lines.concat().as_bytes())
+            .and_then(|()| file.sync_all());
+        if let Err(err) = written.and_then(|()| std::fs::rename(&temp_path, path)) {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+fn nanos_since_epoch(time: SystemTime) -> Option<u128> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since_epoch| since_epoch.as_nanos())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::VerificationCache;
+    use crate::hash::{ContentHash, SHA256};
+
+    #[test]
+    fn cache_roundtrip() {
+        let path = std::env::temp_dir().join(format!("git-assets.verified.{}", std::process::id()));
+        let hash = ContentHash::hash_bytes(SHA256, b"foo");
+        let modified = UNIX_EPOCH + Duration::from_nanos(1_600_000_000_123_456_789);
+
+        let mut cache = VerificationCache::default();
+        cache.insert(hash.clone(), 3, Some(modified));
+        cache.insert(ContentHash::hash_bytes(SHA256, b"bar"), 3, None);
+        cache.save(&path).unwrap();
+
+        let cache = VerificationCache::load(&path).unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        let verified = cache.get(&hash).unwrap();
+        assert!(verified.matches(3, Some(modified)));
+        assert!(!verified.matches(4, Some(modified)));
+        assert!(!verified.matches(3, Some(modified + Duration::from_nanos(1))));
+        assert!(!verified.matches(3, None));
+
+        std::fs::write(&path, "garbage\n").unwrap();
+        assert!(VerificationCache::load(&path).unwrap().get(&hash).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
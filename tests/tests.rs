@@ -2,10 +2,13 @@
 
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+#[cfg(all(feature = "http", feature = "serve"))]
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Duration, SystemTime};
 
-use git_assets_lib;
+use git_assets_lib::hash::{ContentHash, SHA256, SHA512_256};
 
 const TEST_CONTENTS: &[u8] = b"this is a test\nand a second line";
 const TEST_CONTENTS_REF: &[u8] =
@@ -29,57 +32,4010 @@ fn test_store() {
     });
 }
 
+/// Check that storing a file given by path is equivalent to storing it from stdin.
+#[test]
+fn test_store_path() {
+    run_test("store_path", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        let input = env.repo_dir.join("input");
+        fs::write(&input, TEST_CONTENTS).unwrap();
+
+        let bin = env.run_test_command(&["store-file", input.to_str().unwrap()]);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+        assert_empty_staging(env);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+
+        let missing = env.repo_dir.join("missing");
+        let out = env
+            .run_test_command(&["store-file", missing.to_str().unwrap()])
+            .wait_output();
+        assert!(!out.status.success());
+        assert_empty_staging(env);
+    });
+}
+
+/// Check that linked worktrees use the default store of the repository they belong to.
+#[test]
+fn test_default_store_in_worktree() {
+    run_test("default_store_in_worktree", |env| {
+        // Side by side, so that the worktree is not inside the repository
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q", "main"]);
+        env.git(&["-C", "main", "config", "user.name", "git-assets test"]);
+        env.git(&["-C", "main", "config", "user.email", "test@example.com"]);
+        env.git(&["-C", "main", "commit", "-q", "--allow-empty", "-m", "first"]);
+        env.git(&["-C", "main", "worktree", "add", "-q", "../wt"]);
+        fs::create_dir(env.repo_dir.join("wt").join("sub")).unwrap();
+
+        let out = process::Command::new(&env.bin)
+            .current_dir(env.repo_dir.join("wt").join("sub"))
+            .arg("store-file")
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(TEST_CONTENTS)?;
+                child.wait_with_output()
+            })
+            .unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, TEST_CONTENTS_REF);
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object = env
+            .repo_dir
+            .join("main/.git/x-assets/data")
+            .join(&hash[..2]);
+        assert!(object.join(&hash[2..]).is_file());
+    });
+}
+
+/// Check that the default store follows `GIT_DIR`, also outside of the repository.
+#[test]
+fn test_default_store_from_git_dir() {
+    run_test("default_store_from_git_dir", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q", "main"]);
+        env.git(&["-C", "main", "config", "user.name", "git-assets test"]);
+        env.git(&["-C", "main", "config", "user.email", "test@example.com"]);
+        env.git(&["-C", "main", "commit", "-q", "--allow-empty", "-m", "first"]);
+        env.git(&["-C", "main", "worktree", "add", "-q", "../wt"]);
+        fs::create_dir(env.repo_dir.join("elsewhere")).unwrap();
+
+        // The git directory of the worktree, which leads to the common one of the repository
+        let out = process::Command::new(&env.bin)
+            .current_dir(env.repo_dir.join("elsewhere"))
+            .env("GIT_DIR", "../main/.git/worktrees/wt")
+            .arg("store-file")
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(TEST_CONTENTS)?;
+                child.wait_with_output()
+            })
+            .unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, TEST_CONTENTS_REF);
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object = env
+            .repo_dir
+            .join("main/.git/x-assets/data")
+            .join(&hash[..2]);
+        assert!(object.join(&hash[2..]).is_file());
+    });
+}
+
+/// Check that the filters, which git runs once per file, only run git twice each: once to find
+/// the repository and once to read its config.
+#[test]
+fn test_filter_git_calls() {
+    use std::os::unix::fs::PermissionsExt;
+
+    run_test("filter_git_calls", |env| {
+        env.init_repo();
+        let bin_dir = env.repo_dir.join(".git/test-bin");
+        let log = env.repo_dir.join(".git/git-calls");
+        fs::create_dir(&bin_dir).unwrap();
+        let git = bin_dir.join("git");
+        let real_git = String::from_utf8(
+            process::Command::new("sh")
+                .args(["-c", "command -v git"])
+                .output()
+                .unwrap()
+                .stdout,
+        )
+        .unwrap();
+        fs::write(
+            &git,
+            format!(
+                "#!/bin/sh\necho \"$1\" >> '{}'\nexec '{}' \"$@\"\n",
+                log.display(),
+                real_git.trim()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&git, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::join_paths(
+            std::iter::once(bin_dir)
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+
+        for (command, input) in [
+            ("store-file", TEST_CONTENTS),
+            ("retrieve-file", TEST_CONTENTS_REF),
+        ] {
+            let child = env
+                .build_test_cmd()
+                .env("PATH", &path)
+                .arg(command)
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(input);
+            let _ = bin.expect_success();
+            assert_eq!(fs::read_to_string(&log).unwrap(), "rev-parse\nconfig\n");
+            fs::remove_file(&log).unwrap();
+        }
+    });
+}
+
+/// Check that submodules use the default store of their superproject only if it is shared.
+#[test]
+fn test_default_store_in_submodule() {
+    run_test("default_store_in_submodule", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        for repo in ["main", "sub"] {
+            env.git(&["init", "-q", repo]);
+            env.git(&["-C", repo, "config", "user.name", "git-assets test"]);
+            env.git(&["-C", repo, "config", "user.email", "test@example.com"]);
+            env.git(&["-C", repo, "commit", "-q", "--allow-empty", "-m", "first"]);
+        }
+        env.git(&[
+            "-C",
+            "main",
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            "-q",
+            "../sub",
+            "sub",
+        ]);
+
+        let store_file = |contents: &[u8]| {
+            let out = process::Command::new(&env.bin)
+                .current_dir(env.repo_dir.join("main").join("sub"))
+                .arg("store-file")
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .and_then(|mut child| {
+                    child.stdin.take().unwrap().write_all(contents)?;
+                    child.wait_with_output()
+                })
+                .unwrap();
+            assert!(out.status.success());
+            let hash = ContentHash::hash_bytes(SHA256, contents).to_hex_string();
+            move |git_dir: &str| {
+                env.repo_dir
+                    .join(git_dir)
+                    .join("x-assets/data")
+                    .join(&hash[..2])
+                    .join(&hash[2..])
+                    .is_file()
+            }
+        };
+        let is_stored_in = store_file(TEST_CONTENTS);
+        assert!(is_stored_in("main/.git/modules/sub"));
+        assert!(!is_stored_in("main/.git"));
+
+        env.git(&["-C", "main", "config", "assets.submodules", "shared"]);
+        let is_stored_in = store_file(b"other contents\n");
+        assert!(is_stored_in("main/.git"));
+        assert!(!is_stored_in("main/.git/modules/sub"));
+    });
+}
+
 /// Check storing two files at about the same time.
 #[test]
-fn test_store_double() {
-    run_test("store_double", |env| {
-        let mut bin1 = env.run_test_command(&["store-file"]);
-        let mut bin2 = env.run_test_command(&["store-file"]);
+fn test_store_double() {
+    run_test("store_double", |env| {
+        let mut bin1 = env.run_test_command(&["store-file"]);
+        let mut bin2 = env.run_test_command(&["store-file"]);
+
+        bin1.stdin_send(TEST_CONTENTS);
+        bin2.stdin_send(TEST_CONTENTS);
+        bin1.stdin_close();
+        bin2.stdin_close();
+        let out1 = bin1.expect_success();
+        let out2 = bin2.expect_success();
+
+        // Ensure program output is correct
+        assert_eq!(out1.as_slice(), TEST_CONTENTS_REF);
+        assert_eq!(out2.as_slice(), TEST_CONTENTS_REF);
+
+        // Ensure store is in a correct state:
+        assert_empty_staging(env);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+        let _ = env.run_test_command(&["validate"]).expect_success();
+    });
+}
+
+/// Check that a stored file can be retrieved afterwards.
+#[test]
+fn test_store_retrieve() {
+    run_test("store_retrieve", |env| {
+        {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(TEST_CONTENTS);
+
+            // Ensure program output is correct
+            assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+        }
+
+        {
+            let mut bin = env.run_test_command(&["retrieve-file"]);
+            bin.stdin_send(TEST_CONTENTS_REF);
+
+            // Ensure program output is correct
+            assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+        }
+
+        // Ensure store is in a correct state:
+        assert_empty_staging(env);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+        let _ = env.run_test_command(&["validate"]).expect_success();
+    });
+}
+
+/// Check that v2 and JSON pointers record the size and media type, and that they can be
+/// retrieved like v1 and Git LFS pointers.
+#[test]
+fn test_pointer_formats() {
+    run_test("pointer_formats", |env| {
+        env.init_repo();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        let v2_ref = format!("git-assets v2\n{}\nsize 32\ntype text/plain\n", hash);
+
+        let mut bin = env.run_test_command(&["store-file", "--pointer", "v2"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success(), v2_ref.as_bytes());
+
+        // The clean filter picks the format up from the git config
+        env.git(&["config", "assets.pointer", "v2"]);
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "a.bin"]);
+        assert_eq!(env.git(&["show", ":a.bin"]), v2_ref.as_bytes());
+
+        let mut bin = env.run_test_command(&["retrieve-file"]);
+        bin.stdin_send(v2_ref.as_bytes());
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+
+        let json_ref = format!(
+            "{{\"version\":2,\"sha256\":\"{}\",\"size\":32,\"type\":\"text/plain\"}}\n",
+            hash
+        );
+        env.git(&["config", "assets.pointer", "json"]);
+        env.write_file("b.bin", TEST_CONTENTS);
+        env.git(&["add", "b.bin"]);
+        assert_eq!(env.git(&["show", ":b.bin"]), json_ref.as_bytes());
+
+        let mut bin = env.run_test_command(&["retrieve-file"]);
+        bin.stdin_send(json_ref.as_bytes());
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+
+        let lfs_ref = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 32\n",
+            hash
+        );
+        env.git(&["config", "assets.pointer", "lfs"]);
+        env.write_file("c.bin", TEST_CONTENTS);
+        env.git(&["add", "c.bin"]);
+        assert_eq!(env.git(&["show", ":c.bin"]), lfs_ref.as_bytes());
+
+        let mut bin = env.run_test_command(&["retrieve-file"]);
+        bin.stdin_send(lfs_ref.as_bytes());
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+
+        env.git(&["config", "assets.pointer", "v3"]);
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        bin.stdin_close();
+        assert!(!bin.wait_output().status.success());
+    });
+}
+
+/// Check that a committed `.gitassets.toml` doesn't break the filters, even when git-assets is
+/// built without reading configuration files.
+#[test]
+fn test_committed_config_file() {
+    run_test("committed_config_file", |env| {
+        env.init_repo();
+        env.write_file(".gitassets.toml", b"min-size = \"1KiB\"\n");
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        // Without the feature, the file is ignored and the small file stored anyway
+        let staged = env.git(&["show", ":a.bin"]);
+        if cfg!(feature = "config-files") {
+            assert_eq!(staged, TEST_CONTENTS);
+        } else {
+            assert_eq!(staged, TEST_CONTENTS_REF);
+        }
+
+        fs::remove_file(env.repo_dir.join("a.bin")).unwrap();
+        env.git(&["checkout", "--", "a.bin"]);
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        assert!(env.git(&["status", "--porcelain"]).is_empty());
+    });
+}
+
+/// Check that settings from the user's file, `.gitassets.toml`, the git config and flags
+/// override each other in that order.
+#[test]
+#[cfg(feature = "config-files")]
+fn test_config_layers() {
+    run_test("config_layers", |env| {
+        env.init_repo();
+        let xdg = env.repo_dir.join("xdg");
+        fs::create_dir_all(xdg.join("git-assets")).unwrap();
+        fs::write(
+            xdg.join("git-assets").join("config.toml"),
+            "pointer = \"v2\"\nmin-size = \"1KiB\"\n",
+        )
+        .unwrap();
+        let store_file = |args: &[&str]| {
+            let child = env
+                .build_test_cmd()
+                .env("XDG_CONFIG_HOME", &xdg)
+                .arg("store-file")
+                .args(args)
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(TEST_CONTENTS);
+            String::from_utf8(bin.expect_success()).unwrap()
+        };
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        assert_eq!(store_file(&[]).as_bytes(), TEST_CONTENTS);
+
+        env.write_file(".gitassets.toml", b"min-size = 16\n");
+        assert_eq!(
+            store_file(&[]),
+            format!("git-assets v2\n{}\nsize 32\ntype text/plain\n", hash)
+        );
+        env.git(&["config", "assets.pointer", "v1"]);
+        assert_eq!(store_file(&[]).as_bytes(), TEST_CONTENTS_REF);
+        assert!(store_file(&["--pointer", "lfs"]).starts_with("version "));
+
+        env.write_file(".gitassets.toml", b"min-size = \"lots\"\n");
+        let out = env.run_test_command(&["ls"]).wait_output();
+        assert!(!out.status.success());
+
+        // The committed file can't choose where objects go, unless the clone trusts it, and
+        // keys from newer versions are skipped
+        let remote = TestEnv::new("config_layers_remote");
+        let contents = format!(
+            "remote = {:?}\nfrom-the-future = 1\n",
+            remote.store_dir.display().to_string()
+        );
+        env.write_file(".gitassets.toml", contents.as_bytes());
+        let out = env.run_test_command(&["push"]).wait_output();
+        assert!(!out.status.success());
+        assert!(!remote.store_dir.exists());
+        env.git(&["config", "assets.trustRepoConfig", "true"]);
+        let _ = env.run_test_command(&["push"]).expect_success();
+        assert!(remote.store_dir.exists());
+        remote.remove_store();
+    });
+}
+
+/// Check that `GIT_ASSETS_STORE`, `GIT_ASSETS_REMOTE` and `GIT_ASSETS_CONFIG` override the
+/// settings and the default store.
+#[test]
+#[cfg(feature = "config-files")]
+fn test_env_overrides() {
+    run_test("env_overrides", |env| {
+        let remote = TestEnv::new("env_overrides_remote");
+        let mut bin = remote.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+
+        env.init_repo();
+        env.write_file(".gitassets.toml", b"store = \"elsewhere\"\n");
+        env.git(&["config", "assets.pointer", "v1"]);
+        let config = env.repo_dir.join("ci.toml");
+        fs::write(&config, "pointer = \"v2\"\n").unwrap();
+        let run = |args: &[&str], input: &[u8]| {
+            let child = process::Command::new(&env.bin)
+                .current_dir(&env.repo_dir)
+                .env("GIT_ASSETS_STORE", &env.store_dir)
+                .env("GIT_ASSETS_REMOTE", &remote.store_dir)
+                .env("GIT_ASSETS_CONFIG", &config)
+                .args(args)
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(input);
+            bin.expect_success()
+        };
+
+        // The object comes from the remote, and ends up in the store from the environment
+        assert_eq!(run(&["retrieve-file"], TEST_CONTENTS_REF), TEST_CONTENTS);
+        assert_data_count(env, 1);
+        assert!(!env.repo_dir.join("elsewhere").exists());
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        assert_eq!(
+            String::from_utf8(run(&["store-file"], TEST_CONTENTS)).unwrap(),
+            format!("git-assets v2\n{}\nsize 32\ntype text/plain\n", hash)
+        );
+
+        fs::remove_file(&config).unwrap();
+        let child = process::Command::new(&env.bin)
+            .current_dir(&env.repo_dir)
+            .env("GIT_ASSETS_CONFIG", &config)
+            .arg("ls")
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert!(!GitAssetsChild { child }.wait_output().status.success());
+        remote.remove_store();
+    });
+}
+
+/// Check that `assets.allowedRemote` rejects remote stores it doesn't list, and can't be set
+/// by the committed configuration file.
+#[test]
+#[cfg(feature = "config-files")]
+fn test_allowed_remotes() {
+    run_test("allowed_remotes", |env| {
+        env.init_repo();
+        let exit_code = |args: &[&str]| env.run_test_command(args).wait_output().status.code();
+        let denied = "https://denied.example.com/assets";
+        // Nothing listens there, so this fails only once it is allowed
+        let allowed = "http://127.0.0.1:1/assets";
+
+        env.git(&["config", "assets.allowedRemote", "http://127.0.0.1"]);
+        assert_eq!(exit_code(&["--remote", denied, "push"]), Some(9));
+        assert_ne!(exit_code(&["--remote", allowed, "push"]), Some(9));
+
+        // Even a trusted committed file can't widen it
+        env.git(&["config", "assets.trustRepoConfig", "true"]);
+        env.write_file(".gitassets.toml", b"allowed-remotes = [\"*\"]\n");
+        assert_eq!(exit_code(&["--remote", denied, "push"]), Some(9));
+
+        // The user's file can set it as well
+        env.git(&["config", "--unset", "assets.allowedRemote"]);
+        let xdg = env.repo_dir.join("xdg");
+        fs::create_dir_all(xdg.join("git-assets")).unwrap();
+        fs::write(
+            xdg.join("git-assets").join("config.toml"),
+            "allowed-remotes = [\"s3://*\"]\n",
+        )
+        .unwrap();
+        let child = env
+            .build_test_cmd()
+            .env("XDG_CONFIG_HOME", &xdg)
+            .args(["--remote", denied, "push"])
+            .spawn()
+            .unwrap();
+        let out = GitAssetsChild { child }.wait_output();
+        assert_eq!(out.status.code(), Some(9));
+
+        // Local stores are always allowed
+        let remote = TestEnv::new("allowed_remotes_remote");
+        let _ = env
+            .run_test_command(&["--remote", remote.store_dir.to_str().unwrap(), "push"])
+            .expect_success();
+        remote.remove_store();
+    });
+}
+
+/// Check that the verbosity flags and `GIT_ASSETS_LOG` select the messages on stderr.
+#[test]
+fn test_logging() {
+    run_test("logging", |env| {
+        env.init_repo();
+        let run = |args: &[&str], log: Option<&str>| {
+            let mut cmd = env.build_test_cmd();
+            if let Some(log) = log {
+                cmd.env("GIT_ASSETS_LOG", log);
+            }
+            let child = cmd
+                .args(args)
+                .stderr(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(TEST_CONTENTS);
+            let out = bin.wait_output();
+            assert!(out.status.success());
+            String::from_utf8(out.stderr).unwrap()
+        };
+
+        assert_eq!(run(&["store-file"], None), "");
+        let stderr = run(&["-v", "store-file"], None);
+        assert!(stderr.contains("INFO  git_assets] stored"), "{}", stderr);
+        assert!(stderr.contains("finished in"), "{}", stderr);
+        assert!(!stderr.contains("DEBUG"), "{}", stderr);
+        let stderr = run(&["-vv", "store-file"], None);
+        assert!(
+            stderr.contains("DEBUG git_assets_lib::store] stored object"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("DEBUG git_assets_lib::git] git "),
+            "{}",
+            stderr
+        );
+
+        // The environment overrides the flags, module by module
+        let stderr = run(
+            &["-v", "store-file"],
+            Some("warn,git_assets_lib::git=debug"),
+        );
+        assert!(stderr.contains("git_assets_lib::git] git "), "{}", stderr);
+        assert!(!stderr.contains("INFO"), "{}", stderr);
+        assert_eq!(run(&["--quiet", "store-file"], Some("")), "");
+
+        let child = env
+            .build_test_cmd()
+            .env("GIT_ASSETS_LOG", "loud")
+            .args(["store-file"])
+            .spawn()
+            .unwrap();
+        let out = GitAssetsChild { child }.wait_output();
+        assert!(!out.status.success());
+    });
+}
+
+/// Check that objects missing from the store are copied from an alternate store.
+#[test]
+fn test_retrieve_from_alternate() {
+    run_test("retrieve_from_alternate", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+
+        // Use the populated store as alternate for an empty one
+        let other = TestEnv::new("retrieve_from_alternate_other");
+        let mut bin = other.run_test_command(&[
+            "--alternate",
+            env.store_dir.to_str().unwrap(),
+            "retrieve-file",
+        ]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+
+        // The object is now available locally
+        assert_data_count(&other, 1);
+        assert_data_contents(&other, TEST_CONTENTS);
+        assert_empty_staging(&other);
+        other.remove_store();
+    });
+}
+
+/// Check that the alternates can be configured with `assets.alternate`, and that checking out
+/// uses them as well.
+#[test]
+fn test_alternate_config() {
+    run_test("alternate_config", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+
+        let other = TestEnv::new("alternate_config_other");
+        other.init_repo();
+        // Alternates that can't be opened are skipped
+        let missing = env.store_dir.with_extension("missing");
+        other.git(&[
+            "config",
+            "--add",
+            "assets.alternate",
+            missing.to_str().unwrap(),
+        ]);
+        other.git(&[
+            "config",
+            "--add",
+            "assets.alternate",
+            env.store_dir.to_str().unwrap(),
+        ]);
+        other.write_file("a.bin", TEST_CONTENTS_REF);
+        other.git(&["-c", "filter.assets.clean=cat", "add", "a.bin"]);
+
+        let out = other.run_test_command(&["checkout"]).expect_success();
+        assert_eq!(String::from_utf8_lossy(&out), "checked out a.bin\n");
+        assert_eq!(
+            fs::read(other.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS
+        );
+        assert_data_count(&other, 1);
+        other.remove_store();
+    });
+}
+
+/// Check that objects missing locally are downloaded from the configured remote store.
+#[test]
+fn test_retrieve_from_remote() {
+    run_test("retrieve_from_remote", |env| {
+        let remote = TestEnv::new("retrieve_from_remote_remote");
+        let mut bin = remote.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+
+        env.init_repo();
+        env.git(&[
+            "config",
+            "assets.remote",
+            remote.store_dir.to_str().unwrap(),
+        ]);
+        let mut bin = env.run_test_command(&["retrieve-file"]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+
+        // The object is now cached locally
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+        assert_empty_staging(env);
+        remote.remove_store();
+    });
+}
+
+/// Check that contents below the minimum size are not stored, and are checked out unchanged.
+#[test]
+fn test_store_min_size() {
+    run_test("store_min_size", |env| {
+        let mut bin = env.run_test_command(&["store-file", "--min-size", "32B"]);
+        bin.stdin_send(b"small");
+        assert_eq!(bin.expect_success().as_slice(), b"small");
+        // Damaged pointers are stored even if small, so that they don't fail on checkout
+        let damaged = &TEST_CONTENTS_REF[..20];
+        let mut bin = env.run_test_command(&["store-file", "--min-size", "1KiB"]);
+        bin.stdin_send(damaged);
+        let out = bin.expect_success();
+        assert_ne!(out.as_slice(), damaged);
+        assert_data_count(env, 1);
+        // While valid ones are pointers already
+        let mut bin = env.run_test_command(&["store-file", "--min-size", "1KiB"]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+        assert_data_count(env, 1);
+
+        let mut bin = env.run_test_command(&["store-file", "--batch", "--min-size", "32B"]);
+        bin.stdin_send(format!("5\nsmall{}\n", TEST_CONTENTS.len()).as_bytes());
+        bin.stdin_send(TEST_CONTENTS);
+        let out = bin.expect_success();
+        let mut expected = b"5\nsmall".to_vec();
+        expected.extend(format!("{}\n", TEST_CONTENTS_REF.len()).as_bytes());
+        expected.extend(TEST_CONTENTS_REF);
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            String::from_utf8_lossy(&expected)
+        );
+        assert_data_count(env, 2);
+
+        env.init_repo();
+        env.git(&["config", "assets.minSize", "32B"]);
+        env.write_file("small.bin", b"small");
+        env.write_file("large.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        assert_eq!(env.git(&["show", ":small.bin"]), b"small");
+        assert_eq!(env.git(&["show", ":large.bin"]), TEST_CONTENTS_REF);
+        env.git(&["commit", "-q", "-m", "first"]);
+        fs::remove_file(env.repo_dir.join("small.bin")).unwrap();
+        env.git(&["checkout", "--", "small.bin"]);
+        assert_eq!(fs::read(env.repo_dir.join("small.bin")).unwrap(), b"small");
+
+        let mut bin = env.run_test_command(&["retrieve-file", "--batch"]);
+        bin.stdin_send(b"5\nsmall");
+        assert_eq!(bin.expect_success().as_slice(), b"5\nsmall");
+
+        // Damaged pointers are not mistaken for small files
+        let mut bin = env.run_test_command(&["retrieve-file"]);
+        bin.stdin_send(damaged);
+        assert!(!bin.wait_output().status.success());
+        let mut bin = env.run_test_command(&["retrieve-file", "--batch"]);
+        bin.stdin_send(format!("{}\n", damaged.len()).as_bytes());
+        bin.stdin_send(damaged);
+        let out = bin.expect_success();
+        assert!(String::from_utf8_lossy(&out).starts_with("error malformed pointer"));
+    });
+}
+
+/// Check that many files can be stored and retrieved in a single invocation.
+#[test]
+fn test_batch() {
+    run_test("batch", |env| {
+        let mut bin = env.run_test_command(&["store-file", "--batch"]);
+        bin.stdin_send(b"3\nabc0\n");
+        let out = bin.expect_success();
+        let abc = ContentHash::hash_bytes(SHA256, b"abc").to_hex_string();
+        let empty = ContentHash::hash_bytes(SHA256, b"").to_hex_string();
+        let abc_ref = format!("git-assets v1\n{}\n", abc);
+        let empty_ref = format!("git-assets v1\n{}\n", empty);
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!(
+                "{}\n{}{}\n{}",
+                abc_ref.len(),
+                abc_ref,
+                empty_ref.len(),
+                empty_ref
+            )
+        );
+        assert_data_count(env, 2);
+
+        let mut bin = env.run_test_command(&["retrieve-file", "--batch"]);
+        let missing = ContentHash::hash_bytes(SHA256, b"missing");
+        let missing_ref = format!("git-assets v1\n{}\n", missing);
+        let input = format!(
+            "{}\n{}{}\n{}{}\n{}",
+            abc_ref.len(),
+            abc_ref,
+            empty_ref.len(),
+            empty_ref,
+            missing_ref.len(),
+            missing_ref
+        );
+        bin.stdin_send(input.as_bytes());
+        let out = String::from_utf8(bin.expect_success()).unwrap();
+        assert!(out.starts_with("3\nabc0\nerror "), "{}", out);
+        assert_eq!(out.lines().count(), 3);
+
+        // Files stored on several threads are answered in the order they were sent
+        let files: Vec<Vec<u8>> = (0..40)
+            .map(|n| format!("file {}\n", n).repeat(n * 1000).into_bytes())
+            .collect();
+        let mut input = Vec::new();
+        for file in &files {
+            input.extend_from_slice(format!("{}\n", file.len()).as_bytes());
+            input.extend_from_slice(file);
+        }
+        let store_batch = |jobs: usize| {
+            let mut cmd = env.build_test_cmd();
+            let child = with_git_config(&mut cmd, "assets.jobs", &jobs.to_string())
+                .args(["store-file", "--batch"])
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(&input);
+            bin.expect_success()
+        };
+        let out = store_batch(4);
+        let mut expected = String::new();
+        for file in &files {
+            let reference = format!("git-assets v1\n{}\n", ContentHash::hash_bytes(SHA256, file));
+            expected.push_str(&format!("{}\n{}", reference.len(), reference));
+        }
+        assert_eq!(String::from_utf8_lossy(&out), expected);
+        assert_eq!(store_batch(1), out);
+        assert_data_count(env, 2 + files.len() - 1);
+    });
+}
+
+/// Check that objects can be retrieved from a store served over HTTP, and that corrupt
+/// downloads are rejected.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_retrieve_over_http() {
+    run_test("retrieve_over_http", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+
+        // Serve the store directory as is
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", server.server_addr().to_ip().unwrap());
+        let store_dir = env.store_dir.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let path = store_dir.join(request.url().trim_start_matches('/'));
+                let _ = match fs::File::open(path) {
+                    Ok(file) => request.respond(tiny_http::Response::from_file(file)),
+                    Err(_) => request.respond(tiny_http::Response::empty(404)),
+                };
+            }
+        });
+
+        let retrieve = || {
+            let mut child = process::Command::new(&env.bin)
+                .args(["--store", &url, "retrieve-file"])
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .stderr(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(TEST_CONTENTS_REF)
+                .unwrap();
+            child.wait_with_output().unwrap()
+        };
+        let out = retrieve();
+        assert!(out.status.success());
+        assert_eq!(out.stdout.as_slice(), TEST_CONTENTS);
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let path = env.store_dir.join("data").join(&hash[..2]).join(&hash[2..]);
+        fs::write(path, b"corrupted").unwrap();
+        assert!(!retrieve().status.success());
+    });
+}
+
+/// Check that remote stores stage objects in a private directory of the user.
+#[test]
+#[cfg(feature = "ssh")]
+fn test_url_staging_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    run_test("url_staging_dir", |env| {
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let url = format!("ssh://localhost{}", env.store_dir.display());
+        for user in ["alice", "bob"] {
+            let cache_dir = env.store_dir.join(user);
+            let _ = process::Command::new(&env.bin)
+                .env("XDG_CACHE_HOME", &cache_dir)
+                .env("GIT_SSH_COMMAND", "sh -c 'eval \"$3\"' fake-ssh")
+                .args(["--store", &url, "exists", &hash])
+                .output()
+                .unwrap();
+            let staging = fs::metadata(cache_dir.join("git-assets/staging")).unwrap();
+            assert!(staging.is_dir());
+            assert_eq!(staging.permissions().mode() & 0o777, 0o700);
+        }
+    });
+}
+
+/// Check that `serve` answers requests for objects, and works as a remote store.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve() {
+    run_test("serve", |env| {
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object_url = format!("{}/objects/{}", url, hash);
+
+        let status = |result: Result<ureq::Response, ureq::Error>| match result {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(status(ureq::head(&object_url).call()), 404);
+        assert_eq!(status(ureq::put(&object_url).send_bytes(b"wrong")), 400);
+        assert_eq!(
+            status(ureq::put(&object_url).send_bytes(TEST_CONTENTS)),
+            201
+        );
+        assert_data_count(env, 1);
+        let response = ureq::head(&object_url).call().unwrap();
+        assert_eq!(
+            response.header("Content-Length"),
+            Some(TEST_CONTENTS.len().to_string().as_str())
+        );
+        let mut contents = Vec::new();
+        ureq::get(&object_url)
+            .call()
+            .unwrap()
+            .into_reader()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, TEST_CONTENTS);
+        assert_eq!(status(ureq::delete(&object_url).call()), 405);
+        let algorithm = ureq::get(&format!("{}/algorithm", url)).call().unwrap();
+        assert_eq!(algorithm.into_string().unwrap(), "sha256\n");
+
+        let client = TestEnv::new("serve_client");
+        let mut bin = client.run_test_command(&["--remote", &url, "retrieve-file"]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS);
+        assert_data_count(&client, 1);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+    });
+}
+
+/// Check that missing objects are copied from peers running `serve`, given as alternates, and
+/// that peers that can't be reached are skipped.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_peer() {
+    run_test("serve_peer", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+
+        let client = TestEnv::new("serve_peer_client");
+        let mut bin = client.run_test_command(&[
+            "--alternate",
+            "http://127.0.0.1:1",
+            "--alternate",
+            &url,
+            "retrieve-file",
+        ]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS);
+        assert_data_count(&client, 1);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+    });
+}
+
+/// Check that `serve` works as a proxy in front of a store that is not local, here over SSH.
+#[test]
+#[cfg(all(feature = "http", feature = "serve", feature = "ssh"))]
+fn test_serve_remote_store() {
+    run_test("serve_remote_store", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        let store_url = format!("ssh://localhost{}", env.store_dir.display());
+        let mut child = process::Command::new(&env.bin)
+            .args(["--store", &store_url, "serve", "--listen", "127.0.0.1:0"])
+            // Run the remote command locally, see `test_store_retrieve_over_ssh`
+            .env("GIT_SSH_COMMAND", "sh -c 'eval \"$3\"' fake-ssh")
+            .env("XDG_CACHE_HOME", env.repo_dir.join("cache"))
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object_url = format!("{}/objects/{}", url, hash);
+
+        let response = ureq::put(&object_url).send_bytes(TEST_CONTENTS).unwrap();
+        assert_eq!(response.status(), 201);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+
+        let client = TestEnv::new("serve_remote_store_client");
+        let mut bin = client.run_test_command(&["--remote", &url, "retrieve-file"]);
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+    });
+}
+
+/// Check that a remote store that never answers fails retrieving after the read timeout.
+#[test]
+#[cfg(feature = "http")]
+fn test_remote_timeout() {
+    run_test("remote_timeout", |env| {
+        // Accepts connections, but never sends anything
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let retrieve = |timeout: &str| {
+            let child = env
+                .build_test_cmd()
+                .env("GIT_ASSETS_READ_TIMEOUT", timeout)
+                .args(["--remote", &url, "retrieve-file"])
+                .stderr(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(TEST_CONTENTS_REF);
+            bin.wait_output()
+        };
+        let start = std::time::Instant::now();
+        let out = retrieve("1s");
+        assert!(!out.status.success());
+        assert!(start.elapsed() < Duration::from_secs(20));
+
+        let out = retrieve("soon");
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("GIT_ASSETS_READ_TIMEOUT"));
+        drop(listener);
+    });
+}
+
+/// Check that a server with tokens only answers requests bearing one that allows them.
+#[test]
+#[cfg(all(feature = "http", feature = "serve", feature = "config-files"))]
+fn test_serve_tokens() {
+    run_test("serve_tokens", |env| {
+        let config = std::env::temp_dir().join(format!("git-assets-serve-{}", process::id()));
+        fs::create_dir_all(&config).unwrap();
+        fs::write(config.join("tokens"), "# uploads\nwriter write\n").unwrap();
+        fs::write(
+            config.join("config.toml"),
+            "read-tokens = [\"reader\"]\ntoken-file = \"tokens\"\n",
+        )
+        .unwrap();
+        let mut child = env
+            .build_test_cmd()
+            .env("GIT_ASSETS_CONFIG", config.join("config.toml"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object_url = format!("{}/objects/{}", url, hash);
+
+        let status = |result: Result<ureq::Response, ureq::Error>| match result {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{}", err),
+        };
+        let put = |token: &str| {
+            status(
+                ureq::put(&object_url)
+                    .set("Authorization", &format!("Bearer {}", token))
+                    .send_bytes(TEST_CONTENTS),
+            )
+        };
+        assert_eq!(status(ureq::head(&object_url).call()), 401);
+        assert_eq!(put("guess"), 401);
+        assert_eq!(put("reader"), 403);
+        assert_data_count(env, 0);
+        assert_eq!(put("writer"), 201);
+        assert_data_count(env, 1);
+
+        let client = TestEnv::new("serve_tokens_client");
+        let retrieve = |token: &str| {
+            let child = client
+                .build_test_cmd()
+                .env("GIT_ASSETS_TOKEN", token)
+                .args(["--remote", &url, "retrieve-file"])
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(TEST_CONTENTS_REF);
+            bin.wait_output()
+        };
+        assert!(!retrieve("guess").status.success());
+        assert_eq!(retrieve("reader").stdout, TEST_CONTENTS);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+        fs::remove_dir_all(&config).unwrap();
+    });
+}
+
+/// Check that `serve` notifies webhooks of uploads.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_webhooks() {
+    run_test("serve_webhooks", |env| {
+        // Answers a single notification and hands over its body
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = format!("http://{}/uploaded", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let lower = line.to_ascii_lowercase();
+                if let Some(value) = lower.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            body
+        });
+
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0", "--webhook", &webhook])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let object_url = format!("{}/objects/{}", url, hash);
+
+        // Rejected uploads are not announced
+        assert!(ureq::put(&object_url).send_bytes(b"wrong").is_err());
+        let response = ureq::put(&object_url).send_bytes(TEST_CONTENTS).unwrap();
+        assert_eq!(response.status(), 201);
+
+        let notification: serde_json::Value =
+            serde_json::from_slice(&receiver.join().unwrap()).unwrap();
+        assert_eq!(
+            notification,
+            serde_json::json!({
+                "hash": hash,
+                "size": TEST_CONTENTS.len(),
+                "namespace": "default",
+                "uploader": null,
+            })
+        );
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    });
+}
+
+/// Check that namespaces serve other stores, and that tokens managed with `tokens` are only
+/// allowed in the namespaces they are limited to.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_namespaces() {
+    run_test("serve_namespaces", |env| {
+        let token_file = env.store_dir.with_extension("tokens");
+        let token_arg = token_file.to_str().unwrap();
+        let writer = env
+            .run_test_command(&[
+                "tokens",
+                "add",
+                "--token-file",
+                token_arg,
+                "--scope",
+                "write",
+                "--namespace",
+                "confidential",
+            ])
+            .expect_success();
+        let writer = String::from_utf8(writer).unwrap();
+        let writer = writer.trim();
+        assert_eq!(writer.len(), 48);
+        let _ = env
+            .run_test_command(&[
+                "tokens",
+                "add",
+                "--token-file",
+                token_arg,
+                "--token",
+                "*",
+                "--namespace",
+                "default",
+            ])
+            .expect_success();
+
+        let confidential = TestEnv::new("serve_namespaces_confidential");
+        let namespace = format!("confidential={}", confidential.store_dir.display());
+        let mut child = env
+            .build_test_cmd()
+            .args([
+                "serve",
+                "--listen",
+                "127.0.0.1:0",
+                "--token-file",
+                token_arg,
+            ])
+            .args(["--namespace", &namespace])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+
+        let status = |result: Result<ureq::Response, ureq::Error>| match result {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{}", err),
+        };
+        let object_url = |namespace: &str| format!("{}{}/objects/{}", url, namespace, hash);
+        let put = |namespace: &str| {
+            status(
+                ureq::put(&object_url(namespace))
+                    .set("Authorization", &format!("Bearer {}", writer))
+                    .send_bytes(TEST_CONTENTS),
+            )
+        };
+        // Anyone may read the default namespace, but not the confidential one
+        assert_eq!(status(ureq::head(&object_url("")).call()), 404);
+        assert_eq!(status(ureq::head(&object_url("/confidential")).call()), 401);
+        assert_eq!(status(ureq::head(&object_url("/elsewhere")).call()), 404);
+        assert_eq!(put(""), 403);
+        assert_eq!(put("/confidential"), 201);
+        assert_data_count(env, 0);
+        assert_data_count(&confidential, 1);
+
+        let client = TestEnv::new("serve_namespaces_client");
+        let child_client = client
+            .build_test_cmd()
+            .env("GIT_ASSETS_TOKEN", writer)
+            .args([
+                "--remote",
+                &format!("{}/confidential", url),
+                "retrieve-file",
+            ])
+            .spawn()
+            .unwrap();
+        let mut bin = GitAssetsChild {
+            child: child_client,
+        };
+        bin.stdin_send(TEST_CONTENTS_REF);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS);
+
+        // Tokens are listed and removed by their id
+        let list = || {
+            let out = env
+                .run_test_command(&["tokens", "list", "--token-file", token_arg])
+                .expect_success();
+            String::from_utf8(out).unwrap()
+        };
+        let listing = list();
+        assert!(!listing.contains(writer));
+        let id = listing
+            .lines()
+            .find(|line| line.ends_with(" write confidential"))
+            .unwrap()
+            .split(' ')
+            .next()
+            .unwrap()
+            .to_string();
+        let _ = env
+            .run_test_command(&["tokens", "remove", "--token-file", token_arg, &id])
+            .expect_success();
+        assert_eq!(list().lines().count(), 1);
+        let out = env
+            .run_test_command(&["tokens", "remove", "--token-file", token_arg, &id])
+            .wait_output();
+        assert_eq!(out.status.code(), Some(3));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+        confidential.remove_store();
+        fs::remove_file(&token_file).unwrap();
+    });
+}
+
+/// Check that uploads to a server can be sent in parts and resumed at the offset it reports.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_resumable_uploads() {
+    run_test("serve_resumable_uploads", |env| {
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let length = TEST_CONTENTS.len().to_string();
+
+        let answer = |result: Result<ureq::Response, ureq::Error>| match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => panic!("{}", err),
+        };
+        let offset = |response: &ureq::Response| -> usize {
+            response.header("Upload-Offset").unwrap().parse().unwrap()
+        };
+        let start = |hash: &str| {
+            answer(
+                ureq::post(&format!("{}/uploads/{}", url, hash))
+                    .set("Upload-Length", &length)
+                    .call(),
+            )
+        };
+        let started = start(&hash);
+        assert_eq!(started.status(), 201);
+        assert_eq!(offset(&started), 0);
+        let location = format!("{}{}", url, started.header("Location").unwrap());
+        let patch = |at: usize, bytes: &[u8]| {
+            answer(
+                ureq::request("PATCH", &location)
+                    .set("Upload-Offset", &at.to_string())
+                    .send_bytes(bytes),
+            )
+        };
+
+        let patched = patch(0, &TEST_CONTENTS[..10]);
+        assert_eq!(patched.status(), 204);
+        assert_eq!(offset(&patched), 10);
+        // After losing the connection, the client asks where to continue
+        let head = answer(ureq::head(&location).call());
+        assert_eq!(head.status(), 200);
+        assert_eq!(offset(&head), 10);
+        assert_eq!(offset(&start(&hash)), 10);
+        assert_eq!(patch(5, &TEST_CONTENTS[5..]).status(), 409);
+        assert_data_count(env, 0);
+        assert_eq!(patch(10, &TEST_CONTENTS[10..]).status(), 201);
+        assert_data_count(env, 1);
+        assert_eq!(answer(ureq::head(&location).call()).status(), 404);
+        assert_eq!(start(&hash).status(), 200);
+
+        // Contents that don't match the hash are thrown away
+        let other = ContentHash::hash_bytes(SHA256, b"something else").to_hex_string();
+        let location = format!("{}{}", url, start(&other).header("Location").unwrap());
+        let patched = answer(
+            ureq::request("PATCH", &location)
+                .set("Upload-Offset", "0")
+                .send_bytes(TEST_CONTENTS),
+        );
+        assert_eq!(patched.status(), 400);
+        assert_eq!(answer(ureq::head(&location).call()).status(), 404);
+        assert_data_count(env, 1);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    });
+}
+
+/// Check that a server with a certificate is reached over HTTPS by clients trusting its CA.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_tls() {
+    run_test("serve_tls", |env| {
+        let tls = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("tls");
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS_REF);
+
+        let cert = tls.join("cert.pem");
+        let key = tls.join("key.pem");
+        let without_key = env
+            .run_test_command(&["serve", "--tls-cert", cert.to_str().unwrap()])
+            .wait_output();
+        assert!(!without_key.status.success());
+
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0", "--tls-cert"])
+            .arg(&cert)
+            .arg("--tls-key")
+            .arg(&key)
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+        assert!(url.starts_with("https://"), "{}", url);
+
+        let client = TestEnv::new("serve_tls_client");
+        let retrieve = |ca_file: &Path| {
+            let child = client
+                .build_test_cmd()
+                .env("GIT_ASSETS_CA_FILE", ca_file)
+                .args(["--remote", &url, "retrieve-file"])
+                .spawn()
+                .unwrap();
+            let mut bin = GitAssetsChild { child };
+            bin.stdin_send(TEST_CONTENTS_REF);
+            bin.wait_output()
+        };
+        // The server's own certificate is not a certificate authority
+        assert!(!retrieve(&cert).status.success());
+        assert_eq!(retrieve(&tls.join("ca.pem")).stdout, TEST_CONTENTS);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+    });
+}
+
+/// Check that a server with certificate authorities for clients only answers clients with a
+/// certificate issued by them.
+#[test]
+#[cfg(all(feature = "http", feature = "serve"))]
+fn test_serve_tls_client_certificates() {
+    run_test("serve_tls_client_certificates", |env| {
+        let tls = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("tls");
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success(), TEST_CONTENTS_REF);
+
+        let mut child = env
+            .build_test_cmd()
+            .args(["serve", "--listen", "127.0.0.1:0", "--tls-cert"])
+            .arg(tls.join("cert.pem"))
+            .arg("--tls-key")
+            .arg(tls.join("key.pem"))
+            .arg("--tls-client-ca")
+            .arg(tls.join("client-ca.pem"))
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let url = line.trim().rsplit(' ').next().unwrap().to_string();
+
+        let client = TestEnv::new("serve_tls_client_certificates_client");
+        let retrieve = |identity: &[(&str, PathBuf)]| {
+            let mut command = client.build_test_cmd();
+            command.env("GIT_ASSETS_CA_FILE", tls.join("ca.pem")).args([
+                "--remote",
+                &url,
+                "retrieve-file",
+            ]);
+            for (variable, path) in identity {
+                command.env(variable, path);
+            }
+            let mut bin = GitAssetsChild {
+                child: command.spawn().unwrap(),
+            };
+            bin.stdin_send(TEST_CONTENTS_REF);
+            bin.wait_output()
+        };
+        assert!(!retrieve(&[]).status.success());
+        // The server's certificate is issued by another authority, and not for clients
+        let server_identity = [
+            ("GIT_ASSETS_CLIENT_CERT", tls.join("cert.pem")),
+            ("GIT_ASSETS_CLIENT_KEY", tls.join("key.pem")),
+        ];
+        assert!(!retrieve(&server_identity).status.success());
+        // Both files are needed
+        let without_key = retrieve(&[("GIT_ASSETS_CLIENT_CERT", tls.join("client.pem"))]);
+        assert!(!without_key.status.success());
+        let identity = [
+            ("GIT_ASSETS_CLIENT_CERT", tls.join("client.pem")),
+            ("GIT_ASSETS_CLIENT_KEY", tls.join("client-key.pem")),
+        ];
+        assert_eq!(retrieve(&identity).stdout, TEST_CONTENTS);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        client.remove_store();
+    });
+}
+
+/// Check storing and retrieving objects in a store reached over SSH.
+#[test]
+#[cfg(feature = "ssh")]
+fn test_store_retrieve_over_ssh() {
+    run_test("store_retrieve_over_ssh", |env| {
+        let url = format!("ssh://localhost{}", env.store_dir.display());
+        let run = |args: &[&str], input: &[u8]| {
+            let mut child = process::Command::new(&env.bin)
+                .arg("--store")
+                .arg(&url)
+                .args(args)
+                // Instead of connecting anywhere, run the remote command, which follows `--` and
+                // the host, locally
+                .env("GIT_SSH_COMMAND", "sh -c 'eval \"$3\"' fake-ssh")
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let out = child.wait_with_output().unwrap();
+            assert!(out.status.success(), "{:?} failed", args);
+            out.stdout
+        };
+
+        assert_eq!(run(&["store-file"], TEST_CONTENTS), TEST_CONTENTS_REF);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+
+        assert_eq!(run(&["retrieve-file"], TEST_CONTENTS_REF), TEST_CONTENTS);
+        let _ = run(&["validate"], b"");
+
+        // New objects are addressed by the algorithm of the remote store
+        fs::write(env.store_dir.join("algorithm"), "sha512_256\n").unwrap();
+        let hash = ContentHash::hash_bytes(SHA512_256, b"other");
+        assert_eq!(
+            run(&["store-file"], b"other"),
+            format!("git-assets v1\n{}\n", hash).into_bytes()
+        );
+        assert_data_count(env, 2);
+    });
+}
+
+/// Check that pushing uploads only the objects that the remote store is missing.
+#[test]
+fn test_push() {
+    run_test("push", |env| {
+        let remote = TestEnv::new("push_remote");
+        let mut bin = remote.run_test_command(&["store-file"]);
+        bin.stdin_send(b"already there");
+        let _ = bin.expect_success();
+        for contents in [TEST_CONTENTS, b"already there"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+
+        let remote_arg = remote.store_dir.to_str().unwrap();
+        let out = env
+            .run_test_command(&["--remote", remote_arg, "push"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "pushed 1 objects (32 B), 1 already present\n"
+        );
+        assert_data_count(&remote, 2);
+        assert_data_contents(&remote, TEST_CONTENTS);
+        assert_empty_staging(&remote);
+
+        let out = env
+            .run_test_command(&["--remote", remote_arg, "push"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "pushed 0 objects (0 B), 2 already present\n"
+        );
+        remote.remove_store();
+    });
+}
+
+/// Check that the pre-push hook uploads the objects of the outgoing commits only.
+#[test]
+fn test_pre_push() {
+    run_test("pre_push", |env| {
+        let remote = TestEnv::new("pre_push_remote");
+        fs::create_dir(&remote.repo_dir).unwrap();
+        remote.git(&["init", "-q", "--bare"]);
+        env.init_repo();
+        let remote_repo = remote.repo_dir.to_str().unwrap();
+        env.git(&["remote", "add", "origin", remote_repo]);
+        env.git(&[
+            "config",
+            "assets.remote",
+            remote.store_dir.to_str().unwrap(),
+        ]);
+
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.git(&["push", "-q", "origin", "HEAD:refs/heads/main"]);
+        let pushed = String::from_utf8(env.git(&["rev-parse", "HEAD"])).unwrap();
+        env.write_file("b.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "second"]);
+        let head = String::from_utf8(env.git(&["rev-parse", "HEAD"])).unwrap();
+
+        let mut bin = env.run_test_command(&["pre-push", "origin", remote_repo]);
+        bin.stdin_send(
+            format!(
+                "refs/heads/main {} refs/heads/main {}\n",
+                head.trim(),
+                pushed.trim()
+            )
+            .as_bytes(),
+        );
+        let out = bin.expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "pushed 1 objects (15 B), 0 already present\n"
+        );
+        assert_data_count(&remote, 1);
+        assert_data_contents(&remote, b"other contents\n");
+        remote.remove_store();
+    });
+}
+
+/// Check that fetching downloads the objects referenced by a commit, and reports those that
+/// the remote store is missing as well.
+#[test]
+fn test_fetch() {
+    run_test("fetch", |env| {
+        let remote = TestEnv::new("fetch_remote");
+        env.init_repo();
+        env.write_file("a.bin", b"first");
+        env.write_file("dir/b.bin", b"second");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        let remote_arg = remote.store_dir.to_str().unwrap();
+        env.git(&["config", "assets.remote", remote_arg]);
+        let _ = env.run_test_command(&["push"]).expect_success();
+
+        // Start over with an empty local store, and lose one object remotely
+        fs::remove_dir_all(&env.store_dir).unwrap();
+        let hash = ContentHash::hash_bytes(SHA256, b"second").to_hex_string();
+        fs::remove_file(
+            remote
+                .store_dir
+                .join("data")
+                .join(&hash[..2])
+                .join(&hash[2..]),
+        )
+        .unwrap();
+
+        let out = env.run_test_command(&["fetch"]).wait_output();
+        assert!(!out.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            format!(
+                "missing: {} dir/b.bin\nfetched 1 objects (5 B), 0 already present\n",
+                hash
+            )
+        );
+        assert_data_count(env, 1);
+        assert_data_contents(env, b"first");
+        assert_empty_staging(env);
+
+        // Trees work as well
+        let out = env
+            .run_test_command(&["prefetch", "HEAD^{tree}"])
+            .wait_output();
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            format!(
+                "missing: {} dir/b.bin\nfetched 0 objects (0 B), 1 already present\n",
+                hash
+            )
+        );
+        remote.remove_store();
+    });
+}
+
+/// Check that stored objects are signed with the SSH signing key of git, that only objects
+/// signed by an allowed signer are retrieved, and that signatures travel with the objects.
+#[test]
+fn test_sign_objects() {
+    run_test("sign_objects", |env| {
+        let remote = TestEnv::new("sign_objects_remote");
+        env.init_repo();
+        let git_dir = env.repo_dir.join(".git");
+        let keygen = |name: &str| {
+            let key = git_dir.join(name);
+            let status = process::Command::new("ssh-keygen")
+                .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+                .arg(&key)
+                .status()
+                .expect("could not run ssh-keygen");
+            assert!(status.success());
+            key
+        };
+        let key = keygen("signing_key");
+        let other_key = keygen("other_key");
+        let allowed_signers = git_dir.join("allowed_signers");
+        let allow = |key: &Path| {
+            let public_key = fs::read_to_string(key.with_extension("pub")).unwrap();
+            fs::write(&allowed_signers, format!("test@example.com {}", public_key)).unwrap();
+        };
+        allow(&key);
+        env.git(&["config", "gpg.format", "ssh"]);
+        env.git(&["config", "user.signingKey", key.to_str().unwrap()]);
+        env.git(&[
+            "config",
+            "gpg.ssh.allowedSignersFile",
+            allowed_signers.to_str().unwrap(),
+        ]);
+        env.git(&["config", "assets.sign", "true"]);
+        env.git(&["config", "assets.verifySignatures", "true"]);
+
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let signature = env
+            .store_dir
+            .join("signatures")
+            .join(&hash[..2])
+            .join(&hash[2..]);
+        assert!(fs::read_to_string(&signature)
+            .unwrap()
+            .starts_with("-----BEGIN SSH SIGNATURE-----"));
+
+        let retrieve = |batch: bool| {
+            let mut bin = if batch {
+                env.run_test_command(&["retrieve-file", "--batch"])
+            } else {
+                env.run_test_command(&["retrieve-file"])
+            };
+            if batch {
+                bin.stdin_send(format!("{}\n", TEST_CONTENTS_REF.len()).as_bytes());
+            }
+            bin.stdin_send(TEST_CONTENTS_REF);
+            bin.wait_output()
+        };
+        let out = retrieve(false);
+        assert!(out.status.success());
+        assert_eq!(out.stdout.as_slice(), TEST_CONTENTS);
+
+        // Signatures by keys that are not allowed are rejected
+        allow(&other_key);
+        let out = retrieve(false);
+        assert_eq!(out.status.code(), Some(13));
+        assert!(out.stdout.is_empty());
+        let out = retrieve(true);
+        assert!(out.status.success());
+        assert!(String::from_utf8_lossy(&out.stdout).starts_with("error "));
+        allow(&key);
+
+        // So are objects without a signature
+        fs::remove_file(&signature).unwrap();
+        assert_eq!(retrieve(false).status.code(), Some(13));
+
+        // Storing the object again signs it, and its signature is pushed and fetched along
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+        assert!(signature.exists());
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.git(&[
+            "config",
+            "assets.remote",
+            remote.store_dir.to_str().unwrap(),
+        ]);
+        let _ = env.run_test_command(&["push"]).expect_success();
+        fs::remove_dir_all(&env.store_dir).unwrap();
+        let out = env.run_test_command(&["fetch"]).wait_output();
+        assert!(out.status.success());
+        assert!(signature.exists());
+        assert!(retrieve(false).status.success());
+
+        // Fetching fails for objects that are not signed by an allowed signer
+        fs::remove_dir_all(&env.store_dir).unwrap();
+        allow(&other_key);
+        let out = env.run_test_command(&["fetch"]).wait_output();
+        assert_eq!(out.status.code(), Some(13));
+        remote.remove_store();
+    });
+}
+
+/// Check that `doctor` finds misconfigurations and suggests fixes.
+#[test]
+fn test_doctor() {
+    run_test("doctor", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.write_file("raw.dat", b"committed before it was tracked");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "initial"]);
+
+        let out = env.run_test_command(&["doctor"]).expect_success();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.lines().all(|line| line.starts_with("ok ")),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("pointers: 1 pointers in HEAD"),
+            "{}",
+            report
+        );
+
+        // A pointer outside of the tracked patterns is checked out as it is
+        let pointer = env.git(&["show", "HEAD:a.bin"]);
+        env.write_file("pointer.txt", &pointer);
+        env.git(&["add", "pointer.txt"]);
+        env.git(&["commit", "-q", "-m", "pointer"]);
+        let out = env.run_test_command(&["doctor"]).expect_success();
+        let report = String::from_utf8(out).unwrap();
+        assert!(
+            report.contains(
+                "warning attributes: pointers are committed for files that are not tracked"
+            ),
+            "{}",
+            report
+        );
+
+        env.write_file(
+            ".gitattributes",
+            b"*.bin filter=assets\n*.dat filter=assets\n",
+        );
+        env.git(&["config", "--unset", "filter.assets.clean"]);
+        let out = env.run_test_command(&["--json", "doctor"]).wait_output();
+        assert_eq!(out.status.code(), Some(9));
+        let diagnoses: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        let health = |check: &str, message: &str| {
+            diagnoses
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|d| d["check"] == check && d["message"].as_str().unwrap().contains(message))
+                .map(|d| d["health"].clone())
+        };
+        assert_eq!(health("filter", "not configured"), Some("error".into()));
+        assert_eq!(health("attributes", "raw.dat"), Some("error".into()));
+        assert_eq!(health("store", "writable"), Some("ok".into()));
+    });
+}
+
+/// Check that `status` finds the assets of a commit whose objects are missing.
+#[test]
+fn test_status_missing() {
+    run_test("status_missing", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"first");
+        env.write_file("dir/b.bin", b"second");
+        env.write_file("dir/c.bin", b"second");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+
+        let out = env.run_test_command(&["status"]).expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "3 assets (2 objects), 0 missing (0 objects)\n"
+        );
+
+        let hash = ContentHash::hash_bytes(SHA256, b"second").to_hex_string();
+        let object = env.store_dir.join("data").join(&hash[..2]);
+        fs::remove_file(object.join(&hash[2..])).unwrap();
+        let out = env
+            .run_test_command(&["status", "--missing", "HEAD"])
+            .wait_output();
+        assert!(!out.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            format!("{0} dir/b.bin\n{0} dir/c.bin\n", hash)
+        );
+    });
+}
+
+/// Check that `which` shows the object of a tracked file and compares the working tree to it.
+#[test]
+fn test_which() {
+    run_test("which", |env| {
+        env.init_repo();
+        env.write_file("dir/a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let location = env.store_dir.join("data").join(&hash[..2]).join(&hash[2..]);
+        let out = env
+            .run_test_command(&["which", "dir/a.bin"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!(
+                "path: dir/a.bin\nhash: {}\nlocation: {}\nsize: 32 B\nworking tree: matches\n",
+                hash,
+                location.display()
+            )
+        );
+
+        env.write_file("dir/a.bin", TEST_CONTENTS_REF);
+        let out = env
+            .run_test_command(&["which", "--format", "json", "dir/a.bin"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).contains("\"worktree\": \"pointer\""));
+        env.write_file("dir/a.bin", b"changed");
+        let out = env
+            .run_test_command(&["which", "--format", "json", "dir/a.bin"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).contains("\"worktree\": \"modified\""));
+
+        let out = env
+            .run_test_command(&["which", ".gitattributes"])
+            .wait_output();
+        assert!(!out.status.success());
+    });
+}
+
+/// Check that `info` shows who stored an object first, from where and when.
+#[test]
+fn test_info() {
+    run_test("info", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "a.bin"]);
+        // Storing it again doesn't change where it came from
+        env.git(&["config", "user.name", "someone else"]);
+        env.write_file("b.bin", TEST_CONTENTS);
+        env.git(&["add", "b.bin"]);
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let out = env.run_test_command(&["info", &hash[..8]]).expect_success();
+        let out = String::from_utf8_lossy(&out);
+        assert!(out.contains("size: 32 B\npinned: no\n"));
+        assert!(out.contains("stored by: git-assets test <test@example.com>\n"));
+        let repo_dir = fs::canonicalize(&env.repo_dir).unwrap();
+        assert!(out.contains(&format!("stored from: {}\n", repo_dir.display())));
+
+        let out = env
+            .run_test_command(&["info", "--format", "json", &hash])
+            .expect_success();
+        let info: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            info["provenance"]["user"],
+            "git-assets test <test@example.com>"
+        );
+        assert!(info["provenance"]["time"].as_i64().unwrap() > 0);
+
+        // Deleting an object forgets about it
+        let _ = env
+            .run_test_command(&["delete", "--force", &hash])
+            .expect_success();
+        assert!(!env.store_dir.join("metadata").join(&hash).exists());
+    });
+}
+
+/// Check that `textconv` makes `git diff` summarize changed assets.
+#[test]
+fn test_textconv() {
+    run_test("textconv", |env| {
+        env.init_repo();
+        env.write_file(".gitattributes", b"*.bin filter=assets diff=assets\n");
+        let textconv = format!(
+            "'{}' --store '{}' textconv",
+            env.bin.display(),
+            env.store_dir.display()
+        );
+        env.git(&["config", "diff.assets.textconv", &textconv]);
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        env.write_file("a.bin", png);
+        let diff = String::from_utf8(env.git(&["diff", "-U0", "--", "a.bin"])).unwrap();
+        let old = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let new = ContentHash::hash_bytes(SHA256, png).to_hex_string();
+        assert!(diff.contains(&format!("-text/plain 32 B {}…\n", &old[..16])));
+        assert!(diff.contains(&format!("+image/png 16 B {}…\n", &new[..16])));
+
+        // The same summary for a pointer, as with smudging skipped
+        env.write_file("pointer", TEST_CONTENTS_REF);
+        let out = env
+            .run_test_command(&["textconv", "pointer"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!("text/plain 32 B {}…\n", &old[..16])
+        );
+    });
+}
+
+/// Check that `merge-driver` takes over one-sided changes and reports conflicting objects.
+#[test]
+fn test_merge_driver() {
+    run_test("merge_driver", |env| {
+        env.init_repo();
+        env.write_file(".gitattributes", b"*.bin filter=assets merge=assets\n");
+        let driver = |policy: &str| {
+            format!(
+                "'{}' --store '{}' merge-driver {} %O %A %B %P",
+                env.bin.display(),
+                env.store_dir.display(),
+                policy
+            )
+        };
+        env.git(&["config", "merge.assets.driver", &driver("")]);
+        env.write_file("a.bin", b"base a");
+        env.write_file("b.bin", b"base b");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "base"]);
+        env.git(&["checkout", "-q", "-b", "other"]);
+        env.write_file("a.bin", b"their a");
+        env.write_file("b.bin", b"their b");
+        env.git(&["commit", "-q", "-a", "-m", "theirs"]);
+        env.git(&["checkout", "-q", "-"]);
+        env.write_file("b.bin", b"our b");
+        env.git(&["commit", "-q", "-a", "-m", "ours"]);
+
+        let out = process::Command::new("git")
+            .current_dir(&env.repo_dir)
+            .args(["merge", "-q", "other"])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        let our_hash = ContentHash::hash_bytes(SHA256, b"our b");
+        let their_hash = ContentHash::hash_bytes(SHA256, b"their b");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains(&format!(
+            "conflict in b.bin: both sides changed the object\n  ours:   {} (5 B)\n  theirs: {} (7 B)\n",
+            our_hash, their_hash
+        )));
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), b"their a");
+        assert_eq!(fs::read(env.repo_dir.join("b.bin")).unwrap(), b"our b");
+        env.git(&["merge", "--abort"]);
+
+        env.git(&["config", "merge.assets.driver", &driver("--theirs")]);
+        env.git(&["merge", "-q", "other"]);
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), b"their a");
+        assert_eq!(fs::read(env.repo_dir.join("b.bin")).unwrap(), b"their b");
+    });
+}
+
+/// Check that `grep-hash` finds the files referring to an object in `HEAD` or in all commits.
+#[test]
+fn test_grep_hash() {
+    run_test("grep_hash", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        let first = String::from_utf8(env.git(&["rev-parse", "HEAD"])).unwrap();
+        env.git(&["mv", "a.bin", "b.bin"]);
+        env.git(&["commit", "-q", "-m", "second"]);
+        let second = String::from_utf8(env.git(&["rev-parse", "HEAD"])).unwrap();
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let out = env
+            .run_test_command(&["grep-hash", &hash[..8]])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!("{} {} b.bin\n", second.trim(), hash)
+        );
+        let out = env
+            .run_test_command(&["grep-hash", "--all", &hash])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!(
+                "{1} {0} b.bin\n{2} {0} a.bin\n",
+                hash,
+                second.trim(),
+                first.trim()
+            )
+        );
+
+        let other = ContentHash::hash_bytes(SHA256, b"other").to_hex_string();
+        let out = env.run_test_command(&["grep-hash", &other]).wait_output();
+        assert_eq!(out.status.code(), Some(1));
+        assert!(out.stdout.is_empty());
+    });
+}
+
+/// Check that installing configures the filter and creates the store.
+#[test]
+fn test_install() {
+    run_test("install", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        let _ = env.run_test_command(&["install"]).expect_success();
+
+        let config = |key: &str| String::from_utf8(env.git(&["config", "--local", key])).unwrap();
+        assert_eq!(
+            config("filter.assets.clean"),
+            format!(
+                "git-assets --store '{}' store-file\n",
+                env.store_dir.display()
+            )
+        );
+        assert_eq!(
+            config("filter.assets.smudge"),
+            format!(
+                "git-assets --store '{}' retrieve-file\n",
+                env.store_dir.display()
+            )
+        );
+        assert_eq!(config("filter.assets.required"), "true\n");
+        assert!(env.store_dir.join("data").is_dir());
+    });
+}
+
+/// Check that a relative store path is written into the filter as an absolute one, quoted for
+/// the shell, since git runs the filter from the root of the working tree.
+#[test]
+fn test_install_relative_store() {
+    run_test("install_relative_store", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        fs::create_dir(env.repo_dir.join("sub")).unwrap();
+        let child = process::Command::new(&env.bin)
+            .current_dir(env.repo_dir.join("sub"))
+            .args(["--store", "it's here", "install"])
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let _ = GitAssetsChild { child }.expect_success();
+        let store_dir = env.repo_dir.join("sub").join("it's here");
+        assert_eq!(
+            String::from_utf8(env.git(&["config", "filter.assets.clean"])).unwrap(),
+            format!(
+                "git-assets --store '{}' store-file\n",
+                store_dir.display().to_string().replace('\'', r"'\''")
+            )
+        );
+
+        let path = std::env::join_paths(
+            std::iter::once(env.bin.parent().unwrap().to_path_buf())
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        env.write_file(".gitattributes", b"*.bin filter=assets\n");
+        env.write_file("a.bin", TEST_CONTENTS);
+        let status = process::Command::new("git")
+            .current_dir(&env.repo_dir)
+            .env("PATH", path)
+            .args(["add", "a.bin"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(env.git(&["show", ":a.bin"]), TEST_CONTENTS_REF);
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        assert!(store_dir
+            .join("data")
+            .join(&hash[..2])
+            .join(&hash[2..])
+            .is_file());
+    });
+}
+
+/// Check that init sets up everything needed to commit assets.
+#[test]
+fn test_init() {
+    run_test("init", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        let _ = env.run_test_command(&["init", "*.psd"]).expect_success();
+
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.psd filter=assets -diff\n"
+        );
+        let refs: Vec<fs::DirEntry> = fs::read_dir(env.store_dir.join("ref"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(refs.len(), 1);
+        let git_dir = env.repo_dir.join(".git").canonicalize().unwrap();
+        assert_eq!(
+            fs::read_to_string(refs[0].path()).unwrap(),
+            format!("{}\n", git_dir.display())
+        );
+
+        // Patch the installed filter to use the binary under test
+        let filter = |command: &str| {
+            format!(
+                "'{}' --store '{}' {}",
+                env.bin.display(),
+                env.store_dir.display(),
+                command
+            )
+        };
+        env.git(&["config", "filter.assets.clean", &filter("store-file")]);
+        env.write_file("a.psd", TEST_CONTENTS);
+        env.git(&["add", "a.psd"]);
+        assert_eq!(env.git(&["show", ":a.psd"]), TEST_CONTENTS_REF);
+    });
+}
+
+/// Check that a store can be created with another hash algorithm, which is then used for pointers.
+#[test]
+fn test_init_hash() {
+    run_test("init_hash", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        let _ = env
+            .run_test_command(&["init", "--hash", "sha512_256", "*.psd"])
+            .expect_success();
+        assert_eq!(
+            fs::read_to_string(env.store_dir.join("algorithm")).unwrap(),
+            "sha512_256\n"
+        );
+
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let hash = ContentHash::hash_bytes(SHA512_256, TEST_CONTENTS);
+        assert_eq!(
+            bin.expect_success(),
+            format!("git-assets v1\n{}\n", hash).into_bytes()
+        );
+        assert!(hash.to_hex_string().starts_with("sha512_256-"));
+        let _ = env.run_test_command(&["validate"]).expect_success();
+
+        // The algorithm cannot be changed once there are objects
+        let out = env
+            .run_test_command(&["init", "--hash", "sha256"])
+            .wait_output();
+        assert!(!out.status.success());
+        let out = env
+            .run_test_command(&["init", "--hash", "md5"])
+            .wait_output();
+        assert!(!out.status.success());
+    });
+}
+
+/// Check that a repository using Git LFS can be switched to git-assets without changing its
+/// history.
+#[test]
+fn test_migrate_import_lfs() {
+    run_test("migrate_import_lfs", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        env.git(&["config", "user.name", "git-assets test"]);
+        env.git(&["config", "user.email", "test@example.com"]);
+
+        // What Git LFS leaves behind: pointers in the history and the objects in .git/lfs
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        let lfs_ref = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 32\n",
+            hash
+        );
+        env.write_file(
+            ".gitattributes",
+            b"*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        );
+        env.write_file("a.bin", lfs_ref.as_bytes());
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "lfs"]);
+        let oid = hash.to_hex_string();
+        env.write_file(
+            &format!(".git/lfs/objects/{}/{}/{}", &oid[0..2], &oid[2..4], oid),
+            TEST_CONTENTS,
+        );
+
+        let out = env
+            .run_test_command(&["migrate", "import-lfs"])
+            .expect_success();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("imported 1 objects"), "{}", out);
+        assert_data_count(env, 1);
+        assert_data_contents(env, TEST_CONTENTS);
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.bin filter=assets -diff -text\n"
+        );
+        assert_eq!(env.git(&["config", "assets.pointer"]), b"lfs\n");
+
+        // Patch the installed filter to use the binary under test
+        let filter = |command: &str| {
+            format!(
+                "'{}' --store '{}' {}",
+                env.bin.display(),
+                env.store_dir.display(),
+                command
+            )
+        };
+        env.git(&["config", "filter.assets.clean", &filter("store-file")]);
+        env.git(&["config", "filter.assets.smudge", &filter("retrieve-file")]);
+        fs::remove_file(env.repo_dir.join("a.bin")).unwrap();
+        env.git(&["checkout", "--", "a.bin"]);
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        // Cleaning the contents results in the committed pointer
+        env.git(&["add", "a.bin"]);
+        assert_eq!(env.git(&["show", ":a.bin"]), lfs_ref.as_bytes());
+        assert_eq!(
+            env.git(&["diff", "--cached", "--name-only", "HEAD", "--", "a.bin"]),
+            b""
+        );
+    });
+}
+
+/// Check that a repository can be handed over to Git LFS.
+#[test]
+fn test_migrate_export_lfs() {
+    run_test("migrate_export_lfs", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "assets"]);
+        assert_eq!(env.git(&["show", ":a.bin"]), TEST_CONTENTS_REF);
+
+        let _ = env
+            .run_test_command(&["migrate", "export-lfs"])
+            .expect_success();
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        let oid = hash.to_hex_string();
+        let lfs_object = env.repo_dir.join(format!(
+            ".git/lfs/objects/{}/{}/{}",
+            &oid[0..2],
+            &oid[2..4],
+            oid
+        ));
+        assert_eq!(fs::read(lfs_object).unwrap(), TEST_CONTENTS);
+        assert_eq!(
+            env.git(&["show", ":a.bin"]),
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 32\n",
+                oid
+            )
+            .into_bytes()
+        );
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n"
+        );
+    });
+}
+
+/// Check that annexed files are replaced by pointers to the imported objects.
+#[test]
+fn test_migrate_import_annex() {
+    run_test("migrate_import_annex", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+
+        // What git-annex leaves behind: symlinks to the objects in .git/annex
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS);
+        let key = format!("SHA256E-s32--{}.bin", hash);
+        let object = format!(".git/annex/objects/Xx/Yy/{0}/{0}", key);
+        env.write_file(&object, TEST_CONTENTS);
+        std::os::unix::fs::symlink(&object, env.repo_dir.join("a.bin")).unwrap();
+        let absent = format!(
+            ".git/annex/objects/Zz/Zz/SHA256E-s1--{0}.dat/SHA256E-s1--{0}.dat",
+            ContentHash::hash_bytes(SHA256, b"x")
+        );
+        std::os::unix::fs::symlink(absent, env.repo_dir.join("b.dat")).unwrap();
+        // Next to a text file that was never annexed
+        let other = format!(
+            ".git/annex/objects/Xx/Zz/SHA256E-s5--{0}.txt/SHA256E-s5--{0}.txt",
+            ContentHash::hash_bytes(SHA256, b"notes")
+        );
+        env.write_file(&other, b"notes");
+        fs::create_dir(env.repo_dir.join("d")).unwrap();
+        std::os::unix::fs::symlink(format!("../{}", other), env.repo_dir.join("d/c.txt")).unwrap();
+        env.write_file("readme.txt", b"plain text");
+        env.write_file(".git/info/attributes", b"* filter=annex\n");
+        env.git(&["add", "."]);
+
+        let out = env
+            .run_test_command(&["migrate", "import-annex"])
+            .wait_output();
+        // The contents of b.dat are not there
+        assert!(!out.status.success());
+        assert_data_count(env, 2);
+        assert_data_contents(env, TEST_CONTENTS);
+        assert_eq!(
+            env.git(&["show", ":a.bin"]),
+            format!("git-assets v1\n{}\n", hash).into_bytes()
+        );
+        assert_eq!(env.git(&["ls-files", "-s", "a.bin"])[..6], *b"100644");
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        assert_eq!(env.git(&["ls-files", "-s", "b.dat"])[..6], *b"120000");
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.bin filter=assets -diff\n/d/c.txt filter=assets -diff\n"
+        );
+        assert_eq!(fs::read(env.repo_dir.join("d/c.txt")).unwrap(), b"notes");
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".git/info/attributes")).unwrap(),
+            ""
+        );
+    });
+}
+
+/// Check that rewriting the history turns matching files of all commits into pointers.
+#[test]
+fn test_migrate_history() {
+    run_test("migrate_history", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        env.git(&["config", "user.name", "git-assets test"]);
+        env.git(&["config", "user.email", "test@example.com"]);
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.write_file("b.txt", b"text\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.write_file(".gitattributes", b"*.txt text\n");
+        env.write_file("d/c d.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "second"]);
+
+        // The installed filter runs `git-assets` when checking out the rewritten HEAD
+        let path = std::env::join_paths(
+            std::iter::once(env.bin.parent().unwrap().to_path_buf())
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        // Blobs are stored on several threads before the history is rewritten
+        let mut cmd = env.build_test_cmd();
+        let child = with_git_config(&mut cmd, "assets.jobs", "4")
+            .env("PATH", path)
+            .args(["migrate", "history", "*.bin"])
+            .spawn()
+            .unwrap();
+        let _ = GitAssetsChild { child }.expect_success();
+        assert_data_count(env, 2);
+
+        assert_eq!(env.git(&["rev-list", "--count", "HEAD"]), b"2\n");
+        assert_eq!(env.git(&["show", "HEAD~1:a.bin"]), TEST_CONTENTS_REF);
+        assert_eq!(env.git(&["show", "HEAD:a.bin"]), TEST_CONTENTS_REF);
+        assert_eq!(env.git(&["show", "HEAD:b.txt"]), b"text\n");
+        assert!(env
+            .git(&["show", "HEAD:d/c d.bin"])
+            .starts_with(b"git-assets v1\n"));
+        assert_eq!(
+            env.git(&["show", "HEAD~1:.gitattributes"]),
+            b"*.bin filter=assets -diff\n"
+        );
+        assert_eq!(
+            env.git(&["show", "HEAD:.gitattributes"]),
+            b"*.txt text\n*.bin filter=assets -diff\n"
+        );
+        // The working tree has the contents again
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        let filter = format!(
+            "'{}' --store '{}' store-file",
+            env.bin.display(),
+            env.store_dir.display()
+        );
+        env.git(&["config", "filter.assets.clean", &filter]);
+        assert!(env.git(&["status", "--porcelain"]).is_empty());
+    });
+}
+
+/// Check that the commands that install the filter use the store from `GIT_ASSETS_STORE`,
+/// without writing it into the git config.
+#[test]
+fn test_migrate_history_env_store() {
+    run_test("migrate_history_env_store", |env| {
+        fs::create_dir(&env.repo_dir).unwrap();
+        env.git(&["init", "-q"]);
+        env.git(&["config", "user.name", "git-assets test"]);
+        env.git(&["config", "user.email", "test@example.com"]);
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+
+        let path = std::env::join_paths(
+            std::iter::once(env.bin.parent().unwrap().to_path_buf())
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        let run = |args: &[&str]| {
+            let child = process::Command::new(&env.bin)
+                .current_dir(&env.repo_dir)
+                .env("PATH", &path)
+                .env("GIT_ASSETS_STORE", &env.store_dir)
+                .args(args)
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            GitAssetsChild { child }.expect_success()
+        };
+        let _ = run(&["migrate", "history", "*.bin"]);
+        assert_data_count(env, 1);
+        assert!(!env.repo_dir.join(".git/x-assets").exists());
+        assert_eq!(
+            env.git(&["config", "filter.assets.clean"]),
+            b"git-assets store-file\n"
+        );
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+
+        // As if smudging had been skipped
+        env.write_file("a.bin", TEST_CONTENTS_REF);
+        let _ = run(&["uninstall", "--smudge", "--delete-store"]);
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        assert!(!env.store_dir.exists());
+    });
+}
+
+/// Check that smudging can be skipped, and that `checkout` puts the contents in place later.
+#[test]
+fn test_skip_smudge_checkout() {
+    run_test("skip_smudge_checkout", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.write_file("b.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        fs::remove_file(env.repo_dir.join("a.bin")).unwrap();
+        fs::remove_file(env.repo_dir.join("b.bin")).unwrap();
+
+        let status = process::Command::new("git")
+            .current_dir(&env.repo_dir)
+            .env("GIT_ASSETS_SKIP_SMUDGE", "1")
+            .args(["checkout", "--", "."])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+
+        let _ = env.run_test_command(&["checkout", "a.*"]).expect_success();
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        assert!(fs::read(env.repo_dir.join("b.bin"))
+            .unwrap()
+            .starts_with(b"git-assets v1\n"));
+        let _ = env.run_test_command(&["checkout"]).expect_success();
+        assert_eq!(
+            fs::read(env.repo_dir.join("b.bin")).unwrap(),
+            b"other contents\n"
+        );
+        assert!(env.git(&["status", "--porcelain"]).is_empty());
+    });
+}
+
+/// Check that adding the pointer files left by skipped smudging stages them unchanged, rather
+/// than storing the pointers as contents.
+#[test]
+fn test_skip_smudge_add() {
+    run_test("skip_smudge_add", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        fs::remove_file(env.repo_dir.join("a.bin")).unwrap();
+        env.git(&["config", "assets.skipSmudge", "true"]);
+        env.git(&["checkout", "--", "a.bin"]);
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+
+        // Touching the file makes git clean it again
+        std::thread::sleep(Duration::from_millis(10));
+        env.write_file("a.bin", TEST_CONTENTS_REF);
+        assert!(env.git(&["status", "--porcelain"]).is_empty());
+        env.git(&["add", "--renormalize", "."]);
+        assert!(env.git(&["diff", "--cached"]).is_empty());
+        assert_data_count(env, 1);
+
+        // The same goes for batches
+        let mut framed = format!("{}\n", TEST_CONTENTS_REF.len()).into_bytes();
+        framed.extend_from_slice(TEST_CONTENTS_REF);
+        for command in ["retrieve-file", "store-file"] {
+            let mut bin = env.run_test_command(&[command, "--batch"]);
+            bin.stdin_send(&framed);
+            assert_eq!(bin.expect_success(), framed);
+        }
+        assert_data_count(env, 1);
+    });
+}
+
+/// Check that `checkout --pointer` replaces unmodified files by their pointers and keeps them
+/// that way until they are checked out by name.
+#[test]
+fn test_checkout_pointer() {
+    run_test("checkout_pointer", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.write_file("b.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.write_file("b.bin", b"changed contents\n");
+
+        // Modified files are left alone
+        let out = env
+            .run_test_command(&["checkout", "--pointer", "*.bin"])
+            .wait_output();
+        assert!(!out.status.success());
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+        assert_eq!(
+            fs::read(env.repo_dir.join("b.bin")).unwrap(),
+            b"changed contents\n"
+        );
+        assert_eq!(env.git(&["status", "--porcelain"]), b" M b.bin\n");
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".git/x-assets-skipped")).unwrap(),
+            "a.bin\n"
+        );
+
+        // Skipped files stay pointers, unless they are named
+        let _ = env.run_test_command(&["checkout"]).expect_success();
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+        let _ = env
+            .run_test_command(&["checkout", "a.bin"])
+            .expect_success();
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        assert_eq!(env.git(&["status", "--porcelain"]), b" M b.bin\n");
+        assert!(!env.repo_dir.join(".git/x-assets-skipped").exists());
+    });
+}
+
+/// Check that adding a pointer file left by `checkout --pointer` stages the pointer it was, not
+/// a pointer to the pointer.
+#[test]
+fn test_checkout_pointer_add() {
+    run_test("checkout_pointer_add", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        let _ = env
+            .run_test_command(&["checkout", "--pointer", "a.bin"])
+            .expect_success();
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+
+        // Rewriting the file changes its mtime, so that git cleans it again
+        std::thread::sleep(Duration::from_millis(10));
+        env.write_file("a.bin", TEST_CONTENTS_REF);
+        env.git(&["add", "a.bin"]);
+        env.git(&["add", "--renormalize", "."]);
+        assert!(env.git(&["diff", "--cached"]).is_empty());
+        assert!(env.git(&["status", "--porcelain"]).is_empty());
+        assert_data_count(env, 1);
+
+        // Batches pass pointers through as well, but not damaged ones
+        let damaged = &TEST_CONTENTS_REF[..20];
+        let mut bin = env.run_test_command(&["store-file", "--batch"]);
+        let mut input = format!("{}\n", TEST_CONTENTS_REF.len()).into_bytes();
+        input.extend_from_slice(TEST_CONTENTS_REF);
+        input.extend_from_slice(format!("{}\n", damaged.len()).as_bytes());
+        input.extend_from_slice(damaged);
+        bin.stdin_send(&input);
+        let out = bin.expect_success();
+        let mut expected = format!("{}\n", TEST_CONTENTS_REF.len()).into_bytes();
+        expected.extend_from_slice(TEST_CONTENTS_REF);
+        assert!(out.starts_with(&expected));
+        assert!(!out[expected.len()..].ends_with(damaged));
+        assert_data_count(env, 2);
+    });
+}
+
+/// Check that `checkout` with `verify-reads` keeps the pointer file of a corrupt object, so that
+/// the corrupt contents can't be committed by accident.
+#[test]
+fn test_checkout_corrupt_object() {
+    run_test("checkout_corrupt_object", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let data_dir = env.store_dir.join("data");
+        fs::write(data_dir.join(&hash[..2]).join(&hash[2..]), b"bitrot").unwrap();
+        env.write_file("a.bin", TEST_CONTENTS_REF);
+
+        env.git(&["config", "assets.verifyReads", "true"]);
+        let output = env.run_test_command(&["checkout"]).wait_output();
+        assert_eq!(output.status.code(), Some(4));
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+        // No temporary file is left behind either
+        let mut names: Vec<_> = fs::read_dir(&env.repo_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".git", ".gitattributes", "a.bin"]);
+    });
+}
+
+/// Check that the installed hooks check out pointer files left behind after switching branches.
+#[test]
+fn test_hooks() {
+    run_test("hooks", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.git(&["checkout", "-q", "-b", "other"]);
+        env.write_file("b.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "second"]);
+        env.git(&["checkout", "-q", "-"]);
+
+        env.write_file(".git/hooks/post-merge", b"#!/bin/sh\nmake\n");
+        let out = env.run_test_command(&["hooks", "install"]).wait_output();
+        assert!(!out.status.success());
+        assert!(!env.repo_dir.join(".git/hooks/post-checkout").exists());
+        let _ = env
+            .run_test_command(&["hooks", "install", "--force"])
+            .expect_success();
+
+        // The hooks run `git-assets`
+        let path = std::env::join_paths(
+            std::iter::once(env.bin.parent().unwrap().to_path_buf())
+                .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+        )
+        .unwrap();
+        let status = process::Command::new("git")
+            .current_dir(&env.repo_dir)
+            .env("PATH", path)
+            .env("GIT_ASSETS_SKIP_SMUDGE", "1")
+            .args(["checkout", "-q", "other"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(env.repo_dir.join("b.bin")).unwrap(),
+            b"other contents\n"
+        );
+
+        let _ = env
+            .run_test_command(&["hooks", "uninstall"])
+            .expect_success();
+        assert!(!env.repo_dir.join(".git/hooks/post-checkout").exists());
+        assert!(!env.repo_dir.join(".git/hooks/post-merge").exists());
+    });
+}
+
+/// Check that uninstalling removes the filter and restores the contents of pointer files.
+#[test]
+fn test_uninstall() {
+    run_test("uninstall", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        // As if smudging had been skipped
+        env.write_file("a.bin", TEST_CONTENTS_REF);
+
+        // A missing object leaves the pointer file as it was
+        let (shard, rest) = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).sharded();
+        let object = env.store_dir.join("data").join(shard).join(rest);
+        let moved = env.store_dir.join("moved");
+        fs::rename(&object, &moved).unwrap();
+        let out = env
+            .run_test_command(&["uninstall", "--smudge"])
+            .wait_output();
+        assert!(!out.status.success());
+        assert_eq!(
+            fs::read(env.repo_dir.join("a.bin")).unwrap(),
+            TEST_CONTENTS_REF
+        );
+        fs::rename(&moved, &object).unwrap();
+
+        let _ = env
+            .run_test_command(&["uninstall", "--smudge"])
+            .expect_success();
+        assert_eq!(fs::read(env.repo_dir.join("a.bin")).unwrap(), TEST_CONTENTS);
+        let out = process::Command::new("git")
+            .current_dir(&env.repo_dir)
+            .args(["config", "--get-regexp", "^filter\\."])
+            .output()
+            .unwrap();
+        assert!(out.stdout.is_empty());
+        assert!(env.store_dir.join("data").is_dir());
+
+        let _ = env
+            .run_test_command(&["uninstall", "--delete-store"])
+            .expect_success();
+        assert!(!env.store_dir.exists());
+    });
+}
+
+/// Check that tracking a pattern extends `.gitattributes` and reports the affected files.
+#[test]
+fn test_track() {
+    run_test("track", |env| {
+        env.init_repo();
+        env.write_file("a.psd", b"committed");
+        env.write_file("b.txt", b"text");
+        env.git(&["add", "."]);
+        env.write_file("dir/c.psd", b"untracked");
+
+        let out = env
+            .run_test_command(&["track", "*.psd", "*.bin"])
+            .expect_success();
+        let out = String::from_utf8_lossy(&out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "tracking *.psd",
+                "tracking *.bin",
+                "now tracked: a.psd",
+                "now tracked: dir/c.psd"
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.bin filter=assets\n*.psd filter=assets -diff\n"
+        );
+    });
+}
+
+/// Check that `track --above` adds patterns for the files larger than the threshold.
+#[test]
+fn test_track_above() {
+    run_test("track_above", |env| {
+        env.init_repo();
+        env.write_file("small.psd", b"small");
+        env.write_file("dir/large.psd", &[0; 2048]);
+        env.write_file("dir/LARGE", &[0; 2048]);
+        env.write_file("tracked.bin", &[0; 2048]);
+
+        let out = env
+            .run_test_command(&["track", "--above", "1KiB"])
+            .expect_success();
+        let out = String::from_utf8_lossy(&out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "tracking *.psd",
+                "tracking /dir/LARGE",
+                "now tracked: dir/LARGE",
+                "now tracked: dir/large.psd",
+                "now tracked: small.psd",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            "*.bin filter=assets\n*.psd filter=assets -diff\n/dir/LARGE filter=assets -diff\n"
+        );
+
+        env.write_file("dir/other.png", &[0; 2048]);
+        let out = env
+            .run_test_command(&["track", "--above", "1KiB", "--per-file"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).starts_with("tracking /dir/other.png\n"));
+        let out = env
+            .run_test_command(&["track", "--above", "1KiB"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "no untracked files above 1.0 KiB\n"
+        );
+    });
+}
+
+/// Check that untracking a pattern puts the real contents back into the index.
+#[test]
+fn test_untrack() {
+    run_test("untrack", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"smudged");
+        env.write_file("b.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        // As if smudging had been skipped
+        env.write_file("b.bin", TEST_CONTENTS_REF);
+
+        let _ = env
+            .run_test_command(&["untrack", "--restore", "*.bin"])
+            .expect_success();
+        assert_eq!(
+            fs::read_to_string(env.repo_dir.join(".gitattributes")).unwrap(),
+            ""
+        );
+        assert_eq!(env.git(&["show", ":a.bin"]), b"smudged");
+        assert_eq!(env.git(&["show", ":b.bin"]), TEST_CONTENTS);
+    });
+}
+
+/// Check listing the objects of the store.
+#[test]
+fn test_ls() {
+    run_test("ls", |env| {
+        for contents in [&b"abc"[..], b"abcdef"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+
+        let out = env
+            .run_test_command(&["ls", "--sort", "size", "--reverse", "--format", "csv"])
+            .expect_success();
+        let out = String::from_utf8_lossy(&out);
+        let lines: Vec<Vec<&str>> = out.lines().map(|line| line.split(',').collect()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], ["hash", "size", "modified", "chunked"]);
+        assert_eq!(
+            lines[1][..2],
+            [
+                ContentHash::hash_bytes(SHA256, b"abcdef")
+                    .to_hex_string()
+                    .as_str(),
+                "6"
+            ]
+        );
+        assert_eq!(lines[2][1], "3");
+        assert!(lines[2][2].parse::<u64>().is_ok());
+    });
+}
+
+/// Check labeling objects and listing them by their labels.
+#[test]
+fn test_note() {
+    run_test("note", |env| {
+        for contents in [&b"abc"[..], b"abcdef"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let a = ContentHash::hash_bytes(SHA256, b"abc").to_hex_string();
+        let b = ContentHash::hash_bytes(SHA256, b"abcdef").to_hex_string();
 
-        bin1.stdin_send(TEST_CONTENTS);
-        bin2.stdin_send(TEST_CONTENTS);
-        bin1.stdin_close();
-        bin2.stdin_close();
-        let out1 = bin1.expect_success();
-        let out2 = bin2.expect_success();
+        let _ = env
+            .run_test_command(&["note", "add", &a[..8], "license=cc0", "source=vendorX"])
+            .expect_success();
+        let _ = env
+            .run_test_command(&["note", "add", &b, "license=proprietary"])
+            .expect_success();
+        let out = env.run_test_command(&["info", &a]).expect_success();
+        assert!(
+            String::from_utf8_lossy(&out).ends_with("label: license=cc0\nlabel: source=vendorX\n")
+        );
 
-        // Ensure program output is correct
-        assert_eq!(out1.as_slice(), TEST_CONTENTS_REF);
-        assert_eq!(out2.as_slice(), TEST_CONTENTS_REF);
+        let ls = |filters: &[&str]| {
+            let mut args = vec!["ls"];
+            for filter in filters {
+                args.extend(["--filter", filter]);
+            }
+            let out = env.run_test_command(&args).expect_success();
+            String::from_utf8_lossy(&out)
+                .lines()
+                .map(|line| line.split(' ').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ls(&["license=cc0"]), [a.clone()]);
+        assert_eq!(ls(&["license=cc0", "source=vendorY"]), Vec::<String>::new());
+        assert_eq!(ls(&[]).len(), 2);
 
-        // Ensure store is in a correct state:
+        let _ = env
+            .run_test_command(&["note", "remove", &a, "license"])
+            .expect_success();
+        assert_eq!(ls(&["license=cc0"]), Vec::<String>::new());
+        assert_eq!(ls(&["source=vendorX"]), [a.clone()]);
+
+        let out = env
+            .run_test_command(&["note", "add", &a, "bad key=1"])
+            .wait_output();
+        assert!(!out.status.success());
+        let missing = ContentHash::hash_bytes(SHA256, b"missing").to_hex_string();
+        let out = env
+            .run_test_command(&["note", "add", &missing, "license=cc0"])
+            .wait_output();
+        assert_eq!(out.status.code(), Some(3));
+    });
+}
+
+/// Check dumping objects by their full or abbreviated hash.
+#[test]
+fn test_cat() {
+    run_test("cat", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        for arg in [&hash[..], &hash[..8], &hash[..4].to_uppercase()] {
+            let out = env.run_test_command(&["cat", arg]).expect_success();
+            assert_eq!(out.as_slice(), TEST_CONTENTS);
+        }
+        for arg in ["abc", "0000", "not-hex"] {
+            let out = env.run_test_command(&["cat", arg]).wait_output();
+            assert!(!out.status.success());
+        }
+    });
+}
+
+/// Check that the exit status tells the classes of failures apart.
+#[test]
+fn test_exit_codes() {
+    run_test("exit_codes", |env| {
+        for contents in [&b"intact"[..], b"corrupt"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let status = |args: &[&str]| env.run_test_command(args).wait_output().status.code();
+        let missing = ContentHash::hash_bytes(SHA256, b"missing").to_hex_string();
+        assert_eq!(status(&["cat", &missing]), Some(3));
+        assert_eq!(status(&["push"]), Some(6));
+        if cfg!(feature = "http") {
+            assert_eq!(
+                status(&["--remote", "http://127.0.0.1:1/assets", "push"]),
+                Some(5)
+            );
+        }
+        assert_eq!(status(&["--json", "gc"]), Some(10));
+        assert_eq!(status(&["cat", "xyz"]), Some(11));
+
+        let corrupt = ContentHash::hash_bytes(SHA256, b"corrupt").to_hex_string();
+        let data_dir = env.store_dir.join("data");
+        fs::write(data_dir.join(&corrupt[..2]).join(&corrupt[2..]), b"bitrot").unwrap();
+        assert_eq!(status(&["validate"]), Some(4));
+
+        let out = process::Command::new(&env.bin)
+            .current_dir(&env.store_dir)
+            .env("GIT_CEILING_DIRECTORIES", env.store_dir.parent().unwrap())
+            .arg("ls")
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+    });
+}
+
+/// Check that commands and stores left out of the build fail with an explanation.
+#[test]
+#[cfg(not(all(feature = "s3", feature = "serve")))]
+fn test_disabled_features() {
+    run_test("disabled_features", |env| {
+        let stderr = |args: &[&str], code| {
+            let out = env
+                .build_test_cmd()
+                .args(args)
+                .stderr(process::Stdio::piped())
+                .output()
+                .unwrap();
+            assert_eq!(out.status.code(), Some(code));
+            String::from_utf8(out.stderr).unwrap()
+        };
+        if cfg!(not(feature = "s3")) {
+            let err = stderr(&["--remote", "s3://bucket/assets", "push"], 7);
+            assert!(err.contains("the `s3` feature"), "{}", err);
+        }
+        if cfg!(not(feature = "serve")) {
+            let err = stderr(&["serve"], 1);
+            assert!(err.contains("the `serve` feature"), "{}", err);
+        }
+    });
+}
+
+/// Check that `verify-reads` makes retrieving a corrupt object fail instead of returning it.
+#[test]
+fn test_verify_reads() {
+    run_test("verify_reads", |env| {
+        env.init_repo();
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(b"contents");
+        let pointer = bin.expect_success();
+        let hash = ContentHash::hash_bytes(SHA256, b"contents").to_hex_string();
+        let data_dir = env.store_dir.join("data");
+        fs::write(data_dir.join(&hash[..2]).join(&hash[2..]), b"bitrot").unwrap();
+
+        let retrieve = |verify: bool| {
+            let mut cmd = env.build_test_cmd();
+            if verify {
+                with_git_config(&mut cmd, "assets.verifyReads", "true");
+            }
+            let mut bin = GitAssetsChild {
+                child: cmd.arg("retrieve-file").spawn().unwrap(),
+            };
+            bin.stdin_send(&pointer);
+            bin.wait_output()
+        };
+        assert_eq!(retrieve(false).stdout, b"bitrot");
+        assert_eq!(retrieve(true).status.code(), Some(4));
+
+        env.git(&["config", "assets.verifyReads", "true"]);
+        assert_eq!(
+            env.run_test_command(&["cat", &hash])
+                .wait_output()
+                .status
+                .code(),
+            Some(4)
+        );
+    });
+}
+
+/// Check the exit status of `exists` for present and missing objects.
+#[test]
+fn test_exists() {
+    run_test("exists", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+
+        let hash = ContentHash::hash_bytes(SHA256, TEST_CONTENTS).to_hex_string();
+        let missing = ContentHash::hash_bytes(SHA256, b"missing").to_hex_string();
+        let status = |args: &[&str]| env.run_test_command(args).wait_output().status.code();
+        assert_eq!(status(&["exists", &hash]), Some(0));
+        assert_eq!(status(&["has", &hash[..6]]), Some(0));
+        assert_eq!(status(&["exists", &missing]), Some(1));
+        assert_eq!(status(&["exists", &missing[..6]]), Some(1));
+    });
+}
+
+/// Check that deleting refuses to remove objects that are still referenced.
+#[test]
+fn test_delete() {
+    run_test("delete", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"secret");
+        env.git(&["add", "."]);
+        env.write_file("b.bin", b"stored once");
+        env.git(&["add", "b.bin"]);
+        env.git(&["rm", "-q", "--cached", "b.bin"]);
+
+        let secret = ContentHash::hash_bytes(SHA256, b"secret").to_hex_string();
+        let orphan = ContentHash::hash_bytes(SHA256, b"stored once").to_hex_string();
+        let out = env.run_test_command(&["delete", &secret]).wait_output();
+        assert!(!out.status.success());
+        assert_data_count(env, 2);
+
+        let _ = env.run_test_command(&["delete", &orphan]).expect_success();
+        assert_data_count(env, 1);
+        let _ = env
+            .run_test_command(&["delete", "--force", &secret])
+            .expect_success();
+        assert_data_count(env, 0);
+        let out = env.run_test_command(&["delete", &orphan]).wait_output();
+        assert!(!out.status.success());
+    });
+}
+
+/// Check the statistics about the store contents and staging directory.
+#[test]
+fn test_stats() {
+    run_test("stats", |env| {
+        for contents in [&b"abc"[..], b"abcdef"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        // Left behind by an interrupted filter
+        fs::write(env.store_dir.join("staging").join("smudge.1."), b"partial").unwrap();
+
+        let out = env
+            .run_test_command(&["stats", "--format", "json"])
+            .expect_success();
+        let stats: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(stats["objects"], 2);
+        assert_eq!(stats["bytes"], 9);
+        assert_eq!(stats["staging_files"], 1);
+        assert_eq!(stats["staging_bytes"], 7);
+        assert_eq!(stats["largest"][0]["size"], 6);
+        assert_eq!(stats["largest"][1]["size"], 3);
+    });
+}
+
+/// Check that only sufficiently old staging files are cleaned up.
+#[test]
+fn test_gc_staging() {
+    run_test("gc_staging", |env| {
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(TEST_CONTENTS);
+        let _ = bin.expect_success();
+        fs::write(env.store_dir.join("staging").join("smudge.1."), b"partial").unwrap();
+
+        let out = env.run_test_command(&["gc", "--staging"]).expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "removed 0 staging files (0 B), kept 1\n"
+        );
+        let _ = env
+            .run_test_command(&["gc", "--staging", "--older-than", "0s"])
+            .expect_success();
         assert_empty_staging(env);
         assert_data_count(env, 1);
-        assert_data_contents(env, TEST_CONTENTS);
+    });
+}
+
+/// Check that `validate --fix` sets corrupt objects aside and removes what doesn't belong.
+#[test]
+fn test_validate_fix() {
+    run_test("validate_fix", |env| {
+        for contents in [&b"intact"[..], b"corrupt"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let corrupt = ContentHash::hash_bytes(SHA256, b"corrupt").to_hex_string();
+        let data_dir = env.store_dir.join("data");
+        fs::write(data_dir.join(&corrupt[..2]).join(&corrupt[2..]), b"bitrot").unwrap();
+        fs::write(data_dir.join("stray"), b"stray").unwrap();
+
+        let out = env.run_test_command(&["validate"]).wait_output();
+        assert!(!out.status.success());
+        let _ = env
+            .run_test_command(&["validate", "--fix"])
+            .expect_success();
+        assert_eq!(
+            fs::read(env.store_dir.join("lost+found").join(&corrupt)).unwrap(),
+            b"bitrot"
+        );
+        assert!(!data_dir.join("stray").exists());
+        assert_data_count(env, 1);
         let _ = env.run_test_command(&["validate"]).expect_success();
     });
 }
 
-/// Check that a stored file can be retrieved afterwards.
+/// Check that `--dry-run` reports what would be removed or rewritten without doing it.
 #[test]
-fn test_store_retrieve() {
-    run_test("store_retrieve", |env| {
-        {
+fn test_dry_run() {
+    run_test("dry_run", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"committed");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.write_file("b.bin", b"orphan");
+        env.git(&["add", "b.bin"]);
+        env.git(&["rm", "-q", "--cached", "b.bin"]);
+        let orphan = ContentHash::hash_bytes(SHA256, b"orphan");
+        assert_data_count(env, 2);
+        let staging = env.store_dir.join("staging").join("smudge.99.");
+        fs::File::create(&staging)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60))
+            .unwrap();
+
+        let out = env
+            .run_test_command(&["gc", "--grace-period", "0s", "--dry-run"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!(
+                "would remove {}\nwould remove 1 objects (6 B), would keep 1\n",
+                orphan
+            )
+        );
+        let out = env
+            .run_test_command(&["delete", "-n", &orphan.to_hex_string()])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!("would delete {}\n", orphan)
+        );
+        let out = env
+            .run_test_command(&["prune", "--dry-run"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).starts_with("would remove"));
+        assert_data_count(env, 2);
+        let out = env
+            .run_test_command(&["gc", "--staging", "--older-than", "1d", "--dry-run"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).contains("would remove"));
+        assert!(staging.exists());
+
+        // The store is still broken afterwards, so this fails like `validate` does
+        fs::write(env.store_dir.join("data").join("stray"), b"stray").unwrap();
+        let out = env
+            .run_test_command(&["validate", "--fix", "--dry-run"])
+            .wait_output();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stdout).contains("would remove: "));
+        assert!(env.store_dir.join("data").join("stray").exists());
+        assert!(staging.exists());
+
+        let head = env.git(&["rev-parse", "HEAD"]);
+        let out = env
+            .run_test_command(&["migrate", "history", "--dry-run", "*.txt"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "would rewrite 1 commits, store 0 objects (0 B)\nwould track *.txt\n"
+        );
+        assert_eq!(env.git(&["rev-parse", "HEAD"]), head);
+        assert_data_contents(env, b"orphan");
+    });
+}
+
+/// Check that `--json` selects the JSON output of commands that have one.
+#[test]
+fn test_json_flag() {
+    run_test("json_flag", |env| {
+        for contents in [&b"intact"[..], b"corrupt"] {
             let mut bin = env.run_test_command(&["store-file"]);
-            bin.stdin_send(TEST_CONTENTS);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let out = env
+            .run_test_command(&["--json", "validate"])
+            .expect_success();
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["valid"], true);
+        let out = env.run_test_command(&["--json", "stats"]).expect_success();
+        let stats: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(stats["objects"], 2);
 
-            // Ensure program output is correct
-            assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS_REF);
+        let corrupt = ContentHash::hash_bytes(SHA256, b"corrupt").to_hex_string();
+        let data_dir = env.store_dir.join("data");
+        fs::write(data_dir.join(&corrupt[..2]).join(&corrupt[2..]), b"bitrot").unwrap();
+        let out = env.run_test_command(&["--json", "validate"]).wait_output();
+        assert!(!out.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["hash_mismatches"].as_array().unwrap().len(), 1);
+
+        // Commands without JSON output refuse instead of printing text
+        let out = env
+            .run_test_command(&["--json", "gc", "--staging"])
+            .wait_output();
+        assert!(!out.status.success());
+        assert!(out.stdout.is_empty());
+    });
+}
+
+/// Check that garbage collection keeps the objects of other repositories sharing the store.
+#[test]
+fn test_register() {
+    run_test("register", |env| {
+        let other = TestEnv {
+            store_dir: env.store_dir.clone(),
+            ..TestEnv::new("register_other")
+        };
+        other.init_repo();
+        other.write_file("b.bin", b"from the other repository");
+        other.git(&["add", "."]);
+        other.git(&["commit", "-q", "-m", "other"]);
+        let _ = other.run_test_command(&["register"]).expect_success();
+        env.init_repo();
+        env.write_file("a.bin", b"from this repository");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "this"]);
+
+        let out = env
+            .run_test_command(&["register", "--list"])
+            .expect_success();
+        let git_dir = other.repo_dir.join(".git").canonicalize().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            format!("{}\n", git_dir.display())
+        );
+        let _ = env.run_test_command(&["gc"]).expect_success();
+        assert_data_count(env, 2);
+
+        let _ = env
+            .run_test_command(&["unregister", git_dir.to_str().unwrap()])
+            .expect_success();
+        let _ = env
+            .run_test_command(&["gc", "--grace-period", "0s"])
+            .expect_success();
+        assert_data_count(env, 1);
+        fs::remove_dir_all(&other.repo_dir).unwrap();
+    });
+}
+
+/// Check that pinned objects survive garbage collection until they are unpinned.
+#[test]
+fn test_pin() {
+    run_test("pin", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"referenced elsewhere");
+        env.git(&["add", "a.bin"]);
+        env.git(&["rm", "-q", "--cached", "a.bin"]);
+
+        let hash = ContentHash::hash_bytes(SHA256, b"referenced elsewhere").to_hex_string();
+        let _ = env.run_test_command(&["pin", &hash[..8]]).expect_success();
+        let out = env.run_test_command(&["pin", "--list"]).expect_success();
+        assert_eq!(String::from_utf8_lossy(&out), format!("{}\n", hash));
+        let _ = env.run_test_command(&["gc"]).expect_success();
+        assert_data_count(env, 1);
+        let out = env.run_test_command(&["delete", &hash]).wait_output();
+        assert!(!out.status.success());
+
+        let _ = env.run_test_command(&["unpin", &hash]).expect_success();
+        let _ = env
+            .run_test_command(&["gc", "--grace-period", "0s"])
+            .expect_success();
+        assert_data_count(env, 0);
+    });
+}
+
+/// Check that pruning follows the given and the saved retention policy.
+#[test]
+fn test_prune() {
+    run_test("prune", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"referenced");
+        env.git(&["add", "a.bin"]);
+        for contents in [&b"first"[..], b"second", b"third"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
         }
 
-        {
-            let mut bin = env.run_test_command(&["retrieve-file"]);
-            bin.stdin_send(TEST_CONTENTS_REF);
+        let _ = env
+            .run_test_command(&["prune", "--older-than", "1d"])
+            .expect_success();
+        assert_data_count(env, 4);
+        let out = env
+            .run_test_command(&["prune", "--keep-recent", "1", "--save"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).ends_with("removed 2 objects (11 B), kept 2\n"));
+        assert_data_count(env, 2);
+        assert_data_contents(env, b"third");
+        // The saved policy still keeps the last one
+        let _ = env.run_test_command(&["prune"]).expect_success();
+        assert_data_count(env, 2);
+        let _ = env
+            .run_test_command(&["prune", "--keep-recent", "0"])
+            .expect_success();
+        assert_data_count(env, 1);
+        assert_data_contents(env, b"referenced");
+    });
+}
 
-            // Ensure program output is correct
-            assert_eq!(bin.expect_success().as_slice(), TEST_CONTENTS);
+/// Check that pruning with `--filter` only deletes objects with matching labels.
+#[test]
+fn test_prune_filter() {
+    run_test("prune_filter", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"referenced");
+        env.git(&["add", "a.bin"]);
+        for contents in [&b"first"[..], b"second", b"third"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        for contents in [&b"referenced"[..], b"first", b"second"] {
+            let hash = ContentHash::hash_bytes(SHA256, contents).to_hex_string();
+            let _ = env
+                .run_test_command(&["note", "add", &hash, "temporary=true"])
+                .expect_success();
         }
 
-        // Ensure store is in a correct state:
+        let out = env
+            .run_test_command(&["prune", "--filter", "temporary=true"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).ends_with("removed 2 objects (11 B), kept 2\n"));
+        assert_data_count(env, 2);
+        assert_data_contents(env, b"third");
+        let out = env
+            .run_test_command(&["ls", "--filter", "!temporary"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out).split(' ').next().unwrap(),
+            ContentHash::hash_bytes(SHA256, b"third").to_hex_string()
+        );
+    });
+}
+
+/// Check that eviction removes the least recently used objects, but only those in the remote.
+#[test]
+fn test_evict() {
+    run_test("evict", |env| {
+        let remote = TestEnv::new("evict_remote");
+        let remote_arg = remote.store_dir.to_str().unwrap();
+        for contents in [&b"first object"[..], b"second object"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let _ = env
+            .run_test_command(&["--remote", remote_arg, "push"])
+            .expect_success();
+        let mut bin = env.run_test_command(&["store-file"]);
+        bin.stdin_send(b"third object");
+        let _ = bin.expect_success();
+        let first = ContentHash::hash_bytes(SHA256, b"first object").to_hex_string();
+        let _ = env.run_test_command(&["cat", &first]).expect_success();
+
+        let out = env
+            .run_test_command(&["--remote", remote_arg, "evict", "--max-size", "25"])
+            .expect_success();
+        assert!(String::from_utf8_lossy(&out).ends_with("evicted 1 objects (13 B), kept 2\n"));
+        assert_data_count(env, 2);
+
+        // The third object only exists locally
+        let _ = env
+            .run_test_command(&["--remote", remote_arg, "evict", "--max-size", "0"])
+            .expect_success();
+        assert_data_count(env, 1);
+        assert_data_contents(env, b"third object");
+        remote.remove_store();
+    });
+}
+
+/// Check that storing files fails cleanly once the quota would be exceeded.
+#[test]
+fn test_quota() {
+    run_test("quota", |env| {
+        let out = env.run_test_command(&["quota", "20"]).expect_success();
+        assert_eq!(String::from_utf8_lossy(&out), "0 B of 20 B used\n");
+        let store = |contents: &[u8]| {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            bin.wait_output().status.success()
+        };
+        assert!(store(b"first object"));
+        assert!(!store(b"second object"));
         assert_empty_staging(env);
+        // Storing what is already there takes no space
+        assert!(store(b"first object"));
         assert_data_count(env, 1);
-        assert_data_contents(env, TEST_CONTENTS);
+
+        // The usage is cached instead of listing the store every time, and recomputed by
+        // `validate` and `gc`
+        let usage = env.store_dir.join("usage");
+        assert_eq!(fs::read_to_string(&usage).unwrap(), "12\n");
+        fs::write(&usage, "20\n").unwrap();
+        assert!(!store(b"small"));
         let _ = env.run_test_command(&["validate"]).expect_success();
+        assert_eq!(fs::read_to_string(&usage).unwrap(), "12\n");
+        assert!(store(b"small"));
+        assert_eq!(fs::read_to_string(&usage).unwrap(), "17\n");
+
+        let _ = env
+            .run_test_command(&["quota", "--remove"])
+            .expect_success();
+        assert!(store(b"second object"));
+    });
+}
+
+/// Check that objects are copied between stores, skipping those already present.
+#[test]
+fn test_copy() {
+    run_test("copy", |env| {
+        let other = TestEnv::new("copy_other");
+        let other_arg = other.store_dir.to_str().unwrap();
+        let mut bin = other.run_test_command(&["store-file"]);
+        bin.stdin_send(b"already there");
+        let _ = bin.expect_success();
+        for contents in [TEST_CONTENTS, b"already there"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+
+        let out = env
+            .run_test_command(&["copy", "--to", other_arg])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "copied 1 objects (32 B), 1 already present\n"
+        );
+        assert_data_count(&other, 2);
+        assert_data_contents(&other, TEST_CONTENTS);
+        assert_empty_staging(&other);
+
+        // Only the given objects are copied, and missing ones are reported
+        let hash = ContentHash::hash_bytes(SHA256, b"already there").to_hex_string();
+        let missing = ContentHash::hash_bytes(SHA256, b"missing").to_hex_string();
+        fs::remove_dir_all(&env.store_dir).unwrap();
+        let mut bin = env.run_test_command(&["copy", "--from", other_arg, "--stdin"]);
+        bin.stdin_send(format!("{}\n{}\n", hash, missing).as_bytes());
+        let out = bin.wait_output();
+        assert!(!out.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            format!(
+                "missing: {}\ncopied 1 objects (13 B), 0 already present\n",
+                missing
+            )
+        );
+        assert_data_count(env, 1);
+        other.remove_store();
+    });
+}
+
+/// Check that exported archives contain the requested objects and are reproducible.
+#[test]
+#[cfg(feature = "archive")]
+fn test_export() {
+    run_test("export", |env| {
+        for contents in [&b"first object"[..], b"second object"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let first = ContentHash::hash_bytes(SHA256, b"first object").to_hex_string();
+        let archive = env.store_dir.with_extension("tar");
+
+        let _ = env
+            .run_test_command(&["export", archive.to_str().unwrap()])
+            .expect_success();
+        let all = fs::read(&archive).unwrap();
+        let _ = env
+            .run_test_command(&["export", archive.to_str().unwrap()])
+            .expect_success();
+        assert_eq!(fs::read(&archive).unwrap(), all);
+
+        // Another store with the same objects, stored in a different order
+        let other = TestEnv::new("export_other");
+        for contents in [&b"second object"[..], b"first object"] {
+            let mut bin = other.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        assert_eq!(
+            other.run_test_command(&["export", "-"]).expect_success(),
+            all
+        );
+        other.remove_store();
+
+        let list = |archive: &[u8]| {
+            let listing = process::Command::new("tar")
+                .args(["-t", "-f", "-"])
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .and_then(|mut tar| {
+                    tar.stdin.take().unwrap().write_all(archive)?;
+                    tar.wait_with_output()
+                })
+                .unwrap();
+            String::from_utf8_lossy(&listing.stdout).into_owned()
+        };
+        let mut bin = env.run_test_command(&["export", "--stdin", "-"]);
+        bin.stdin_send(format!("{}\n", &first[..8]).as_bytes());
+        let selected = bin.expect_success();
+        assert_eq!(list(&selected), format!("MANIFEST\nobjects/{}\n", first));
+
+        // Only the objects with matching labels
+        let second = ContentHash::hash_bytes(SHA256, b"second object").to_hex_string();
+        let _ = env
+            .run_test_command(&["note", "add", &second, "license=redistributable"])
+            .expect_success();
+        let selected = env
+            .run_test_command(&["export", "--filter", "license=redistributable", "-"])
+            .expect_success();
+        assert_eq!(list(&selected), format!("MANIFEST\nobjects/{}\n", second));
+        fs::remove_file(archive).unwrap();
+    });
+}
+
+/// Check that `backup verify` accepts an exported archive and reports damaged objects.
+#[test]
+#[cfg(feature = "archive")]
+fn test_backup_verify() {
+    run_test("backup_verify", |env| {
+        for contents in [&b"first object"[..], b"second object"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let first = ContentHash::hash_bytes(SHA256, b"first object").to_hex_string();
+        let archive = env.store_dir.with_extension("tar");
+        let archive_arg = archive.to_str().unwrap();
+        let _ = env
+            .run_test_command(&["export", archive_arg])
+            .expect_success();
+
+        let out = env
+            .run_test_command(&["backup", "verify", archive_arg])
+            .expect_success();
+        assert_eq!(String::from_utf8_lossy(&out), "verified 2 objects (25 B)\n");
+
+        // Same length, so only the hash can tell
+        let contents = fs::read(&archive).unwrap();
+        let offset = contents
+            .windows(12)
+            .position(|window| window == b"first object")
+            .unwrap();
+        let mut damaged = contents.clone();
+        damaged[offset] = b'F';
+        fs::write(&archive, &damaged).unwrap();
+
+        let mut bin = env.run_test_command(&["backup", "verify", "--format", "json", "-"]);
+        bin.stdin_send(&damaged);
+        let output = bin.wait_output();
+        assert_eq!(output.status.code(), Some(4));
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(report["verified"], 1);
+        assert_eq!(report["corrupt"][0], first);
+        fs::remove_file(archive).unwrap();
+    });
+}
+
+/// Check that importing an archive verifies the objects and skips those already present.
+#[test]
+#[cfg(feature = "archive")]
+fn test_import() {
+    run_test("import", |env| {
+        for contents in [&b"first object"[..], b"second object"] {
+            let mut bin = env.run_test_command(&["store-file"]);
+            bin.stdin_send(contents);
+            let _ = bin.expect_success();
+        }
+        let archive = env.store_dir.with_extension("tar");
+        let archive_arg = archive.to_str().unwrap();
+        let _ = env
+            .run_test_command(&["export", archive_arg])
+            .expect_success();
+
+        let other = TestEnv::new("import_other");
+        let out = other
+            .run_test_command(&["import", archive_arg])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "imported 2 objects (25 B), 0 already present\n"
+        );
+        assert_data_count(&other, 2);
+        assert_data_contents(&other, b"first object");
+        let out = other
+            .run_test_command(&["import", archive_arg])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "imported 0 objects (0 B), 2 already present\n"
+        );
+        other.remove_store();
+
+        // Corrupt contents are rejected
+        let contents = fs::read(&archive).unwrap();
+        let at = contents
+            .windows(12)
+            .position(|window| window == b"first object")
+            .unwrap();
+        let mut corrupt = contents.clone();
+        corrupt[at..at + 12].copy_from_slice(b"first OBJECT");
+        fs::write(&archive, corrupt).unwrap();
+        let out = other
+            .run_test_command(&["import", archive_arg])
+            .wait_output();
+        assert!(!out.status.success());
+        let first = ContentHash::hash_bytes(SHA256, b"first object").to_hex_string();
+        let out = other.run_test_command(&["exists", &first]).wait_output();
+        assert!(!out.status.success());
+        assert_empty_staging(&other);
+        other.remove_store();
+        fs::remove_file(archive).unwrap();
+    });
+}
+
+/// Check that garbage collection only removes objects that are no longer referenced.
+#[test]
+fn test_gc() {
+    run_test("gc", |env| {
+        env.init_repo();
+        env.write_file("a.bin", b"first version");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+        env.write_file("a.bin", b"second version");
+        env.git(&["commit", "-q", "-a", "-m", "second"]);
+        // staged, but never committed
+        env.write_file("b.bin", b"staged");
+        env.git(&["add", "b.bin"]);
+        // stored once, but then removed from the index again
+        env.write_file("c.bin", b"orphan");
+        env.git(&["add", "c.bin"]);
+        env.git(&["rm", "-q", "--cached", "c.bin"]);
+        // staged only in a linked worktree, which shares the store
+        env.git(&["worktree", "add", "-q", "wt"]);
+        env.write_file("wt/d.bin", b"staged elsewhere");
+        env.git(&["-C", "wt", "add", "d.bin"]);
+        assert_data_count(env, 5);
+
+        // The orphan was stored within the grace period
+        let _ = env.run_test_command(&["gc"]).expect_success();
+        assert_data_count(env, 5);
+        env.git(&["config", "assets.gcGracePeriod", "1h"]);
+        let (shard, rest) = ContentHash::hash_bytes(SHA256, b"orphan").sharded();
+        fs::File::options()
+            .write(true)
+            .open(env.store_dir.join("data").join(shard).join(rest))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+        let _ = env.run_test_command(&["gc"]).expect_success();
+        assert_data_count(env, 4);
+        let _ = env
+            .run_test_command(&["gc", "--grace-period", "0s"])
+            .expect_success();
+        assert_data_count(env, 4);
+        assert_data_contents(env, b"first version");
+        assert_data_contents(env, b"second version");
+        assert_data_contents(env, b"staged");
+        assert_data_contents(env, b"staged elsewhere");
+    });
+}
+
+/// Check that the manifest lists assets committed through the filter.
+#[test]
+fn test_manifest() {
+    run_test("manifest", |env| {
+        env.init_repo();
+        env.write_file("assets/a.bin", TEST_CONTENTS);
+        env.write_file("readme.txt", b"not an asset");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "add assets"]);
+
+        let out = env.run_test_command(&["manifest"]).expect_success();
+        let expected = format!(
+            "{} {:>12} assets/a.bin\n",
+            ContentHash::hash_bytes(SHA256, TEST_CONTENTS),
+            TEST_CONTENTS.len()
+        );
+        assert_eq!(String::from_utf8_lossy(&out), expected);
+
+        let out = env
+            .run_test_command(&["manifest", "--format", "json", "HEAD"])
+            .expect_success();
+        let json = String::from_utf8_lossy(&out);
+        assert!(json.contains("\"path\": \"assets/a.bin\""));
+        assert!(!json.contains("readme.txt"));
+
+        // Sizes and media types recorded in pointers are used without the object
+        env.git(&["config", "assets.pointer", "v2"]);
+        env.write_file("b.bin", b"0123456789");
+        env.git(&["add", "b.bin"]);
+        env.git(&["commit", "-q", "-m", "add b"]);
+        let (shard, rest) = ContentHash::hash_bytes(SHA256, b"0123456789").sharded();
+        fs::remove_file(env.store_dir.join("data").join(shard).join(rest)).unwrap();
+        let out = env.run_test_command(&["manifest"]).expect_success();
+        assert!(String::from_utf8_lossy(&out).ends_with("           10 b.bin\n"));
+        let out = env
+            .run_test_command(&["manifest", "--format", "json"])
+            .expect_success();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let b = &json["assets"][1];
+        assert_eq!(b["path"], "b.bin");
+        assert_eq!(b["size"], 10);
+        assert_eq!(b["media_type"], "text/plain");
+        assert_eq!(json["assets"][0]["media_type"], serde_json::Value::Null);
+    });
+}
+
+/// Check that `diff` reports added, removed and changed assets with their sizes, taking them
+/// from the pointers where they are recorded and from the store otherwise.
+#[test]
+fn test_diff() {
+    run_test("diff", |env| {
+        env.init_repo();
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.write_file("b.bin", b"other contents\n");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "first"]);
+
+        // Pointers in the v2 format record the size
+        env.git(&["config", "assets.pointer", "v2"]);
+        env.write_file("a.bin", &[b'a'; 100]);
+        fs::remove_file(env.repo_dir.join("b.bin")).unwrap();
+        env.write_file("c.bin", b"0123456789");
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "second"]);
+        for contents in [&[b'a'; 100][..], b"0123456789"] {
+            let (shard, rest) = ContentHash::hash_bytes(SHA256, contents).sharded();
+            fs::remove_file(env.store_dir.join("data").join(shard).join(rest)).unwrap();
+        }
+
+        let out = env.run_test_command(&["diff", "HEAD~1"]).expect_success();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "M a.bin (32 B -> 100 B)\n\
+             D b.bin (15 B)\n\
+             A c.bin (10 B)\n\
+             total: +63 B (63 bytes)\n"
+        );
+
+        let out = env
+            .run_test_command(&["diff", "HEAD", "HEAD~1", "--format", "json"])
+            .expect_success();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.contains("\"delta\": -63"));
+        assert!(json.contains("\"unknown_sizes\": 0"));
+    });
+}
+
+/// Check that `stats --history` attributes objects to the month and author of the commit that
+/// added them first.
+#[test]
+fn test_stats_history() {
+    run_test("stats_history", |env| {
+        env.init_repo();
+        let commit = |author: &str, date: &str| {
+            let status = process::Command::new("git")
+                .current_dir(&env.repo_dir)
+                .env("GIT_AUTHOR_NAME", author)
+                .env("GIT_AUTHOR_DATE", date)
+                .env("GIT_COMMITTER_DATE", date)
+                .args(["commit", "-q", "-m", author])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        env.write_file("a.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        commit("Alice", "2024-01-15T12:00:00Z");
+        env.write_file("b.bin", &[b'b'; 100]);
+        env.write_file("c.bin", b"0123456789");
+        // The same object again is not counted twice
+        env.write_file("d.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        commit("Bob", "2024-03-02T12:00:00Z");
+
+        let out = env
+            .run_test_command(&["stats", "--history", "--format", "csv"])
+            .expect_success();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "month,new_objects,new_bytes,total_bytes\n\
+             2024-01,1,32,32\n\
+             2024-03,2,110,142\n"
+        );
+
+        let out = env
+            .run_test_command(&["stats", "--history"])
+            .expect_success();
+        let out = String::from_utf8(out).unwrap();
+        let top: Vec<Vec<&str>> = out
+            .lines()
+            .skip_while(|line| *line != "top contributors:")
+            .skip(1)
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            top,
+            [
+                vec!["110", "B", "2", "objects", "Bob"],
+                vec!["32", "B", "1", "objects", "Alice"]
+            ]
+        );
+    });
+}
+
+/// Check that `du --by-path` attributes sizes to every containing directory.
+#[test]
+fn test_du_by_path() {
+    run_test("du_by_path", |env| {
+        env.init_repo();
+        env.write_file("x/a.bin", TEST_CONTENTS);
+        env.write_file("x/y/b.bin", TEST_CONTENTS);
+        env.git(&["add", "."]);
+        env.git(&["commit", "-q", "-m", "add assets"]);
+
+        let out = env.run_test_command(&["du", "--by-path"]).expect_success();
+        let out = String::from_utf8_lossy(&out);
+        let lines: Vec<Vec<&str>> = out
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        // deduplicated and total sizes are printed with their unit
+        assert_eq!(lines[1], vec!["32", "B", "64", "B", "2", "."]);
+        assert_eq!(lines[2], vec!["32", "B", "64", "B", "2", "x"]);
+        assert_eq!(lines[3], vec!["32", "B", "32", "B", "1", "x/y"]);
+    });
+}
+
+/// Check that tracked paths are determined from the gitattributes.
+#[test]
+fn test_tracked_paths() {
+    use git_assets_lib::attributes::{self, AttrSource};
+
+    run_test("tracked_paths", |env| {
+        env.init_repo();
+        env.write_file(
+            "sub/.gitattributes",
+            b"*.psd filter=assets\nskip.bin -filter\n",
+        );
+        let repo = git_assets_lib::git::Repository::new(env.repo_dir.clone());
+
+        let paths = ["a.bin", "a.txt", "sub/b.psd", "sub/skip.bin", "c.psd"];
+        let tracked = attributes::tracked_paths(
+            &repo,
+            attributes::DEFAULT_FILTER,
+            &paths,
+            AttrSource::WorkTree,
+        )
+        .unwrap();
+        assert_eq!(tracked, vec!["a.bin", "sub/b.psd"]);
+
+        // Nothing has been staged yet, so the index knows no attributes
+        let tracked =
+            attributes::tracked_paths(&repo, attributes::DEFAULT_FILTER, &paths, AttrSource::Index)
+                .unwrap();
+        assert!(tracked.is_empty());
+    });
+}
+
+/// Check that completion scripts are generated for the supported shells.
+#[test]
+fn test_completions() {
+    run_test("completions", |env| {
+        let out = env
+            .run_test_command(&["completions", "bash"])
+            .expect_success();
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("_git-assets()"));
+        assert!(script.contains("store-file"));
+        let out = env
+            .run_test_command(&["completions", "fish"])
+            .expect_success();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("complete -c git-assets"));
+
+        let out = env.run_test_command(&["completions", "tcsh"]).wait_output();
+        assert!(!out.status.success());
     });
 }
 
@@ -91,16 +4047,19 @@ fn assert_empty_staging(env: &TestEnv) {
 }
 
 fn assert_data_count(env: &TestEnv, num_data_files: usize) {
-    assert_eq!(
-        fs::read_dir(env.store_dir.join("data")).unwrap().count(),
-        num_data_files
-    );
+    // Objects are spread over one subdirectory per first byte of their hash
+    let count: usize = fs::read_dir(env.store_dir.join("data"))
+        .unwrap()
+        .map(|shard| fs::read_dir(shard.unwrap().path()).unwrap().count())
+        .sum();
+    assert_eq!(count, num_data_files);
 }
 
 /// Assert that the given contents are stored in a data file with the corresponding hash as name.
 fn assert_data_contents(env: &TestEnv, contents: &[u8]) {
-    let hash = git_assets_lib::hash::Sha256Hash::hash_bytes(contents);
-    let actual = fs::read(env.store_dir.join("data").join(hash.to_hex_string())).unwrap();
+    let hash = ContentHash::hash_bytes(SHA256, contents).to_hex_string();
+    let path = env.store_dir.join("data").join(&hash[..2]).join(&hash[2..]);
+    let actual = fs::read(path).unwrap();
     assert_eq!(actual.as_slice(), contents);
 }
 
@@ -144,6 +4103,8 @@ impl GitAssetsChild {
 
 struct TestEnv {
     store_dir: PathBuf,
+    /// Git repository used by tests that need one, only created by `init_repo`.
+    repo_dir: PathBuf,
     bin: PathBuf,
 }
 
@@ -169,12 +4130,63 @@ impl TestEnv {
             );
         }
 
-        Self { store_dir, bin }
+        let repo_dir =
+            std::env::temp_dir().join(format!("git-assets.{}.{}.repo", name, process_id));
+
+        Self {
+            store_dir,
+            repo_dir,
+            bin,
+        }
+    }
+
+    /// Create a git repository that uses git-assets as filter for `*.bin` files.
+    fn init_repo(&self) {
+        fs::create_dir(&self.repo_dir).expect("could not create test repository");
+        self.git(&["init", "-q"]);
+        self.git(&["config", "user.name", "git-assets test"]);
+        self.git(&["config", "user.email", "test@example.com"]);
+        let filter = |command: &str| {
+            format!(
+                "'{}' --store '{}' {}",
+                self.bin.display(),
+                self.store_dir.display(),
+                command
+            )
+        };
+        self.git(&["config", "filter.assets.clean", &filter("store-file")]);
+        self.git(&["config", "filter.assets.smudge", &filter("retrieve-file")]);
+        self.git(&["config", "filter.assets.required", "true"]);
+        self.write_file(".gitattributes", b"*.bin filter=assets\n");
+    }
+
+    /// Write a file in the test repository, creating parent directories as needed.
+    fn write_file(&self, path: &str, contents: &[u8]) {
+        let path = self.repo_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Run git in the test repository, assert that it succeeded and return its stdout.
+    fn git(&self, args: &[&str]) -> Vec<u8> {
+        let out = process::Command::new("git")
+            .current_dir(&self.repo_dir)
+            .args(args)
+            .output()
+            .expect("could not run git");
+        if !out.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&out.stderr));
+        }
+        assert!(out.status.success(), "git {:?} failed", args);
+        out.stdout
     }
 
     /// Build a test command with piped stdin/stdout and an initial `--store` argument.
     fn build_test_cmd(&self) -> process::Command {
         let mut cmd = process::Command::new(&self.bin);
+        if self.repo_dir.exists() {
+            cmd.current_dir(&self.repo_dir);
+        }
         cmd.arg("--store")
             .arg(&self.store_dir)
             .stdin(process::Stdio::piped())
@@ -193,13 +4205,29 @@ impl TestEnv {
     }
 
     fn remove_store(&self) {
-        fs::remove_dir_all(&self.store_dir).expect("could not clean up temp store");
+        if self.store_dir.exists() {
+            fs::remove_dir_all(&self.store_dir).expect("could not clean up temp store");
+        }
+        if self.repo_dir.exists() {
+            fs::remove_dir_all(&self.repo_dir).expect("could not clean up temp repository");
+        }
     }
 }
 
+/// Set a git config variable for a command and the git processes it runs, like `git -c`.
+fn with_git_config<'a>(
+    cmd: &'a mut process::Command,
+    key: &str,
+    value: &str,
+) -> &'a mut process::Command {
+    cmd.env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", key)
+        .env("GIT_CONFIG_VALUE_0", value)
+}
+
 /// Generate a temporary store directory name and call the closure.
 /// This does not yet create the store directory.
-fn run_test<F: FnOnce(&TestEnv) -> () + std::panic::UnwindSafe>(name: &str, callback: F) {
+fn run_test<F: FnOnce(&TestEnv) + std::panic::UnwindSafe>(name: &str, callback: F) {
     let env = TestEnv::new(name);
 
     eprintln!("Test using store: {}", env.store_dir.display());